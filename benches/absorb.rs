@@ -0,0 +1,95 @@
+use criterion::{criterion_group, criterion_main, BenchmarkId, Criterion};
+
+// shared with the tests, whose style it keeps
+#[allow(dead_code, clippy::needless_borrow)]
+#[path = "../src/tests/repo_utils.rs"]
+mod repo_utils;
+
+/// (commits in the stack, staged hunks)
+const SIZES: &[(usize, usize)] = &[(10, 10), (50, 100), (200, 400)];
+
+fn plan(c: &mut Criterion) {
+    let logger = slog::Logger::root(slog::Discard, slog::o!());
+    let rebase_options = Vec::new();
+    let config = git_absorb::Config {
+        dry_run: true,
+        force_author: false,
+        force_detach: false,
+        cross_branch_boundaries: false,
+        force_rewrite: false,
+        force_overlap: false,
+        base: &[],
+        range: None,
+        diff_algorithm: None,
+        diff_context: None,
+        and_rebase: false,
+        no_rebase: false,
+        rebase_options: &rebase_options,
+        whole_file: false,
+        split_hunks: false,
+        one_fixup_per_commit: false,
+        squash: false,
+        message: None,
+        unstaged: false,
+        from_stash: None,
+        patch_file: None,
+        into: None,
+        target_touched: None,
+        verify_build: None,
+        paths: &[],
+        exclude_hunks_matching: &[],
+        include_hunks_matching: &[],
+        recurse_submodules: false,
+        select: false,
+        yes: false,
+        check: false,
+        all_or_nothing: false,
+        print_stack: false,
+        undo: false,
+        emit_script: false,
+        annotate: false,
+        show_final: false,
+        export_series: None,
+        stage_only: false,
+        rewrite: false,
+        extract: false,
+        amend_head: false,
+        only_my_lines: false,
+        additions_only: false,
+        deletions_only: false,
+        stat: false,
+        config_file: None,
+        stats_file: None,
+        porcelain: false,
+        timings: false,
+        truncate_subject: false,
+        fixup_by_sha: false,
+        time_limit: None,
+        max_report: None,
+        plan_only: None,
+        apply_plan: None,
+    };
+
+    let mut group = c.benchmark_group("plan");
+    for &(commits, hunks) in SIZES {
+        let (ctx, _) = repo_utils::prepare_synthetic_stack(commits, hunks);
+        repo_utils::set_config_option(&ctx.repo, "absorb.maxStack", &(commits + 1).to_string());
+
+        // run() discovers the repository from the environment, and a dry run
+        // leaves it untouched, so the same repository serves every iteration
+        std::env::set_var("GIT_DIR", ctx.repo.path());
+        std::env::set_var("GIT_WORK_TREE", ctx.dir.path());
+        group.bench_with_input(
+            BenchmarkId::from_parameter(format!("{commits}x{hunks}")),
+            &config,
+            |b, config| b.iter(|| git_absorb::run(&logger, config).unwrap()),
+        );
+    }
+    group.finish();
+
+    std::env::remove_var("GIT_DIR");
+    std::env::remove_var("GIT_WORK_TREE");
+}
+
+criterion_group!(benches, plan);
+criterion_main!(benches);
@@ -1,52 +1,30 @@
 use crate::owned;
 
-/// Tests if all elements of the iterator are equal to each other.
-///
-/// An empty iterator returns `true`.
-///
-/// `uniform()` is short-circuiting. It will stop processing as soon
-/// as it finds two pairwise inequal elements.
-fn uniform<I, E>(iter: I) -> bool
-where
-    I: IntoIterator<Item = E>,
-    E: Eq,
-{
-    let mut iter = iter.into_iter();
-    match iter.next() {
-        Some(first) => iter.all(|e| e == first),
-        None => true,
-    }
-}
+use std::rc::Rc;
 
-pub fn commute(first: &owned::Hunk, second: &owned::Hunk) -> Option<(owned::Hunk, owned::Hunk)> {
+/// Swaps two hunks, `first` applied before `second`, if they can be applied
+/// in the other order, which they can if there are more than `context`
+/// unchanged lines between them.
+///
+/// Only where the hunks are decides, never what lines they change, so that
+/// each hunk is matched on its own: a hunk that adds the same lines as one
+/// right next to it never moves past it.
+pub fn commute(
+    first: &owned::Hunk,
+    second: &owned::Hunk,
+    context: usize,
+) -> Option<(owned::Hunk, owned::Hunk)> {
     let (_, _, first_upper, first_lower) = first.anchors();
     let (second_upper, second_lower, _, _) = second.anchors();
 
     // represent hunks in content order rather than application order
     let (first_above, above, below) = {
-        if first_lower <= second_upper {
+        if first_lower + context <= second_upper {
             (true, first, second)
-        } else if second_lower <= first_upper {
+        } else if second_lower + context <= first_upper {
             (false, second, first)
         } else {
-            // if both hunks are exclusively adding or removing, and
-            // both hunks are composed entirely of the same line being
-            // repeated, then they commute no matter what their
-            // offsets are, because they can be interleaved in any
-            // order without changing the final result
-            if (first.added.lines.is_empty()
-                && second.added.lines.is_empty()
-                && uniform(first.removed.lines.iter().chain(&*second.removed.lines)))
-                || (first.removed.lines.is_empty()
-                    && second.removed.lines.is_empty()
-                    && uniform(first.added.lines.iter().chain(&*second.added.lines)))
-            {
-                // TODO: removed/added start positions probably need to be
-                // tweaked here
-                return Some((second.clone(), first.clone()));
-            }
-            // these hunks overlap and cannot be interleaved, so they
-            // do not commute
+            // these hunks overlap, so they do not commute
             return None;
         }
     };
@@ -65,7 +43,11 @@ pub fn commute(first: &owned::Hunk, second: &owned::Hunk) -> Option<(owned::Hunk
     })
 }
 
-pub fn commute_diff_before<'a, I>(after: &owned::Hunk, before: I) -> Option<owned::Hunk>
+pub fn commute_diff_before<'a, I>(
+    after: &owned::Hunk,
+    before: I,
+    context: usize,
+) -> Option<owned::Hunk>
 where
     I: IntoIterator<Item = &'a owned::Hunk>,
     <I as IntoIterator>::IntoIter: DoubleEndedIterator,
@@ -78,14 +60,97 @@ where
         // happens to be reverse of the order they're stored
         .rev()
         .try_fold(after.clone(), |after, next| {
-            commute(next, &after).map(|(commuted_after, _)| commuted_after)
+            commute(next, &after, context).map(|(commuted_after, _)| commuted_after)
         })
 }
 
+/// Does the same as [`commute_diff_before`] for the hunks of one patch,
+/// which are sorted by line, without commuting with each of them in turn.
+///
+/// The hunks below `after` leave it where it is, and the ones above it only
+/// move it by how many lines they add or remove, so binary searches find
+/// the few hunks in between, next to or overlapping `after`, which are the
+/// only ones it has to be commuted with.
+pub fn commute_patch_before(
+    after: &owned::Hunk,
+    before: &[owned::Hunk],
+    context: usize,
+) -> Option<owned::Hunk> {
+    let (after_upper, after_lower, _, _) = after.anchors();
+    let above = before.partition_point(|hunk| hunk.anchors().3 + context <= after_upper);
+    let below = before.partition_point(|hunk| {
+        let (_, _, upper, lower) = hunk.anchors();
+        lower + context <= after_upper || after_lower + context > upper
+    });
+    let mut commuted = commute_diff_before(after, &before[above..below], context)?;
+    let offset: isize = before[..above]
+        .iter()
+        .map(owned::Hunk::changed_offset)
+        .sum();
+    commuted.added.start = (commuted.added.start as isize - offset) as usize;
+    commuted.removed.start = (commuted.removed.start as isize - offset) as usize;
+    Some(commuted)
+}
+
+/// Splits `hunk` into one hunk for each removed line, each with its share
+/// of the added lines, so that the parts can be absorbed separately.
+///
+/// A hunk that removes nothing cannot be split.
+pub fn split(hunk: &owned::Hunk) -> Vec<owned::Hunk> {
+    let removed = hunk.removed.lines.len();
+    let added = hunk.added.lines.len();
+    if removed == 0 {
+        return vec![hunk.clone()];
+    }
+    (0..removed)
+        .map(|idx| {
+            let (lo, hi) = (idx * added / removed, (idx + 1) * added / removed);
+            owned::Hunk {
+                removed: owned::Block {
+                    start: hunk.removed.start + idx,
+                    lines: Rc::new(hunk.removed.lines.slice(idx..idx + 1)),
+                },
+                added: owned::Block {
+                    // an empty block starts at the line before it
+                    start: match (added, hi > lo) {
+                        (0, _) => hunk.added.start,
+                        (_, true) => hunk.added.start + lo,
+                        (_, false) => hunk.added.start + lo - 1,
+                    },
+                    lines: Rc::new(hunk.added.lines.slice(lo..hi)),
+                },
+                funcname: hunk.funcname.clone(),
+            }
+        })
+        .collect()
+}
+
+/// Joins two hunks that are right next to each other, as [`split`]
+/// leaves them.
+pub fn join(above: &owned::Hunk, below: &owned::Hunk) -> owned::Hunk {
+    let concat = |above: &owned::Block, below: &owned::Block| {
+        Rc::new(above.lines.iter().chain(&*below.lines).collect())
+    };
+    owned::Hunk {
+        removed: owned::Block {
+            start: above.removed.start,
+            lines: concat(&above.removed, &below.removed),
+        },
+        added: owned::Block {
+            start: if above.added.lines.is_empty() && !below.added.lines.is_empty() {
+                below.added.start
+            } else {
+                above.added.start
+            },
+            lines: concat(&above.added, &below.added),
+        },
+        funcname: above.funcname.clone(),
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
-    use std::rc::Rc;
 
     #[test]
     fn test_commute() {
@@ -95,12 +160,13 @@ mod tests {
         let hunk1 = owned::Hunk {
             added: owned::Block {
                 start: 2,
-                lines: Rc::new(vec![b"bar\n".to_vec()]),
+                lines: lines(&["bar\n"]),
             },
             removed: owned::Block {
                 start: 1,
-                lines: Rc::new(vec![]),
+                lines: lines(&[]),
             },
+            funcname: None,
         };
         // after hunk1: <<EOF
         // foo
@@ -109,12 +175,13 @@ mod tests {
         let hunk2 = owned::Hunk {
             added: owned::Block {
                 start: 1,
-                lines: Rc::new(vec![b"bar\n".to_vec()]),
+                lines: lines(&["bar\n"]),
             },
             removed: owned::Block {
                 start: 0,
-                lines: Rc::new(vec![]),
+                lines: lines(&[]),
             },
+            funcname: None,
         };
         // after hunk2: <<EOF
         // bar
@@ -122,67 +189,69 @@ mod tests {
         // bar
         // EOF
 
-        let (new1, new2) = commute(&hunk1, &hunk2).unwrap();
+        let (new1, new2) = commute(&hunk1, &hunk2, 0).unwrap();
         assert_eq!(new1.added.start, 1);
         assert_eq!(new2.added.start, 3);
     }
 
     #[test]
-    fn test_commute_trivial_add() {
-        let mut line = ::std::iter::repeat(b"bar\n".to_vec());
+    fn test_commute_same_lines_added() {
+        let mut line = ::std::iter::repeat(&b"bar\n"[..]);
         let hunk1 = owned::Hunk {
             added: owned::Block {
                 start: 1,
-                lines: Rc::new((&mut line).take(4).collect::<Vec<_>>()),
+                lines: Rc::new((&mut line).take(4).collect::<owned::Lines>()),
             },
             removed: owned::Block {
                 start: 0,
-                lines: Rc::new(vec![]),
+                lines: lines(&[]),
             },
+            funcname: None,
         };
         let hunk2 = owned::Hunk {
             added: owned::Block {
                 start: 1,
-                lines: Rc::new((&mut line).take(2).collect::<Vec<_>>()),
+                lines: Rc::new((&mut line).take(2).collect::<owned::Lines>()),
             },
             removed: owned::Block {
                 start: 0,
-                lines: Rc::new(vec![]),
+                lines: lines(&[]),
             },
+            funcname: None,
         };
 
-        let (new1, new2) = commute(&hunk1, &hunk2).unwrap();
-        assert_eq!(new1.added.lines.len(), 2);
-        assert_eq!(new2.added.lines.len(), 4);
+        // adding the same line right next to where it was added before does
+        // not make the hunks independent
+        assert!(commute(&hunk1, &hunk2, 0).is_none());
     }
 
     #[test]
-    fn test_commute_trivial_remove() {
-        let mut line = ::std::iter::repeat(b"bar\n".to_vec());
+    fn test_commute_same_lines_removed() {
+        let mut line = ::std::iter::repeat(&b"bar\n"[..]);
         let hunk1 = owned::Hunk {
             added: owned::Block {
                 start: 1,
-                lines: Rc::new(vec![]),
+                lines: lines(&[]),
             },
             removed: owned::Block {
                 start: 4,
-                lines: Rc::new((&mut line).take(4).collect::<Vec<_>>()),
+                lines: Rc::new((&mut line).take(4).collect::<owned::Lines>()),
             },
+            funcname: None,
         };
         let hunk2 = owned::Hunk {
             added: owned::Block {
                 start: 1,
-                lines: Rc::new(vec![]),
+                lines: lines(&[]),
             },
             removed: owned::Block {
                 start: 2,
-                lines: Rc::new((&mut line).take(2).collect::<Vec<_>>()),
+                lines: Rc::new((&mut line).take(2).collect::<owned::Lines>()),
             },
+            funcname: None,
         };
 
-        let (new1, new2) = commute(&hunk1, &hunk2).unwrap();
-        assert_eq!(new1.removed.lines.len(), 2);
-        assert_eq!(new2.removed.lines.len(), 4);
+        assert!(commute(&hunk1, &hunk2, 0).is_none());
     }
 
     #[test]
@@ -195,22 +264,24 @@ mod tests {
             owned::Hunk {
                 added: owned::Block {
                     start: 1,
-                    lines: Rc::new(vec![b"bar\n".to_vec()]),
+                    lines: lines(&["bar\n"]),
                 },
                 removed: owned::Block {
                     start: 0,
-                    lines: Rc::new(vec![]),
+                    lines: lines(&[]),
                 },
+                funcname: None,
             },
             owned::Hunk {
                 added: owned::Block {
                     start: 3,
-                    lines: Rc::new(vec![b"bar\n".to_vec()]),
+                    lines: lines(&["bar\n"]),
                 },
                 removed: owned::Block {
                     start: 1,
-                    lines: Rc::new(vec![]),
+                    lines: lines(&[]),
                 },
+                funcname: None,
             },
         ];
         // after patch: <<EOF
@@ -222,12 +293,13 @@ mod tests {
         let hunk = owned::Hunk {
             added: owned::Block {
                 start: 5,
-                lines: Rc::new(vec![b"bar\n".to_vec()]),
+                lines: lines(&["bar\n"]),
             },
             removed: owned::Block {
                 start: 4,
-                lines: Rc::new(vec![]),
+                lines: lines(&[]),
             },
+            funcname: None,
         };
         // after hunk: <<EOF
         // bar
@@ -237,7 +309,185 @@ mod tests {
         // bar
         // EOF
 
-        let commuted = commute_diff_before(&hunk, &patch).unwrap();
+        let commuted = commute_diff_before(&hunk, &patch, 0).unwrap();
         assert_eq!(commuted.added.start, 3);
     }
+
+    /// Builds a hunk that removes `removed` lines at `removed_start`, and adds
+    /// `added` lines at `added_start`, giving empty blocks the line before.
+    fn hunk_at(
+        removed_start: usize,
+        removed: usize,
+        added_start: usize,
+        added: usize,
+    ) -> owned::Hunk {
+        let block = |start: usize, len: usize, line: &[u8]| owned::Block {
+            start: if len == 0 { start - 1 } else { start },
+            lines: Rc::new(std::iter::repeat(line).take(len).collect()),
+        };
+        owned::Hunk {
+            added: block(added_start, added, b"x\n"),
+            removed: block(removed_start, removed, b"y\n"),
+            funcname: None,
+        }
+    }
+
+    #[test]
+    fn test_commute_patch_matches_commuting_each_hunk() {
+        // a patch of hunks of every shape, with one or two lines between them
+        let mut patch = vec![];
+        let (mut old_line, mut new_line) = (0, 0);
+        for idx in 0..30 {
+            let gap = 1 + idx % 2;
+            let (removed, added) = [(1, 1), (0, 1), (1, 0), (2, 1), (0, 2), (3, 0)][idx % 6];
+            patch.push(hunk_at(
+                old_line + gap + 1,
+                removed,
+                new_line + gap + 1,
+                added,
+            ));
+            old_line += gap + removed;
+            new_line += gap + added;
+        }
+
+        for context in [0, 2] {
+            for line in 1..new_line + 3 {
+                for (removed, added) in [(1, 1), (0, 1), (1, 0), (2, 3), (0, 2)] {
+                    let hunk = hunk_at(line, removed, line, added);
+                    assert_eq!(
+                        commute_patch_before(&hunk, &patch, context).map(|hunk| hunk.header()),
+                        commute_diff_before(&hunk, &patch, context).map(|hunk| hunk.header()),
+                        "{} with {} lines of context",
+                        hunk.header(),
+                        context
+                    );
+                }
+            }
+        }
+    }
+
+    /// Builds a hunk replacing line `line` (on both sides) with another line.
+    fn modification(line: usize) -> owned::Hunk {
+        owned::Hunk {
+            added: owned::Block {
+                start: line,
+                lines: lines(&["new\n"]),
+            },
+            removed: owned::Block {
+                start: line,
+                lines: lines(&["old\n"]),
+            },
+            funcname: None,
+        }
+    }
+
+    /// The second hunk of a patch that removed one line above, then removed
+    /// lines 8 and 9, so it reads `-8,2 +6,0`.
+    fn shifted_deletion() -> owned::Hunk {
+        owned::Hunk {
+            added: owned::Block {
+                start: 6,
+                lines: lines(&[]),
+            },
+            removed: owned::Block {
+                start: 8,
+                lines: lines(&["foo\n", "bar\n"]),
+            },
+            funcname: None,
+        }
+    }
+
+    /// The second hunk of a patch that added one line above, then added
+    /// two lines after line 5, so it reads `-5,0 +7,2`.
+    fn shifted_insertion() -> owned::Hunk {
+        owned::Hunk {
+            added: owned::Block {
+                start: 7,
+                lines: lines(&["foo\n", "bar\n"]),
+            },
+            removed: owned::Block {
+                start: 5,
+                lines: lines(&[]),
+            },
+            funcname: None,
+        }
+    }
+
+    #[test]
+    fn test_anchors_of_empty_blocks() {
+        assert_eq!(shifted_deletion().anchors(), (7, 10, 6, 7));
+        assert_eq!(shifted_insertion().anchors(), (5, 6, 6, 9));
+    }
+
+    #[test]
+    fn test_commute_immediately_before_deletion() {
+        let (commuted, _) = commute(&shifted_deletion(), &modification(5), 0).unwrap();
+        assert_eq!(commuted.added.start, 5);
+        assert_eq!(commuted.removed.start, 5);
+    }
+
+    #[test]
+    fn test_commute_adjacent_to_deletion() {
+        // the lines right above and right below the removed ones
+        assert!(commute(&shifted_deletion(), &modification(6), 0).is_none());
+        assert!(commute(&shifted_deletion(), &modification(7), 0).is_none());
+    }
+
+    #[test]
+    fn test_commute_immediately_after_deletion() {
+        let (commuted, _) = commute(&shifted_deletion(), &modification(8), 0).unwrap();
+        assert_eq!(commuted.added.start, 10);
+        assert_eq!(commuted.removed.start, 10);
+    }
+
+    #[test]
+    fn test_commute_around_insertion() {
+        let (commuted, _) = commute(&shifted_insertion(), &modification(5), 0).unwrap();
+        assert_eq!(commuted.added.start, 5);
+
+        // the lines right above and right below the added ones
+        assert!(commute(&shifted_insertion(), &modification(6), 0).is_none());
+        assert!(commute(&shifted_insertion(), &modification(9), 0).is_none());
+
+        let (commuted, _) = commute(&shifted_insertion(), &modification(10), 0).unwrap();
+        assert_eq!(commuted.added.start, 8);
+        assert_eq!(commuted.removed.start, 8);
+    }
+
+    fn lines(lines: &[&str]) -> Rc<owned::Lines> {
+        Rc::new(lines.iter().map(|line| line.as_bytes()).collect())
+    }
+
+    #[test]
+    fn test_split_proportionally() {
+        // -3,3 +3,2
+        let hunk = owned::Hunk {
+            removed: owned::Block {
+                start: 3,
+                lines: lines(&["a\n", "b\n", "c\n"]),
+            },
+            added: owned::Block {
+                start: 3,
+                lines: lines(&["x\n", "y\n"]),
+            },
+            funcname: None,
+        };
+        let parts = split(&hunk);
+        let headers: Vec<_> = parts.iter().map(owned::Hunk::header).collect();
+        assert_eq!(headers, vec!["-3,1 +2,0", "-4,1 +3,1", "-5,1 +4,1"]);
+        assert_eq!(*parts[2].added.lines, *lines(&["y\n"]));
+
+        let joined = join(&join(&parts[0], &parts[1]), &parts[2]);
+        assert_eq!(joined.header(), hunk.header());
+        assert_eq!(joined.removed.lines, hunk.removed.lines);
+        assert_eq!(joined.added.lines, hunk.added.lines);
+    }
+
+    #[test]
+    fn test_split_deletion() {
+        let parts = split(&shifted_deletion());
+        let headers: Vec<_> = parts.iter().map(owned::Hunk::header).collect();
+        assert_eq!(headers, vec!["-8,1 +6,0", "-9,1 +6,0"]);
+        assert_eq!(split(&shifted_insertion()).len(), 1);
+    }
 }
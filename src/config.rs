@@ -1,5 +1,7 @@
 use crate::Config;
-use git2::Repository;
+use anyhow::{anyhow, Result};
+use git2::{ConfigLevel, Repository};
+use std::path::Path;
 
 pub const MAX_STACK_CONFIG_NAME: &str = "absorb.maxStack";
 pub const MAX_STACK: usize = 10;
@@ -10,18 +12,126 @@ pub const FORCE_AUTHOR_DEFAULT: bool = false;
 pub const FORCE_DETACH_CONFIG_NAME: &str = "absorb.forceDetach";
 pub const FORCE_DETACH_DEFAULT: bool = false;
 
+pub const CROSS_BRANCH_BOUNDARIES_CONFIG_NAME: &str = "absorb.crossBranchBoundaries";
+pub const CROSS_BRANCH_BOUNDARIES_DEFAULT: bool = false;
+
+pub const SPLIT_HUNKS_CONFIG_NAME: &str = "absorb.splitHunks";
+pub const SPLIT_HUNKS_DEFAULT: bool = false;
+
 pub const ONE_FIXUP_PER_COMMIT_CONFIG_NAME: &str = "absorb.oneFixupPerCommit";
 pub const ONE_FIXUP_PER_COMMIT_DEFAULT: bool = false;
 
+pub const ALL_OR_NOTHING_CONFIG_NAME: &str = "absorb.allOrNothing";
+pub const ALL_OR_NOTHING_DEFAULT: bool = false;
+
 pub const AUTO_STAGE_IF_NOTHING_STAGED_CONFIG_NAME: &str = "absorb.autoStageIfNothingStaged";
 pub const AUTO_STAGE_IF_NOTHING_STAGED_DEFAULT: bool = false;
 
 pub const FIXUP_TARGET_ALWAYS_SHA_CONFIG_NAME: &str = "absorb.fixupTargetAlwaysSHA";
-pub const FIXUP_TARGET_ALWAYS_SHA_DEFAULT: bool = false;
+pub const FIXUP_TARGET_ALWAYS_SHA_DEFAULT: FixupTarget = FixupTarget::Auto;
+
+/// How a fixup message names the commit it fixes up.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FixupTarget {
+    /// By its subject, or by its SHA if another commit in the stack has
+    /// the same subject.
+    Subject,
+    /// Always by its SHA.
+    Sha,
+    /// By its subject, or by its SHA if autosquash could mistake another
+    /// commit in the stack for it.
+    Auto,
+}
 
 pub const CREATE_SQUASH_COMMITS_CONFIG_NAME: &str = "absorb.createSquashCommits";
 pub const CREATE_SQUASH_COMMITS_DEFAULT: bool = false;
 
+pub const AMEND_HEAD_WHEN_POSSIBLE_CONFIG_NAME: &str = "absorb.amendHeadWhenPossible";
+pub const AMEND_HEAD_WHEN_POSSIBLE_DEFAULT: bool = false;
+
+pub const AND_REBASE_ENV_NAME: &str = "GIT_ABSORB_AND_REBASE";
+pub const AND_REBASE_CONFIG_NAME: &str = "absorb.andRebase";
+pub const AND_REBASE_DEFAULT: bool = false;
+
+pub const REBASE_WHOLE_STACK_CONFIG_NAME: &str = "absorb.rebaseWholeStack";
+pub const REBASE_WHOLE_STACK_DEFAULT: bool = false;
+
+pub const EXCLUDE_PATHS_CONFIG_NAME: &str = "absorb.excludePaths";
+pub const EXCLUDE_PATHS_DEFAULT: &str = "target/ node_modules/ vendor/";
+
+pub const EXCLUDE_HUNK_PATTERN_CONFIG_NAME: &str = "absorb.excludeHunkPattern";
+
+pub const WRITE_NOTES_CONFIG_NAME: &str = "absorb.writeNotes";
+pub const WRITE_NOTES_DEFAULT: bool = false;
+
+pub const REMOTES_TO_PROTECT_CONFIG_NAME: &str = "absorb.remotesToProtect";
+
+pub const REBASE_AUTO_STASH_CONFIG_NAME: &str = "rebase.autoStash";
+
+pub const IGNORE_CASE_CONFIG_NAME: &str = "core.ignorecase";
+
+pub const QUOTE_PATH_CONFIG_NAME: &str = "core.quotePath";
+
+pub const BASE_FROM_PUSH_CONFIG_NAME: &str = "absorb.baseFromPush";
+pub const BASE_FROM_PUSH_DEFAULT: bool = false;
+
+pub const DIFF_ALGORITHM_CONFIG_NAME: &str = "absorb.diffAlgorithm";
+pub const GIT_DIFF_ALGORITHM_CONFIG_NAME: &str = "diff.algorithm";
+
+/// How the staged changes and the commits in the stack are split into hunks.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DiffAlgorithm {
+    Myers,
+    Minimal,
+    /// Also used for `histogram`, which libgit2 does not have, and which
+    /// git built as an extension of patience.
+    Patience,
+}
+
+impl DiffAlgorithm {
+    /// The algorithm named by `name`, as `diff.algorithm` names them.
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name.to_ascii_lowercase().as_str() {
+            "myers" | "default" => Some(DiffAlgorithm::Myers),
+            "minimal" => Some(DiffAlgorithm::Minimal),
+            "patience" | "histogram" => Some(DiffAlgorithm::Patience),
+            _ => None,
+        }
+    }
+
+    pub fn apply(self, options: &mut git2::DiffOptions) -> &mut git2::DiffOptions {
+        options
+            .patience(self == DiffAlgorithm::Patience)
+            .minimal(self == DiffAlgorithm::Minimal)
+    }
+}
+
+pub const DIFF_CONTEXT_CONFIG_NAME: &str = "absorb.diffContext";
+pub const DIFF_CONTEXT_DEFAULT: usize = 0;
+/// The number of context lines `git diff` shows by default. Staged hunks
+/// that close together would be shown as one, so they are absorbed as one,
+/// unless -U or absorb.diffContext give another number, which then also
+/// replaces [`DIFF_CONTEXT_DEFAULT`] in matching them.
+pub const GROUP_CONTEXT_DEFAULT: usize = 3;
+
+pub const MERGE_CONFLICT_STYLE_CONFIG_NAME: &str = "merge.conflictStyle";
+
+pub const MAX_SUBJECT_LENGTH_CONFIG_NAME: &str = "absorb.maxSubjectLength";
+
+pub const MAX_FILE_SIZE_CONFIG_NAME: &str = "absorb.maxFileSize";
+
+pub const COLOR_DIFF_CONFIG_NAME: &str = "color.diff";
+pub const COLOR_UI_CONFIG_NAME: &str = "color.ui";
+
+pub const COMMIT_GPG_SIGN_CONFIG_NAME: &str = "commit.gpgSign";
+pub const GPG_FORMAT_CONFIG_NAME: &str = "gpg.format";
+pub const SIGNING_KEY_CONFIG_NAME: &str = "user.signingKey";
+pub const GPG_PROGRAM_CONFIG_NAME: &str = "gpg.program";
+
+pub const ABBREV_CONFIG_NAME: &str = "core.abbrev";
+/// The fewest characters git shortens an id to, however small the repository.
+pub const ABBREV_MIN_DEFAULT: usize = 7;
+
 pub fn unify<'config>(config: &'config Config, repo: &Repository) -> Config<'config> {
     Config {
         // here, we default to the git config value,
@@ -45,20 +155,51 @@ pub fn unify<'config>(config: &'config Config, repo: &Repository) -> Config<'con
                 CREATE_SQUASH_COMMITS_CONFIG_NAME,
                 CREATE_SQUASH_COMMITS_DEFAULT,
             ),
+        split_hunks: config.split_hunks
+            || bool_value(repo, SPLIT_HUNKS_CONFIG_NAME, SPLIT_HUNKS_DEFAULT),
         force_author: config.force_author
             || bool_value(repo, FORCE_AUTHOR_CONFIG_NAME, FORCE_AUTHOR_DEFAULT),
         force_detach: config.force_detach
             || bool_value(repo, FORCE_DETACH_CONFIG_NAME, FORCE_DETACH_DEFAULT),
+        cross_branch_boundaries: config.cross_branch_boundaries
+            || bool_value(
+                repo,
+                CROSS_BRANCH_BOUNDARIES_CONFIG_NAME,
+                CROSS_BRANCH_BOUNDARIES_DEFAULT,
+            ),
+        amend_head: config.amend_head
+            || bool_value(
+                repo,
+                AMEND_HEAD_WHEN_POSSIBLE_CONFIG_NAME,
+                AMEND_HEAD_WHEN_POSSIBLE_DEFAULT,
+            ),
+        all_or_nothing: config.all_or_nothing
+            || bool_value(repo, ALL_OR_NOTHING_CONFIG_NAME, ALL_OR_NOTHING_DEFAULT),
+        // --extract takes the changes out of the commits in place
+        rewrite: config.rewrite || config.extract,
+        // neither --check, --emit-script nor --annotate ever change anything
+        dry_run: config.dry_run
+            || config.check
+            || config.emit_script
+            || config.annotate
+            || config.export_series.is_some(),
         ..*config
     }
 }
 
+/// Layers the file at `path` over all other git config for `repo`,
+/// without changing any of the files git reads on its own.
+pub fn add_file(repo: &Repository, path: &Path) -> Result<()> {
+    if !path.is_file() {
+        return Err(anyhow!("config file {} does not exist", path.display()));
+    }
+    repo.config()?.add_file(path, ConfigLevel::App, false)?;
+    Ok(())
+}
+
 pub fn max_stack(repo: &git2::Repository) -> usize {
-    match repo
-        .config()
-        .and_then(|config| config.get_i64(MAX_STACK_CONFIG_NAME))
-    {
-        Ok(max_stack) if max_stack > 0 => max_stack as usize,
+    match get_i64(repo, MAX_STACK_CONFIG_NAME) {
+        Some(max_stack) if max_stack > 0 => max_stack as usize,
         _ => MAX_STACK,
     }
 }
@@ -71,20 +212,378 @@ pub fn auto_stage_if_nothing_staged(repo: &git2::Repository) -> bool {
     )
 }
 
-pub fn fixup_target_always_sha(repo: &git2::Repository) -> bool {
+/// Whether to rebase even without --and-rebase, as the environment says,
+/// or else the git config.
+pub fn and_rebase(repo: &git2::Repository) -> bool {
+    match std::env::var(AND_REBASE_ENV_NAME) {
+        Ok(value) => git2::Config::parse_bool(value).unwrap_or(AND_REBASE_DEFAULT),
+        Err(_) => bool_value(repo, AND_REBASE_CONFIG_NAME, AND_REBASE_DEFAULT),
+    }
+}
+
+pub fn rebase_whole_stack(repo: &git2::Repository) -> bool {
     bool_value(
         repo,
-        FIXUP_TARGET_ALWAYS_SHA_CONFIG_NAME,
-        FIXUP_TARGET_ALWAYS_SHA_DEFAULT,
+        REBASE_WHOLE_STACK_CONFIG_NAME,
+        REBASE_WHOLE_STACK_DEFAULT,
     )
 }
 
+pub fn write_notes(repo: &git2::Repository) -> bool {
+    bool_value(repo, WRITE_NOTES_CONFIG_NAME, WRITE_NOTES_DEFAULT)
+}
+
+/// The remotes whose remote-tracking branches keep commits from being
+/// rewritten, separated by spaces or commas, or `None` for all of them.
+pub fn remotes_to_protect(repo: &git2::Repository) -> Option<Vec<String>> {
+    let value = get_string(repo, REMOTES_TO_PROTECT_CONFIG_NAME)?;
+    Some(
+        value
+            .split(|c: char| c == ',' || c.is_whitespace())
+            .filter(|remote| !remote.is_empty())
+            .map(str::to_owned)
+            .collect(),
+    )
+}
+
+/// The directories whose changes are never absorbed, separated by spaces
+/// or commas. An empty value leaves none out.
+pub fn exclude_paths(repo: &git2::Repository) -> Vec<String> {
+    get_string(repo, EXCLUDE_PATHS_CONFIG_NAME)
+        .unwrap_or_else(|| EXCLUDE_PATHS_DEFAULT.to_owned())
+        .split(|c: char| c == ',' || c.is_whitespace())
+        .map(|dir| dir.trim_matches('/'))
+        .filter(|dir| !dir.is_empty())
+        .map(str::to_owned)
+        .collect()
+}
+
+/// The patterns of the added lines whose hunks are never absorbed, one for
+/// each value of the setting in any config file or `git -c` option. An
+/// empty value drops the ones before it.
+pub fn exclude_hunk_patterns(repo: &git2::Repository) -> Vec<String> {
+    let mut values = vec![];
+    if let Ok(config) = repo.config() {
+        if let Ok(entries) = config.multivar(EXCLUDE_HUNK_PATTERN_CONFIG_NAME, None) {
+            let _ = entries.for_each(|entry| values.push(entry.value().map(str::to_owned)));
+        }
+    }
+    values.extend(
+        parameters(|name| std::env::var(name).ok())
+            .into_iter()
+            .filter(|(key, _)| same_key(key, EXCLUDE_HUNK_PATTERN_CONFIG_NAME))
+            .map(|(_, value)| value),
+    );
+
+    let mut patterns = vec![];
+    for value in values.into_iter().flatten() {
+        if value.is_empty() {
+            patterns.clear();
+        } else {
+            patterns.push(value);
+        }
+    }
+    patterns
+}
+
+pub fn rebase_auto_stash(repo: &git2::Repository) -> bool {
+    bool_value(repo, REBASE_AUTO_STASH_CONFIG_NAME, false)
+}
+
+/// Whether the working tree is on a filesystem that does not tell apart
+/// names that only differ in case, as git found when it made the repository.
+pub fn ignore_case(repo: &git2::Repository) -> bool {
+    bool_value(repo, IGNORE_CASE_CONFIG_NAME, false)
+}
+
+/// Whether paths are shown with anything but ASCII escaped, as git does by
+/// default.
+pub fn quote_path(repo: &git2::Repository) -> bool {
+    bool_value(repo, QUOTE_PATH_CONFIG_NAME, true)
+}
+
+/// The name of the diff algorithm given by `absorb.diffAlgorithm`, or else
+/// by `diff.algorithm`, if either is set.
+pub fn diff_algorithm(repo: &git2::Repository) -> Option<String> {
+    get_string(repo, DIFF_ALGORITHM_CONFIG_NAME)
+        .or_else(|| get_string(repo, GIT_DIFF_ALGORITHM_CONFIG_NAME))
+}
+
+/// How many unchanged lines between two hunks still keep them from being
+/// reordered, if it is set, which fails for a negative number.
+pub fn diff_context(repo: &git2::Repository) -> Result<Option<usize>> {
+    match get_i64(repo, DIFF_CONTEXT_CONFIG_NAME) {
+        Some(context) if context < 0 => Err(anyhow!(
+            "{} must not be negative, but is {}",
+            DIFF_CONTEXT_CONFIG_NAME,
+            context
+        )),
+        Some(context) => Ok(Some(context as usize)),
+        None => Ok(None),
+    }
+}
+
+/// The most characters a fixup subject should have, if there is a limit.
+pub fn max_subject_length(repo: &git2::Repository) -> Option<usize> {
+    match get_i64(repo, MAX_SUBJECT_LENGTH_CONFIG_NAME) {
+        Some(length) if length > 0 => Some(length as usize),
+        _ => None,
+    }
+}
+
+/// The most bytes a file may have for its changes to be read, if there is
+/// a limit.
+pub fn max_file_size(repo: &git2::Repository) -> Option<u64> {
+    match get_i64(repo, MAX_FILE_SIZE_CONFIG_NAME) {
+        Some(size) if size > 0 => Some(size as u64),
+        _ => None,
+    }
+}
+
+pub fn base_from_push(repo: &git2::Repository) -> bool {
+    bool_value(repo, BASE_FROM_PUSH_CONFIG_NAME, BASE_FROM_PUSH_DEFAULT)
+}
+
+pub fn fixup_target(repo: &git2::Repository) -> FixupTarget {
+    if let Some(value) = get_string(repo, FIXUP_TARGET_ALWAYS_SHA_CONFIG_NAME) {
+        if value.eq_ignore_ascii_case("auto") {
+            return FixupTarget::Auto;
+        }
+    }
+    match get_bool(repo, FIXUP_TARGET_ALWAYS_SHA_CONFIG_NAME) {
+        Some(true) => FixupTarget::Sha,
+        Some(false) => FixupTarget::Subject,
+        None => FIXUP_TARGET_ALWAYS_SHA_DEFAULT,
+    }
+}
+
+/// How many characters of a commit id to show, like git does for
+/// `core.abbrev`: a number, `false` for the whole id, or, by default,
+/// `auto` to pick a length from the number of objects in the repository.
+pub fn abbrev_length(repo: &git2::Repository) -> usize {
+    if let Some(value) = get_string(repo, ABBREV_CONFIG_NAME) {
+        if !value.eq_ignore_ascii_case("auto") {
+            if let Some(length) = get_i64(repo, ABBREV_CONFIG_NAME) {
+                return length.clamp(4, 40) as usize;
+            }
+            if let Some(false) = get_bool(repo, ABBREV_CONFIG_NAME) {
+                return 40;
+            }
+        }
+    }
+    let mut objects = 0;
+    let counted = repo.odb().and_then(|odb| {
+        odb.foreach(|_| {
+            objects += 1;
+            true
+        })
+    });
+    match counted {
+        Ok(()) => auto_abbrev_length(objects),
+        Err(_) => ABBREV_MIN_DEFAULT,
+    }
+}
+
+/// Whether `core.abbrev` is set, rather than left to its default.
+pub fn abbrev_configured(repo: &git2::Repository) -> bool {
+    get_string(repo, ABBREV_CONFIG_NAME).is_some()
+}
+
+/// Enough characters to tell apart `objects` objects, the way git's
+/// `core.abbrev = auto` estimates it: half the bits needed to count them.
+pub fn auto_abbrev_length(objects: usize) -> usize {
+    let bits = (usize::BITS - objects.leading_zeros()) as usize;
+    bits.div_ceil(2).max(ABBREV_MIN_DEFAULT)
+}
+
+/// Whether to color a diff, as `color.diff`, or else `color.ui`, says:
+/// `always`, `never`, or by default `auto`, only on a `terminal`.
+pub fn color_diff(repo: &git2::Repository, terminal: bool) -> bool {
+    let value = get_string(repo, COLOR_DIFF_CONFIG_NAME)
+        .or_else(|| get_string(repo, COLOR_UI_CONFIG_NAME))
+        .map(|value| value.to_ascii_lowercase());
+    match value.as_deref() {
+        Some("always") => true,
+        Some("never" | "false" | "no" | "off" | "0") => false,
+        _ => terminal,
+    }
+}
+
+pub fn conflict_style(repo: &git2::Repository) -> Option<String> {
+    get_string(repo, MERGE_CONFLICT_STYLE_CONFIG_NAME)
+}
+
+pub fn commit_gpg_sign(repo: &git2::Repository) -> bool {
+    bool_value(repo, COMMIT_GPG_SIGN_CONFIG_NAME, false)
+}
+
+pub fn gpg_format(repo: &git2::Repository) -> Option<String> {
+    get_string(repo, GPG_FORMAT_CONFIG_NAME)
+}
+
+pub fn signing_key(repo: &git2::Repository) -> Option<String> {
+    get_string(repo, SIGNING_KEY_CONFIG_NAME)
+}
+
+/// The program that signs in `format`, as `gpg.<format>.program` names it,
+/// or for `openpgp` also `gpg.program`, the setting from before there were
+/// other formats.
+pub fn gpg_program(repo: &git2::Repository, format: &str) -> Option<String> {
+    get_string(repo, &format!("gpg.{}.program", format)).or_else(|| match format {
+        "openpgp" => get_string(repo, GPG_PROGRAM_CONFIG_NAME),
+        _ => None,
+    })
+}
+
 fn bool_value(repo: &Repository, setting_name: &str, default_value: bool) -> bool {
-    match repo
-        .config()
-        .and_then(|config| config.get_bool(setting_name))
-    {
-        Ok(value) => value,
-        _ => default_value,
+    get_bool(repo, setting_name).unwrap_or(default_value)
+}
+
+fn get_bool(repo: &Repository, name: &str) -> Option<bool> {
+    get(repo, name, git2::Config::get_bool, |value| match value {
+        // a key without a value, like `git -c absorb.forceAuthor`, is true
+        None => Some(true),
+        Some(value) => git2::Config::parse_bool(value).ok(),
+    })
+}
+
+fn get_i64(repo: &Repository, name: &str) -> Option<i64> {
+    get(repo, name, git2::Config::get_i64, |value| {
+        git2::Config::parse_i64(value?).ok()
+    })
+}
+
+fn get_string(repo: &Repository, name: &str) -> Option<String> {
+    get(repo, name, git2::Config::get_string, |value| {
+        value.map(str::to_owned)
+    })
+}
+
+/// Looks `name` up the way git would with the `git -c` options that git
+/// passes down in the environment, which libgit2 does not read.
+fn get<T>(
+    repo: &Repository,
+    name: &str,
+    from_config: fn(&git2::Config, &str) -> Result<T, git2::Error>,
+    from_parameter: fn(Option<&str>) -> Option<T>,
+) -> Option<T> {
+    let parameters = parameters(|name| std::env::var(name).ok());
+    layered(repo, &parameters, name, from_config, from_parameter)
+}
+
+/// Looks `name` up in the file given with --config-file first, then in the
+/// `git -c` options in `parameters`, and then in the rest of the git
+/// config.
+pub fn layered<T>(
+    repo: &Repository,
+    parameters: &[(String, Option<String>)],
+    name: &str,
+    from_config: fn(&git2::Config, &str) -> Result<T, git2::Error>,
+    from_parameter: fn(Option<&str>) -> Option<T>,
+) -> Option<T> {
+    let config = repo.config().ok()?;
+    let from_file = config
+        .open_level(ConfigLevel::App)
+        .and_then(|file| from_config(&file, name));
+    if let Ok(value) = from_file {
+        return Some(value);
+    }
+    if let Some((_, value)) = parameters.iter().rev().find(|(key, _)| same_key(key, name)) {
+        return from_parameter(value.as_deref());
+    }
+    from_config(&config, name).ok()
+}
+
+/// The config given with `git -c` in the environment that `var` reads, in
+/// the order git applies it: first the counted `GIT_CONFIG_KEY_<n>` and
+/// `GIT_CONFIG_VALUE_<n>` pairs, then `GIT_CONFIG_PARAMETERS`. A key given
+/// without a value has none.
+pub fn parameters(var: impl Fn(&str) -> Option<String>) -> Vec<(String, Option<String>)> {
+    let mut parameters = vec![];
+    let count = var("GIT_CONFIG_COUNT")
+        .and_then(|count| count.parse().ok())
+        .unwrap_or(0);
+    for n in 0..count {
+        let key = var(&format!("GIT_CONFIG_KEY_{}", n));
+        let value = var(&format!("GIT_CONFIG_VALUE_{}", n));
+        if let (Some(key), Some(value)) = (key, value) {
+            parameters.push((key, Some(value)));
+        }
+    }
+    if let Some(quoted) = var("GIT_CONFIG_PARAMETERS") {
+        parameters.extend(parse_parameters(&quoted));
+    }
+    parameters
+}
+
+/// Parses `GIT_CONFIG_PARAMETERS`, where each option is quoted for the
+/// shell, either as a whole as `'key=value'`, the way older versions of
+/// git wrote them, or as `'key'='value'`, or as `'key'` for a key without a
+/// value. Stops at the first thing that is neither.
+fn parse_parameters(quoted: &str) -> Vec<(String, Option<String>)> {
+    let mut parameters = vec![];
+    let mut rest = quoted;
+    loop {
+        rest = rest.trim_start();
+        let key;
+        (key, rest) = match dequote(rest) {
+            Some(dequoted) => dequoted,
+            None => break,
+        };
+        if let Some(after) = rest.strip_prefix('=') {
+            match dequote(after) {
+                Some((value, after)) => {
+                    parameters.push((key, Some(value)));
+                    rest = after;
+                }
+                None => {
+                    parameters.push((key, Some(String::new())));
+                    rest = after;
+                }
+            }
+        } else {
+            match key.split_once('=') {
+                Some((key, value)) => parameters.push((key.to_owned(), Some(value.to_owned()))),
+                None => parameters.push((key, None)),
+            }
+        }
+    }
+    parameters
+}
+
+/// Takes one word quoted for the shell the way git quotes it, in single
+/// quotes with `\'` and `\!` outside of them, off the start of `quoted`.
+fn dequote(quoted: &str) -> Option<(String, &str)> {
+    let mut rest = quoted.strip_prefix('\'')?;
+    let mut word = String::new();
+    loop {
+        let end = rest.find('\'')?;
+        word.push_str(&rest[..end]);
+        rest = &rest[end + 1..];
+        let mut chars = rest.chars();
+        match (chars.next(), chars.next()) {
+            (Some('\\'), Some(c @ ('\'' | '!'))) => {
+                word.push(c);
+                rest = chars.as_str().strip_prefix('\'')?;
+            }
+            _ => return Some((word, rest)),
+        }
+    }
+}
+
+/// Whether two config keys name the same variable: their section and name
+/// are compared without case, and a subsection between them exactly.
+fn same_key(a: &str, b: &str) -> bool {
+    let split = |key: &str| match (key.find('.'), key.rfind('.')) {
+        (Some(first), Some(last)) => Some((
+            key[..first].to_ascii_lowercase(),
+            key[first..last].to_owned(),
+            key[last..].to_ascii_lowercase(),
+        )),
+        _ => None,
+    };
+    match (split(a), split(b)) {
+        (Some(a), Some(b)) => a == b,
+        _ => false,
     }
 }
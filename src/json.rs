@@ -0,0 +1,332 @@
+//! Just enough JSON for the plans of --plan-only, which are written out for
+//! whoever reviews them, and may be edited before they are read back.
+
+use anyhow::{anyhow, Result};
+
+/// A JSON value, with numbers kept as they were written.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Value {
+    Null,
+    Bool(bool),
+    Number(String),
+    String(String),
+    Array(Vec<Value>),
+    /// The members in the order they were written.
+    Object(Vec<(String, Value)>),
+}
+
+static NULL: Value = Value::Null;
+
+impl Value {
+    pub fn number(number: impl std::fmt::Display) -> Self {
+        Value::Number(number.to_string())
+    }
+
+    pub fn object<const N: usize>(members: [(&str, Value); N]) -> Self {
+        Value::Object(
+            members
+                .into_iter()
+                .map(|(name, value)| (name.to_owned(), value))
+                .collect(),
+        )
+    }
+
+    pub fn as_array(&self) -> Option<&Vec<Value>> {
+        match self {
+            Value::Array(values) => Some(values),
+            _ => None,
+        }
+    }
+
+    pub fn as_str(&self) -> Option<&str> {
+        match self {
+            Value::String(string) => Some(string),
+            _ => None,
+        }
+    }
+
+    pub fn as_u64(&self) -> Option<u64> {
+        match self {
+            Value::Number(number) => number.parse().ok(),
+            _ => None,
+        }
+    }
+
+    /// Parses `text`, which has to be a single JSON value.
+    pub fn parse(text: &[u8]) -> Result<Self> {
+        let text = std::str::from_utf8(text).map_err(|_| anyhow!("not UTF-8"))?;
+        let mut parser = Parser { text, at: 0 };
+        let value = parser.value()?;
+        parser.skip_whitespace();
+        if parser.at != text.len() {
+            return Err(parser.error("end of input"));
+        }
+        Ok(value)
+    }
+
+    /// Writes the value indented by two spaces for each level, the way
+    /// `JSON.stringify(value, null, 2)` does.
+    pub fn pretty(&self) -> String {
+        let mut out = String::new();
+        self.write_pretty(&mut out, 0);
+        out
+    }
+
+    fn write_pretty(&self, out: &mut String, depth: usize) {
+        let indent = |out: &mut String, depth| out.extend(std::iter::repeat("  ").take(depth));
+        match self {
+            Value::Null => out.push_str("null"),
+            Value::Bool(value) => out.push_str(if *value { "true" } else { "false" }),
+            Value::Number(number) => out.push_str(number),
+            Value::String(string) => write_string(out, string),
+            Value::Array(values) if values.is_empty() => out.push_str("[]"),
+            Value::Array(values) => {
+                out.push('[');
+                for (i, value) in values.iter().enumerate() {
+                    out.push_str(if i == 0 { "\n" } else { ",\n" });
+                    indent(out, depth + 1);
+                    value.write_pretty(out, depth + 1);
+                }
+                out.push('\n');
+                indent(out, depth);
+                out.push(']');
+            }
+            Value::Object(members) if members.is_empty() => out.push_str("{}"),
+            Value::Object(members) => {
+                out.push('{');
+                for (i, (name, value)) in members.iter().enumerate() {
+                    out.push_str(if i == 0 { "\n" } else { ",\n" });
+                    indent(out, depth + 1);
+                    write_string(out, name);
+                    out.push_str(": ");
+                    value.write_pretty(out, depth + 1);
+                }
+                out.push('\n');
+                indent(out, depth);
+                out.push('}');
+            }
+        }
+    }
+}
+
+/// The member called `name`, or null if there is none or this is not an
+/// object.
+impl std::ops::Index<&str> for Value {
+    type Output = Value;
+
+    fn index(&self, name: &str) -> &Value {
+        match self {
+            Value::Object(members) => members
+                .iter()
+                .find(|(member, _)| member == name)
+                .map_or(&NULL, |(_, value)| value),
+            _ => &NULL,
+        }
+    }
+}
+
+fn write_string(out: &mut String, string: &str) {
+    out.push('"');
+    for c in string.chars() {
+        match c {
+            '"' => out.push_str("\\\""),
+            '\\' => out.push_str("\\\\"),
+            '\n' => out.push_str("\\n"),
+            '\r' => out.push_str("\\r"),
+            '\t' => out.push_str("\\t"),
+            c if c < ' ' => out.push_str(&format!("\\u{:04x}", c as u32)),
+            c => out.push(c),
+        }
+    }
+    out.push('"');
+}
+
+struct Parser<'t> {
+    text: &'t str,
+    at: usize,
+}
+
+impl Parser<'_> {
+    fn error(&self, expected: &str) -> anyhow::Error {
+        anyhow!("expected {} at byte {}", expected, self.at)
+    }
+
+    fn skip_whitespace(&mut self) {
+        let rest = &self.text[self.at..];
+        self.at += rest.len() - rest.trim_start_matches([' ', '\t', '\n', '\r']).len();
+    }
+
+    /// Takes `token` off the input, after any whitespace, if it is next.
+    fn eat(&mut self, token: &str) -> bool {
+        self.skip_whitespace();
+        if self.text[self.at..].starts_with(token) {
+            self.at += token.len();
+            true
+        } else {
+            false
+        }
+    }
+
+    fn value(&mut self) -> Result<Value> {
+        self.skip_whitespace();
+        if self.eat("null") {
+            Ok(Value::Null)
+        } else if self.eat("true") {
+            Ok(Value::Bool(true))
+        } else if self.eat("false") {
+            Ok(Value::Bool(false))
+        } else if self.eat("[") {
+            let mut values = vec![];
+            if !self.eat("]") {
+                loop {
+                    values.push(self.value()?);
+                    if self.eat("]") {
+                        break;
+                    }
+                    if !self.eat(",") {
+                        return Err(self.error("',' or ']'"));
+                    }
+                }
+            }
+            Ok(Value::Array(values))
+        } else if self.eat("{") {
+            let mut members = vec![];
+            if !self.eat("}") {
+                loop {
+                    self.skip_whitespace();
+                    let name = self.string()?;
+                    if !self.eat(":") {
+                        return Err(self.error("':'"));
+                    }
+                    members.push((name, self.value()?));
+                    if self.eat("}") {
+                        break;
+                    }
+                    if !self.eat(",") {
+                        return Err(self.error("',' or '}'"));
+                    }
+                }
+            }
+            Ok(Value::Object(members))
+        } else if self.text[self.at..].starts_with('"') {
+            Ok(Value::String(self.string()?))
+        } else {
+            self.number()
+        }
+    }
+
+    fn number(&mut self) -> Result<Value> {
+        let rest = &self.text[self.at..];
+        let end = rest
+            .find(|c: char| !(c.is_ascii_digit() || "+-.eE".contains(c)))
+            .unwrap_or(rest.len());
+        let number = &rest[..end];
+        if number.parse::<f64>().is_err() {
+            return Err(self.error("a value"));
+        }
+        self.at += end;
+        Ok(Value::Number(number.to_owned()))
+    }
+
+    fn string(&mut self) -> Result<String> {
+        if !self.text[self.at..].starts_with('"') {
+            return Err(self.error("a string"));
+        }
+        self.at += 1;
+        let mut string = String::new();
+        loop {
+            let mut chars = self.text[self.at..].chars();
+            let c = chars.next().ok_or_else(|| self.error("'\"'"))?;
+            self.at += c.len_utf8();
+            match c {
+                '"' => return Ok(string),
+                '\\' => {
+                    let escaped = chars.next().ok_or_else(|| self.error("an escape"))?;
+                    self.at += 1;
+                    string.push(match escaped {
+                        '"' => '"',
+                        '\\' => '\\',
+                        '/' => '/',
+                        'b' => '\u{8}',
+                        'f' => '\u{c}',
+                        'n' => '\n',
+                        'r' => '\r',
+                        't' => '\t',
+                        'u' => self.unicode_escape()?,
+                        _ => return Err(self.error("an escape")),
+                    });
+                }
+                c if c < ' ' => return Err(self.error("'\"'")),
+                c => string.push(c),
+            }
+        }
+    }
+
+    /// The character of a `\u` escape, whose `\u` has been taken already,
+    /// along with the second half of a surrogate pair.
+    fn unicode_escape(&mut self) -> Result<char> {
+        let first = self.hex4()?;
+        let code = if (0xd800..0xdc00).contains(&first) {
+            if !self.text[self.at..].starts_with("\\u") {
+                return Err(self.error("a low surrogate"));
+            }
+            self.at += 2;
+            let second = self.hex4()?;
+            if !(0xdc00..0xe000).contains(&second) {
+                return Err(self.error("a low surrogate"));
+            }
+            0x10000 + ((first - 0xd800) << 10) + (second - 0xdc00)
+        } else {
+            first
+        };
+        char::from_u32(code).ok_or_else(|| self.error("a character"))
+    }
+
+    fn hex4(&mut self) -> Result<u32> {
+        let digits = self
+            .text
+            .get(self.at..self.at + 4)
+            .filter(|digits| digits.chars().all(|c| c.is_ascii_hexdigit()))
+            .ok_or_else(|| self.error("four hex digits"))?;
+        self.at += 4;
+        Ok(u32::from_str_radix(digits, 16).expect("hex digits"))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn round_trip() {
+        let value = Value::object([
+            ("version", Value::number(2)),
+            ("none", Value::Null),
+            (
+                "lines",
+                Value::Array(vec![
+                    Value::String("tab\there \"quoted\" \\ é \u{1}\n".to_owned()),
+                    Value::Bool(false),
+                ]),
+            ),
+            ("empty", Value::Array(vec![])),
+        ]);
+        let text = value.pretty();
+        assert_eq!(Value::parse(text.as_bytes()).unwrap(), value);
+        assert_eq!(value["version"].as_u64(), Some(2));
+        assert_eq!(value["missing"], Value::Null);
+    }
+
+    #[test]
+    fn parses_what_others_write() {
+        let value =
+            Value::parse(br#" {"a":[1, -2.5e3,"\u00e9\ud83d\ude00\/"] , "b" : {}} "#).unwrap();
+        assert_eq!(value["a"].as_array().unwrap()[0].as_u64(), Some(1));
+        assert_eq!(value["a"].as_array().unwrap()[1].as_u64(), None);
+        assert_eq!(value["a"].as_array().unwrap()[2].as_str(), Some("é😀/"));
+        assert_eq!(value["b"], Value::Object(vec![]));
+        assert!(Value::parse(b"{\"a\": 1,}").is_err());
+        assert!(Value::parse(b"[1] 2").is_err());
+        assert!(Value::parse(b"\"\\ud83d\"").is_err());
+    }
+}
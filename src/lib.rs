@@ -4,50 +4,569 @@ use anyhow::{anyhow, Result};
 
 mod commute;
 mod config;
+mod json;
+mod notes;
 mod owned;
+mod plan;
+mod promisor;
+mod prompt;
+mod rewrite;
+mod script;
+mod series;
+mod sign;
 mod stack;
+mod stats;
+mod undo;
 
-use std::io::Write;
-use std::path::Path;
+use std::io::{IsTerminal, Write};
+use std::path::{Path, PathBuf};
+
+/// Where --stage-only leaves its fixup commits.
+const STAGED_FIXUPS_REF: &str = "refs/absorb/fixups";
+/// Where the branch tip is kept, with a reflog, before any history is
+/// rewritten, unlike ORIG_HEAD, which later git commands overwrite.
+const ORIG_HEAD_REF: &str = "refs/absorb/ORIG_HEAD";
 
 pub struct Config<'a> {
     pub dry_run: bool,
     pub force_author: bool,
     pub force_detach: bool,
-    pub base: Option<&'a str>,
+    pub cross_branch_boundaries: bool,
+    pub force_rewrite: bool,
+    pub force_overlap: bool,
+    pub base: &'a [&'a str],
+    pub range: Option<&'a str>,
+    pub diff_algorithm: Option<&'a str>,
+    pub diff_context: Option<usize>,
     pub and_rebase: bool,
+    pub no_rebase: bool,
     pub rebase_options: &'a Vec<&'a str>,
     pub whole_file: bool,
+    pub split_hunks: bool,
     pub one_fixup_per_commit: bool,
     pub squash: bool,
     pub message: Option<&'a str>,
+    pub unstaged: bool,
+    pub from_stash: Option<&'a str>,
+    pub patch_file: Option<&'a std::path::Path>,
+    pub into: Option<&'a str>,
+    pub target_touched: Option<&'a str>,
+    pub verify_build: Option<&'a str>,
+    pub paths: &'a [&'a str],
+    pub exclude_hunks_matching: &'a [&'a str],
+    pub include_hunks_matching: &'a [&'a str],
+    pub recurse_submodules: bool,
+    pub select: bool,
+    pub yes: bool,
+    pub check: bool,
+    pub all_or_nothing: bool,
+    pub print_stack: bool,
+    pub undo: bool,
+    pub rewrite: bool,
+    pub extract: bool,
+    pub amend_head: bool,
+    pub only_my_lines: bool,
+    pub additions_only: bool,
+    pub deletions_only: bool,
+    pub stat: bool,
+    pub porcelain: bool,
+    pub emit_script: bool,
+    pub annotate: bool,
+    pub show_final: bool,
+    pub export_series: Option<&'a std::path::Path>,
+    pub stage_only: bool,
+    pub config_file: Option<&'a std::path::Path>,
+    pub stats_file: Option<&'a std::path::Path>,
+    pub timings: bool,
+    pub truncate_subject: bool,
+    pub fixup_by_sha: bool,
+    pub time_limit: Option<std::time::Duration>,
+    pub max_report: Option<usize>,
+    pub plan_only: Option<&'a std::path::Path>,
+    pub apply_plan: Option<&'a std::path::Path>,
+}
+
+/// What [`run`] did, for programs that use git-absorb as a library.
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct Summary {
+    /// The fixup commits that were created, oldest first, each with the
+    /// commit it fixes up. None are with a dry run, nor with `rewrite` or
+    /// `amend_head`, which fold the changes in instead.
+    pub created_fixups: Vec<(git2::Oid, git2::Oid)>,
+    /// The staged hunks and whole file changes that could not be absorbed,
+    /// as listed at the end of the run.
+    pub leftover_hunks: usize,
+}
+
+/// Why [`run`] failed, for programs that need to tell the failures apart.
+#[derive(Debug, thiserror::Error)]
+#[non_exhaustive]
+pub enum AbsorbError {
+    /// There is no repository to absorb in.
+    #[error(transparent)]
+    NotARepository(git2::Error),
+    /// The options cannot be used together, or not in this repository.
+    #[error("{0}")]
+    InvalidOptions(&'static str),
+    /// A rebase, merge or the like has to be finished first.
+    #[error("cannot absorb while a {0} is in progress, finish or abort it first")]
+    OperationInProgress(&'static str),
+    /// The index has conflicts at these paths, which have to be resolved
+    /// first.
+    #[error(
+        "cannot absorb while the index has unmerged paths, resolve them or abort \
+         the merge first: {}",
+        .0.join(", ")
+    )]
+    UnmergedPaths(Vec<String>),
+    /// HEAD is not a branch, and `force_detach` is not set.
+    #[error("HEAD is not a branch, use --force-detach to override")]
+    DetachedHead,
+    /// Another git process holds the lock on the index at `path`.
+    #[error(
+        "the index is locked by another git process, if none is running remove {}.lock",
+        path.display()
+    )]
+    IndexLocked { path: std::path::PathBuf },
+    /// The plan to apply does not fit the repository anymore.
+    #[error("{0}")]
+    PlanMismatch(String),
+    /// A commit to rewrite is signed, and `force_rewrite` is not set.
+    #[error("cannot rewrite signed commit {commit}, use --force-rewrite to override")]
+    SignedCommit { commit: git2::Oid },
+    /// A commit to rewrite is reachable from `remote_ref`, and
+    /// `force_rewrite` is not set.
+    #[error(
+        "cannot rewrite commit {commit}, which is reachable from {remote_ref}, \
+         use --force-rewrite to override"
+    )]
+    PushedCommit {
+        commit: git2::Oid,
+        remote_ref: String,
+    },
+    /// Two hunks to absorb change the same lines of a file, but go into
+    /// different commits, and `force_overlap` is not set.
+    #[error(
+        "the hunks at {first} and {second} change the same lines, but go into \
+         {first_target} and {second_target}, \
+         use --force-overlap to only absorb the one for the older commit"
+    )]
+    OverlappingHunks {
+        first: String,
+        first_target: git2::Oid,
+        second: String,
+        second_target: git2::Oid,
+    },
+    /// The fixups for a commit to rewrite conflict with the commits above it.
+    #[error("cannot rewrite commit {commit}, the changes conflict")]
+    RewriteConflict { commit: git2::Oid },
+    /// The fixups could not be signed as `commit.gpgSign` asks.
+    #[error("{0}")]
+    SigningFailed(String),
+    /// Objects a partial clone left out could not be fetched from `remote`.
+    #[error("{}", missing_objects(ids, remote.as_deref()))]
+    MissingObjects {
+        ids: Vec<git2::Oid>,
+        remote: Option<String>,
+    },
+    /// git rebase exited with `status`, or was killed if there is none.
+    #[error("{}", match status {
+        Some(status) => format!("git rebase exited with status {}", status),
+        None => "git rebase was killed by a signal".to_owned(),
+    })]
+    RebaseFailed { status: Option<i32> },
+    /// `check` or `all_or_nothing` is set and some staged changes could
+    /// not be absorbed.
+    #[error("{unabsorbed} staged change(s) could not be absorbed")]
+    CheckFailed { unabsorbed: usize },
+    /// `undo` is set, but there is no run to undo, or the branch or the
+    /// index changed since.
+    #[error("cannot undo: {0}")]
+    CannotUndo(&'static str),
+    /// The hunk of the patch file that starts at `line` of `path` does not
+    /// match HEAD.
+    #[error("patch failed: {path}:{line}, the lines around it do not match HEAD")]
+    PatchFailed { path: String, line: u32 },
+    /// Anything else, which is not expected to happen.
+    #[error(transparent)]
+    Internal(anyhow::Error),
+}
+
+fn missing_objects(ids: &[git2::Oid], remote: Option<&str>) -> String {
+    let ids: Vec<_> = ids.iter().map(git2::Oid::to_string).collect();
+    let mut message = format!("objects {} are missing from the repository", ids.join(" "));
+    if let Some(remote) = remote {
+        message.push_str(&format!(
+            ", fetch them with `git fetch {} {}`",
+            remote,
+            ids.join(" ")
+        ));
+    }
+    message
+}
+
+/// The error from anywhere inside git-absorb, which is an [`AbsorbError`]
+/// once it knows why it failed, and [`AbsorbError::Internal`] otherwise,
+/// so it is downcast rather than wrapped with `#[from]`.
+impl From<anyhow::Error> for AbsorbError {
+    fn from(e: anyhow::Error) -> Self {
+        e.downcast().unwrap_or_else(AbsorbError::Internal)
+    }
 }
 
-pub fn run(logger: &slog::Logger, config: &Config) -> Result<()> {
-    let repo = git2::Repository::open_from_env()?;
+pub fn run(logger: &slog::Logger, config: &Config) -> Result<Summary, AbsorbError> {
+    let repo = git2::Repository::open_from_env().map_err(AbsorbError::NotARepository)?;
     debug!(logger, "repository found"; "path" => repo.path().to_str());
 
     run_with_repo(logger, config, &repo)
 }
 
-fn run_with_repo(logger: &slog::Logger, config: &Config, repo: &git2::Repository) -> Result<()> {
-    let config = config::unify(config, repo);
+fn run_with_repo(
+    logger: &slog::Logger,
+    config: &Config,
+    repo: &git2::Repository,
+) -> Result<Summary, AbsorbError> {
+    if let Some(path) = config.config_file {
+        config::add_file(repo, path)?;
+        debug!(logger, "loaded config file"; "path" => path.to_str());
+    }
+    let result = absorb_with_stats(logger, config, repo, &mut std::io::stdout());
+    if !config.recurse_submodules {
+        return result;
+    }
+    let mut worst = result;
+    for failure in absorb_submodules(logger, config, repo).map_err(AbsorbError::from)? {
+        worst = match worst {
+            // what could not be absorbed in each repository adds up
+            Err(AbsorbError::CheckFailed { unabsorbed }) => match failure {
+                AbsorbError::CheckFailed { unabsorbed: more } => Err(AbsorbError::CheckFailed {
+                    unabsorbed: unabsorbed + more,
+                }),
+                failure => Err(failure),
+            },
+            // the first real error is the one to report
+            Err(e) => Err(e),
+            Ok(_) => Err(failure),
+        };
+    }
+    worst
+}
 
-    if !config.rebase_options.is_empty() && !config.and_rebase {
-        return Err(anyhow!(
-            "REBASE_OPTIONS were specified without --and-rebase flag"
+/// With --recurse-submodules, absorbs the changes in each initialized
+/// submodule of `repo` that has some, as a repository of its own, and
+/// returns how it failed in those where it did.
+///
+/// The options that name revisions or paths are only for the superproject.
+fn absorb_submodules(
+    logger: &slog::Logger,
+    config: &Config,
+    repo: &git2::Repository,
+) -> Result<Vec<AbsorbError>> {
+    let config = Config {
+        base: &[],
+        range: None,
+        into: None,
+        target_touched: None,
+        paths: &[],
+        ..*config
+    };
+    let mut failures = vec![];
+    for submodule in repo.submodules()? {
+        let logger = logger.new(o!(
+            "submodule" => submodule.path().to_string_lossy().into_owned(),
         ));
+        let submodule = match submodule.open() {
+            Ok(submodule) => submodule,
+            Err(_) => {
+                debug!(logger, "skipped submodule that is not initialized");
+                continue;
+            }
+        };
+        let changed = if config.unstaged {
+            !nothing_left_in_workdir(&submodule)?
+        } else {
+            !nothing_left_in_index(&submodule)?
+        };
+        if !changed {
+            debug!(logger, "skipped submodule with nothing to absorb");
+            continue;
+        }
+        if let Err(e) = run_with_repo(&logger, &config, &submodule) {
+            announce(&logger, Announcement::SubmoduleFailed(&e));
+            failures.push(e);
+        }
+    }
+    Ok(failures)
+}
+
+fn absorb_with_stats(
+    logger: &slog::Logger,
+    config: &Config,
+    repo: &git2::Repository,
+    out: &mut dyn Write,
+) -> Result<Summary, AbsorbError> {
+    let start = std::time::Instant::now();
+    let mut stats = stats::Stats::default();
+    let mut summary = Summary::default();
+    // HEAD and the index from before, to undo the run with
+    let before = match config.undo || config.dry_run {
+        true => None,
+        false => undo::State::current(repo).ok(),
+    };
+    let result = absorb(
+        logger,
+        config,
+        repo,
+        &mut prompt::Terminal,
+        out,
+        &mut stats,
+        &mut summary,
+    )
+    .map_err(AbsorbError::from);
+    if let (Ok(()), Some(before)) = (&result, before) {
+        // the fixups are there already, whether or not they can be undone
+        if let Err(e) = undo::record(repo, &before, &summary.created_fixups) {
+            announce(logger, Announcement::UndoNotRecorded(&e));
+        }
+    }
+    if let Some(mut timings) = stats.timings {
+        timings.finish();
+        stats.timings = Some(timings);
+        write!(out, "{}", timings.table(&stats)).map_err(anyhow::Error::from)?;
+    }
+    if let Some(path) = config.stats_file {
+        // a failed check still ran to completion
+        if matches!(result, Ok(()) | Err(AbsorbError::CheckFailed { .. })) {
+            stats.append_to(path, start.elapsed())?;
+        }
+    }
+    result?;
+    Ok(summary)
+}
+
+fn absorb(
+    logger: &slog::Logger,
+    config: &Config,
+    repo: &git2::Repository,
+    prompt: &mut dyn prompt::Prompt,
+    out: &mut dyn Write,
+    stats: &mut stats::Stats,
+    summary: &mut Summary,
+) -> Result<()> {
+    if config.timings {
+        stats.timings = Some(stats::Timings::default());
+    }
+    stats.enter(stats::Phase::Options);
+    let config = config::unify(config, repo);
+    let deadline = config
+        .time_limit
+        .map(|limit| std::time::Instant::now() + limit);
+
+    let mut assume_yes = prompt::AssumeYes;
+    let prompt: &mut dyn prompt::Prompt = if config.yes { &mut assume_yes } else { prompt };
+
+    // before the first commit there is no stack, and no HEAD to look at
+    if let Err(e) = repo.head() {
+        if e.code() == git2::ErrorCode::UnbornBranch {
+            announce(logger, Announcement::NoCommits);
+            return Ok(());
+        }
+    }
+
+    if config.print_stack {
+        return print_stack(logger, &config, repo);
+    }
+
+    // git keeps its own idea of where HEAD and the branches are while it
+    // is in the middle of one of these, which moving them would break
+    if !config.dry_run && config.plan_only.is_none() {
+        if let Some(operation) = operation_in_progress(repo.state()) {
+            return Err(AbsorbError::OperationInProgress(operation).into());
+        }
+    }
+
+    // the conflicted stages would show up as changes to absorb
+    let unmerged = unmerged_paths(repo)?;
+    if !unmerged.is_empty() {
+        return Err(AbsorbError::UnmergedPaths(unmerged).into());
+    }
+
+    if config.undo {
+        let undone = undo::undo(repo)?;
+        record_orig_head(repo, undone, "undo")?;
+        announce(
+            logger,
+            Announcement::Undone(&repo.head()?.peel_to_commit()?, config::abbrev_length(repo)),
+        );
+        return Ok(());
+    }
+
+    if config.select {
+        prompt.check_interactive()?;
+    }
+
+    if !config.rebase_options.is_empty()
+        && (config.no_rebase || !(config.and_rebase || config::and_rebase(repo)))
+    {
+        return Err(AbsorbError::InvalidOptions(
+            "REBASE_OPTIONS were specified without --and-rebase flag",
+        )
+        .into());
+    }
+
+    if config.unstaged
+        && (config.plan_only.is_some()
+            || config.apply_plan.is_some()
+            || config.emit_script
+            || config.stage_only)
+    {
+        return Err(AbsorbError::InvalidOptions("--plan-only, --apply-plan, --emit-script and --stage-only cannot be used with --unstaged").into());
+    }
+
+    if config.rewrite && (config.and_rebase || config.squash) {
+        return Err(AbsorbError::InvalidOptions(
+            "--rewrite cannot be used with --and-rebase or --squash",
+        )
+        .into());
+    }
+
+    if config.recurse_submodules
+        && (config.plan_only.is_some()
+            || config.apply_plan.is_some()
+            || config.emit_script
+            || config.from_stash.is_some()
+            || config.patch_file.is_some())
+    {
+        return Err(AbsorbError::InvalidOptions(
+            "--plan-only, --apply-plan, --emit-script, --from-stash and --patch-file cannot be used with --recurse-submodules",
+        )
+        .into());
+    }
+
+    if config.show_final && !config.dry_run {
+        return Err(
+            AbsorbError::InvalidOptions("--show-final can only be used with --dry-run").into(),
+        );
+    }
+
+    if config.export_series.is_some()
+        && (config.plan_only.is_some()
+            || config.apply_plan.is_some()
+            || config.emit_script
+            || config.stage_only
+            || config.recurse_submodules)
+    {
+        return Err(AbsorbError::InvalidOptions(
+            "--plan-only, --apply-plan, --emit-script, --stage-only and --recurse-submodules cannot be used with --export-series",
+        )
+        .into());
+    }
+
+    if config.additions_only && config.deletions_only {
+        return Err(AbsorbError::InvalidOptions(
+            "--additions-only cannot be used with --deletions-only",
+        )
+        .into());
+    }
+
+    if config.stage_only && (config.and_rebase || config.rewrite) {
+        return Err(AbsorbError::InvalidOptions(
+            "--stage-only cannot be used with --and-rebase or --rewrite",
+        )
+        .into());
     }
 
+    if config.from_stash.is_some()
+        && (config.unstaged || config.plan_only.is_some() || config.apply_plan.is_some())
+    {
+        return Err(AbsorbError::InvalidOptions(
+            "--unstaged, --plan-only and --apply-plan cannot be used with --from-stash",
+        )
+        .into());
+    }
+    if config.patch_file.is_some()
+        && (config.unstaged
+            || config.from_stash.is_some()
+            || config.plan_only.is_some()
+            || config.apply_plan.is_some())
+    {
+        return Err(AbsorbError::InvalidOptions(
+            "--unstaged, --from-stash, --plan-only and --apply-plan cannot be used with --patch-file",
+        )
+        .into());
+    }
+    // the saved changes to absorb in place of the staged ones, as a tree on
+    // top of HEAD, and what they were saved in
+    let saved = match (config.from_stash, config.patch_file) {
+        (Some(revision), _) => Some((stash_commit(repo, revision)?.tree()?, "the stash")),
+        (None, Some(path)) => Some((patched_tree(repo, path)?, "the patch")),
+        (None, None) => None,
+    };
+
+    // the staged changes, and the ones auto-staged, are only those to paths
+    // under one of these
+    let pathspec = resolve_pathspec(repo, config.paths)?;
+    debug!(logger, "pathspec"; "paths" => ?pathspec);
+
     let mut we_added_everything_to_index = false;
-    if nothing_left_in_index(repo)? {
-        if config::auto_stage_if_nothing_staged(repo) {
+    // the paths the auto-staging changed, the only ones to unstage again
+    let mut auto_staged = vec![];
+    if config.unstaged {
+        if !nothing_left_in_index(repo)? {
+            return Err(AbsorbError::InvalidOptions(
+                "--unstaged cannot be used while changes are staged",
+            )
+            .into());
+        }
+        if nothing_left_in_workdir(repo)? {
+            announce(logger, Announcement::NothingUnstaged);
+            return Ok(());
+        }
+    } else if saved.is_none() && nothing_left_in_index(repo)? {
+        // --check only looks at what the user staged, and a plan is only
+        // good for the changes that were staged when it was made. with
+        // --stage-only, the changes stay staged for the user to commit, and
+        // with --all-or-nothing, an abort must leave the index as it was.
+        let may_auto_stage = !config.check
+            && !config.all_or_nothing
+            && !config.emit_script
+            && !config.annotate
+            && !config.stage_only
+            && config.plan_only.is_none()
+            && config.apply_plan.is_none();
+        if may_auto_stage && config::auto_stage_if_nothing_staged(repo) {
             // no matter from what subdirectory we're executing,
             // "." will still refer to the root workdir.
-            let pathspec = ["."];
+            let add_pathspec = match &pathspec {
+                Some(pathspec) => pathspec.iter().map(String::as_str).collect(),
+                None => vec!["."],
+            };
             let mut index = repo.index()?;
-            index.add_all(pathspec.iter(), git2::IndexAddOption::DEFAULT, None)?;
-            index.write()?;
+            // like `git add`, leave alone the files that are not meant to
+            // be checked out, whatever is in the working tree in their place
+            let ignore_case = config::ignore_case(repo);
+            let mut skip_worktree = std::collections::HashSet::new();
+            for entry in index.iter() {
+                if entry.flags_extended & git2::IndexEntryExtendedFlag::SKIP_WORKTREE.bits() != 0 {
+                    skip_worktree.insert(worktree_key(&entry.path, ignore_case));
+                }
+            }
+            index.add_all(
+                add_pathspec.iter(),
+                git2::IndexAddOption::DEFAULT,
+                Some(&mut |path: &Path, _: &[u8]| {
+                    let key = path_to_bytes(path).map(|path| worktree_key(&path, ignore_case));
+                    if key.is_ok_and(|key| skip_worktree.contains(&key)) {
+                        return 1;
+                    }
+                    auto_staged.push(path.to_owned());
+                    0
+                }),
+            )?;
+            // a dry run only needs the changes staged in memory
+            if !config.dry_run {
+                write_index(&mut index)?;
+            }
 
             if nothing_left_in_index(repo)? {
                 announce(logger, Announcement::NothingStagedAfterAutoStaging);
@@ -61,288 +580,1035 @@ fn run_with_repo(logger: &slog::Logger, config: &Config, repo: &git2::Repository
         }
     }
 
+    stats.enter(stats::Phase::Stack);
+    let stack_start = std::time::Instant::now();
+    let (base, range_tip) = stack_bases(repo, &config, logger)?;
     let (stack, stack_end_reason) = stack::working_stack(
         repo,
-        config.base,
+        &base,
         config.force_author,
         config.force_detach,
+        config.cross_branch_boundaries,
         logger,
     )?;
+    debug!(logger, "stack found";
+           "commits" => stack.len(),
+           "elapsed_ms" => stack_start.elapsed().as_millis() as u64,
+    );
+    stats.stack = stack.len();
+    let abbrev = config::abbrev_length(repo);
+    let quote_path = config::quote_path(repo);
+    // so that anyone can check how far back the changes could be absorbed
+    announce_stack_base(logger, repo, &stack, abbrev)?;
+
+    // the staged changes and the stack must be split into hunks the same
+    // way, or they may not line up
+    let diff_algorithm = match config
+        .diff_algorithm
+        .map(str::to_owned)
+        .or_else(|| config::diff_algorithm(repo))
+    {
+        Some(name) => config::DiffAlgorithm::from_name(&name).unwrap_or_else(|| {
+            announce(logger, Announcement::UnknownDiffAlgorithm(&name));
+            config::DiffAlgorithm::Myers
+        }),
+        None => config::DiffAlgorithm::Myers,
+    };
+    debug!(logger, "diff algorithm"; "algorithm" => ?diff_algorithm);
+    let context = match config.diff_context {
+        Some(context) => Some(context),
+        None => config::diff_context(repo)?,
+    };
+    let matching = Matching {
+        whole_file: config.whole_file,
+        context: context.unwrap_or(config::DIFF_CONTEXT_DEFAULT),
+        quote_path,
+    };
+    // staged hunks this close go into the same fixup, as a diff with that
+    // much context would show them as one
+    let group_context = context.unwrap_or(config::GROUP_CONTEXT_DEFAULT);
 
+    stats.enter(stats::Phase::Diffs);
     let mut diff_options = Some({
         let mut ret = git2::DiffOptions::new();
-        ret.context_lines(0)
+        diff_algorithm
+            .apply(&mut ret)
+            .context_lines(0)
             .id_abbrev(40)
             .ignore_filemode(true)
             .ignore_submodules(true);
+        for path in pathspec.iter().flatten() {
+            ret.pathspec(path);
+        }
         ret
     });
 
-    let (stack, summary_counts): (Vec<_>, _) = {
+    // the blobs of files above the limit are never read, so that a huge
+    // file cannot exhaust memory
+    let odb = repo.odb()?;
+    let max_file_size = config::max_file_size(repo).map(|max| (&odb, max));
+
+    let mut head_tree = repo.head()?.peel_to_tree()?;
+    let index_diff = if let Some((saved, _)) = &saved {
+        // the stash was saved on top of HEAD, and the patch was applied to
+        // it, so the tree holds exactly the changes they saved, whether
+        // they were staged or not
+        repo.diff_tree_to_tree(Some(&head_tree), Some(saved), diff_options.as_mut())?
+    } else if config.unstaged {
+        // only tracked files are included, since untracked files are
+        // not part of the diff unless explicitly requested
+        repo.diff_tree_to_workdir(Some(&head_tree), diff_options.as_mut())?
+    } else {
+        // the staged content comes from the blobs in the index, never from
+        // the files in the working tree, which may have further edits
+        repo.diff_tree_to_index(Some(&head_tree), None, diff_options.as_mut())?
+    };
+    let index = owned::Diff::new(&index_diff, max_file_size)?;
+    trace!(logger, "parsed index";
+           "index" => ?index,
+    );
+    if let (Some((_, from)), false, false) = (&saved, config.dry_run, config.stage_only) {
+        check_untouched(repo, &index, from)?;
+    }
+
+    // only the staged paths matter in the stack, and leaving out the others
+    // means never reading their blobs, which a partial clone may not have
+    let mut stack_diff_options = git2::DiffOptions::new();
+    diff_algorithm
+        .apply(&mut stack_diff_options)
+        .context_lines(0)
+        .id_abbrev(40)
+        .ignore_filemode(true)
+        .ignore_submodules(true)
+        .disable_pathspec_match(true);
+    for patch in index.iter() {
+        stack_diff_options.pathspec(patch.old_path.clone());
+        stack_diff_options.pathspec(patch.new_path.clone());
+    }
+    if let Some(path) = config.target_touched {
+        stack_diff_options.pathspec(path.trim_start_matches("./").trim_end_matches('/'));
+    }
+
+    let (stack, (summary_counts, subject_counts)): (Vec<_>, _) = {
         let mut diffs = Vec::with_capacity(stack.len());
         for commit in &stack {
-            let diff = owned::Diff::new(
-                &repo.diff_tree_to_tree(
-                    if commit.parents().len() == 0 {
-                        None
-                    } else {
-                        Some(commit.parent(0)?.tree()?)
-                    }
-                    .as_ref(),
-                    Some(&commit.tree()?),
-                    diff_options.as_mut(),
-                )?,
+            let diff = repo.diff_tree_to_tree(
+                if commit.parents().len() == 0 {
+                    None
+                } else {
+                    Some(commit.parent(0)?.tree()?)
+                }
+                .as_ref(),
+                Some(&commit.tree()?),
+                if index.is_empty() {
+                    diff_options.as_mut()
+                } else {
+                    Some(&mut stack_diff_options)
+                },
             )?;
+            promisor::fetch_missing_blobs(repo, &diff, logger)?;
+            let diff = owned::Diff::new(&diff, max_file_size)?;
             trace!(logger, "parsed commit diff";
                    "commit" => commit.id().to_string(),
-                   "diff" => format!("{:?}", diff),
+                   "bytes" => diff.copied_bytes(),
+                   "diff" => ?diff,
             );
             diffs.push(diff);
         }
 
-        let summary_counts = stack::summary_counts(&stack);
+        let summary_counts = (
+            stack::summary_counts(&stack),
+            stack::autosquash_subject_counts(&stack),
+        );
         (stack.into_iter().zip(diffs).collect(), summary_counts)
     };
 
-    let mut head_tree = repo.head()?.peel_to_tree()?;
-    let index = owned::Diff::new(&repo.diff_tree_to_index(
-        Some(&head_tree),
-        None,
-        diff_options.as_mut(),
-    )?)?;
-    trace!(logger, "parsed index";
-           "index" => format!("{:?}", index),
-    );
+    let forced_target = match config.into {
+        Some(into) => Some(commit_by_revision_or_subject(repo, &stack, into)?),
+        None => None,
+    };
+    // with --target-touched, the only commits hunks may be absorbed into
+    let touched_targets: Option<Vec<git2::Oid>> = config.target_touched.map(|path| {
+        let path = path.trim_start_matches("./").trim_end_matches('/');
+        stack
+            .iter()
+            .filter(|(_, diff)| diff.touches(path.as_bytes()))
+            .map(|(commit, _)| commit.id())
+            .collect()
+    });
+    // with --range, the commits newer than its tip are only commuted past
+    let in_range: Option<Vec<git2::Oid>> = range_tip.map(|tip| {
+        stack
+            .iter()
+            .map(|(commit, _)| commit.id())
+            .skip_while(|id| *id != tip)
+            .collect()
+    });
+    let eligible = |commit: &git2::Commit| {
+        forced_target.map_or(true, |target| target == commit.id())
+            && touched_targets
+                .as_ref()
+                .map_or(true, |touched| touched.contains(&commit.id()))
+    };
 
-    let signature = repo
-        .signature()
-        .or_else(|_| git2::Signature::now("nobody", "nobody@example.com"))?;
+    let signature = default_signature(repo)?;
     let mut head_commit = repo.head()?.peel_to_commit()?;
 
+    let applied_plan = match config.apply_plan {
+        Some(path) => {
+            let plan = plan::Plan::read(path)?;
+            let stack: Vec<_> = stack.iter().map(|(commit, _)| commit).collect();
+            plan.check(head_commit.id(), repo.index()?.write_tree()?, &stack)?;
+            debug!(logger, "applying plan"; "path" => path.to_str());
+            Some(plan)
+        }
+        None => None,
+    };
+
+    stats.enter(stats::Phase::Matching);
     let mut hunks_with_commit = vec![];
+    for planned in applied_plan.iter().flat_map(|plan| &plan.fixups) {
+        // the target may have been edited, so make sure the hunk can still
+        // be moved all the way down to it
+        if let Some(hunk) = &planned.hunk {
+            let path = planned.patch.new_path.as_slice();
+            let reached = find_dest_commit(
+                &stack,
+                path,
+                hunk.clone(),
+                Some(planned.target),
+                Matching {
+                    whole_file: false,
+                    ..matching
+                },
+                logger,
+            );
+            if reached.map(|commit| commit.id()) != Some(planned.target) {
+                return Err(AbsorbError::PlanMismatch(format!(
+                    "the plan absorbs the hunk at {} into {}, but it cannot be moved past {}",
+                    hunk_location(path, quote_path, hunk),
+                    planned.target,
+                    reached.map_or("the stack".to_owned(), |commit| commit.id().to_string())
+                ))
+                .into());
+            }
+        }
+        hunks_with_commit.push(HunkWithCommit {
+            hunk_to_apply: planned.hunk.clone(),
+            // the plan was checked against the stack
+            dest_commit: stack
+                .iter()
+                .map(|(commit, _)| commit)
+                .find(|commit| commit.id() == planned.target)
+                .unwrap(),
+            index_patch: &planned.patch,
+            group: planned.group,
+        });
+    }
+    // a group whose hunks were sent to different commits cannot be one fixup
+    for idx in 0..hunks_with_commit.len() {
+        let group = hunks_with_commit[idx].group;
+        let target = hunks_with_commit[idx].dest_commit.id();
+        if group.is_some()
+            && hunks_with_commit
+                .iter()
+                .any(|other| other.group == group && other.dest_commit.id() != target)
+        {
+            for hunk in hunks_with_commit
+                .iter_mut()
+                .filter(|hunk| hunk.group == group)
+            {
+                hunk.group = None;
+            }
+        }
+    }
+    // a plan already says where every hunk goes
+    let unplanned: &[owned::Patch] = if applied_plan.is_some() { &[] } else { &index };
 
     let mut modified_hunks_without_target = 0usize;
     let mut non_modified_patches = 0usize;
-    'patch: for index_patch in index.iter() {
+    // the staged changes that could not be absorbed, and why
+    let mut unabsorbed = vec![];
+    // with --select, whether the user has already answered for all the
+    // remaining hunks at once
+    let mut remaining_selected = if config.select { None } else { Some(true) };
+    let mut next_group = 0usize;
+    // with --time-limit, whether to stop matching the remaining changes
+    let mut time_limit_reached = false;
+    // with --rewrite, the fixups to fold into their target commits
+    let mut fixups = vec![];
+    let mut annotations = Annotations {
+        entries: config.annotate.then(Vec::new),
+        quote_path,
+    };
+    // generated and vendored files are left to whatever makes them
+    let exclude_paths = config::exclude_paths(repo);
+    // so are the hunks with lines that are not meant to be absorbed
+    let exclude_hunks = hunk_patterns(
+        config.exclude_hunks_matching.iter().copied().chain(
+            config::exclude_hunk_patterns(repo)
+                .iter()
+                .map(String::as_str),
+        ),
+    )?;
+    let include_hunks = hunk_patterns(config.include_hunks_matching.iter().copied())?;
+    'patch: for index_patch in unplanned {
         let old_path = index_patch.new_path.as_slice();
+        if time_limit_reached {
+            if index_patch.status == git2::Delta::Modified && !index_patch.binary {
+                stats.hunks += index_patch.hunks.len();
+                for index_hunk in &index_patch.hunks {
+                    unabsorbed.push(Unabsorbed::hunk(
+                        old_path,
+                        quote_path,
+                        index_hunk,
+                        "time-limit",
+                    ));
+                    annotations.hunk(old_path, index_hunk, None);
+                }
+            } else {
+                stats.hunks += 1;
+                unabsorbed.push(Unabsorbed::file(old_path, quote_path, "time-limit"));
+                annotations.file(old_path, status_reason(index_patch.status), None);
+            }
+            continue 'patch;
+        }
+        if index_patch.status != git2::Delta::Modified {
+            stats.hunks += 1;
+        }
+        if is_excluded(&exclude_paths, old_path) {
+            debug!(logger, "skipped patch in excluded directory";
+                    "path" => display_path(old_path, quote_path),
+            );
+            unabsorbed.push(Unabsorbed::file(old_path, quote_path, "excluded"));
+            annotations.file(old_path, "excluded", None);
+            continue 'patch;
+        }
+        if index_patch.status == git2::Delta::Deleted {
+            if config.additions_only {
+                debug!(logger, "skipped deletion with --additions-only");
+                continue 'patch;
+            }
+            let selected = select(
+                prompt,
+                &mut remaining_selected,
+                &format!("{}\ndeleted file\n", display_path(old_path, quote_path)),
+                "Absorb this deletion",
+            )?;
+            if !selected {
+                debug!(logger, "skipped unselected deletion");
+                continue 'patch;
+            }
+
+            let dest_commit = deletion_target(&stack, old_path).filter(|commit| eligible(commit));
+            if let Some(dest_commit) = dest_commit {
+                debug!(logger, "found commit that added deleted file";
+                       "path" => display_path(old_path, quote_path),
+                       "commit" => dest_commit.id().to_string(),
+                );
+                hunks_with_commit.push(HunkWithCommit {
+                    hunk_to_apply: None,
+                    dest_commit,
+                    index_patch,
+                    group: None,
+                });
+                annotations.file(old_path, "deleted", Some(dest_commit));
+                continue 'patch;
+            }
+        }
         if index_patch.status != git2::Delta::Modified {
             debug!(logger, "skipped non-modified patch";
-                    "path" => String::from_utf8_lossy(old_path).into_owned(),
-                    "status" => format!("{:?}", index_patch.status),
+                    "path" => display_path(old_path, quote_path),
+                    "status" => ?index_patch.status,
             );
             non_modified_patches += 1;
+            annotations.file(old_path, status_reason(index_patch.status), None);
+            // auto-staged files were never meant to be absorbed
+            if !we_added_everything_to_index {
+                unabsorbed.push(Unabsorbed::file(
+                    old_path,
+                    quote_path,
+                    status_reason(index_patch.status),
+                ));
+            }
+            continue 'patch;
+        }
+        if index_patch.binary {
+            debug!(logger, "skipped binary patch";
+                    "path" => display_path(old_path, quote_path),
+            );
+            unabsorbed.push(Unabsorbed::file(old_path, quote_path, "binary"));
+            annotations.file(old_path, "binary", None);
+            continue 'patch;
+        }
+        // a commit whose changes to the file were not read could be
+        // mistaken for one that does not touch it
+        if index_patch.too_large
+            || stack
+                .iter()
+                .any(|(_, diff)| diff.by_new(old_path).is_some_and(|patch| patch.too_large))
+        {
+            debug!(logger, "skipped patch of too large file";
+                    "path" => display_path(old_path, quote_path),
+            );
+            unabsorbed.push(Unabsorbed::file(old_path, quote_path, "too-large"));
+            annotations.file(old_path, "too-large", None);
+            continue 'patch;
+        }
+        if config.whole_file && merges_specially(repo, old_path)? {
+            // a custom merge driver must be trusted to know its file
+            // better than the first commit that touched it
+            debug!(logger, "skipped patch with merge attributes";
+                    "path" => display_path(old_path, quote_path),
+            );
+            unabsorbed.push(Unabsorbed::file(old_path, quote_path, "attributes"));
+            annotations.file(old_path, "modified", None);
             continue 'patch;
         }
 
+        let blame = if config.only_my_lines {
+            let mut options = git2::BlameOptions::new();
+            options.newest_commit(head_commit.id());
+            Some(repo.blame_file(path_from_bytes(old_path)?, Some(&mut options))?)
+        } else {
+            None
+        };
+
         let mut preceding_hunks_offset = 0isize;
         let mut applied_hunks_offset = 0isize;
-        'hunk: for index_hunk in &index_patch.hunks {
+        let groups = index_patch.nearby_hunk_groups(group_context);
+        let first_pushed = hunks_with_commit.len();
+        'hunk: for (index_hunk, &group) in index_patch.hunks.iter().zip(&groups) {
             debug!(logger, "next hunk";
                    "header" => index_hunk.header(),
-                   "path" => String::from_utf8_lossy(old_path).into_owned(),
+                   "path" => display_path(old_path, quote_path),
             );
+            stats.hunks += 1;
+            if time_limit_reached {
+                unabsorbed.push(Unabsorbed::hunk(
+                    old_path,
+                    quote_path,
+                    index_hunk,
+                    "time-limit",
+                ));
+                annotations.hunk(old_path, index_hunk, None);
+                continue 'hunk;
+            }
+            if (config.additions_only && index_hunk.added.lines.is_empty())
+                || (config.deletions_only && index_hunk.removed.lines.is_empty())
+            {
+                // left in the index, to be committed on their own
+                debug!(logger, "skipped hunk of the excluded kind");
+                annotations.hunk(old_path, index_hunk, None);
+                preceding_hunks_offset += index_hunk.changed_offset();
+                continue 'hunk;
+            }
 
-            // To properly handle files ("patches" in libgit2 lingo) with multiple hunks, we
-            // need to find the updated line coordinates (`header`) of the current hunk in
-            // two cases:
-            // 1) As if it were the only hunk in the index. This only involves shifting the
-            // "added" side *up* by the offset introduced by the preceding hunks:
-            let isolated_hunk = index_hunk
-                .clone()
-                .shift_added_block(-preceding_hunks_offset);
-
-            // 2) When applied on top of the previously committed hunks. This requires shifting
-            // both the "added" and the "removed" sides of the previously isolated hunk *down*
-            // by the offset of the committed hunks:
-            let hunk_to_apply = isolated_hunk
-                .clone()
-                .shift_both_blocks(applied_hunks_offset);
-
-            // The offset is the number of lines added minus the number of lines removed by a hunk:
-            let hunk_offset = index_hunk.changed_offset();
-
-            // To aid in understanding these arithmetic, here's an illustration.
-            // There are two hunks in the original patch, each adding one line ("line2" and
-            // "line5"). Assuming the first hunk (with offset = -1) was already processed
-            // and applied, the table shows the three versions of the patch, with line numbers
-            // on the <A>dded and <R>emoved sides for each:
-            // |----------------|-----------|------------------|
-            // |                |           | applied on top   |
-            // | original patch | isolated  | of the preceding |
-            // |----------------|-----------|------------------|
-            // | <R> <A>        | <R> <A>   | <R> <A>          |
-            // |----------------|-----------|------------------|
-            // |  1   1  line1  |  1   1    |  1   1   line1   |
-            // |  2      line2  |  2   2    |  2   2   line3   |
-            // |  3   2  line3  |  3   3    |  3   3   line4   |
-            // |  4   3  line4  |  4   4    |  4       line5   |
-            // |  5      line5  |  5        |                  |
-            // |----------------|-----------|------------------|
-            // |       So the second hunk's `header` is:       |
-            // |   -5,1 +3,0    | -5,1 +4,0 |    -4,1 +3,0     |
-            // |----------------|-----------|------------------|
-
-            debug!(logger, "";
-                "to apply" => hunk_to_apply.header(),
-                "to commute" => isolated_hunk.header(),
-                "preceding hunks" => format!("{}/{}", applied_hunks_offset, preceding_hunks_offset),
-            );
+            if is_filtered(&exclude_hunks, &include_hunks, index_hunk) {
+                debug!(logger, "skipped hunk filtered by its added lines");
+                unabsorbed.push(Unabsorbed::hunk(
+                    old_path, quote_path, index_hunk, "filtered",
+                ));
+                annotations.hunk(old_path, index_hunk, None);
+                preceding_hunks_offset += index_hunk.changed_offset();
+                continue 'hunk;
+            }
 
-            preceding_hunks_offset += hunk_offset;
+            let selected = select(
+                prompt,
+                &mut remaining_selected,
+                &describe_hunk(old_path, index_hunk, quote_path),
+                "Absorb this hunk",
+            )?;
+            if !selected {
+                // left in the index just like a hunk without a target
+                debug!(logger, "skipped unselected hunk");
+                annotations.hunk(old_path, index_hunk, None);
+                preceding_hunks_offset += index_hunk.changed_offset();
+                continue 'hunk;
+            }
+            if let Some(blame) = &blame {
+                if !authored_by(blame, index_hunk, &signature) {
+                    // left in the index, someone else's code is theirs to fix
+                    debug!(logger, "skipped hunk over lines by another author");
+                    annotations.hunk(old_path, index_hunk, None);
+                    preceding_hunks_offset += index_hunk.changed_offset();
+                    continue 'hunk;
+                }
+            }
 
-            // find the newest commit that the hunk cannot commute with
-            let mut dest_commit = None;
-            let mut commuted_old_path = old_path;
-            let mut commuted_index_hunk = isolated_hunk;
+            // with --split-hunks, a hunk over lines from different commits
+            // is absorbed in parts, one for each of those commits
+            let parts = if config.split_hunks && forced_target.is_none() && !config.whole_file {
+                split_between_commits(
+                    &stack,
+                    old_path,
+                    index_hunk,
+                    preceding_hunks_offset,
+                    matching,
+                    logger,
+                )
+            } else {
+                vec![index_hunk.clone()]
+            };
+            'part: for index_hunk in &parts {
+                // To properly handle files ("patches" in libgit2 lingo) with multiple hunks, we
+                // need to find the updated line coordinates (`header`) of the current hunk in
+                // two cases:
+                // 1) As if it were the only hunk in the index. This only involves shifting the
+                // "added" side *up* by the offset introduced by the preceding hunks:
+                let isolated_hunk = index_hunk
+                    .clone()
+                    .shift_added_block(-preceding_hunks_offset);
+
+                // 2) When applied on top of the previously committed hunks. This requires shifting
+                // both the "added" and the "removed" sides of the previously isolated hunk *down*
+                // by the offset of the committed hunks:
+                let hunk_to_apply = isolated_hunk
+                    .clone()
+                    .shift_both_blocks(applied_hunks_offset);
+
+                // The offset is the number of lines added minus the number of lines removed by a hunk:
+                let hunk_offset = index_hunk.changed_offset();
+
+                // To aid in understanding these arithmetic, here's an illustration.
+                // There are two hunks in the original patch, each adding one line ("line2" and
+                // "line5"). Assuming the first hunk (with offset = -1) was already processed
+                // and applied, the table shows the three versions of the patch, with line numbers
+                // on the <A>dded and <R>emoved sides for each:
+                // |----------------|-----------|------------------|
+                // |                |           | applied on top   |
+                // | original patch | isolated  | of the preceding |
+                // |----------------|-----------|------------------|
+                // | <R> <A>        | <R> <A>   | <R> <A>          |
+                // |----------------|-----------|------------------|
+                // |  1   1  line1  |  1   1    |  1   1   line1   |
+                // |  2      line2  |  2   2    |  2   2   line3   |
+                // |  3   2  line3  |  3   3    |  3   3   line4   |
+                // |  4   3  line4  |  4   4    |  4       line5   |
+                // |  5      line5  |  5        |                  |
+                // |----------------|-----------|------------------|
+                // |       So the second hunk's `header` is:       |
+                // |   -5,1 +3,0    | -5,1 +4,0 |    -4,1 +3,0     |
+                // |----------------|-----------|------------------|
+
+                trace!(logger, "shifted hunk";
+                    "to apply" => hunk_to_apply.header(),
+                    "to commute" => isolated_hunk.header(),
+                    "applied offset" => applied_hunks_offset,
+                    "preceding offset" => preceding_hunks_offset,
+                );
 
-            'commit: for (commit, diff) in &stack {
-                let c_logger = logger.new(o!(
-                    "commit" => commit.id().to_string(),
-                ));
-                let next_patch = match diff.by_new(commuted_old_path) {
-                    Some(patch) => patch,
-                    // this commit doesn't touch the hunk's file, so
-                    // they trivially commute, and the next commit
-                    // should be considered
-                    None => {
-                        debug!(c_logger, "skipped commit with no path");
-                        continue 'commit;
-                    }
-                };
+                preceding_hunks_offset += hunk_offset;
 
-                // sometimes we just forget some change (eg: intializing some object) that
-                // happens in a completely unrelated place with the current hunks. In those
-                // cases, might be helpful to just match the first commit touching the same
-                // file as the current hunk. Use this option with care!
-                if config.whole_file {
-                    debug!(
-                        c_logger,
-                        "Commit touches the hunk file and match whole file is enabled"
-                    );
-                    dest_commit = Some(commit);
-                    break 'commit;
+                let mut dest_commit = find_dest_commit(
+                    &stack,
+                    old_path,
+                    isolated_hunk.clone(),
+                    forced_target,
+                    matching,
+                    logger,
+                );
+                let mut reason = if stack_end_reason == stack::StackEndReason::ReachedRoot {
+                    "no-target"
+                } else {
+                    // the commit it belongs to may be past the end of the stack
+                    "boundary"
+                };
+                if forced_target.is_some() && dest_commit.map(|c| c.id()) != forced_target {
+                    // the hunk cannot be moved past some newer commit, so it
+                    // cannot be absorbed into the forced target
+                    dest_commit = None;
+                    reason = "blocked";
                 }
-
-                if next_patch.status == git2::Delta::Added {
-                    debug!(c_logger, "found noncommutative commit by add");
-                    dest_commit = Some(commit);
-                    break 'commit;
+                if dest_commit.is_some_and(|commit| !eligible(commit)) {
+                    // the commit it belongs to did not change the path
+                    // given to --target-touched
+                    dest_commit = None;
+                    reason = "untouched";
                 }
-                if commuted_old_path != next_patch.old_path.as_slice() {
-                    debug!(c_logger, "changed commute path";
-                           "path" => String::from_utf8_lossy(&next_patch.old_path).into_owned(),
+                if dest_commit.is_some_and(|commit| {
+                    in_range
+                        .as_ref()
+                        .is_some_and(|in_range| !in_range.contains(&commit.id()))
+                }) {
+                    dest_commit = None;
+                    reason = "out-of-range";
+                }
+                if config.extract
+                    && dest_commit.is_some_and(|commit| {
+                        !inverts_hunk_of(&stack, old_path, &isolated_hunk, commit, matching.context)
+                    })
+                {
+                    // only what the commit added is taken back out of it
+                    dest_commit = None;
+                    reason = "not-inverse";
+                }
+                if let (Some(command), Some(commit)) = (config.verify_build, dest_commit) {
+                    let allowed = |commit: &git2::Commit| {
+                        eligible(commit)
+                            && in_range
+                                .as_ref()
+                                .map_or(true, |in_range| in_range.contains(&commit.id()))
+                    };
+                    dest_commit = verified_target(
+                        repo,
+                        &stack,
+                        old_path,
+                        &isolated_hunk,
+                        commit,
+                        &allowed,
+                        command,
+                        matching,
+                        logger,
+                    )?;
+                    reason = "verify-build";
+                }
+                if let Some(conflicting) = dest_commit.and_then(|commit| {
+                    conflicting_descendant(
+                        &stack,
+                        old_path,
+                        &isolated_hunk,
+                        commit,
+                        matching.context,
+                    )
+                }) {
+                    // squashing the fixup would conflict with the next
+                    // commits, so it is better not made at all
+                    debug!(logger, "fixup would conflict with a newer commit";
+                           "commit" => conflicting.id().to_string(),
                     );
-                    commuted_old_path = next_patch.old_path.as_slice();
+                    dest_commit = None;
+                    reason = "would-conflict";
                 }
-                commuted_index_hunk = match commute::commute_diff_before(
-                    &commuted_index_hunk,
-                    &next_patch.hunks,
-                ) {
-                    Some(hunk) => {
-                        debug!(c_logger, "commuted hunk with commit";
-                               "offset" => (hunk.added.start as i64) - (commuted_index_hunk.added.start as i64),
-                        );
-                        hunk
-                    }
-                    // this commit contains a hunk that cannot
-                    // commute with the hunk being absorbed
+                annotations.hunk(old_path, index_hunk, dest_commit);
+                let dest_commit = match dest_commit {
+                    Some(commit) => commit,
+                    // the hunk commutes with every commit in the stack,
+                    // so there is no commit to absorb it into
                     None => {
-                        debug!(c_logger, "found noncommutative commit by conflict");
-                        dest_commit = Some(commit);
-                        break 'commit;
+                        modified_hunks_without_target += 1;
+                        unabsorbed.push(Unabsorbed::hunk(old_path, quote_path, index_hunk, reason));
+                        continue 'part;
                     }
                 };
+
+                let hunk_with_commit = HunkWithCommit {
+                    hunk_to_apply: Some(hunk_to_apply),
+                    dest_commit,
+                    index_patch,
+                    group: Some(next_group + group),
+                };
+                hunks_with_commit.push(hunk_with_commit);
+
+                applied_hunks_offset += hunk_offset;
             }
-            let dest_commit = match dest_commit {
-                Some(commit) => commit,
-                // the hunk commutes with every commit in the stack,
-                // so there is no commit to absorb it into
-                None => {
-                    modified_hunks_without_target += 1;
-                    continue 'hunk;
-                }
-            };
 
-            let hunk_with_commit = HunkWithCommit {
-                hunk_to_apply,
-                dest_commit,
-                index_patch,
-            };
-            hunks_with_commit.push(hunk_with_commit);
+            if deadline.is_some_and(|deadline| std::time::Instant::now() >= deadline) {
+                time_limit_reached = true;
+                // the deadline is only set with a time limit
+                announce(
+                    logger,
+                    Announcement::TimeLimitReached(config.time_limit.unwrap()),
+                );
+            }
+        }
 
-            applied_hunks_offset += hunk_offset;
+        // nearby hunks are only kept together if all of them were absorbed
+        // into the same commit, otherwise each one stands on its own
+        let mut group_sizes = vec![0usize; groups.last().map_or(0, |last| last + 1)];
+        for &group in &groups {
+            group_sizes[group] += 1;
+        }
+        let pushed = &mut hunks_with_commit[first_pushed..];
+        let mut start = 0;
+        while start < pushed.len() {
+            let group = pushed[start].group;
+            let end = start
+                + pushed[start..]
+                    .iter()
+                    .take_while(|hunk| hunk.group == group)
+                    .count();
+            let complete =
+                group.is_some_and(|group| group_sizes[group - next_group] == end - start);
+            let same_commit = pushed[start..end]
+                .iter()
+                .all(|hunk| hunk.dest_commit.id() == pushed[start].dest_commit.id());
+            if !complete || !same_commit {
+                for hunk in &mut pushed[start..end] {
+                    hunk.group = None;
+                }
+            }
+            start = end;
         }
+        next_group += group_sizes.len();
     }
 
-    let target_always_sha: bool = config::fixup_target_always_sha(repo);
+    // a plan has its hunks as they apply to HEAD on their own, so that
+    // editing the target of one does not change where the others apply
+    let mut unshifted = match applied_plan {
+        Some(_) => hunks_with_commit
+            .iter()
+            .map(|hunk| hunk.hunk_to_apply.clone())
+            .collect(),
+        None => unshifted_hunks(&hunks_with_commit),
+    };
 
-    if !config.dry_run {
-        repo.reference("PRE_ABSORB_HEAD", head_commit.id(), true, "")?;
+    // the fixups for two commits cannot both change the same lines, or
+    // squashing the second one conflicts
+    while let Some((first, second)) = overlapping_hunks(&hunks_with_commit, &unshifted) {
+        let location = |idx: usize| {
+            let path = hunks_with_commit[idx].index_patch.new_path.as_slice();
+            // only hunks overlap, never whole files
+            hunk_location(path, quote_path, unshifted[idx].as_ref().unwrap())
+        };
+        if !config.force_overlap {
+            return Err(AbsorbError::OverlappingHunks {
+                first: location(first),
+                first_target: hunks_with_commit[first].dest_commit.id(),
+                second: location(second),
+                second_target: hunks_with_commit[second].dest_commit.id(),
+            }
+            .into());
+        }
+        // the stack is newest first
+        let position = |idx: usize| {
+            let target = hunks_with_commit[idx].dest_commit.id();
+            stack.iter().position(|(commit, _)| commit.id() == target)
+        };
+        let newer = if position(first) < position(second) {
+            first
+        } else {
+            second
+        };
+        let dropped = hunks_with_commit.remove(newer);
+        let hunk = unshifted.remove(newer).unwrap();
+        unabsorbed.push(Unabsorbed::hunk(
+            &dropped.index_patch.new_path,
+            quote_path,
+            &hunk,
+            "overlap",
+        ));
     }
 
-    // * apply all hunks that are going to be fixed up into `dest_commit`
-    // * commit the fixup
-    // * repeat for all `dest_commit`s
-    //
-    // the `.zip` here will gives us something similar to `.windows`, but with
-    // an extra iteration for the last element (otherwise we would have to
-    // special case the last element and commit it separately)
-    for (current, next) in hunks_with_commit
-        .iter()
-        .zip(hunks_with_commit.iter().skip(1).map(Some).chain([None]))
-    {
-        let new_head_tree = apply_hunk_to_tree(
-            repo,
-            &head_tree,
-            &current.hunk_to_apply,
-            &current.index_patch.old_path,
+    if let Some(annotations) = annotations.entries {
+        write_annotations(
+            out,
+            &annotations,
+            abbrev,
+            config::color_diff(repo, std::io::stdout().is_terminal()),
         )?;
+        return Ok(());
+    }
 
-        // whether there are no more hunks to apply to `dest_commit`
+    if let Some(path) = config.plan_only {
+        let plan = plan::Plan::new(
+            head_commit.id(),
+            repo.index()?.write_tree()?,
+            &hunks_with_commit,
+            &unshifted,
+        );
+        let stack: Vec<_> = stack.iter().map(|(commit, _)| commit).collect();
+        plan.write(path, &stack)?;
+        unabsorbed.sort_by(|a, b| (&a.path, a.line).cmp(&(&b.path, b.line)));
+        summary.leftover_hunks = unabsorbed.len();
+        announce_unabsorbed(logger, &unabsorbed, config.max_report);
+        announce(
+            logger,
+            Announcement::WrotePlan(path, hunks_with_commit.len()),
+        );
+        return Ok(());
+    }
+    // nothing has been committed yet, so nothing has to be undone
+    if config.all_or_nothing && !unabsorbed.is_empty() {
+        unabsorbed.sort_by(|a, b| (&a.path, a.line).cmp(&(&b.path, b.line)));
+        summary.leftover_hunks = unabsorbed.len();
+        announce_unabsorbed(logger, &unabsorbed, config.max_report);
+        return check_absorbed(&unabsorbed);
+    }
+    sort_by_target(&mut hunks_with_commit, &unshifted, &stack);
+
+    stats.enter(stats::Phase::Fixups);
+    let fixup_target = if config.fixup_by_sha {
+        config::FixupTarget::Sha
+    } else {
+        config::fixup_target(repo)
+    };
+    let max_subject_length = config::max_subject_length(repo);
+    // autosquash finds the commit from a short SHA just as well, but only
+    // shorten it when asked to
+    let abbrev_fixup_target = config::abbrev_configured(repo);
+    // with absorb.writeNotes, every fixup is noted as made by this run
+    let write_notes = !config.dry_run && config::write_notes(repo);
+    // with commit.gpgSign, the fixups are signed like git signs its commits
+    let signing = sign::signing(repo, &default_signature(repo)?)?;
+    let run_time = std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .map_or(0, |since| since.as_secs());
+
+    // --stage-only leaves HEAD where it is
+    if !config.dry_run && !config.stage_only {
+        repo.reference("PRE_ABSORB_HEAD", head_commit.id(), true, "")?;
+    }
+    let orig_head = head_commit.id();
+    let mut staged_fixups = vec![];
+    let mut last_fixup_time = None;
+    // with --dry-run, the subjects of the fixups that would have been made
+    let mut would_have_committed = vec![];
+    // with --show-final, the fixups a dry run makes without committing them
+    let mut simulated = vec![];
+
+    // when every hunk belongs to HEAD, amending it leaves nothing to squash
+    let amend_head = config.amend_head
+        && !config.rewrite
+        && !config.stage_only
+        && !config.emit_script
+        && !hunks_with_commit.is_empty()
+        && hunks_with_commit
+            .iter()
+            .all(|hunk| hunk.dest_commit.id() == head_commit.id())
+        && (config.force_rewrite
+            || match rewrite::check_rewritable(repo, &[&head_commit]) {
+                Ok(()) => true,
+                Err(e) => {
+                    announce(logger, Announcement::WillNotAmendHead(&e));
+                    false
+                }
+            });
+
+    // with --emit-script, the tree the script has committed so far
+    let mut script_tree = head_tree.clone();
+    if config.emit_script {
+        let stack: Vec<_> = stack.iter().map(|(commit, _)| commit).collect();
+        script::write_header(out, &stack)?;
+    }
+
+    // * apply all hunks that are going to be fixed up into `dest_commit`
+    // * commit the fixup
+    // * repeat for all `dest_commit`s
+    //
+    // the `.zip` here will gives us something similar to `.windows`, but with
+    // an extra iteration for the last element (otherwise we would have to
+    // special case the last element and commit it separately)
+    for (current, next) in hunks_with_commit
+        .iter()
+        .zip(hunks_with_commit.iter().skip(1).map(Some).chain([None]))
+    {
+        let new_head_tree = match &current.hunk_to_apply {
+            Some(hunk) => {
+                apply_hunk_to_tree(repo, &head_tree, hunk, &current.index_patch.old_path)?
+            }
+            None => remove_from_tree(repo, &head_tree, &current.index_patch.old_path)?,
+        };
+
+        // whether there are no more hunks to apply to `dest_commit`
         let commit_fixup = next.map_or(true, |next| {
+            // the amended HEAD gets all of them at once
+            if amend_head {
+                return false;
+            }
+            // nearby hunks go into the same fixup
+            if current.group.is_some() && next.group == current.group {
+                return false;
+            }
             // if the next hunk is for a different commit -- commit what we have so far
             !config.one_fixup_per_commit || next.dest_commit.id() != current.dest_commit.id()
         });
-        if commit_fixup {
-            // TODO: the git2 api only supports utf8 commit messages,
-            // so it's okay to use strings instead of bytes here
-            // https://docs.rs/git2/0.7.5/src/git2/repo.rs.html#998
-            // https://libgit2.org/libgit2/#HEAD/group/commit/git_commit_create
-            let dest_commit_id = current.dest_commit.id().to_string();
-            let dest_commit_locator = match target_always_sha {
-                true => &dest_commit_id,
-                false => current
-                    .dest_commit
-                    .summary()
+        if commit_fixup && amend_head {
+            let tree_diff =
+                repo.diff_tree_to_tree(Some(&head_commit.tree()?), Some(&new_head_tree), None)?;
+            let diff = tree_diff.stats()?;
+            if config.stat {
+                write_stat(out, &head_commit, &diff, true, abbrev)?;
+            }
+            if config.dry_run {
+                announce(
+                    logger,
+                    Announcement::WouldHaveAmended(&head_commit, &diff, abbrev),
+                );
+            } else {
+                // same author and message, but it is amended now
+                let committer = default_signature(repo)?;
+                record_orig_head(repo, orig_head, "amend HEAD")?;
+                let amended = head_commit.clone();
+                head_commit = repo.find_commit(head_commit.amend(
+                    Some("HEAD"),
+                    None,
+                    Some(&committer),
+                    None,
+                    None,
+                    Some(&new_head_tree),
+                )?)?;
+                head_tree = new_head_tree;
+                if config.porcelain {
+                    write_porcelain(out, "amend", &head_commit, &amended, &tree_diff, quote_path)?;
+                }
+                announce(logger, Announcement::Amended(&head_commit, &diff, abbrev));
+            }
+        } else if commit_fixup {
+            // the fixup is made with a utf8 message, which is why a target
+            // whose subject is in another encoding is named by its sha
+            let dest_commit_id = if abbrev_fixup_target {
+                short_id(current.dest_commit, abbrev)
+            } else {
+                current.dest_commit.id().to_string()
+            };
+            let mut dest_commit_locator = match fixup_target {
+                config::FixupTarget::Sha => &dest_commit_id,
+                config::FixupTarget::Subject => fixup_subject(current.dest_commit)
                     .filter(|&msg| summary_counts[msg] == 1)
                     .unwrap_or(&dest_commit_id),
+                config::FixupTarget::Auto => match fixup_subject(current.dest_commit) {
+                    Some(msg) if subject_counts[stack::autosquash_subject(msg)] == 1 => msg,
+                    Some(_) => {
+                        announce(
+                            logger,
+                            Announcement::AmbiguousSubject(current.dest_commit, abbrev),
+                        );
+                        &dest_commit_id
+                    }
+                    None => &dest_commit_id,
+                },
+            };
+            let verb = if config.squash { "squash" } else { "fixup" };
+            // the "fixup! " before the locator
+            let prefix = verb.len() + 2;
+            let length = prefix + dest_commit_locator.chars().count();
+            if let Some(max) = max_subject_length.filter(|&max| length > max) {
+                // a SHA cannot be shortened and still name the commit
+                let truncated = if config.truncate_subject && dest_commit_locator != dest_commit_id
+                {
+                    truncated_locator(dest_commit_locator, max.saturating_sub(prefix), &stack)
+                } else {
+                    None
+                };
+                match truncated {
+                    Some(truncated) => dest_commit_locator = truncated,
+                    None => announce(
+                        logger,
+                        Announcement::LongSubject(current.dest_commit, abbrev, length, max),
+                    ),
+                }
+            }
+            let mut message = format!("{}! {}\n", verb, dest_commit_locator);
+            if let Some(m) = config.message.filter(|m| !m.is_empty()) {
+                message.push('\n');
+                message.push_str(m);
+                message.push('\n');
             };
-            let diff = repo
-                .diff_tree_to_tree(Some(&head_commit.tree()?), Some(&new_head_tree), None)?
-                .stats()?;
+            if is_duplicate_fixup(
+                repo,
+                &head_commit,
+                verb,
+                current.dest_commit,
+                &new_head_tree,
+            )? {
+                announce(logger, Announcement::AlreadyFixedUp(&head_commit, abbrev));
+                // the hunks stay staged, and the next fixup starts over
+                // from the last one
+                head_tree = if config.emit_script {
+                    script_tree.clone()
+                } else {
+                    head_commit.tree()?
+                };
+                continue;
+            }
+            stats.fixups += 1;
+            let tree_diff =
+                repo.diff_tree_to_tree(Some(&head_commit.tree()?), Some(&new_head_tree), None)?;
+            let diff = tree_diff.stats()?;
+            if config.stat {
+                write_stat(out, current.dest_commit, &diff, stats.fixups == 1, abbrev)?;
+            }
             if !config.dry_run {
                 head_tree = new_head_tree;
-                let verb = if config.squash { "squash" } else { "fixup" };
-                let mut message = format!("{}! {}\n", verb, dest_commit_locator);
-                if let Some(m) = config.message.filter(|m| !m.is_empty()) {
-                    message.push('\n');
-                    message.push_str(m);
-                    message.push('\n');
-                };
-                head_commit = repo.find_commit(repo.commit(
-                    Some("HEAD"),
+                let signature = later_than(&default_signature(repo)?, last_fixup_time)?;
+                last_fixup_time = Some(signature.when());
+                head_commit = repo.find_commit(sign::commit(
+                    repo,
+                    signing.as_ref(),
+                    // the fixups never become part of the branch when rewriting,
+                    // nor with --stage-only
+                    if config.rewrite || config.stage_only {
+                        None
+                    } else {
+                        Some("HEAD")
+                    },
                     &signature,
                     &signature,
                     &message,
                     &head_tree,
                     &[&head_commit],
                 )?)?;
-                announce(logger, Announcement::Committed(&head_commit, &diff));
+                if write_notes {
+                    // the fixup is made all the same
+                    if let Err(e) =
+                        notes::note_fixup(repo, &head_commit, current.dest_commit, run_time)
+                    {
+                        announce(logger, Announcement::NoteNotWritten(&e));
+                    }
+                }
+                if config.rewrite {
+                    fixups.push((head_commit.clone(), current.dest_commit.id()));
+                } else {
+                    if config.stage_only {
+                        staged_fixups.push(head_commit.id());
+                    }
+                    summary
+                        .created_fixups
+                        .push((head_commit.id(), current.dest_commit.id()));
+                    if config.porcelain {
+                        write_porcelain(
+                            out,
+                            verb,
+                            &head_commit,
+                            current.dest_commit,
+                            &tree_diff,
+                            quote_path,
+                        )?;
+                    }
+                    announce(
+                        logger,
+                        Announcement::Committed(&head_commit, current.dest_commit, &diff, abbrev),
+                    );
+                }
             } else {
+                would_have_committed.push(format!("{}! {}", verb, dest_commit_locator));
+                if config.emit_script {
+                    script::write_fixup(out, repo, &script_tree, &new_head_tree, &message)?;
+                    // the next fixup goes on top of this one
+                    script_tree = new_head_tree.clone();
+                    head_tree = new_head_tree;
+                } else if config.show_final || config.export_series.is_some() {
+                    // the fixup is made as it would be, only without a ref
+                    // pointing to it, to rewrite the stack with later
+                    let signature = default_signature(repo)?;
+                    head_commit = repo.find_commit(repo.commit(
+                        None,
+                        &signature,
+                        &signature,
+                        &message,
+                        &new_head_tree,
+                        &[&head_commit],
+                    )?)?;
+                    simulated.push((head_commit.clone(), current.dest_commit.id()));
+                    head_tree = new_head_tree;
+                }
                 announce(
                     logger,
-                    Announcement::WouldHaveCommitted(dest_commit_locator, &diff),
+                    Announcement::WouldHaveCommitted(
+                        dest_commit_locator,
+                        current.dest_commit,
+                        &diff,
+                        abbrev,
+                    ),
                 );
+                if let Some(signing) = &signing {
+                    announce(
+                        logger,
+                        Announcement::WouldSign(dest_commit_locator, &signing.key),
+                    );
+                }
             }
         } else {
             // we didn't commit anything, but we applied a hunk
@@ -350,17 +1616,132 @@ fn run_with_repo(logger: &slog::Logger, config: &Config, repo: &git2::Repository
         }
     }
 
+    stats.absorbed = hunks_with_commit.len();
+
+    if config.emit_script {
+        script::write_footer(out)?;
+    }
+
+    if !simulated.is_empty() && config.show_final {
+        let stack: Vec<_> = stack.iter().map(|(commit, _)| commit).collect();
+        let tip = rewrite::rewrite_stack(repo, &stack, &simulated, true, logger)?;
+        write_final_diff(out, repo, &stack, &tip)?;
+        if tip.tree_id() != head_tree.id() {
+            announce(logger, Announcement::FinalTreeDiffers(&tip, abbrev));
+        }
+    }
+
+    if let (Some(to), Some((newest, _))) = (config.export_series, stack.first()) {
+        let stack: Vec<_> = stack.iter().map(|(commit, _)| commit).collect();
+        // the commits without fixups are exported as they are
+        let tip = match simulated.is_empty() {
+            true => newest.clone(),
+            false => rewrite::rewrite_stack(repo, &stack, &simulated, true, logger)?,
+        };
+        series::export(out, repo, &tip, stack.len(), to)?;
+    }
+
+    if let Some(last) = staged_fixups.last() {
+        repo.reference(STAGED_FIXUPS_REF, *last, true, "absorb: stage fixups")?;
+        for fixup in &staged_fixups {
+            writeln!(out, "{}", fixup)?;
+        }
+    }
+
+    if !fixups.is_empty() {
+        let stack: Vec<_> = stack.iter().map(|(commit, _)| commit).collect();
+        head_commit = rewrite::rewrite_stack(repo, &stack, &fixups, config.force_rewrite, logger)?;
+        record_orig_head(repo, orig_head, "rewrite stack")?;
+        if repo.head()?.is_branch() {
+            repo.head()?
+                .set_target(head_commit.id(), "absorb: rewrite stack")?;
+        } else {
+            repo.set_head_detached(head_commit.id())?;
+        }
+        head_tree = head_commit.tree()?;
+        // the rewritten commits are where the stack was, one for one
+        let mut rewritten = Some(head_commit.clone());
+        for commit in stack {
+            let squashed: Vec<_> = fixups
+                .iter()
+                .filter(|(_, target)| *target == commit.id())
+                .map(|(fixup, _)| fixup.id())
+                .collect();
+            if !squashed.is_empty() {
+                announce(logger, Announcement::Rewrote(commit, abbrev));
+                if let (true, Some(rewritten)) = (write_notes, &rewritten) {
+                    if let Err(e) = notes::copy_notes(repo, rewritten.id(), &squashed) {
+                        announce(logger, Announcement::NoteNotWritten(&e));
+                    }
+                }
+            }
+            rewritten = rewritten.and_then(|rewritten| rewritten.parent(0).ok());
+        }
+        if config.extract {
+            restore_extracted(
+                repo,
+                &repo.find_commit(orig_head)?.tree()?,
+                &head_tree,
+                logger,
+            )?;
+        }
+    }
+
     if we_added_everything_to_index {
         // now that the fixup commits have been created,
         // we should unstage the remaining changes from the index.
 
         let mut index = repo.index()?;
-        index.read_tree(&head_tree)?;
-        index.write()?;
+        if config.dry_run {
+            // forget the changes that were only staged in memory
+            index.read(true)?;
+        } else {
+            // only the auto-staged entries go back to HEAD, every other one
+            // keeps its flags, such as skip-worktree and assume-unchanged
+            let paths: Vec<&Path> = auto_staged.iter().map(AsRef::as_ref).collect();
+            stage_tree_entries(repo, &head_tree, &paths)?;
+        }
+    }
+
+    if config.unstaged && !config.dry_run && !hunks_with_commit.is_empty() {
+        // the absorbed hunks were never staged, so without this the index
+        // would still hold the old content of every absorbed file, which
+        // would show up as staged reverts of the fixups. the new HEAD tree
+        // contains exactly the absorbed hunks on top of the (previously
+        // clean) index content, so stage those entries and nothing else.
+        let mut paths = hunks_with_commit
+            .iter()
+            .map(|hunk| path_from_bytes(&hunk.index_patch.new_path))
+            .collect::<Result<Vec<_>>>()?;
+        // the hunks are in the order of their targets, not of their paths
+        paths.sort();
+        paths.dedup();
+        stage_tree_entries(repo, &head_tree, &paths)?;
     }
 
+    if saved.is_some() && !config.dry_run && !config.stage_only && !hunks_with_commit.is_empty() {
+        // what was absorbed is in HEAD now, so bring it into the index and
+        // the working tree too, which had none of the saved changes
+        let mut checkout = git2::build::CheckoutBuilder::new();
+        checkout.force().disable_pathspec_match(true);
+        for hunk in &hunks_with_commit {
+            checkout.path(hunk.index_patch.new_path.as_slice());
+        }
+        let index_path = repo.index()?.path().map(Path::to_path_buf);
+        retry_locked(index_path.as_deref(), || {
+            repo.checkout_tree(head_tree.as_object(), Some(&mut checkout))
+        })?;
+    }
+
+    unabsorbed.sort_by(|a, b| (&a.path, a.line).cmp(&(&b.path, b.line)));
+    summary.leftover_hunks = unabsorbed.len();
+
     if non_modified_patches == index.len() {
         announce(logger, Announcement::NoFileModifications);
+        announce_unabsorbed(logger, &unabsorbed, config.max_report);
+        if config.check {
+            check_absorbed(&unabsorbed)?;
+        }
         return Ok(());
     }
 
@@ -401,10 +1782,7 @@ fn run_with_repo(logger: &slog::Logger, config: &Config, repo: &git2::Repository
                 );
             }
             stack::StackEndReason::CommitsHiddenByBase => {
-                announce(
-                    logger,
-                    Announcement::CommitsHiddenByBase(config.base.unwrap()),
-                );
+                announce(logger, Announcement::CommitsHiddenByBase(&base));
             }
             stack::StackEndReason::CommitsHiddenByBranches => {
                 announce(logger, Announcement::CommitsHiddenByBranches);
@@ -412,10 +1790,22 @@ fn run_with_repo(logger: &slog::Logger, config: &Config, repo: &git2::Repository
         }
     }
 
-    if !hunks_with_commit.is_empty() {
-        use std::process::Command;
+    announce_unabsorbed(logger, &unabsorbed, config.max_report);
+
+    if !hunks_with_commit.is_empty() && !config.rewrite && !amend_head {
+        // only the commits from the oldest one that gets a fixup up need to
+        // be rewritten, unless the whole stack should be
+        let rebased = if config::rebase_whole_stack(repo) {
+            stack.len()
+        } else {
+            let targets: Vec<_> = hunks_with_commit
+                .iter()
+                .map(|hunk| hunk.dest_commit.id())
+                .collect();
+            rebase_depth(&stack, &targets)
+        };
         // unwrap() is safe here, as we exit early if the stack is empty
-        let last_commit_in_stack = &stack.last().unwrap().0;
+        let last_commit_in_stack = &stack[..rebased].last().unwrap().0;
         // The stack isn't supposed to have any merge commits, per the check in working_stack()
         let number_of_parents = last_commit_in_stack.parents().len();
         assert!(number_of_parents <= 1);
@@ -423,8 +1813,8 @@ fn run_with_repo(logger: &slog::Logger, config: &Config, repo: &git2::Repository
         let rebase_root = if number_of_parents == 0 {
             "--root"
         } else {
-            // Use a range that is guaranteed to include all the commits we might have
-            // committed "fixup!" commits for.
+            // Use a range that is guaranteed to include all the commits we
+            // have committed "fixup!" commits for.
             &*last_commit_in_stack.parent(0)?.id().to_string()
         };
 
@@ -436,289 +1826,7628 @@ fn run_with_repo(logger: &slog::Logger, config: &Config, repo: &git2::Repository
             rebase_root,
         ];
 
-        if config.and_rebase {
-            let mut command = Command::new("git");
-
-            // We'd generally expect to be run from within the repository, but just in case,
-            // try to have git run rebase from the repository root.
-            // This simplifies writing tests that execute from within git-absorb's source directory
-            // but operate on temporary repositories created elsewhere.
-            // (The tests could explicitly change directories, but then must be serialized.)
-            let repo_path = repo.workdir().and_then(Path::to_str);
-            match repo_path {
-                Some(path) => {
-                    command.args(["-C", path]);
-                }
-                _ => {
-                    announce(logger, Announcement::CouldNotFindRepositoryPath);
-                }
-            }
-
-            command.args(rebase_args);
-
-            for arg in config.rebase_options {
-                command.arg(arg);
-            }
+        // --and-rebase always rebases, absorb.andRebase only when it is safe
+        let and_rebase = config.and_rebase
+            || (!config.no_rebase
+                && !config.stage_only
+                && config::and_rebase(repo)
+                && can_rebase_by_default(logger, repo)?);
+        if and_rebase {
+            stats.enter(stats::Phase::Rebase);
+            let mut command = rebase_command(logger, &config, repo, &rebase_args);
 
             if config.dry_run {
-                announce(logger, Announcement::WouldHaveRebased(&command));
+                if config.emit_script {
+                    let args: Vec<_> = rebase_args
+                        .iter()
+                        .chain(config.rebase_options)
+                        .copied()
+                        .collect();
+                    script::write_command(out, &args)?;
+                }
+                let stack: Vec<_> = stack[..rebased].iter().map(|(commit, _)| commit).collect();
+                let todo = rebase_todo(&stack, &would_have_committed, config::abbrev_length(repo));
+                announce(logger, Announcement::WouldHaveRebased(&command, &todo));
             } else {
-                debug!(logger, "running git rebase"; "command" => format!("{:?}", command));
-                // Don't check that we have successfully absorbed everything, nor git's
-                // exit code -- as git will print helpful messages on its own.
-                command.status().expect("could not run git rebase");
+                record_orig_head(repo, orig_head, "rebase")?;
+                debug!(logger, "running git rebase"; "command" => ?command);
+                // Don't check that we have successfully absorbed everything -- as git
+                // will print helpful messages on its own. Only pass on its exit code.
+                let status = command
+                    .status()
+                    .map_err(|e| anyhow!("could not run git rebase: {}", e))?;
+                if !status.success() {
+                    return Err(AbsorbError::RebaseFailed {
+                        status: status.code(),
+                    }
+                    .into());
+                }
             }
+        } else if config.stage_only {
+            announce(logger, Announcement::HowToPickStagedFixups);
         } else if !config.dry_run {
             announce(logger, Announcement::HowToSquash(rebase_args.join(" ")));
         }
     }
 
+    if config.check {
+        check_absorbed(&unabsorbed)?;
+    }
+
     Ok(())
 }
 
-struct HunkWithCommit<'c, 'r, 'p> {
-    hunk_to_apply: owned::Hunk,
-    dest_commit: &'c git2::Commit<'r>,
-    index_patch: &'p owned::Patch,
+/// Points [`ORIG_HEAD_REF`] at `orig_head`, the branch tip from before
+/// the run, and adds to its reflog, so that every earlier tip can still be
+/// found after `action` rewrites the history.
+fn record_orig_head(repo: &git2::Repository, orig_head: git2::Oid, action: &str) -> Result<()> {
+    // git keeps no reflog for refs outside of refs/heads and the like
+    repo.reference_ensure_log(ORIG_HEAD_REF)?;
+    repo.reference(
+        ORIG_HEAD_REF,
+        orig_head,
+        true,
+        &format!("absorb: before {}", action),
+    )?;
+    Ok(())
 }
 
-fn apply_hunk_to_tree<'repo>(
-    repo: &'repo git2::Repository,
-    base: &git2::Tree,
-    hunk: &owned::Hunk,
-    path: &[u8],
-) -> Result<git2::Tree<'repo>> {
-    let mut treebuilder = repo.treebuilder(Some(base))?;
+/// How staged hunks are matched to the commits in the stack.
+#[derive(Clone, Copy)]
+struct Matching {
+    whole_file: bool,
+    /// how many unchanged lines between two hunks still keep them in order
+    context: usize,
+    quote_path: bool,
+}
 
-    // recurse into nested tree if applicable
-    if let Some(slash) = path.iter().position(|&x| x == b'/') {
-        let (first, rest) = path.split_at(slash);
-        let rest = &rest[1..];
+/// Finds the newest commit in `stack` that `hunk` cannot be moved past,
+/// which is the commit it should be absorbed into.
+fn find_dest_commit<'s, 'repo>(
+    stack: &'s [(git2::Commit<'repo>, owned::Diff)],
+    old_path: &[u8],
+    hunk: owned::Hunk,
+    forced_target: Option<git2::Oid>,
+    matching: Matching,
+    logger: &slog::Logger,
+) -> Option<&'s git2::Commit<'repo>> {
+    let Matching {
+        whole_file,
+        context,
+        quote_path,
+    } = matching;
+    // find the newest commit that the hunk cannot commute with
+    let mut dest_commit = None;
+    // with --whole-file, the newest commit touching the file, and
+    // whether a commit touching the same function was found instead
+    let mut first_touching = None;
+    let mut matched_function = false;
+    let mut commuted_old_path = old_path;
+    let mut commuted_index_hunk = hunk;
+
+    'commit: for (commit, diff) in stack {
+        let c_logger = logger.new(o!(
+            "commit" => commit.id().to_string(),
+        ));
+        // the hunk made it all the way down to the requested
+        // target, so that's where it goes
+        if forced_target == Some(commit.id()) {
+            debug!(c_logger, "reached forced target commit");
+            dest_commit = Some(commit);
+            break 'commit;
+        }
+        let next_patch = match diff.by_new(commuted_old_path) {
+            Some(patch) => patch,
+            // this commit doesn't touch the hunk's file, so
+            // they trivially commute, and the next commit
+            // should be considered
+            None => {
+                trace!(c_logger, "skipped commit with no path");
+                continue 'commit;
+            }
+        };
 
-        let (subtree, submode) = {
-            let entry = treebuilder
-                .get(first)?
-                .ok_or_else(|| anyhow!("couldn't find tree entry in tree for path"))?;
-            (repo.find_tree(entry.id())?, entry.filemode())
+        // sometimes we just forget some change (eg: intializing some object) that
+        // happens in a completely unrelated place with the current hunks. In those
+        // cases, might be helpful to just match the first commit touching the same
+        // file as the current hunk. Use this option with care!
+        //
+        // the hunk can still be moved past any commit it commutes with, so
+        // if one of those touched the same function, that's a better guess.
+        if whole_file {
+            let funcname = commuted_index_hunk
+                .funcname
+                .as_deref()
+                .filter(|funcname| next_patch.touches_function(funcname));
+            if let Some(funcname) = funcname {
+                debug!(c_logger, "Commit touches the hunk function";
+                       "function" => String::from_utf8_lossy(funcname).into_owned(),
+                );
+                dest_commit = Some(commit);
+                matched_function = true;
+                break 'commit;
+            }
+            if first_touching.is_none() {
+                debug!(
+                    c_logger,
+                    "Commit touches the hunk file and match whole file is enabled"
+                );
+                first_touching = Some(commit);
+            }
+        }
+
+        if next_patch.status == git2::Delta::Added {
+            debug!(c_logger, "found noncommutative commit by add");
+            dest_commit = Some(commit);
+            break 'commit;
+        }
+        if commuted_old_path != next_patch.old_path.as_slice() {
+            trace!(c_logger, "changed commute path";
+                   "path" => display_path(&next_patch.old_path, quote_path),
+            );
+            commuted_old_path = next_patch.old_path.as_slice();
+        }
+        commuted_index_hunk = match commute::commute_patch_before(
+            &commuted_index_hunk,
+            &next_patch.hunks,
+            context,
+        ) {
+            Some(hunk) => {
+                trace!(c_logger, "commuted hunk with commit";
+                       "offset" => (hunk.added.start as i64) - (commuted_index_hunk.added.start as i64),
+                );
+                hunk
+            }
+            // this commit contains a hunk that cannot
+            // commute with the hunk being absorbed
+            None => {
+                debug!(c_logger, "found noncommutative commit by conflict");
+                dest_commit = Some(commit);
+                break 'commit;
+            }
         };
-        // TODO: loop instead of recursing to avoid potential stack overflow
-        let result_subtree = apply_hunk_to_tree(repo, &subtree, hunk, rest)?;
+    }
+    if whole_file && !matched_function && forced_target.is_none() {
+        dest_commit = first_touching.or(dest_commit);
+    }
+    dest_commit
+}
 
-        treebuilder.insert(first, result_subtree.id(), submode)?;
-        return Ok(repo.find_tree(treebuilder.write()?)?);
+/// Splits `index_hunk` into the parts that belong to different commits in
+/// `stack`, joining neighbouring lines that belong to the same commit.
+///
+/// The hunk is kept whole unless every part has a commit to go to.
+fn split_between_commits(
+    stack: &[(git2::Commit, owned::Diff)],
+    old_path: &[u8],
+    index_hunk: &owned::Hunk,
+    preceding_hunks_offset: isize,
+    matching: Matching,
+    logger: &slog::Logger,
+) -> Vec<owned::Hunk> {
+    let mut parts: Vec<(owned::Hunk, git2::Oid)> = vec![];
+    let mut offset = preceding_hunks_offset;
+    for part in commute::split(index_hunk) {
+        let isolated_part = part.clone().shift_added_block(-offset);
+        offset += part.changed_offset();
+        let dest_commit = match find_dest_commit(
+            stack,
+            old_path,
+            isolated_part,
+            None,
+            Matching {
+                whole_file: false,
+                ..matching
+            },
+            logger,
+        ) {
+            Some(commit) => commit.id(),
+            None => return vec![index_hunk.clone()],
+        };
+        match parts.last_mut() {
+            Some((above, id)) if *id == dest_commit => *above = commute::join(above, &part),
+            _ => parts.push((part, dest_commit)),
+        }
+    }
+    if parts.len() > 1 {
+        debug!(logger, "split hunk between commits";
+               "header" => index_hunk.header(),
+               "parts" => parts.len(),
+        );
     }
+    parts.into_iter().map(|(part, _)| part).collect()
+}
 
-    let (blob, mode) = {
-        let entry = treebuilder
-            .get(path)?
-            .ok_or_else(|| anyhow!("couldn't find blob entry in tree for path"))?;
-        (repo.find_blob(entry.id())?, entry.filemode())
+/// Whether `head` is already a `verb` commit for `target` that makes the
+/// changes from its own tree to `tree`, as it is when the same hunks are
+/// staged and absorbed again, so that committing them would duplicate it.
+/// Hunks that add the same lines again right next to those `head` added
+/// belong to `head` itself, which counts as well.
+fn is_duplicate_fixup(
+    repo: &git2::Repository,
+    head: &git2::Commit,
+    verb: &str,
+    target: &git2::Commit,
+    tree: &git2::Tree,
+) -> Result<bool> {
+    // the fixup may name the target either way, whichever was chosen
+    // when it was made
+    let locator = match head
+        .summary()
+        .and_then(|summary| summary.strip_prefix(verb))
+        .and_then(|rest| rest.strip_prefix("! "))
+    {
+        Some(locator) => locator,
+        None => return Ok(false),
     };
+    // autosquash also matches a subject by its start, as truncated
+    // subjects are
+    let names_target = target.id() == head.id()
+        || fixup_subject(target)
+            .is_some_and(|subject| !locator.is_empty() && subject.starts_with(locator))
+        || (locator.len() >= 4 && target.id().to_string().starts_with(locator));
+    if head.parent_count() != 1 || !names_target {
+        return Ok(false);
+    }
+    // without context lines, the same hunks give the same patch id
+    // wherever they end up in the file
+    let mut options = git2::DiffOptions::new();
+    options.context_lines(0);
+    let head_tree = head.tree()?;
+    let existing = repo
+        .diff_tree_to_tree(
+            Some(&head.parent(0)?.tree()?),
+            Some(&head_tree),
+            Some(&mut options),
+        )?
+        .patchid(None)?;
+    let new = repo
+        .diff_tree_to_tree(Some(&head_tree), Some(tree), Some(&mut options))?
+        .patchid(None)?;
+    Ok(existing == new)
+}
 
-    // TODO: convert path to OsStr and pass it during blob_writer
-    // creation, to get gitattributes handling (note that converting
-    // &[u8] to &std::path::Path is only possible on unixy platforms)
-    let mut blobwriter = repo.blob_writer(None)?;
-    let old_content = blob.content();
-    let (old_start, _, _, _) = hunk.anchors();
+fn default_signature(repo: &git2::Repository) -> Result<git2::Signature<'static>> {
+    Ok(repo
+        .signature()
+        .or_else(|_| git2::Signature::now("nobody", "nobody@example.com"))?)
+}
 
-    // first, write the lines from the old content that are above the
-    // hunk
-    let old_content = {
-        let (pre, post) = split_lines_after(old_content, old_start);
-        blobwriter.write_all(pre)?;
-        post
-    };
-    // next, write the added side of the hunk
-    for line in &*hunk.added.lines {
-        blobwriter.write_all(line)?;
+/// `signature`, moved to a second after `previous` unless it already is
+/// later, so that fixups made within the same second still have strictly
+/// increasing timestamps, like git gives commits made in a row.
+fn later_than(
+    signature: &git2::Signature,
+    previous: Option<git2::Time>,
+) -> Result<git2::Signature<'static>> {
+    match previous {
+        Some(previous) if signature.when().seconds() <= previous.seconds() => {
+            let when = git2::Time::new(previous.seconds() + 1, signature.when().offset_minutes());
+            Ok(git2::Signature::new(
+                &String::from_utf8_lossy(signature.name_bytes()),
+                &String::from_utf8_lossy(signature.email_bytes()),
+                &when,
+            )?)
+        }
+        _ => Ok(signature.to_owned()),
     }
-    // if this hunk removed lines from the old content, those must be
-    // skipped
-    let (_, old_content) = split_lines_after(old_content, hunk.removed.lines.len());
-    // finally, write the remaining lines of the old content
-    blobwriter.write_all(old_content)?;
-
-    treebuilder.insert(path, blobwriter.commit()?, mode)?;
-    Ok(repo.find_tree(treebuilder.write()?)?)
 }
 
-/// Return slices for lines [1..n] and [n+1; ...]
-fn split_lines_after(content: &[u8], n: usize) -> (&[u8], &[u8]) {
-    let split_index = if n > 0 {
-        memchr::Memchr::new(b'\n', content)
-            .fuse() // TODO: is fuse necessary here?
-            .nth(n - 1) // the position of '\n' ending the `n`-th line
-            .map(|x| x + 1)
-            .unwrap_or_else(|| content.len())
-    } else {
-        0
-    };
-    content.split_at(split_index)
+/// The paths with conflicts in the index, each once, however many of its
+/// stages are there.
+fn unmerged_paths(repo: &git2::Repository) -> Result<Vec<String>> {
+    let index = repo.index()?;
+    if !index.has_conflicts() {
+        return Ok(vec![]);
+    }
+    let quote_path = config::quote_path(repo);
+    let mut paths = vec![];
+    for conflict in index.conflicts()? {
+        let conflict = conflict?;
+        if let Some(entry) = conflict.our.or(conflict.their).or(conflict.ancestor) {
+            paths.push(display_path(&entry.path, quote_path));
+        }
+    }
+    Ok(paths)
 }
 
-fn nothing_left_in_index(repo: &git2::Repository) -> Result<bool> {
-    let stats = index_stats(repo)?;
-    let nothing = stats.files_changed() == 0 && stats.insertions() == 0 && stats.deletions() == 0;
-    Ok(nothing)
+/// The git operation that `state` is in the middle of, if it matters to us.
+fn operation_in_progress(state: git2::RepositoryState) -> Option<&'static str> {
+    use git2::RepositoryState::*;
+    match state {
+        Merge => Some("merge"),
+        Revert | RevertSequence => Some("revert"),
+        CherryPick | CherryPickSequence => Some("cherry-pick"),
+        Rebase | RebaseInteractive | RebaseMerge => Some("rebase"),
+        ApplyMailbox | ApplyMailboxOrRebase => Some("git am"),
+        Clean | Bisect => None,
+    }
 }
 
-fn index_stats(repo: &git2::Repository) -> Result<git2::DiffStats> {
-    let head = repo.head()?.peel_to_tree()?;
-    let diff = repo.diff_tree_to_index(Some(&head), Some(&repo.index()?), None)?;
-    let stats = diff.stats()?;
-    Ok(stats)
+/// Whether absorb.andRebase may rebase: not while git is in the middle of
+/// something else, and not over changes in the working tree, unless
+/// rebase.autoStash is set.
+fn can_rebase_by_default(logger: &slog::Logger, repo: &git2::Repository) -> Result<bool> {
+    if let Some(operation) = operation_in_progress(repo.state()) {
+        announce(
+            logger,
+            Announcement::WillNotRebase(&format!("a {} is in progress", operation)),
+        );
+        return Ok(false);
+    }
+    if !config::rebase_auto_stash(repo) {
+        let mut options = git2::StatusOptions::new();
+        options.include_untracked(false).exclude_submodules(true);
+        if !repo.statuses(Some(&mut options))?.is_empty() {
+            announce(
+                logger,
+                Announcement::WillNotRebase(
+                    "the working tree or index has changes, and rebase.autoStash is not set",
+                ),
+            );
+            return Ok(false);
+        }
+    }
+    Ok(true)
 }
 
-// Messages that will be shown to users during normal operations (not debug messages).
-enum Announcement<'r> {
-    Committed(&'r git2::Commit<'r>, &'r git2::DiffStats),
-    WouldHaveCommitted(&'r str, &'r git2::DiffStats),
-    WouldHaveRebased(&'r std::process::Command),
-    HowToSquash(String),
-    NothingStagedAfterAutoStaging,
-    NothingStaged,
-    NoFileModifications,
-    NonFileModifications,
-    FileModificationsWithoutTarget,
-    CannotFixUpPastFirstCommit,
-    CannotFixUpPastMerge(&'r git2::Commit<'r>),
-    WillNotFixUpPastAnotherAuthor(&'r git2::Commit<'r>),
-    WillNotFixUpPastStackLimit(usize),
-    CommitsHiddenByBase(&'r str),
-    CommitsHiddenByBranches,
-    CouldNotFindRepositoryPath,
+/// The bases to pass to [`stack::working_stack`], and with --range, the
+/// newest commit that may be absorbed into.
+fn stack_bases(
+    repo: &git2::Repository,
+    config: &Config,
+    logger: &slog::Logger,
+) -> Result<(Vec<String>, Option<git2::Oid>)> {
+    match config.range {
+        Some(range) => {
+            let (base, tip) = stack::resolve_range(repo, range)?;
+            Ok((vec![base], Some(tip)))
+        }
+        None => Ok((stack::resolve_base(repo, config.base, logger)?, None)),
+    }
 }
 
-fn announce(logger: &slog::Logger, announcement: Announcement) {
-    match announcement {
-        Announcement::Committed(commit, diff) => info!(
-            logger,
-            "committed";
-            "commit" => &commit.id().to_string(),
-            "header" => format!("+{},-{}", &diff.insertions(), &diff.deletions())
-        ),
-        Announcement::WouldHaveCommitted(fixup, diff) => info!(
-            logger,
-            "would have committed";
-            "fixup" => fixup,
-            "header" => format!("+{},-{}", &diff.insertions(), &diff.deletions())
-        ),
-        Announcement::WouldHaveRebased(command) => info!(
-            logger, "would have run git rebase"; "command" => format!("{:?}", command)
-        ),
-        Announcement::HowToSquash(rebase_args) => info!(
-            logger,
-            "To squash the new commits, rebase:";
-            "command" => format!("git {}", rebase_args),
-        ),
-        Announcement::NothingStagedAfterAutoStaging => warn!(
-            logger,
-            "No changes staged, even after auto-staging. Try adding something to the index.",
-        ),
-        Announcement::NothingStaged => warn!(
-            logger,
-            "No changes staged. Try adding something to the index or set {} = true.",
-            config::AUTO_STAGE_IF_NOTHING_STAGED_CONFIG_NAME
-        ),
-        Announcement::NoFileModifications => warn!(
-            logger,
-            "No changes were in-place file modifications. \
-                Added, removed, or renamed files cannot be automatically absorbed."
-        ),
-        Announcement::NonFileModifications => warn!(
-            logger,
-            "Some changes were not in-place file modifications. \
-                Added, removed, or renamed files cannot be automatically absorbed."
-        ),
-        Announcement::FileModificationsWithoutTarget => warn!(
-            logger,
-            "Some file modifications did not have an available commit to fix up. \
-                You will have to manually create fixup commits."
-        ),
-        Announcement::CannotFixUpPastFirstCommit => warn!(
-            logger,
-            "Cannot fix up past the first commit in the repository."
-        ),
-        Announcement::CannotFixUpPastMerge(commit) => warn!(
-            logger,
-            "Cannot fix up past a merge commit";
-            "commit" => commit.id().to_string()
-        ),
-        Announcement::WillNotFixUpPastAnotherAuthor(commit) => warn!(
-            logger,
-            "Will not fix up past commits by another author. Use --force-author to override";
-            "commit" => commit.id().to_string()
-        ),
-        Announcement::WillNotFixUpPastStackLimit(max_stack_limit) => warn!(
-            logger,
-            "Will not fix up past maximum stack limit. Use --base or configure {} to override",
-            config::MAX_STACK_CONFIG_NAME;
-            "limit" => max_stack_limit,
-        ),
-        Announcement::CommitsHiddenByBase(base) => warn!(
-            logger,
-            "Will not fix up past specified base commit. \
-            Consider using --base to specify a different base commit";
-            "base" => base,
-        ),
-        Announcement::CommitsHiddenByBranches => warn!(
+fn print_stack(logger: &slog::Logger, config: &Config, repo: &git2::Repository) -> Result<()> {
+    let (base, _) = stack_bases(repo, config, logger)?;
+    let (stack, _) = stack::working_stack(
+        repo,
+        &base,
+        config.force_author,
+        config.force_detach,
+        config.cross_branch_boundaries,
+        logger,
+    )?;
+    let abbrev = config::abbrev_length(repo);
+    for commit in &stack {
+        announce(logger, Announcement::StackCommit(commit, abbrev));
+    }
+    announce_stack_base(logger, repo, &stack, abbrev)
+}
+
+/// Names the commit right below `stack`, the one nothing is absorbed into.
+fn announce_stack_base(
+    logger: &slog::Logger,
+    repo: &git2::Repository,
+    stack: &[git2::Commit],
+    abbrev: usize,
+) -> Result<()> {
+    let below_stack = match stack.last() {
+        Some(commit) => commit.parents().next(),
+        None => Some(repo.head()?.peel_to_commit()?),
+    };
+    announce(
+        logger,
+        Announcement::StackBase(below_stack.as_ref(), abbrev),
+    );
+    Ok(())
+}
+
+/// The `git rebase` that --and-rebase runs, with `rebase_args` and then the
+/// REBASE_OPTIONS.
+fn rebase_command(
+    logger: &slog::Logger,
+    config: &Config,
+    repo: &git2::Repository,
+    rebase_args: &[&str],
+) -> std::process::Command {
+    let mut command = std::process::Command::new("git");
+
+    // We'd generally expect to be run from within the repository, but just in case,
+    // try to have git run rebase from the repository root.
+    // This simplifies writing tests that execute from within git-absorb's source directory
+    // but operate on temporary repositories created elsewhere.
+    // (The tests could explicitly change directories, but then must be serialized.)
+    let repo_path = repo.workdir().and_then(Path::to_str);
+    match repo_path {
+        Some(path) => {
+            command.args(["-C", path]);
+        }
+        _ => {
+            announce(logger, Announcement::CouldNotFindRepositoryPath);
+        }
+    }
+
+    // git only reads its own config files, so a conflict style that git-absorb
+    // was given in some other way (like --config-file) has to be passed along
+    if let Some(style) = config::conflict_style(repo) {
+        command.args([
+            "-c",
+            &format!("{}={}", config::MERGE_CONFLICT_STYLE_CONFIG_NAME, style),
+        ]);
+    }
+
+    // git copies the notes of the fixups onto the commits they are squashed
+    // into, if it is told which notes to copy
+    if config::write_notes(repo) {
+        command.args(["-c", &format!("notes.rewriteRef={}", notes::NOTES_REF)]);
+    }
+
+    command.args(rebase_args);
+
+    for arg in config.rebase_options {
+        command.arg(arg);
+    }
+    command
+}
+
+/// How many of the commits in `stack` a rebase has to rewrite: those from
+/// the oldest of `targets` up, and further down to whatever the fixups
+/// already among them fix up, which autosquash would otherwise leave as
+/// they are.
+fn rebase_depth(stack: &[(git2::Commit, owned::Diff)], targets: &[git2::Oid]) -> usize {
+    let oldest_first: Vec<_> = stack.iter().rev().map(|(commit, _)| commit).collect();
+    let subjects: Vec<_> = oldest_first
+        .iter()
+        .map(|commit| String::from_utf8_lossy(commit.summary_bytes().unwrap_or_default()))
+        .collect();
+    let commits: Vec<_> = oldest_first
+        .iter()
+        .zip(&subjects)
+        .map(|(commit, subject)| (Some(commit.id()), &**subject))
+        .collect();
+    let autosquashed = stack::autosquash_targets(&commits);
+
+    let mut oldest = match oldest_first
+        .iter()
+        .position(|commit| targets.contains(&commit.id()))
+    {
+        Some(oldest) => oldest,
+        None => return stack.len(),
+    };
+    while let Some(deeper) = autosquashed[oldest..]
+        .iter()
+        .flatten()
+        .copied()
+        .filter(|&target| target < oldest)
+        .min()
+    {
+        oldest = deeper;
+    }
+    stack.len() - oldest
+}
+
+/// The todo list the rebase would start with, as `git rebase` shows it, if
+/// the fixups with the subjects `fixups` were made on top of `stack`.
+fn rebase_todo(stack: &[&git2::Commit], fixups: &[String], abbrev: usize) -> Vec<String> {
+    let oldest_first: Vec<_> = stack.iter().rev().collect();
+    let subjects: Vec<_> = oldest_first
+        .iter()
+        .map(|commit| String::from_utf8_lossy(commit.summary_bytes().unwrap_or_default()))
+        .collect();
+    let commits: Vec<_> = oldest_first
+        .iter()
+        .zip(&subjects)
+        .map(|(commit, subject)| (Some(commit.id()), &**subject))
+        .chain(fixups.iter().map(|subject| (None, subject.as_str())))
+        .collect();
+    stack::autosquash_todo(&commits)
+        .into_iter()
+        .map(|(action, idx)| match oldest_first.get(idx) {
+            Some(commit) => format!("{} {} {}", action, short_id(commit, abbrev), commits[idx].1),
+            None => format!("{} (new) {}", action, commits[idx].1),
+        })
+        .collect()
+}
+
+/// `command` as it would be typed into a shell.
+fn command_line(command: &std::process::Command) -> String {
+    std::iter::once(command.get_program())
+        .chain(command.get_args())
+        .map(|arg| script::quote(&arg.to_string_lossy()))
+        .collect::<Vec<_>>()
+        .join(" ")
+}
+
+/// Reports every change that could not be absorbed, failing if there are any.
+fn check_absorbed(unabsorbed: &[Unabsorbed]) -> Result<()> {
+    if unabsorbed.is_empty() {
+        return Ok(());
+    }
+    Err(AbsorbError::CheckFailed {
+        unabsorbed: unabsorbed.len(),
+    }
+    .into())
+}
+
+fn announce_unabsorbed(logger: &slog::Logger, unabsorbed: &[Unabsorbed], max: Option<usize>) {
+    let shown = max.unwrap_or(unabsorbed.len()).min(unabsorbed.len());
+    for change in &unabsorbed[..shown] {
+        announce(
             logger,
-            "Will not fix up commits reachable by other branches. \
-                Use --base to specify a base commit."
-        ),
-        Announcement::CouldNotFindRepositoryPath => warn!(
+            Announcement::NotAbsorbed(&change.location, change.reason),
+        );
+    }
+    if shown < unabsorbed.len() {
+        announce(
             logger,
-            "Could not determine repository path for rebase. Running in current directory."
-        ),
+            Announcement::MoreNotAbsorbed(unabsorbed.len() - shown),
+        );
+    }
+}
+
+/// A staged change that was left in the index.
+struct Unabsorbed {
+    path: String,
+    /// The first line of the hunk, or 0 for the whole file.
+    line: usize,
+    location: String,
+    /// One word for why it was left, for scripts to match on.
+    reason: &'static str,
+}
+
+impl Unabsorbed {
+    fn hunk(path: &[u8], quote_path: bool, hunk: &owned::Hunk, reason: &'static str) -> Self {
+        Unabsorbed {
+            path: display_path(path, quote_path),
+            line: hunk.added.start,
+            location: hunk_location(path, quote_path, hunk),
+            reason,
+        }
+    }
+
+    fn file(path: &[u8], quote_path: bool, reason: &'static str) -> Self {
+        let path = display_path(path, quote_path);
+        Unabsorbed {
+            location: path.clone(),
+            path,
+            line: 0,
+            reason,
+        }
+    }
+}
+
+/// With --annotate, a staged hunk, or a whole staged file, and the commit it
+/// would be absorbed into, if any.
+struct Annotation<'c, 'r> {
+    path: String,
+    /// `None` for a whole file, which `what` describes instead.
+    hunk: Option<owned::Hunk>,
+    what: &'static str,
+    target: Option<&'c git2::Commit<'r>>,
+}
+
+/// What --annotate shows, which is nothing without it.
+struct Annotations<'c, 'r> {
+    entries: Option<Vec<Annotation<'c, 'r>>>,
+    quote_path: bool,
+}
+
+impl<'c, 'r> Annotations<'c, 'r> {
+    fn hunk(&mut self, path: &[u8], hunk: &owned::Hunk, target: Option<&'c git2::Commit<'r>>) {
+        if let Some(entries) = &mut self.entries {
+            entries.push(Annotation {
+                path: display_path(path, self.quote_path),
+                hunk: Some(hunk.clone()),
+                what: "modified",
+                target,
+            });
+        }
+    }
+
+    fn file(&mut self, path: &[u8], what: &'static str, target: Option<&'c git2::Commit<'r>>) {
+        if let Some(entries) = &mut self.entries {
+            entries.push(Annotation {
+                path: display_path(path, self.quote_path),
+                hunk: None,
+                what,
+                target,
+            });
+        }
+    }
+}
+
+/// What --annotate shows for changes that no commit would get.
+const UNATTRIBUTED: &str = "????????";
+
+/// The most characters of a commit subject shown by --annotate.
+const ANNOTATE_SUBJECT_WIDTH: usize = 20;
+
+/// Writes the staged changes the way `git diff` shows them, with each line
+/// after the short id and subject of the commit it would be absorbed into.
+fn write_annotations(
+    out: &mut dyn Write,
+    annotations: &[Annotation],
+    abbrev: usize,
+    color: bool,
+) -> Result<()> {
+    let paint = |code: &str, text: String| {
+        if color {
+            format!("\x1b[{}m{}\x1b[m", code, text)
+        } else {
+            text
+        }
+    };
+    let ids: Vec<_> = annotations
+        .iter()
+        .map(|annotation| annotation.target.map(|commit| short_id(commit, abbrev)))
+        .collect();
+    let id_width = ids
+        .iter()
+        .flatten()
+        .map(String::len)
+        .fold(UNATTRIBUTED.len(), usize::max);
+
+    let mut last_path = None;
+    for (annotation, id) in annotations.iter().zip(&ids) {
+        if last_path != Some(&annotation.path) {
+            let header = format!("diff --git a/{0} b/{0}", annotation.path);
+            writeln!(out, "{}", paint("1", header))?;
+            last_path = Some(&annotation.path);
+        }
+        let target = paint(
+            "33",
+            format!(
+                "{:<id_width$} {:<subject_width$}",
+                id.as_deref().unwrap_or(UNATTRIBUTED),
+                annotation.target.map_or(String::new(), |commit| {
+                    let summary =
+                        String::from_utf8_lossy(commit.summary_bytes().unwrap_or_default());
+                    truncate_to_width(&summary, ANNOTATE_SUBJECT_WIDTH)
+                }),
+                subject_width = ANNOTATE_SUBJECT_WIDTH,
+            ),
+        );
+        let hunk = match &annotation.hunk {
+            Some(hunk) => hunk,
+            None => {
+                writeln!(out, "{} {}", target, annotation.what)?;
+                continue;
+            }
+        };
+        let mut header = format!("@@ {} @@", hunk.header());
+        if let Some(funcname) = &hunk.funcname {
+            header.push(' ');
+            header.push_str(&String::from_utf8_lossy(funcname));
+        }
+        writeln!(out, "{}", paint("36", header))?;
+        for (origin, code, lines) in [
+            ("-", "31", &hunk.removed.lines),
+            ("+", "32", &hunk.added.lines),
+        ] {
+            for line in lines.iter() {
+                let line = String::from_utf8_lossy(line);
+                let line = format!("{}{}", origin, line.trim_end_matches('\n'));
+                writeln!(out, "{} {}", target, paint(code, line))?;
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Why a whole file change was left in the index.
+fn status_reason(status: git2::Delta) -> &'static str {
+    match status {
+        git2::Delta::Added => "added",
+        git2::Delta::Deleted => "deleted",
+        git2::Delta::Renamed => "renamed",
+        git2::Delta::Copied => "copied",
+        git2::Delta::Typechange => "typechange",
+        _ => "unsupported",
+    }
+}
+
+/// The path and line range of a hunk in the index, eg: `src/lib.rs:10-12`.
+fn hunk_location(path: &[u8], quote_path: bool, hunk: &owned::Hunk) -> String {
+    let path = display_path(path, quote_path);
+    let start = hunk.added.start;
+    match hunk.added.lines.len() {
+        0 | 1 => format!("{}:{}", path, start),
+        len => format!("{}:{}-{}", path, start, start + len - 1),
+    }
+}
+
+struct HunkWithCommit<'c, 'r, 'p> {
+    /// `None` if the whole file is deleted.
+    hunk_to_apply: Option<owned::Hunk>,
+    dest_commit: &'c git2::Commit<'r>,
+    index_patch: &'p owned::Patch,
+    /// Hunks of the same group are committed in the same fixup.
+    group: Option<usize>,
+}
+
+/// Each hunk to apply as it applies to HEAD on its own.
+///
+/// Each hunk to apply was shifted by the absorbed hunks above it in its file,
+/// as if all of them were applied in file order.
+fn unshifted_hunks(hunks_with_commit: &[HunkWithCommit]) -> Vec<Option<owned::Hunk>> {
+    let mut offsets = std::collections::HashMap::new();
+    hunks_with_commit
+        .iter()
+        .map(|hunk| {
+            hunk.hunk_to_apply.as_ref().map(|hunk_to_apply| {
+                let offset = offsets
+                    .entry(hunk.index_patch.old_path.as_slice())
+                    .or_insert(0isize);
+                let unshifted = hunk_to_apply.clone().shift_both_blocks(-*offset);
+                *offset += hunk_to_apply.changed_offset();
+                unshifted
+            })
+        })
+        .collect()
+}
+
+/// The first two of `hunks_with_commit` that change the same lines of the
+/// same file, going by `unshifted`, but go into different commits.
+///
+/// Each line of the file and each gap between two lines gets a place of its
+/// own, so that a hunk that only adds lines overlaps those that add lines in
+/// the same gap, or that change the lines around it.
+fn overlapping_hunks(
+    hunks_with_commit: &[HunkWithCommit],
+    unshifted: &[Option<owned::Hunk>],
+) -> Option<(usize, usize)> {
+    let mut spans: Vec<_> = hunks_with_commit
+        .iter()
+        .zip(unshifted)
+        .enumerate()
+        .filter_map(|(idx, (hunk, unshifted))| {
+            let removed = &unshifted.as_ref()?.removed;
+            let (first, last) = match removed.lines.len() {
+                0 => (2 * removed.start + 1, 2 * removed.start + 1),
+                len => (2 * removed.start, 2 * (removed.start + len - 1)),
+            };
+            Some((hunk.index_patch.new_path.as_slice(), first, last, idx))
+        })
+        .collect();
+    spans.sort();
+
+    // the hunk reaching furthest down the file among those seen so far
+    let mut furthest: Option<(&[u8], usize, usize)> = None;
+    for (path, first, last, idx) in spans {
+        match furthest {
+            Some((other_path, other_last, other)) if other_path == path && first <= other_last => {
+                if hunks_with_commit[idx].dest_commit.id()
+                    != hunks_with_commit[other].dest_commit.id()
+                {
+                    return Some((other.min(idx), other.max(idx)));
+                }
+                if last > other_last {
+                    furthest = Some((path, last, idx));
+                }
+            }
+            _ => furthest = Some((path, last, idx)),
+        }
+    }
+    None
+}
+
+/// Puts the hunks in stack order, those for the oldest commit first, so that
+/// the fixups come out in the same order as the commits they fix up.
+///
+/// `unshifted` are the hunks as they apply to HEAD on their own. They do not
+/// overlap, so the order only changes which of them are already applied, and
+/// each hunk is shifted by exactly the ones that come before it in the new
+/// order.
+fn sort_by_target(
+    hunks_with_commit: &mut Vec<HunkWithCommit>,
+    unshifted: &[Option<owned::Hunk>],
+    stack: &[(git2::Commit, owned::Diff)],
+) {
+    // the stack is newest first. the sort is stable, so the hunks for each
+    // commit, and the groups among them, stay in file order
+    let mut order: Vec<_> = (0..hunks_with_commit.len()).collect();
+    order.sort_by_key(|&idx| {
+        let target = hunks_with_commit[idx].dest_commit.id();
+        std::cmp::Reverse(stack.iter().position(|(commit, _)| commit.id() == target))
+    });
+
+    let mut hunks: Vec<_> = hunks_with_commit.drain(..).map(Some).collect();
+    // for each file, where the hunks applied so far start, and by how many
+    // lines they moved the rest of it
+    let mut applied = std::collections::HashMap::new();
+    for idx in order {
+        let mut hunk = hunks[idx].take().unwrap();
+        if let Some(unshifted) = unshifted[idx].clone() {
+            let applied = applied
+                .entry(hunk.index_patch.old_path.as_slice())
+                .or_insert_with(Vec::new);
+            let position = (unshifted.removed.start, idx);
+            let offset: isize = applied
+                .iter()
+                .filter(|(other, _)| *other < position)
+                .map(|(_, offset)| offset)
+                .sum();
+            applied.push((position, unshifted.changed_offset()));
+            hunk.hunk_to_apply = Some(unshifted.shift_both_blocks(offset));
+        }
+        hunks_with_commit.push(hunk);
+    }
+}
+
+/// Whether the gitattributes for `path` unset its merge attribute, or give
+/// it a merge driver other than the usual text one. Files marked `binary`
+/// unset it too, though their diffs already are binary.
+fn merges_specially(repo: &git2::Repository, path: &[u8]) -> Result<bool> {
+    let merge = repo.get_attr(
+        path_from_bytes(path)?,
+        "merge",
+        git2::AttrCheckFlags::FILE_THEN_INDEX,
+    )?;
+    Ok(match git2::AttrValue::from_string(merge) {
+        git2::AttrValue::False => true,
+        git2::AttrValue::String(driver) => driver != "text",
+        _ => false,
+    })
+}
+
+/// Whether `hunk`, moved down the stack to `target`, exactly undoes one of
+/// the hunks that `target` made.
+fn inverts_hunk_of(
+    stack: &[(git2::Commit, owned::Diff)],
+    old_path: &[u8],
+    hunk: &owned::Hunk,
+    target: &git2::Commit,
+    context: usize,
+) -> bool {
+    let mut path = old_path;
+    let mut hunk = hunk.clone();
+    for (commit, diff) in stack {
+        let patch = match diff.by_new(path) {
+            Some(patch) => patch,
+            None => continue,
+        };
+        if commit.id() == target.id() {
+            return patch.hunks.iter().any(|made| {
+                hunk.removed.start == made.added.start
+                    && hunk.removed.lines == made.added.lines
+                    && hunk.added.lines == made.removed.lines
+            });
+        }
+        path = patch.old_path.as_slice();
+        hunk = match commute::commute_patch_before(&hunk, &patch.hunks, context) {
+            Some(hunk) => hunk,
+            None => return false,
+        };
+    }
+    false
+}
+
+/// The newest commit above `target` that `hunk` cannot be moved past, if
+/// any, in which case squashing a fixup into `target` conflicts once that
+/// commit is applied again on top of it.
+fn conflicting_descendant<'s, 'repo>(
+    stack: &'s [(git2::Commit<'repo>, owned::Diff)],
+    old_path: &[u8],
+    hunk: &owned::Hunk,
+    target: &git2::Commit,
+    context: usize,
+) -> Option<&'s git2::Commit<'repo>> {
+    let mut path = old_path;
+    let mut hunk = hunk.clone();
+    for (commit, diff) in stack {
+        if commit.id() == target.id() {
+            return None;
+        }
+        let patch = match diff.by_new(path) {
+            Some(patch) => patch,
+            None => continue,
+        };
+        if patch.status == git2::Delta::Added {
+            return Some(commit);
+        }
+        path = patch.old_path.as_slice();
+        hunk = match commute::commute_patch_before(&hunk, &patch.hunks, context) {
+            Some(hunk) => hunk,
+            None => return Some(commit),
+        };
+    }
+    None
+}
+
+/// With --verify-build, the oldest of `target` and the commits above it
+/// whose tree passes `command` once `hunk` is absorbed into it, if any.
+/// Each tree is checked out into a directory of its own to run `command`.
+#[allow(clippy::too_many_arguments)]
+fn verified_target<'s, 'repo>(
+    repo: &git2::Repository,
+    stack: &'s [(git2::Commit<'repo>, owned::Diff)],
+    old_path: &[u8],
+    hunk: &owned::Hunk,
+    target: &git2::Commit,
+    allowed: &dyn Fn(&git2::Commit) -> bool,
+    command: &str,
+    matching: Matching,
+    logger: &slog::Logger,
+) -> Result<Option<&'s git2::Commit<'repo>>> {
+    // the hunk as it applies to the tree of each commit down to the target,
+    // newest first
+    let mut candidates = vec![];
+    let mut path = old_path;
+    let mut hunk = hunk.clone();
+    for (commit, diff) in stack {
+        candidates.push((commit, path, hunk.clone()));
+        if commit.id() == target.id() {
+            break;
+        }
+        if let Some(patch) = diff.by_new(path) {
+            path = patch.old_path.as_slice();
+            hunk = match commute::commute_patch_before(&hunk, &patch.hunks, matching.context) {
+                Some(hunk) => hunk,
+                None => break,
+            };
+        }
+    }
+
+    for (commit, path, hunk) in candidates.into_iter().rev() {
+        if !allowed(commit) {
+            continue;
+        }
+        let tree = apply_hunk_to_tree(repo, &commit.tree()?, &hunk, path)?;
+        // removed when it goes out of scope, even if the checkout fails
+        let dir = tempfile::Builder::new()
+            .prefix("git-absorb-verify-")
+            .tempdir()?;
+        let mut checkout = git2::build::CheckoutBuilder::new();
+        checkout
+            .target_dir(dir.path())
+            .update_index(false)
+            .recreate_missing(true)
+            .force();
+        repo.checkout_tree(tree.as_object(), Some(&mut checkout))?;
+        let status = std::process::Command::new("sh")
+            .args(["-c", command])
+            .current_dir(dir.path())
+            .stdin(std::process::Stdio::null())
+            .stdout(std::process::Stdio::null())
+            .stderr(std::process::Stdio::null())
+            .status();
+        let passed = status.as_ref().is_ok_and(|status| status.success());
+        debug!(logger, "verified build";
+               "commit" => commit.id().to_string(),
+               "path" => display_path(path, matching.quote_path),
+               "status" => ?status,
+        );
+        if passed {
+            return Ok(Some(commit));
+        }
+    }
+    Ok(None)
+}
+
+/// Puts what --extract took out of the stack back into the working tree,
+/// by applying the changes from `new` back to `old` to the files there, so
+/// that it can be committed again later.
+fn restore_extracted(
+    repo: &git2::Repository,
+    old: &git2::Tree,
+    new: &git2::Tree,
+    logger: &slog::Logger,
+) -> Result<()> {
+    let extracted = repo.diff_tree_to_tree(Some(new), Some(old), None)?;
+    let reverted = repo.diff_tree_to_tree(Some(old), Some(new), None)?;
+    // one file at a time, so that a file that cannot take its changes back
+    // does not keep them out of the others
+    for delta in extracted.deltas() {
+        let path = delta.new_file().path_bytes().unwrap_or_default();
+        let apply = |diff: &git2::Diff, check: bool| {
+            let mut options = git2::ApplyOptions::new();
+            options.check(check).delta_callback(|delta| {
+                delta.is_some_and(|delta| delta.new_file().path_bytes() == Some(path))
+            });
+            repo.apply(diff, git2::ApplyLocation::WorkDir, Some(&mut options))
+                .is_ok()
+        };
+        // a file that still has the extracted lines, because only the
+        // index lost them, is already as it should be
+        if !apply(&extracted, false) && !apply(&reverted, true) {
+            announce(logger, Announcement::NotRestored(path));
+        }
+    }
+    Ok(())
+}
+
+/// Finds the commit that added a file deleted in the index, so long as no
+/// newer commit in the stack touched it.
+fn deletion_target<'c, 'r>(
+    stack: &'c [(git2::Commit<'r>, owned::Diff)],
+    path: &[u8],
+) -> Option<&'c git2::Commit<'r>> {
+    for (commit, diff) in stack {
+        if let Some(patch) = diff.by_new(path) {
+            // deleting the file before a later change to it would make
+            // that change conflict
+            return Some(commit).filter(|_| patch.status == git2::Delta::Added);
+        }
+    }
+    None
+}
+
+fn remove_from_tree<'repo>(
+    repo: &'repo git2::Repository,
+    base: &git2::Tree,
+    path: &[u8],
+) -> Result<git2::Tree<'repo>> {
+    let mut treebuilder = repo.treebuilder(Some(base))?;
+
+    // recurse into nested tree if applicable
+    if let Some(slash) = path.iter().position(|&x| x == b'/') {
+        let (first, rest) = path.split_at(slash);
+        let rest = &rest[1..];
+
+        let (subtree, submode) = {
+            let entry = treebuilder
+                .get(first)?
+                .ok_or_else(|| anyhow!("couldn't find tree entry in tree for path"))?;
+            (repo.find_tree(entry.id())?, entry.filemode())
+        };
+        let result_subtree = remove_from_tree(repo, &subtree, rest)?;
+
+        // git does not track empty directories
+        if result_subtree.is_empty() {
+            treebuilder.remove(first)?;
+        } else {
+            treebuilder.insert(first, result_subtree.id(), submode)?;
+        }
+        return Ok(repo.find_tree(treebuilder.write()?)?);
+    }
+
+    treebuilder.remove(path)?;
+    Ok(repo.find_tree(treebuilder.write()?)?)
+}
+
+fn apply_hunk_to_tree<'repo>(
+    repo: &'repo git2::Repository,
+    base: &git2::Tree,
+    hunk: &owned::Hunk,
+    path: &[u8],
+) -> Result<git2::Tree<'repo>> {
+    let mut treebuilder = repo.treebuilder(Some(base))?;
+
+    // recurse into nested tree if applicable
+    if let Some(slash) = path.iter().position(|&x| x == b'/') {
+        let (first, rest) = path.split_at(slash);
+        let rest = &rest[1..];
+
+        let (subtree, submode) = {
+            let entry = treebuilder
+                .get(first)?
+                .ok_or_else(|| anyhow!("couldn't find tree entry in tree for path"))?;
+            (repo.find_tree(entry.id())?, entry.filemode())
+        };
+        // TODO: loop instead of recursing to avoid potential stack overflow
+        let result_subtree = apply_hunk_to_tree(repo, &subtree, hunk, rest)?;
+
+        treebuilder.insert(first, result_subtree.id(), submode)?;
+        return Ok(repo.find_tree(treebuilder.write()?)?);
+    }
+
+    let (blob, mode) = {
+        let entry = treebuilder
+            .get(path)?
+            .ok_or_else(|| anyhow!("couldn't find blob entry in tree for path"))?;
+        (repo.find_blob(entry.id())?, entry.filemode())
+    };
+
+    // TODO: convert path to OsStr and pass it during blob_writer
+    // creation, to get gitattributes handling (note that converting
+    // &[u8] to &std::path::Path is only possible on unixy platforms)
+    let mut blobwriter = repo.blob_writer(None)?;
+    let old_content = blob.content();
+    let (old_start, _, _, _) = hunk.anchors();
+
+    // first, write the lines from the old content that are above the
+    // hunk
+    let old_content = {
+        let (pre, post) = split_lines_after(old_content, old_start);
+        blobwriter.write_all(pre)?;
+        post
+    };
+    // next, write the added side of the hunk
+    for line in &*hunk.added.lines {
+        blobwriter.write_all(line)?;
+    }
+    // if this hunk removed lines from the old content, those must be
+    // skipped
+    let (_, old_content) = split_lines_after(old_content, hunk.removed.lines.len());
+    // finally, write the remaining lines of the old content
+    blobwriter.write_all(old_content)?;
+
+    treebuilder.insert(path, blobwriter.commit()?, mode)?;
+    Ok(repo.find_tree(treebuilder.write()?)?)
+}
+
+/// Return slices for lines [1..n] and [n+1; ...]
+fn split_lines_after(content: &[u8], n: usize) -> (&[u8], &[u8]) {
+    let split_index = if n > 0 {
+        memchr::Memchr::new(b'\n', content)
+            .fuse() // TODO: is fuse necessary here?
+            .nth(n - 1) // the position of '\n' ending the `n`-th line
+            .map(|x| x + 1)
+            .unwrap_or_else(|| content.len())
+    } else {
+        0
+    };
+    content.split_at(split_index)
+}
+
+/// Update the index entries at `paths` to match their content in `tree`.
+///
+/// Only the named entries are touched, all other entries (and their flags)
+/// are left as they are, and so are the named ones that already match.
+fn stage_tree_entries(repo: &git2::Repository, tree: &git2::Tree, paths: &[&Path]) -> Result<()> {
+    let mut index = repo.index()?;
+    for &path in paths {
+        let tree_entry = match tree.get_path(path) {
+            Ok(tree_entry) => tree_entry,
+            Err(e) if e.code() == git2::ErrorCode::NotFound => {
+                if index.get_path(path, 0).is_some() {
+                    index.remove_path(path)?;
+                }
+                continue;
+            }
+            Err(e) => return Err(e.into()),
+        };
+        let mode = tree_entry.filemode() as u32;
+        let mut index_entry = match index.get_path(path, 0) {
+            Some(index_entry) if index_entry.id == tree_entry.id() && index_entry.mode == mode => {
+                continue;
+            }
+            Some(index_entry) => index_entry,
+            None => git2::IndexEntry {
+                ctime: git2::IndexTime::new(0, 0),
+                mtime: git2::IndexTime::new(0, 0),
+                dev: 0,
+                ino: 0,
+                mode,
+                uid: 0,
+                gid: 0,
+                file_size: 0,
+                id: tree_entry.id(),
+                flags: 0,
+                flags_extended: 0,
+                path: path_to_bytes(path)?,
+            },
+        };
+        let blob = repo.find_blob(tree_entry.id())?;
+        // an index that ignores case may have found the entry by another
+        // case of its name, but the tree's is the one to keep
+        index_entry.path = path_to_bytes(path)?;
+        index_entry.id = tree_entry.id();
+        index_entry.mode = mode;
+        index_entry.file_size = blob.size() as u32;
+        // the working tree file still has the same stat data as before,
+        // so make sure git does not mistake it for matching the new content
+        index_entry.mtime = git2::IndexTime::new(0, 0);
+        index.add(&index_entry)?;
+    }
+    write_index(&mut index)?;
+    Ok(())
+}
+
+/// How many times to try taking a lock that another git process holds.
+const LOCK_ATTEMPTS: u32 = 5;
+/// How long to wait before trying again the first time, doubling each time.
+const LOCK_BACKOFF: std::time::Duration = std::time::Duration::from_millis(50);
+
+/// Writes `index` to disk, waiting a while for any other git process that
+/// has it locked.
+fn write_index(index: &mut git2::Index) -> Result<()> {
+    let path = index.path().map(Path::to_path_buf);
+    retry_locked(path.as_deref(), || index.write())
+}
+
+/// Runs `f`, which writes the index at `index_path`, again for as long as
+/// another git process holds the index lock, up to [`LOCK_ATTEMPTS`] times.
+fn retry_locked<T>(
+    index_path: Option<&Path>,
+    mut f: impl FnMut() -> Result<T, git2::Error>,
+) -> Result<T> {
+    let mut delay = LOCK_BACKOFF;
+    for _ in 1..LOCK_ATTEMPTS {
+        match f() {
+            Err(e) if e.code() == git2::ErrorCode::Locked => {
+                std::thread::sleep(delay);
+                delay *= 2;
+            }
+            result => return Ok(result?),
+        }
+    }
+    f().map_err(|e| match (e.code(), index_path) {
+        (git2::ErrorCode::Locked, Some(path)) => AbsorbError::IndexLocked {
+            path: path.to_owned(),
+        }
+        .into(),
+        _ => e.into(),
+    })
+}
+
+/// The stash entry at `revision`, which must have been saved on top of HEAD.
+fn stash_commit<'repo>(
+    repo: &'repo git2::Repository,
+    revision: &str,
+) -> Result<git2::Commit<'repo>> {
+    let stash = repo.revparse_single(revision)?.peel_to_commit()?;
+    // a stash entry is a merge of the commit it was saved on top of and of
+    // the index at the time, and maybe of a commit of the untracked files
+    if stash.parent_count() < 2 {
+        return Err(anyhow!("{} is not a stash entry", revision));
+    }
+    let base = stash.parent_id(0)?;
+    if base != repo.head()?.peel_to_commit()?.id() {
+        return Err(anyhow!(
+            "{} was saved on top of {}, not on top of HEAD",
+            revision,
+            base
+        ));
+    }
+    Ok(stash)
+}
+
+fn hunk_patterns<'p>(patterns: impl Iterator<Item = &'p str>) -> Result<Vec<regex::bytes::Regex>> {
+    patterns
+        .map(|pattern| {
+            regex::bytes::Regex::new(pattern)
+                .map_err(|e| anyhow!("invalid hunk pattern {}: {}", pattern, e))
+        })
+        .collect()
+}
+
+/// Whether `hunk` is to be left alone because one of its added lines
+/// matches one of `exclude`, or because none matches any of `include`,
+/// when there are some.
+fn is_filtered(
+    exclude: &[regex::bytes::Regex],
+    include: &[regex::bytes::Regex],
+    hunk: &owned::Hunk,
+) -> bool {
+    let matches = |patterns: &[regex::bytes::Regex]| {
+        hunk.added.lines.iter().any(|line| {
+            let line = line.strip_suffix(b"\n").unwrap_or(line);
+            patterns.iter().any(|pattern| pattern.is_match(line))
+        })
+    };
+    matches(exclude) || (!include.is_empty() && !matches(include))
+}
+
+/// Whether `path` is inside one of `dirs`, each of which is found at any
+/// depth.
+fn is_excluded(dirs: &[String], path: &[u8]) -> bool {
+    dirs.iter().any(|dir| {
+        let dir = dir.as_bytes();
+        (0..path.len())
+            .filter(|&start| start == 0 || path[start - 1] == b'/')
+            .any(|start| {
+                path[start..].starts_with(dir) && path.get(start + dir.len()) == Some(&b'/')
+            })
+    })
+}
+
+/// HEAD's tree with the patch file at `path` applied to it, failing at the
+/// first hunk that does not match HEAD, as `git apply` would.
+fn patched_tree<'repo>(repo: &'repo git2::Repository, path: &Path) -> Result<git2::Tree<'repo>> {
+    let patch = std::fs::read(path)
+        .map_err(|e| anyhow!("could not read the patch file {}: {}", path.display(), e))?;
+    let diff = git2::Diff::from_buffer(&patch)?;
+    let head_tree = repo.head()?.peel_to_tree()?;
+    match repo.apply_to_tree(&head_tree, &diff, None) {
+        Ok(mut index) => Ok(repo.find_tree(index.write_tree_to(repo)?)?),
+        Err(e) => match failed_hunk(repo, &head_tree, &diff)? {
+            Some((path, line)) => Err(AbsorbError::PatchFailed { path, line }.into()),
+            None => Err(e.into()),
+        },
+    }
+}
+
+/// The path and the first line of the first hunk in `diff` that does not
+/// apply to `tree` on its own, if any.
+fn failed_hunk(
+    repo: &git2::Repository,
+    tree: &git2::Tree,
+    diff: &git2::Diff,
+) -> Result<Option<(String, u32)>> {
+    for (delta_idx, delta) in diff.deltas().enumerate() {
+        let patch = match git2::Patch::from_diff(diff, delta_idx)? {
+            Some(patch) => patch,
+            None => continue,
+        };
+        for hunk_idx in 0..patch.num_hunks() {
+            let mut options = git2::ApplyOptions::new();
+            let mut next_delta = 0;
+            options.delta_callback(move |_| {
+                next_delta += 1;
+                next_delta - 1 == delta_idx
+            });
+            let mut next_hunk = 0;
+            options.hunk_callback(move |_| {
+                next_hunk += 1;
+                next_hunk - 1 == hunk_idx
+            });
+            if repo.apply_to_tree(tree, diff, Some(&mut options)).is_err() {
+                let (hunk, _) = patch.hunk(hunk_idx)?;
+                let path = delta.old_file().path_bytes().unwrap_or_default();
+                return Ok(Some((
+                    display_path(path, config::quote_path(repo)),
+                    hunk.old_start(),
+                )));
+            }
+        }
+    }
+    Ok(None)
+}
+
+/// Fails if any file that `diff` changes is modified in the index or the
+/// working tree, where absorbing the change saved in `from` would overwrite
+/// it.
+fn check_untouched(repo: &git2::Repository, diff: &owned::Diff, from: &str) -> Result<()> {
+    for patch in diff.iter() {
+        let path = path_from_bytes(&patch.new_path)?;
+        match repo.status_file(path) {
+            Ok(status) if status.is_empty() => {}
+            Err(e) if e.code() == git2::ErrorCode::NotFound => {}
+            Ok(_) => {
+                return Err(anyhow!(
+                    "{} has changes of its own, which absorbing from {} would overwrite",
+                    display_path(&patch.new_path, config::quote_path(repo)),
+                    from
+                ))
+            }
+            Err(e) => return Err(e.into()),
+        }
+    }
+    Ok(())
+}
+
+/// The path git stores as `path`, which need not be UTF-8.
+#[cfg(unix)]
+fn path_from_bytes(path: &[u8]) -> Result<&Path> {
+    use std::os::unix::ffi::OsStrExt;
+    Ok(Path::new(std::ffi::OsStr::from_bytes(path)))
+}
+
+/// The path git stores as `path`. Elsewhere than on Unix, paths that are
+/// not UTF-8 cannot be passed on to the filesystem.
+#[cfg(not(unix))]
+fn path_from_bytes(path: &[u8]) -> Result<&Path> {
+    Ok(Path::new(std::str::from_utf8(path).map_err(|_| {
+        anyhow!("path is not UTF-8: {}", display_path(path, true))
+    })?))
+}
+
+/// The bytes git stores for `path`.
+#[cfg(unix)]
+fn path_to_bytes(path: &Path) -> Result<Vec<u8>> {
+    use std::os::unix::ffi::OsStrExt;
+    Ok(path.as_os_str().as_bytes().to_vec())
+}
+
+/// The bytes git stores for `path`, with `/` between its components.
+#[cfg(not(unix))]
+fn path_to_bytes(path: &Path) -> Result<Vec<u8>> {
+    match path.to_str() {
+        Some(path) => Ok(path.replace('\\', "/").into_bytes()),
+        None => Err(anyhow!("path is not UTF-8: {}", path.display())),
+    }
+}
+
+/// What `path` is compared by when it names a file in the working tree,
+/// which on a filesystem that ignores case is any case of it. Inside the
+/// trees and the index, paths are always told apart by case.
+fn worktree_key(path: &[u8], ignore_case: bool) -> Vec<u8> {
+    if ignore_case {
+        path.to_ascii_lowercase()
+    } else {
+        path.to_vec()
+    }
+}
+
+/// The pathspec for `paths`, relative to the current directory or
+/// absolute, as paths from the top of the working tree. `None` if there
+/// are no paths, or one of them is the whole working tree.
+fn resolve_pathspec(repo: &git2::Repository, paths: &[&str]) -> Result<Option<Vec<String>>> {
+    if paths.is_empty() {
+        return Ok(None);
+    }
+    let workdir = match repo.workdir() {
+        Some(workdir) => workdir,
+        None => {
+            return Err(
+                AbsorbError::InvalidOptions("paths cannot be given in a bare repository").into(),
+            )
+        }
+    };
+    // the working tree may be named through a symlink, which the current
+    // directory never is
+    let workdirs = [workdir.to_owned(), workdir.canonicalize()?];
+    let cwd = std::env::current_dir()?;
+    let mut pathspec = vec![];
+    for path in paths {
+        // like git, resolve `..` without following symlinks, since the
+        // path may not exist anymore
+        let mut full = PathBuf::new();
+        for component in cwd.join(path).components() {
+            match component {
+                std::path::Component::CurDir => {}
+                std::path::Component::ParentDir => {
+                    full.pop();
+                }
+                component => full.push(component),
+            }
+        }
+        let relative = workdirs
+            .iter()
+            .find_map(|workdir| full.strip_prefix(workdir).ok())
+            .ok_or_else(|| anyhow!("{} is outside the repository", path))?;
+        if relative.as_os_str().is_empty() {
+            return Ok(None);
+        }
+        let relative = path_to_bytes(relative)?;
+        pathspec
+            .push(String::from_utf8(relative).map_err(|_| anyhow!("path is not UTF-8: {}", path))?);
+    }
+    Ok(Some(pathspec))
+}
+
+/// `path` as text for the user, quoted the way git quotes it whenever it
+/// has a byte that could garble the output: a control character, a double
+/// quote or a backslash, or, unless `quote_path` is false as
+/// `core.quotePath` can say, anything but ASCII. A path that is not UTF-8
+/// is quoted as if `quote_path` were true, since it cannot be shown as is.
+///
+/// Every path meant for a reader goes through here.
+fn display_path(path: &[u8], quote_path: bool) -> String {
+    let plain =
+        |c: char| !(c.is_control() || c == '"' || c == '\\' || (quote_path && !c.is_ascii()));
+    let mut ret = String::from("\"");
+    match std::str::from_utf8(path) {
+        Ok(text) if text.chars().all(plain) => return text.to_owned(),
+        Ok(text) => {
+            for c in text.chars() {
+                if plain(c) {
+                    ret.push(c);
+                } else {
+                    for &b in c.encode_utf8(&mut [0; 4]).as_bytes() {
+                        ret.push_str(&escape_byte(b));
+                    }
+                }
+            }
+        }
+        Err(_) => {
+            for &b in path {
+                if b.is_ascii() && plain(b as char) {
+                    ret.push(b as char);
+                } else {
+                    ret.push_str(&escape_byte(b));
+                }
+            }
+        }
+    }
+    ret.push('"');
+    ret
+}
+
+/// `b` escaped the way C, and git, escape bytes in quoted strings.
+fn escape_byte(b: u8) -> String {
+    match b {
+        b'"' => "\\\"".to_owned(),
+        b'\\' => "\\\\".to_owned(),
+        0x07 => "\\a".to_owned(),
+        0x08 => "\\b".to_owned(),
+        b'\t' => "\\t".to_owned(),
+        b'\n' => "\\n".to_owned(),
+        0x0b => "\\v".to_owned(),
+        0x0c => "\\f".to_owned(),
+        b'\r' => "\\r".to_owned(),
+        b => format!("\\{:03o}", b),
+    }
+}
+
+/// Asks whether to absorb something, unless the user already answered for
+/// all the remaining ones.
+fn select(
+    prompt: &mut dyn prompt::Prompt,
+    remaining_selected: &mut Option<bool>,
+    text: &str,
+    question: &str,
+) -> Result<bool> {
+    if let Some(selected) = *remaining_selected {
+        return Ok(selected);
+    }
+    Ok(match prompt.ask(text, question)? {
+        prompt::Answer::Yes => true,
+        prompt::Answer::No => false,
+        prompt::Answer::All => *remaining_selected.insert(true),
+        prompt::Answer::Quit => *remaining_selected.insert(false),
+    })
+}
+
+/// Renders a hunk for the user, like `git add -p` does.
+fn describe_hunk(path: &[u8], hunk: &owned::Hunk, quote_path: bool) -> String {
+    let mut ret = format!(
+        "{}\n@@ {} @@\n",
+        display_path(path, quote_path),
+        hunk.header()
+    );
+    let removed = hunk.removed.lines.iter().map(|line| ('-', line));
+    let added = hunk.added.lines.iter().map(|line| ('+', line));
+    for (sign, line) in removed.chain(added) {
+        ret.push(sign);
+        ret.push_str(&String::from_utf8_lossy(line));
+        if !line.ends_with(b"\n") {
+            ret.push('\n');
+        }
+    }
+    ret
+}
+
+/// Finds the commit in the stack named by `into`, either as a revision, like
+/// `HEAD~2` or a commit id, or by a substring of its summary.
+fn commit_by_revision_or_subject(
+    repo: &git2::Repository,
+    stack: &[(git2::Commit, owned::Diff)],
+    into: &str,
+) -> Result<git2::Oid> {
+    let revision = repo
+        .revparse_single(into)
+        .and_then(|object| object.peel_to_commit());
+    if let Ok(commit) = &revision {
+        if stack.iter().any(|(c, _)| c.id() == commit.id()) {
+            return Ok(commit.id());
+        }
+    }
+    commit_by_subject(stack, into).map_err(|e| match revision {
+        Ok(commit) => anyhow!(
+            "{} ({}) is not in the stack of commits to absorb into",
+            into,
+            commit.id()
+        ),
+        Err(_) => e,
+    })
+}
+
+/// Finds the single commit in the stack whose summary contains `needle`.
+fn commit_by_subject(stack: &[(git2::Commit, owned::Diff)], needle: &str) -> Result<git2::Oid> {
+    let matches: Vec<_> = stack
+        .iter()
+        .map(|(commit, _)| commit)
+        .filter(|commit| commit.summary().is_some_and(|s| s.contains(needle)))
+        .collect();
+    match matches.as_slice() {
+        [commit] => Ok(commit.id()),
+        [] => Err(anyhow!(
+            "no commit in the stack has a subject containing {:?}",
+            needle
+        )),
+        _ => Err(anyhow!(
+            "{} commits in the stack have a subject containing {:?}: {}",
+            matches.len(),
+            needle,
+            matches
+                .iter()
+                .map(|commit| commit.id().to_string())
+                .collect::<Vec<_>>()
+                .join(", ")
+        )),
+    }
+}
+
+/// The first `abbrev` characters of the id of `commit`, or more if that is
+/// not enough to tell it apart from the other objects in the repository.
+fn short_id(commit: &git2::Commit, abbrev: usize) -> String {
+    let id = commit.id().to_string();
+    let unique = match commit.as_object().short_id() {
+        Ok(short) => short.as_str().map_or(0, str::len),
+        Err(_) => 0,
+    };
+    id[..abbrev.max(unique).min(id.len())].to_owned()
+}
+
+/// Whether the lines of HEAD that `hunk` changes were all last changed by
+/// the author of `signature`.
+fn authored_by(blame: &git2::Blame, hunk: &owned::Hunk, signature: &git2::Signature) -> bool {
+    let lines = if hunk.removed.lines.is_empty() {
+        // an insertion changes no lines of its own, so look at the lines
+        // around it instead
+        hunk.removed.start..hunk.removed.start + 2
+    } else {
+        hunk.removed.start..hunk.removed.start + hunk.removed.lines.len()
+    };
+    lines
+        .filter(|&line| line > 0)
+        .filter_map(|line| blame.get_line(line))
+        .all(|blame_hunk| blame_hunk.final_signature().email_bytes() == signature.email_bytes())
+}
+
+/// The width `git diff --stat` uses when not writing to a terminal.
+const STAT_WIDTH: usize = 80;
+
+/// Writes what a fixup for `target` changes, the way `git diff --stat` does.
+fn write_stat(
+    out: &mut dyn Write,
+    target: &git2::Commit,
+    diff: &git2::DiffStats,
+    first: bool,
+    abbrev: usize,
+) -> Result<()> {
+    if !first {
+        writeln!(out)?;
+    }
+    writeln!(
+        out,
+        "{} {}",
+        short_id(target, abbrev),
+        display_subject(target)
+    )?;
+    let stat = diff.to_buf(git2::DiffStatsFormat::FULL, STAT_WIDTH)?;
+    out.write_all(&stat)?;
+    Ok(())
+}
+
+/// Writes what the whole stack changes, from the commit below it to `tip`,
+/// the way `git diff` shows it.
+fn write_final_diff(
+    out: &mut dyn Write,
+    repo: &git2::Repository,
+    stack: &[&git2::Commit],
+    tip: &git2::Commit,
+) -> Result<()> {
+    let base = match stack.last().map(|oldest| oldest.parent(0)) {
+        Some(Ok(base)) => Some(base.tree()?),
+        _ => None,
+    };
+    let mut patch = vec![];
+    repo.diff_tree_to_tree(base.as_ref(), Some(&tip.tree()?), None)?
+        .print(git2::DiffFormat::Patch, |_, _, line| {
+            if matches!(line.origin(), '+' | '-' | ' ') {
+                patch.push(line.origin() as u8);
+            }
+            patch.extend_from_slice(line.content());
+            true
+        })?;
+    out.write_all(&patch)?;
+    Ok(())
+}
+
+/// Writes a line for each file that `fixup` changes, as
+/// `<verb> TAB <fixup id> TAB <target id> TAB <path>`, which --porcelain
+/// promises to keep the same from version to version. An amended HEAD is
+/// listed with the verb `amend`, as the fixup of the HEAD it replaced.
+fn write_porcelain(
+    out: &mut dyn Write,
+    verb: &str,
+    fixup: &git2::Commit,
+    target: &git2::Commit,
+    diff: &git2::Diff,
+    quote_path: bool,
+) -> Result<()> {
+    for delta in diff.deltas() {
+        let file = match delta.status() {
+            git2::Delta::Deleted => delta.old_file(),
+            _ => delta.new_file(),
+        };
+        writeln!(
+            out,
+            "{}\t{}\t{}\t{}",
+            verb,
+            fixup.id(),
+            target.id(),
+            display_path(file.path_bytes().unwrap_or_default(), quote_path)
+        )?;
+    }
+    Ok(())
+}
+
+/// The summary of `commit`, for a fixup message to name it by, unless git
+/// might not see the same text: because it is not UTF-8, or because the
+/// commit declares another encoding, which git converts it from.
+fn fixup_subject<'c>(commit: &'c git2::Commit) -> Option<&'c str> {
+    match commit.message_encoding() {
+        Some(encoding)
+            if !encoding.eq_ignore_ascii_case("utf-8")
+                && !encoding.eq_ignore_ascii_case("utf8") =>
+        {
+            None
+        }
+        _ => commit.summary(),
+    }
+}
+
+/// `subject` cut down to at most `max` characters, which autosquash still
+/// matches to the commit with that subject, as long as no other commit in
+/// `stack` has a subject that starts the same.
+fn truncated_locator<'s>(
+    subject: &'s str,
+    max: usize,
+    stack: &[(git2::Commit, owned::Diff)],
+) -> Option<&'s str> {
+    let end = subject
+        .char_indices()
+        .nth(max)
+        .map_or(subject.len(), |(idx, _)| idx);
+    let truncated = subject[..end].trim_end();
+    let ambiguous = stack
+        .iter()
+        .filter_map(|(commit, _)| fixup_subject(commit))
+        .any(|other| other != subject && other.starts_with(truncated));
+    if truncated.is_empty() || ambiguous {
+        None
+    } else {
+        Some(truncated)
+    }
+}
+
+/// The most characters of a commit subject shown in messages.
+const SUBJECT_WIDTH: usize = 50;
+
+/// The first line of the commit message, shortened to fit in messages.
+fn display_subject(commit: &git2::Commit) -> String {
+    let summary = String::from_utf8_lossy(commit.summary_bytes().unwrap_or_default());
+    truncate_to_width(&summary, SUBJECT_WIDTH)
+}
+
+fn truncate_to_width(text: &str, width: usize) -> String {
+    if text.chars().count() <= width {
+        return text.to_owned();
+    }
+    let mut truncated: String = text.chars().take(width - 1).collect();
+    truncated.push('…');
+    truncated
+}
+
+fn nothing_left_in_workdir(repo: &git2::Repository) -> Result<bool> {
+    let head = repo.head()?.peel_to_tree()?;
+    let stats = repo.diff_tree_to_workdir(Some(&head), None)?.stats()?;
+    Ok(stats.files_changed() == 0)
+}
+
+fn nothing_left_in_index(repo: &git2::Repository) -> Result<bool> {
+    let stats = index_stats(repo)?;
+    let nothing = stats.files_changed() == 0 && stats.insertions() == 0 && stats.deletions() == 0;
+    Ok(nothing)
+}
+
+fn index_stats(repo: &git2::Repository) -> Result<git2::DiffStats> {
+    let head = repo.head()?.peel_to_tree()?;
+    let diff = repo.diff_tree_to_index(Some(&head), Some(&repo.index()?), None)?;
+    let stats = diff.stats()?;
+    Ok(stats)
+}
+
+// Messages that will be shown to users during normal operations (not debug messages).
+enum Announcement<'r> {
+    // the last field of the variants that show a commit is how many
+    // characters of its id to show
+    Committed(
+        &'r git2::Commit<'r>,
+        &'r git2::Commit<'r>,
+        &'r git2::DiffStats,
+        usize,
+    ),
+    WouldHaveCommitted(&'r str, &'r git2::Commit<'r>, &'r git2::DiffStats, usize),
+    WouldHaveRebased(&'r std::process::Command, &'r [String]),
+    Amended(&'r git2::Commit<'r>, &'r git2::DiffStats, usize),
+    WouldHaveAmended(&'r git2::Commit<'r>, &'r git2::DiffStats, usize),
+    WillNotAmendHead(&'r anyhow::Error),
+    AlreadyFixedUp(&'r git2::Commit<'r>, usize),
+    WillNotRebase(&'r str),
+    HowToSquash(String),
+    HowToPickStagedFixups,
+    TimeLimitReached(std::time::Duration),
+    AmbiguousSubject(&'r git2::Commit<'r>, usize),
+    LongSubject(&'r git2::Commit<'r>, usize, usize, usize),
+    UnknownDiffAlgorithm(&'r str),
+    NoCommits,
+    NothingStagedAfterAutoStaging,
+    NothingStaged,
+    NothingUnstaged,
+    NoFileModifications,
+    NonFileModifications,
+    FileModificationsWithoutTarget,
+    CannotFixUpPastFirstCommit,
+    CannotFixUpPastMerge(&'r git2::Commit<'r>),
+    WillNotFixUpPastAnotherAuthor(&'r git2::Commit<'r>),
+    WillNotFixUpPastStackLimit(usize),
+    CommitsHiddenByBase(&'r [String]),
+    CommitsHiddenByBranches,
+    NotAbsorbed(&'r str, &'r str),
+    MoreNotAbsorbed(usize),
+    Rewrote(&'r git2::Commit<'r>, usize),
+    NotRestored(&'r [u8]),
+    FinalTreeDiffers(&'r git2::Commit<'r>, usize),
+    WrotePlan(&'r Path, usize),
+    StackCommit(&'r git2::Commit<'r>, usize),
+    StackBase(Option<&'r git2::Commit<'r>>, usize),
+    CouldNotFindRepositoryPath,
+    SubmoduleFailed(&'r AbsorbError),
+    Undone(&'r git2::Commit<'r>, usize),
+    NoteNotWritten(&'r anyhow::Error),
+    UndoNotRecorded(&'r anyhow::Error),
+    WouldSign(&'r str, &'r str),
+}
+
+fn announce(logger: &slog::Logger, announcement: Announcement) {
+    match announcement {
+        Announcement::Committed(commit, target, diff, abbrev) => info!(
+            logger,
+            "committed";
+            "commit" => &commit.id().to_string(),
+            "target" => short_id(target, abbrev),
+            "target_id" => target.id().to_string(),
+            "subject" => display_subject(target),
+            "header" => format!("+{},-{}", &diff.insertions(), &diff.deletions()),
+            "files_changed" => diff.files_changed(),
+            "insertions" => diff.insertions(),
+            "deletions" => diff.deletions(),
+        ),
+        Announcement::WouldHaveCommitted(fixup, target, diff, abbrev) => info!(
+            logger,
+            "would have committed";
+            "fixup" => fixup,
+            "target" => short_id(target, abbrev),
+            "target_id" => target.id().to_string(),
+            "subject" => display_subject(target),
+            "header" => format!("+{},-{}", &diff.insertions(), &diff.deletions()),
+            "files_changed" => diff.files_changed(),
+            "insertions" => diff.insertions(),
+            "deletions" => diff.deletions(),
+        ),
+        Announcement::Amended(commit, diff, abbrev) => info!(
+            logger,
+            "amended HEAD";
+            "commit" => short_id(commit, abbrev),
+            "commit_id" => commit.id().to_string(),
+            "subject" => display_subject(commit),
+            "header" => format!("+{},-{}", &diff.insertions(), &diff.deletions()),
+            "files_changed" => diff.files_changed(),
+            "insertions" => diff.insertions(),
+            "deletions" => diff.deletions(),
+        ),
+        Announcement::WouldHaveAmended(commit, diff, abbrev) => info!(
+            logger,
+            "would have amended HEAD";
+            "commit" => short_id(commit, abbrev),
+            "commit_id" => commit.id().to_string(),
+            "subject" => display_subject(commit),
+            "header" => format!("+{},-{}", &diff.insertions(), &diff.deletions()),
+            "files_changed" => diff.files_changed(),
+            "insertions" => diff.insertions(),
+            "deletions" => diff.deletions(),
+        ),
+        Announcement::WillNotAmendHead(err) => warn!(
+            logger,
+            "Will not amend HEAD, creating fixup commits instead";
+            "reason" => err.to_string(),
+        ),
+        Announcement::AlreadyFixedUp(commit, abbrev) => warn!(
+            logger,
+            "HEAD is already a fixup with the same changes, leaving them staged instead of committing them again";
+            "commit" => short_id(commit, abbrev),
+            "commit_id" => commit.id().to_string(),
+        ),
+        Announcement::WillNotRebase(reason) => warn!(
+            logger,
+            "Will not rebase as absorb.andRebase asks, only creating fixup commits";
+            "reason" => reason,
+        ),
+        Announcement::WouldHaveRebased(command, todo) => {
+            info!(
+                logger,
+                "would have run git rebase, nothing was run";
+                "command" => command_line(command),
+            );
+            for line in todo {
+                info!(logger, "would have rebased"; "todo" => line);
+            }
+        }
+        Announcement::HowToSquash(rebase_args) => info!(
+            logger,
+            "To squash the new commits, rebase:";
+            "command" => format!("git {}", rebase_args),
+        ),
+        Announcement::AmbiguousSubject(commit, abbrev) => info!(
+            logger,
+            "Another commit in the stack has the same subject, so the fixup names this one by SHA";
+            "commit" => short_id(commit, abbrev),
+            "commit_id" => commit.id().to_string(),
+            "subject" => display_subject(commit),
+        ),
+        Announcement::LongSubject(commit, abbrev, length, max) => warn!(
+            logger,
+            "The fixup subject is longer than absorb.maxSubjectLength";
+            "commit" => short_id(commit, abbrev),
+            "commit_id" => commit.id().to_string(),
+            "length" => length,
+            "max" => max,
+        ),
+        Announcement::UnknownDiffAlgorithm(name) => warn!(
+            logger,
+            "Unknown diff algorithm, using myers";
+            "algorithm" => name,
+        ),
+        Announcement::TimeLimitReached(limit) => warn!(
+            logger,
+            "Time limit reached, the remaining changes were left staged without matching them";
+            "limit" => format!("{}s", limit.as_secs_f64()),
+        ),
+        Announcement::HowToPickStagedFixups => info!(
+            logger,
+            "The fixups were left out of the branch. To add them, cherry-pick:";
+            "command" => format!("git cherry-pick HEAD..{}", STAGED_FIXUPS_REF),
+        ),
+        Announcement::NoCommits => warn!(logger, "No commits to absorb into."),
+        Announcement::NothingStagedAfterAutoStaging => warn!(
+            logger,
+            "No changes staged, even after auto-staging. Try adding something to the index.",
+        ),
+        Announcement::NothingStaged => warn!(
+            logger,
+            "No changes staged. Try adding something to the index or set {} = true.",
+            config::AUTO_STAGE_IF_NOTHING_STAGED_CONFIG_NAME
+        ),
+        Announcement::NothingUnstaged => warn!(
+            logger,
+            "No unstaged changes to tracked files. Nothing to absorb."
+        ),
+        Announcement::NoFileModifications => warn!(
+            logger,
+            "No changes were in-place file modifications. \
+                Added or renamed files cannot be automatically absorbed, \
+                and removed files only into the commit that added them."
+        ),
+        Announcement::NonFileModifications => warn!(
+            logger,
+            "Some changes were not in-place file modifications. \
+                Added or renamed files cannot be automatically absorbed, \
+                and removed files only into the commit that added them."
+        ),
+        Announcement::FileModificationsWithoutTarget => warn!(
+            logger,
+            "Some file modifications did not have an available commit to fix up. \
+                You will have to manually create fixup commits."
+        ),
+        Announcement::CannotFixUpPastFirstCommit => warn!(
+            logger,
+            "Cannot fix up past the first commit in the repository."
+        ),
+        Announcement::CannotFixUpPastMerge(commit) => warn!(
+            logger,
+            "Cannot fix up past a merge commit";
+            "commit" => commit.id().to_string(),
+            "subject" => display_subject(commit),
+        ),
+        Announcement::WillNotFixUpPastAnotherAuthor(commit) => warn!(
+            logger,
+            "Will not fix up past commits by another author. Use --force-author to override";
+            "commit" => commit.id().to_string(),
+            "subject" => display_subject(commit),
+        ),
+        Announcement::WillNotFixUpPastStackLimit(max_stack_limit) => warn!(
+            logger,
+            "Will not fix up past maximum stack limit. Use --base or configure {} to override",
+            config::MAX_STACK_CONFIG_NAME;
+            "limit" => max_stack_limit,
+        ),
+        Announcement::CommitsHiddenByBase(base) => warn!(
+            logger,
+            "Will not fix up past specified base commit. \
+            Consider using --base to specify a different base commit";
+            "base" => base.join(" "),
+        ),
+        Announcement::CommitsHiddenByBranches => warn!(
+            logger,
+            "Will not fix up commits reachable by other branches. \
+                Use --base to specify a base commit."
+        ),
+        Announcement::NotAbsorbed(location, reason) => warn!(
+            logger,
+            "Staged change could not be absorbed";
+            "location" => location,
+            "reason" => reason,
+        ),
+        Announcement::MoreNotAbsorbed(count) => warn!(
+            logger,
+            "...and {} more.", count;
+            "count" => count,
+        ),
+        Announcement::WrotePlan(path, hunks) => info!(
+            logger,
+            "wrote plan";
+            "path" => path.display().to_string(),
+            "hunks" => hunks,
+        ),
+        Announcement::Rewrote(commit, abbrev) => info!(
+            logger,
+            "rewrote";
+            "commit" => short_id(commit, abbrev),
+            "commit_id" => commit.id().to_string(),
+            "subject" => display_subject(commit),
+        ),
+        Announcement::FinalTreeDiffers(tip, abbrev) => warn!(
+            logger,
+            "Squashing the fixups would not give the same tree as creating them";
+            "tip" => short_id(tip, abbrev),
+            "tip_id" => tip.id().to_string(),
+        ),
+        Announcement::NotRestored(path) => warn!(
+            logger,
+            "Could not put the extracted changes back in the working tree, they are still in PRE_ABSORB_HEAD";
+            "path" => String::from_utf8_lossy(path).into_owned(),
+        ),
+        Announcement::StackCommit(commit, abbrev) => info!(
+            logger,
+            "stack commit";
+            "commit" => short_id(commit, abbrev),
+            "commit_id" => commit.id().to_string(),
+            "subject" => display_subject(commit),
+            "author" => commit.author().to_string(),
+        ),
+        Announcement::StackBase(Some(commit), abbrev) => info!(
+            logger,
+            "stack base";
+            "commit" => short_id(commit, abbrev),
+            "commit_id" => commit.id().to_string(),
+            "subject" => display_subject(commit),
+        ),
+        Announcement::StackBase(None, _) => {
+            info!(logger, "stack reaches the first commit in the repository")
+        }
+        Announcement::CouldNotFindRepositoryPath => warn!(
+            logger,
+            "Could not determine repository path for rebase. Running in current directory."
+        ),
+        Announcement::NoteNotWritten(err) => warn!(
+            logger,
+            "Could not write the note for a fixup, carrying on without it";
+            "err" => err.to_string(),
+        ),
+        Announcement::UndoNotRecorded(err) => warn!(
+            logger,
+            "Could not record how to undo the run, so --undo cannot undo it";
+            "err" => err.to_string(),
+        ),
+        Announcement::WouldSign(fixup, key) => info!(
+            logger,
+            "would sign with key";
+            "fixup" => fixup,
+            "key" => key,
+        ),
+        Announcement::Undone(head, abbrev) => info!(
+            logger,
+            "undid the last absorb";
+            "head" => short_id(head, abbrev),
+        ),
+        Announcement::SubmoduleFailed(err) => error!(
+            logger,
+            "absorb failed in submodule";
+            "err" => err.to_string(),
+        ),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use git2::message_trailers_strs;
+    use serde_json::json;
+    use std::path::PathBuf;
+
+    use super::*;
+    mod log_utils;
+    pub mod repo_utils;
+
+    #[test]
+    fn no_commits_in_repo() {
+        let dir = tempfile::tempdir().unwrap();
+        let repo = git2::Repository::init_opts(
+            dir.path(),
+            git2::RepositoryInitOptions::new().initial_head("master"),
+        )
+        .unwrap();
+        let mut capturing_logger = log_utils::CapturingLogger::new();
+        run_with_repo(&capturing_logger.logger, &DEFAULT_CONFIG, &repo).unwrap();
+        log_utils::assert_log_messages_are(
+            capturing_logger.visible_logs(),
+            vec![&json!({"level": "WARN", "msg": "No commits to absorb into."})],
+        );
+    }
+
+    #[test]
+    fn no_commits_in_repo_with_staged_changes() {
+        let dir = tempfile::tempdir().unwrap();
+        let repo = git2::Repository::init(dir.path()).unwrap();
+        let path = PathBuf::from("test-file.txt");
+        std::fs::write(dir.path().join(&path), "line\n").unwrap();
+        repo_utils::add(&repo, &path);
+
+        let mut capturing_logger = log_utils::CapturingLogger::new();
+        run_with_repo(&capturing_logger.logger, &DEFAULT_CONFIG, &repo).unwrap();
+
+        // nothing was committed, and the change is still staged
+        assert_eq!(
+            repo.head().err().unwrap().code(),
+            git2::ErrorCode::UnbornBranch
+        );
+        assert!(repo.index().unwrap().get_path(&path, 0).is_some());
+        log_utils::assert_log_messages_are(
+            capturing_logger.visible_logs(),
+            vec![&json!({"level": "WARN", "msg": "No commits to absorb into."})],
+        );
+    }
+
+    #[test]
+    fn multiple_fixups_per_commit() {
+        let ctx = repo_utils::prepare_and_stage();
+
+        let actual_pre_absorb_commit = ctx.repo.head().unwrap().peel_to_commit().unwrap().id();
+
+        // run 'git-absorb'
+        let mut capturing_logger = log_utils::CapturingLogger::new();
+        run_with_repo(&capturing_logger.logger, &DEFAULT_CONFIG, &ctx.repo).unwrap();
+
+        let mut revwalk = ctx.repo.revwalk().unwrap();
+        revwalk.push_head().unwrap();
+        assert_eq!(revwalk.count(), 3);
+
+        assert!(nothing_left_in_index(&ctx.repo).unwrap());
+
+        let pre_absorb_ref_commit = ctx.repo.refname_to_id("PRE_ABSORB_HEAD").unwrap();
+        assert_eq!(pre_absorb_ref_commit, actual_pre_absorb_commit);
+
+        assert_eq!(
+            extract_commit_messages(&ctx.repo),
+            vec![
+                "fixup! Initial commit.\n",
+                "fixup! Initial commit.\n",
+                "Initial commit.",
+            ]
+        );
+
+        let target = ctx.repo.find_commit(pre_absorb_ref_commit).unwrap();
+        log_utils::assert_log_messages_are(
+            capturing_logger.visible_logs(),
+            vec![
+                &json!({
+                    "level": "INFO",
+                    "msg": "stack reaches the first commit in the repository",
+                }),
+                &json!({
+                    "level": "INFO",
+                    "msg": "committed",
+                    "target": short_id(&target, 7),
+                    "subject": "Initial commit.",
+                }),
+                &json!({
+                    "level": "INFO",
+                    "msg": "committed",
+                    "target": short_id(&target, 7),
+                    "subject": "Initial commit.",
+                }),
+                &json!({
+                    "level": "INFO",
+                    "msg": "To squash the new commits, rebase:",
+                    "command": "git rebase --interactive --autosquash --autostash --root",
+                }),
+            ],
+        );
+    }
+
+    #[test]
+    fn exceed_stack_limit_with_modified_hunk() {
+        let (ctx, file_path) = repo_utils::prepare_repo();
+
+        let parent_commit = ctx.repo.head().unwrap().peel_to_commit().unwrap();
+        repo_utils::empty_commit_chain(&ctx.repo, "HEAD", &[&parent_commit], config::MAX_STACK);
+        repo_utils::stage_file_changes(&ctx, &file_path);
+
+        // run 'git-absorb'
+        let mut capturing_logger = log_utils::CapturingLogger::new();
+        run_with_repo(&capturing_logger.logger, &DEFAULT_CONFIG, &ctx.repo).unwrap();
+
+        let mut revwalk = ctx.repo.revwalk().unwrap();
+        revwalk.push_head().unwrap();
+        assert_eq!(
+            revwalk.count(),
+            config::MAX_STACK + 1,
+            "Wrong number of commits."
+        );
+
+        let is_something_in_index = !nothing_left_in_index(&ctx.repo).unwrap();
+        assert!(is_something_in_index);
+
+        log_utils::assert_log_messages_are(
+            capturing_logger.visible_logs(),
+            vec![
+                &json!({"level": "INFO", "msg": "stack base"}),
+                &json!({
+                    "level": "WARN",
+                    "msg": "Some file modifications did not have an available commit to fix up. \
+                           You will have to manually create fixup commits.",
+                }),
+                &json!({
+                    "level": "WARN",
+                    "msg": format!(
+                        "Will not fix up past maximum stack limit. \
+                        Use --base or configure {} to override",
+                        config::MAX_STACK_CONFIG_NAME
+                    ),
+                    "limit": config::MAX_STACK,
+                }),
+                &json!({
+                    "level": "WARN",
+                    "msg": "Staged change could not be absorbed",
+                    "location": "test-file.txt:1",
+                    "reason": "boundary",
+                }),
+                &json!({
+                    "level": "WARN",
+                    "msg": "Staged change could not be absorbed",
+                    "location": "test-file.txt:8-9",
+                    "reason": "boundary",
+                }),
+            ],
+        );
+    }
+
+    #[test]
+    fn exceed_stack_limit_with_non_modified_patch() {
+        // non-modified patches commute with everything, and
+        // have special handling above, so make sure we test with one
+        let (ctx, _) = repo_utils::prepare_repo();
+        let parent_commit = ctx.repo.head().unwrap().peel_to_commit().unwrap();
+        repo_utils::empty_commit_chain(&ctx.repo, "HEAD", &[&parent_commit], config::MAX_STACK);
+        let a_new_file_path = PathBuf::from("a_whole_new_file.txt");
+        std::fs::write(ctx.join(&a_new_file_path), "contents").unwrap();
+        repo_utils::stage_file_changes(&ctx, &a_new_file_path);
+        let another_new_file_path = PathBuf::from("another_whole_new_file.txt");
+        std::fs::write(ctx.join(&another_new_file_path), "contents").unwrap();
+        repo_utils::stage_file_changes(&ctx, &another_new_file_path);
+
+        // run 'git-absorb'
+        let mut capturing_logger = log_utils::CapturingLogger::new();
+        run_with_repo(&capturing_logger.logger, &DEFAULT_CONFIG, &ctx.repo).unwrap();
+
+        let mut revwalk = ctx.repo.revwalk().unwrap();
+        revwalk.push_head().unwrap();
+        assert_eq!(
+            revwalk.count(),
+            config::MAX_STACK + 1,
+            "Wrong number of commits."
+        );
+
+        let is_something_in_index = !nothing_left_in_index(&ctx.repo).unwrap();
+        assert!(is_something_in_index);
+
+        log_utils::assert_log_messages_are(
+            capturing_logger.visible_logs(),
+            vec![
+                &json!({"level": "INFO", "msg": "stack base"}),
+                &json!({
+                    "level": "WARN",
+                    "msg": "No changes were in-place file modifications. \
+                           Added or renamed files cannot be automatically absorbed, \
+                           and removed files only into the commit that added them.",
+                }),
+                &json!({
+                    "level": "WARN",
+                    "msg": "Staged change could not be absorbed",
+                    "location": "a_whole_new_file.txt",
+                    "reason": "added",
+                }),
+                &json!({
+                    "level": "WARN",
+                    "msg": "Staged change could not be absorbed",
+                    "location": "another_whole_new_file.txt",
+                    "reason": "added",
+                }),
+            ],
+        );
+    }
+
+    #[test]
+    fn exceed_stack_limit_with_modified_patch_and_non_modified_hunks() {
+        // non-modified patches commute with everything, and
+        // have special handling above. Test with both modified hunks (a patch is made of hunks)
+        // and non-modified patches to ensure we don't confuse the messaging to the user.
+        let (ctx, file_path) = repo_utils::prepare_repo();
+        let new_file_path = PathBuf::from("a_whole_new_file.txt");
+        let parent_commit = ctx.repo.head().unwrap().peel_to_commit().unwrap();
+        repo_utils::empty_commit_chain(&ctx.repo, "HEAD", &[&parent_commit], config::MAX_STACK);
+        std::fs::write(ctx.join(&new_file_path), "contents").unwrap();
+        repo_utils::stage_file_changes(&ctx, &new_file_path);
+        repo_utils::stage_file_changes(&ctx, &file_path);
+
+        // run 'git-absorb'
+        let mut capturing_logger = log_utils::CapturingLogger::new();
+        run_with_repo(&capturing_logger.logger, &DEFAULT_CONFIG, &ctx.repo).unwrap();
+
+        let mut revwalk = ctx.repo.revwalk().unwrap();
+        revwalk.push_head().unwrap();
+        assert_eq!(
+            revwalk.count(),
+            config::MAX_STACK + 1,
+            "Wrong number of commits."
+        );
+
+        let is_something_in_index = !nothing_left_in_index(&ctx.repo).unwrap();
+        assert!(is_something_in_index);
+
+        log_utils::assert_log_messages_are(
+            capturing_logger.visible_logs(),
+            vec![
+                &json!({"level": "INFO", "msg": "stack base"}),
+                &json!({
+                    "level": "WARN",
+                    "msg": "Some changes were not in-place file modifications. \
+                           Added or renamed files cannot be automatically absorbed, \
+                           and removed files only into the commit that added them.",
+                }),
+                &json!({
+                    "level": "WARN",
+                    "msg": "Some file modifications did not have an available commit to fix up. \
+                           You will have to manually create fixup commits.",
+                }),
+                &json!({
+                    "level": "WARN",
+                    "msg": format!(
+                        "Will not fix up past maximum stack limit. \
+                        Use --base or configure {} to override",
+                        config::MAX_STACK_CONFIG_NAME
+                    ),
+                }),
+                &json!({
+                    "level": "WARN",
+                    "msg": "Staged change could not be absorbed",
+                    "location": "a_whole_new_file.txt",
+                    "reason": "added",
+                }),
+                &json!({
+                    "level": "WARN",
+                    "msg": "Staged change could not be absorbed",
+                    "location": "test-file.txt:1",
+                    "reason": "boundary",
+                }),
+                &json!({
+                    "level": "WARN",
+                    "msg": "Staged change could not be absorbed",
+                    "location": "test-file.txt:8-9",
+                    "reason": "boundary",
+                }),
+            ],
+        );
+    }
+
+    #[test]
+    fn reached_root() {
+        let (ctx, _) = repo_utils::prepare_repo();
+        let file_path = PathBuf::from("a_whole_new_file.txt");
+        std::fs::write(ctx.join(&file_path), "contents").unwrap();
+        repo_utils::stage_file_changes(&ctx, &file_path);
+
+        // run 'git-absorb'
+        let mut capturing_logger = log_utils::CapturingLogger::new();
+        run_with_repo(&capturing_logger.logger, &DEFAULT_CONFIG, &ctx.repo).unwrap();
+
+        let mut revwalk = ctx.repo.revwalk().unwrap();
+        revwalk.push_head().unwrap();
+        assert_eq!(revwalk.count(), 1, "Wrong number of commits.");
+
+        let is_something_in_index = !nothing_left_in_index(&ctx.repo).unwrap();
+        assert!(is_something_in_index);
+
+        log_utils::assert_log_messages_are(
+            capturing_logger.visible_logs(),
+            vec![
+                &json!({
+                    "level": "INFO",
+                    "msg": "stack reaches the first commit in the repository",
+                }),
+                &json!({
+                    "level": "WARN",
+                    "msg": "No changes were in-place file modifications. \
+                           Added or renamed files cannot be automatically absorbed, \
+                           and removed files only into the commit that added them."
+                }),
+                &json!({
+                    "level": "WARN",
+                    "msg": "Staged change could not be absorbed",
+                    "location": "a_whole_new_file.txt",
+                    "reason": "added",
+                }),
+            ],
+        );
+    }
+
+    #[test]
+    fn stack_base_is_announced() {
+        let ctx = repo_utils::prepare_and_stage();
+        let commits = {
+            let initial = ctx.repo.head().unwrap().peel_to_commit().unwrap();
+            repo_utils::empty_commit_chain(&ctx.repo, "HEAD", &[&initial], 2)
+        };
+
+        let mut capturing_logger = log_utils::CapturingLogger::new();
+        let config = Config {
+            base: &["HEAD~1"],
+            ..DEFAULT_CONFIG
+        };
+        run_with_repo(&capturing_logger.logger, &config, &ctx.repo).unwrap();
+
+        let logs = capturing_logger.visible_logs();
+        let base: Vec<_> = logs
+            .iter()
+            .filter(|log| log["msg"] == "stack base")
+            .collect();
+        assert_eq!(base.len(), 1, "{:?}", logs);
+        assert_eq!(base[0]["commit_id"], commits[0].id().to_string());
+        assert_eq!(base[0]["subject"], "0");
+    }
+
+    #[test]
+    fn user_defined_base_hides_target_commit() {
+        let ctx = repo_utils::prepare_and_stage();
+
+        // run 'git-absorb'
+        let mut capturing_logger = log_utils::CapturingLogger::new();
+        let config = Config {
+            base: &["HEAD"],
+            ..DEFAULT_CONFIG
+        };
+        run_with_repo(&capturing_logger.logger, &config, &ctx.repo).unwrap();
+
+        let mut revwalk = ctx.repo.revwalk().unwrap();
+        revwalk.push_head().unwrap();
+        assert_eq!(revwalk.count(), 1, "Wrong number of commits.");
+
+        let is_something_in_index = !nothing_left_in_index(&ctx.repo).unwrap();
+        assert!(is_something_in_index);
+
+        log_utils::assert_log_messages_are(
+            capturing_logger.visible_logs(),
+            vec![
+                &json!({"level": "INFO", "msg": "stack base"}),
+                &json!({
+                    "level": "WARN",
+                    "msg": "Some file modifications did not have an available commit to fix up. \
+                           You will have to manually create fixup commits.",
+                }),
+                &json!({
+                    "level": "WARN",
+                    "msg": "Will not fix up past specified base commit. \
+                           Consider using --base to specify a different base commit",
+                    "base": "HEAD",
+                }),
+                &json!({
+                    "level": "WARN",
+                    "msg": "Staged change could not be absorbed",
+                    "location": "test-file.txt:1",
+                    "reason": "boundary",
+                }),
+                &json!({
+                    "level": "WARN",
+                    "msg": "Staged change could not be absorbed",
+                    "location": "test-file.txt:8-9",
+                    "reason": "boundary",
+                }),
+            ],
+        );
+    }
+
+    #[test]
+    fn merge_commit_found() {
+        let (ctx, file_path) = repo_utils::prepare_repo();
+        repo_utils::merge_commit(
+            &ctx.repo,
+            &[&ctx.repo.head().unwrap().peel_to_commit().unwrap()],
+        );
+        repo_utils::stage_file_changes(&ctx, &file_path);
+
+        // run 'git-absorb'
+        let mut capturing_logger = log_utils::CapturingLogger::new();
+        run_with_repo(&capturing_logger.logger, &DEFAULT_CONFIG, &ctx.repo).unwrap();
+
+        let mut revwalk = ctx.repo.revwalk().unwrap();
+        revwalk.push_head().unwrap();
+        assert_eq!(revwalk.count(), 4, "Wrong number of commits.");
+
+        let is_something_in_index = !nothing_left_in_index(&ctx.repo).unwrap();
+        assert!(is_something_in_index);
+
+        log_utils::assert_log_messages_are(
+            capturing_logger.visible_logs(),
+            vec![
+                &json!({"level": "INFO", "msg": "stack base"}),
+                &json!({
+                    "level": "WARN",
+                    "msg": "Some file modifications did not have an available commit to fix up. \
+                           You will have to manually create fixup commits.",
+                }),
+                &json!({
+                    "level": "WARN",
+                    "msg": "Cannot fix up past a merge commit",
+                }),
+                &json!({
+                    "level": "WARN",
+                    "msg": "Staged change could not be absorbed",
+                    "location": "test-file.txt:1",
+                    "reason": "boundary",
+                }),
+                &json!({
+                    "level": "WARN",
+                    "msg": "Staged change could not be absorbed",
+                    "location": "test-file.txt:8-9",
+                    "reason": "boundary",
+                }),
+            ],
+        );
+    }
+
+    #[test]
+    fn octopus_merge_commit_found() {
+        let (ctx, file_path) = repo_utils::prepare_repo();
+        let merge = repo_utils::octopus_merge_commit(
+            &ctx.repo,
+            &[&ctx.repo.head().unwrap().peel_to_commit().unwrap()],
+            3,
+        );
+        repo_utils::stage_file_changes(&ctx, &file_path);
+
+        let mut capturing_logger = log_utils::CapturingLogger::new();
+        run_with_repo(&capturing_logger.logger, &DEFAULT_CONFIG, &ctx.repo).unwrap();
+
+        // nothing is absorbed into the merge, nor past it
+        assert_eq!(
+            ctx.repo.head().unwrap().peel_to_commit().unwrap().id(),
+            merge.id()
+        );
+        let logs = capturing_logger.visible_logs();
+        let base = logs.iter().find(|log| log["msg"] == "stack base").unwrap();
+        assert_eq!(base["commit_id"], merge.id().to_string());
+        let reasons: Vec<_> = logs
+            .iter()
+            .filter(|log| log["msg"] == "Staged change could not be absorbed")
+            .map(|log| log["reason"].as_str().unwrap())
+            .collect();
+        assert_eq!(reasons, ["boundary", "boundary"]);
+        assert!(logs
+            .iter()
+            .any(|log| log["msg"] == "Cannot fix up past a merge commit"));
+    }
+
+    #[test]
+    fn merge_commit_before_target_commit() {
+        let (ctx, file_path) = repo_utils::prepare_repo();
+        let merge_commit = repo_utils::merge_commit(
+            &ctx.repo,
+            &[&ctx.repo.head().unwrap().peel_to_commit().unwrap()],
+        );
+
+        std::fs::write(&ctx.join(&file_path), "new content").unwrap();
+        let tree = repo_utils::add(&ctx.repo, &file_path);
+        repo_utils::commit(
+            &ctx.repo,
+            "HEAD",
+            "Change after merge",
+            &tree,
+            &[&merge_commit],
+        );
+
+        repo_utils::stage_file_changes(&ctx, &file_path);
+
+        // run 'git-absorb'
+        let mut capturing_logger = log_utils::CapturingLogger::new();
+        run_with_repo(&capturing_logger.logger, &DEFAULT_CONFIG, &ctx.repo).unwrap();
+
+        let mut revwalk = ctx.repo.revwalk().unwrap();
+        revwalk.push_head().unwrap();
+        assert_eq!(revwalk.count(), 6, "Wrong number of commits.");
+
+        assert!(nothing_left_in_index(&ctx.repo).unwrap());
+
+        log_utils::assert_log_messages_are(
+            capturing_logger.visible_logs(),
+            vec![
+                &json!({"level": "INFO", "msg": "stack base"}),
+                &json!({"level": "INFO", "msg": "committed",}),
+                &json!({
+                    "level": "INFO",
+                    "msg": "To squash the new commits, rebase:",
+                    "command": format!(
+                        "git rebase --interactive --autosquash --autostash {}",
+                        merge_commit.id()),
+                }),
+            ],
+        );
+    }
+
+    #[test]
+    fn first_hidden_commit_is_merge() {
+        let (ctx, file_path) = repo_utils::prepare_repo();
+        let merge_commit = repo_utils::merge_commit(
+            &ctx.repo,
+            &[&ctx.repo.head().unwrap().peel_to_commit().unwrap()],
+        );
+        repo_utils::empty_commit(&ctx.repo, "HEAD", "empty commit", &[&merge_commit]);
+        repo_utils::stage_file_changes(&ctx, &file_path);
+
+        // run 'git-absorb'
+        let mut capturing_logger = log_utils::CapturingLogger::new();
+        let base_id = merge_commit.id().to_string();
+        let config = Config {
+            base: &[&base_id],
+            ..DEFAULT_CONFIG
+        };
+        run_with_repo(&capturing_logger.logger, &config, &ctx.repo).unwrap();
+
+        let mut revwalk = ctx.repo.revwalk().unwrap();
+        revwalk.push_head().unwrap();
+        assert_eq!(revwalk.count(), 5, "Wrong number of commits.");
+
+        let is_something_in_index = !nothing_left_in_index(&ctx.repo).unwrap();
+        assert!(is_something_in_index);
+
+        log_utils::assert_log_messages_are(
+            capturing_logger.visible_logs(),
+            vec![
+                &json!({"level": "INFO", "msg": "stack base"}),
+                &json!({
+                    "level": "WARN",
+                    "msg": "Some file modifications did not have an available commit to fix up. \
+                           You will have to manually create fixup commits.",
+                }),
+                &json!({
+                    "level": "WARN",
+                    "msg": "Cannot fix up past a merge commit",
+                }),
+                &json!({
+                    "level": "WARN",
+                    "msg": "Staged change could not be absorbed",
+                    "location": "test-file.txt:1",
+                    "reason": "boundary",
+                }),
+                &json!({
+                    "level": "WARN",
+                    "msg": "Staged change could not be absorbed",
+                    "location": "test-file.txt:8-9",
+                    "reason": "boundary",
+                }),
+            ],
+        );
+    }
+
+    #[test]
+    fn first_hidden_commit_is_by_another_author() {
+        let (ctx, file_path) = repo_utils::prepare_repo();
+        let first_commit = ctx.repo.head().unwrap().peel_to_commit().unwrap();
+        ctx.repo
+            .branch("some-branch", &first_commit, false)
+            .unwrap();
+        repo_utils::become_author(&ctx.repo, "nobody2", "nobody2@example.com");
+        repo_utils::empty_commit(&ctx.repo, "HEAD", "empty commit", &[&first_commit]);
+        repo_utils::stage_file_changes(&ctx, &file_path);
+
+        // run 'git-absorb'
+        let mut capturing_logger = log_utils::CapturingLogger::new();
+        run_with_repo(&capturing_logger.logger, &DEFAULT_CONFIG, &ctx.repo).unwrap();
+
+        let mut revwalk = ctx.repo.revwalk().unwrap();
+        revwalk.push_head().unwrap();
+        assert_eq!(revwalk.count(), 2, "Wrong number of commits.");
+
+        let is_something_in_index = !nothing_left_in_index(&ctx.repo).unwrap();
+        assert!(is_something_in_index);
+
+        log_utils::assert_log_messages_are(
+            capturing_logger.visible_logs(),
+            vec![
+                &json!({"level": "INFO", "msg": "stack base"}),
+                &json!({
+                    "level": "WARN",
+                    "msg": "Some file modifications did not have an available commit to fix up. \
+                           You will have to manually create fixup commits.",
+                }),
+                &json!({
+                    "level": "WARN",
+                    "msg": "Will not fix up past commits by another author. \
+                           Use --force-author to override",
+                }),
+                &json!({
+                    "level": "WARN",
+                    "msg": "Staged change could not be absorbed",
+                    "location": "test-file.txt:1",
+                    "reason": "boundary",
+                }),
+                &json!({
+                    "level": "WARN",
+                    "msg": "Staged change could not be absorbed",
+                    "location": "test-file.txt:8-9",
+                    "reason": "boundary",
+                }),
+            ],
+        );
+    }
+
+    #[test]
+    fn first_hidden_commit_is_regular_commit() {
+        let (ctx, file_path) = repo_utils::prepare_repo();
+        let first_commit = ctx.repo.head().unwrap().peel_to_commit().unwrap();
+        ctx.repo
+            .branch("some-branch", &first_commit, false)
+            .unwrap();
+        repo_utils::empty_commit(&ctx.repo, "HEAD", "empty commit", &[&first_commit]);
+        repo_utils::stage_file_changes(&ctx, &file_path);
+
+        // run 'git-absorb'
+        let mut capturing_logger = log_utils::CapturingLogger::new();
+        run_with_repo(&capturing_logger.logger, &DEFAULT_CONFIG, &ctx.repo).unwrap();
+
+        let mut revwalk = ctx.repo.revwalk().unwrap();
+        revwalk.push_head().unwrap();
+        assert_eq!(revwalk.count(), 2, "Wrong number of commits.");
+
+        let is_something_in_index = !nothing_left_in_index(&ctx.repo).unwrap();
+        assert!(is_something_in_index);
+
+        log_utils::assert_log_messages_are(
+            capturing_logger.visible_logs(),
+            vec![
+                &json!({"level": "INFO", "msg": "stack base"}),
+                &json!({
+                    "level": "WARN",
+                    "msg": "Some file modifications did not have an available commit to fix up. \
+                           You will have to manually create fixup commits.",
+                }),
+                &json!({
+                    "level": "WARN",
+                    "msg": "Will not fix up commits reachable by other branches. \
+                           Use --base to specify a base commit.",
+                }),
+                &json!({
+                    "level": "WARN",
+                    "msg": "Staged change could not be absorbed",
+                    "location": "test-file.txt:1",
+                    "reason": "boundary",
+                }),
+                &json!({
+                    "level": "WARN",
+                    "msg": "Staged change could not be absorbed",
+                    "location": "test-file.txt:8-9",
+                    "reason": "boundary",
+                }),
+            ],
+        );
+    }
+
+    #[test]
+    fn one_fixup_per_commit() {
+        let ctx = repo_utils::prepare_and_stage();
+
+        // run 'git-absorb'
+        let mut capturing_logger = log_utils::CapturingLogger::new();
+        let config = Config {
+            one_fixup_per_commit: true,
+            ..DEFAULT_CONFIG
+        };
+        run_with_repo(&capturing_logger.logger, &config, &ctx.repo).unwrap();
+
+        let mut revwalk = ctx.repo.revwalk().unwrap();
+        revwalk.push_head().unwrap();
+        assert_eq!(revwalk.count(), 2);
+
+        assert!(nothing_left_in_index(&ctx.repo).unwrap());
+
+        log_utils::assert_log_messages_are(
+            capturing_logger.visible_logs(),
+            vec![
+                &json!({
+                    "level": "INFO",
+                    "msg": "stack reaches the first commit in the repository",
+                }),
+                &json!({"level": "INFO", "msg": "committed"}),
+                &json!({
+                    "level": "INFO",
+                    "msg": "To squash the new commits, rebase:",
+                    "command": "git rebase --interactive --autosquash --autostash --root",
+                }),
+            ],
+        );
+    }
+
+    #[test]
+    fn another_author() {
+        let ctx = repo_utils::prepare_and_stage();
+
+        repo_utils::become_author(&ctx.repo, "nobody2", "nobody2@example.com");
+
+        // run 'git-absorb'
+        let mut capturing_logger = log_utils::CapturingLogger::new();
+        run_with_repo(&capturing_logger.logger, &DEFAULT_CONFIG, &ctx.repo).unwrap();
+
+        let mut revwalk = ctx.repo.revwalk().unwrap();
+        revwalk.push_head().unwrap();
+        assert_eq!(revwalk.count(), 1);
+        let is_something_in_index = !nothing_left_in_index(&ctx.repo).unwrap();
+        assert!(is_something_in_index);
+
+        log_utils::assert_log_messages_are(
+            capturing_logger.visible_logs(),
+            vec![
+                &json!({"level": "INFO", "msg": "stack base"}),
+                &json!({
+                    "level": "WARN",
+                    "msg": "Some file modifications did not have an available commit to fix up. \
+                           You will have to manually create fixup commits.",
+                }),
+                &json!({
+                    "level": "WARN",
+                    "msg": "Will not fix up past commits by another author. \
+                           Use --force-author to override"
+                }),
+                &json!({
+                    "level": "WARN",
+                    "msg": "Staged change could not be absorbed",
+                    "location": "test-file.txt:1",
+                    "reason": "boundary",
+                }),
+                &json!({
+                    "level": "WARN",
+                    "msg": "Staged change could not be absorbed",
+                    "location": "test-file.txt:8-9",
+                    "reason": "boundary",
+                }),
+            ],
+        );
+    }
+
+    #[test]
+    fn another_author_with_force_author_flag() {
+        let ctx = repo_utils::prepare_and_stage();
+
+        repo_utils::become_author(&ctx.repo, "nobody2", "nobody2@example.com");
+
+        // run 'git-absorb'
+        let mut capturing_logger = log_utils::CapturingLogger::new();
+        let config = Config {
+            force_author: true,
+            ..DEFAULT_CONFIG
+        };
+        run_with_repo(&capturing_logger.logger, &config, &ctx.repo).unwrap();
+
+        let mut revwalk = ctx.repo.revwalk().unwrap();
+        revwalk.push_head().unwrap();
+        assert_eq!(revwalk.count(), 3);
+
+        assert!(nothing_left_in_index(&ctx.repo).unwrap());
+
+        log_utils::assert_log_messages_are(
+            capturing_logger.visible_logs(),
+            vec![
+                &json!({
+                    "level": "INFO",
+                    "msg": "stack reaches the first commit in the repository",
+                }),
+                &json!({"level": "INFO", "msg": "committed"}),
+                &json!({"level": "INFO", "msg": "committed"}),
+                &json!({
+                    "level": "INFO",
+                    "msg": "To squash the new commits, rebase:",
+                    "command": "git rebase --interactive --autosquash --autostash --root",
+                }),
+            ],
+        );
+    }
+
+    #[test]
+    fn another_author_with_force_author_config() {
+        let ctx = repo_utils::prepare_and_stage();
+
+        repo_utils::become_author(&ctx.repo, "nobody2", "nobody2@example.com");
+
+        repo_utils::set_config_flag(&ctx.repo, "absorb.forceAuthor");
+
+        // run 'git-absorb'
+        let mut capturing_logger = log_utils::CapturingLogger::new();
+        run_with_repo(&capturing_logger.logger, &DEFAULT_CONFIG, &ctx.repo).unwrap();
+
+        let mut revwalk = ctx.repo.revwalk().unwrap();
+        revwalk.push_head().unwrap();
+        assert_eq!(revwalk.count(), 3);
+
+        assert!(nothing_left_in_index(&ctx.repo).unwrap());
+
+        log_utils::assert_log_messages_are(
+            capturing_logger.visible_logs(),
+            vec![
+                &json!({
+                    "level": "INFO",
+                    "msg": "stack reaches the first commit in the repository",
+                }),
+                &json!({"level": "INFO", "msg": "committed"}),
+                &json!({"level": "INFO", "msg": "committed"}),
+                &json!({
+                    "level": "INFO",
+                    "msg": "To squash the new commits, rebase:",
+                    "command": "git rebase --interactive --autosquash --autostash --root",
+                }),
+            ],
+        );
+    }
+
+    #[test]
+    fn detached_head() {
+        let ctx = repo_utils::prepare_and_stage();
+        repo_utils::detach_head(&ctx.repo);
+
+        // run 'git-absorb'
+        let capturing_logger = log_utils::CapturingLogger::new();
+        let err = run_with_repo(&capturing_logger.logger, &DEFAULT_CONFIG, &ctx.repo).unwrap_err();
+        assert!(matches!(err, AbsorbError::DetachedHead), "{:?}", err);
+        assert_eq!(
+            err.to_string(),
+            "HEAD is not a branch, use --force-detach to override"
+        );
+
+        let mut revwalk = ctx.repo.revwalk().unwrap();
+        revwalk.push_head().unwrap();
+        assert_eq!(revwalk.count(), 1);
+        let is_something_in_index = !nothing_left_in_index(&ctx.repo).unwrap();
+        assert!(is_something_in_index);
+    }
+
+    #[test]
+    fn force_author_does_not_allow_detached_head() {
+        let ctx = repo_utils::prepare_and_stage();
+        repo_utils::detach_head(&ctx.repo);
+
+        let drain = slog::Discard;
+        let logger = slog::Logger::root(drain, o!());
+        let config = Config {
+            force_author: true,
+            ..DEFAULT_CONFIG
+        };
+        let result = run_with_repo(&logger, &config, &ctx.repo);
+        assert_eq!(
+            result.err().unwrap().to_string(),
+            "HEAD is not a branch, use --force-detach to override"
+        );
+        assert!(!nothing_left_in_index(&ctx.repo).unwrap());
+    }
+
+    #[test]
+    fn force_detach_does_not_allow_another_author() {
+        let ctx = repo_utils::prepare_and_stage();
+        repo_utils::become_author(&ctx.repo, "nobody2", "nobody2@example.com");
+        repo_utils::detach_head(&ctx.repo);
+        repo_utils::delete_branch(&ctx.repo, "master");
+
+        let drain = slog::Discard;
+        let logger = slog::Logger::root(drain, o!());
+        let config = Config {
+            force_detach: true,
+            ..DEFAULT_CONFIG
+        };
+        run_with_repo(&logger, &config, &ctx.repo).unwrap();
+
+        // detaching was allowed, but the commit by another author was not
+        // fixed up
+        assert_eq!(extract_commit_messages(&ctx.repo).len(), 1);
+        assert!(!nothing_left_in_index(&ctx.repo).unwrap());
+
+        let config = Config {
+            force_author: true,
+            force_detach: true,
+            ..DEFAULT_CONFIG
+        };
+        run_with_repo(&logger, &config, &ctx.repo).unwrap();
+        assert_eq!(extract_commit_messages(&ctx.repo).len(), 3);
+        assert!(nothing_left_in_index(&ctx.repo).unwrap());
+    }
+
+    #[test]
+    fn detached_head_pointing_at_branch_with_force_detach_flag() {
+        let ctx = repo_utils::prepare_and_stage();
+        repo_utils::detach_head(&ctx.repo);
+
+        // run 'git-absorb'
+        let mut capturing_logger = log_utils::CapturingLogger::new();
+        let config = Config {
+            force_detach: true,
+            ..DEFAULT_CONFIG
+        };
+        run_with_repo(&capturing_logger.logger, &config, &ctx.repo).unwrap();
+        let mut revwalk = ctx.repo.revwalk().unwrap();
+        revwalk.push_head().unwrap();
+
+        assert_eq!(revwalk.count(), 1); // nothing was committed
+        let is_something_in_index = !nothing_left_in_index(&ctx.repo).unwrap();
+        assert!(is_something_in_index);
+
+        log_utils::assert_log_messages_are(
+            capturing_logger.visible_logs(),
+            vec![
+                &json!({
+                    "level": "WARN",
+                    "msg": "HEAD is not a branch, but --force-detach used to continue."}),
+                &json!({
+                    "level": "INFO",
+                    "msg": "stack base",
+                    "subject": "Initial commit.",
+                }),
+                &json!({
+                    "level": "WARN",
+                    "msg": "Some file modifications did not have an available commit to fix up. \
+                           You will have to manually create fixup commits.",
+                }),
+                &json!({
+                    "level": "WARN",
+                    "msg": "Will not fix up commits reachable by other branches. \
+                    Use --base to specify a base commit."
+                }),
+                &json!({
+                    "level": "WARN",
+                    "msg": "Staged change could not be absorbed",
+                    "location": "test-file.txt:1",
+                    "reason": "boundary",
+                }),
+                &json!({
+                    "level": "WARN",
+                    "msg": "Staged change could not be absorbed",
+                    "location": "test-file.txt:8-9",
+                    "reason": "boundary",
+                }),
+            ],
+        );
+    }
+
+    #[test]
+    fn detached_head_with_force_detach_flag() {
+        let ctx = repo_utils::prepare_and_stage();
+        repo_utils::detach_head(&ctx.repo);
+        repo_utils::delete_branch(&ctx.repo, "master");
+
+        // run 'git-absorb'
+        let mut capturing_logger = log_utils::CapturingLogger::new();
+        let config = Config {
+            force_detach: true,
+            ..DEFAULT_CONFIG
+        };
+        run_with_repo(&capturing_logger.logger, &config, &ctx.repo).unwrap();
+        let mut revwalk = ctx.repo.revwalk().unwrap();
+        revwalk.push_head().unwrap();
+
+        assert_eq!(revwalk.count(), 3);
+        assert!(nothing_left_in_index(&ctx.repo).unwrap());
+
+        log_utils::assert_log_messages_are(
+            capturing_logger.visible_logs(),
+            vec![
+                &json!({
+                    "level": "WARN",
+                    "msg": "HEAD is not a branch, but --force-detach used to continue.",
+                }),
+                &json!({
+                    "level": "INFO",
+                    "msg": "stack reaches the first commit in the repository",
+                }),
+                &json!({"level": "INFO", "msg": "committed"}),
+                &json!({"level": "INFO", "msg": "committed"}),
+                &json!({
+                    "level": "INFO",
+                    "msg": "To squash the new commits, rebase:",
+                    "command": "git rebase --interactive --autosquash --autostash --root",
+                }),
+            ],
+        );
+    }
+
+    #[test]
+    fn detached_head_with_force_detach_config() {
+        let ctx = repo_utils::prepare_and_stage();
+        repo_utils::detach_head(&ctx.repo);
+        repo_utils::delete_branch(&ctx.repo, "master");
+
+        repo_utils::set_config_flag(&ctx.repo, "absorb.forceDetach");
+
+        // run 'git-absorb'
+        let mut capturing_logger = log_utils::CapturingLogger::new();
+        run_with_repo(&capturing_logger.logger, &DEFAULT_CONFIG, &ctx.repo).unwrap();
+        let mut revwalk = ctx.repo.revwalk().unwrap();
+        revwalk.push_head().unwrap();
+
+        assert_eq!(revwalk.count(), 3);
+        assert!(nothing_left_in_index(&ctx.repo).unwrap());
+
+        log_utils::assert_log_messages_are(
+            capturing_logger.visible_logs(),
+            vec![
+                &json!({
+                    "level": "WARN",
+                    "msg": "HEAD is not a branch, but --force-detach used to continue.",
+                }),
+                &json!({
+                    "level": "INFO",
+                    "msg": "stack reaches the first commit in the repository",
+                }),
+                &json!({"level": "INFO", "msg": "committed"}),
+                &json!({"level": "INFO", "msg": "committed"}),
+                &json!({
+                    "level": "INFO",
+                    "msg": "To squash the new commits, rebase:",
+                    "command": "git rebase --interactive --autosquash --autostash --root",
+                }),
+            ],
+        );
+    }
+
+    fn prepare_long_subject() -> repo_utils::Context {
+        let (ctx, file_path) = repo_utils::prepare_repo();
+        {
+            let tree = repo_utils::stage_file_changes(&ctx, &file_path);
+            let head = ctx.repo.head().unwrap().peel_to_commit().unwrap();
+            repo_utils::commit(
+                &ctx.repo,
+                "HEAD",
+                "Add a very long subject that goes on and on about what it changes",
+                &tree,
+                &[&head],
+            );
+        }
+        repo_utils::stage_file_changes(&ctx, &file_path);
+        repo_utils::set_config_option(&ctx.repo, config::MAX_SUBJECT_LENGTH_CONFIG_NAME, "30");
+        ctx
+    }
+
+    #[test]
+    fn long_fixup_subject_is_warned_about() {
+        let ctx = prepare_long_subject();
+
+        let mut capturing_logger = log_utils::CapturingLogger::new();
+        run_with_repo(&capturing_logger.logger, &DEFAULT_CONFIG, &ctx.repo).unwrap();
+
+        assert_eq!(
+            extract_commit_messages(&ctx.repo)[0],
+            "fixup! Add a very long subject that goes on and on about what it changes\n"
+        );
+        log_utils::assert_log_messages_are(
+            capturing_logger.visible_logs(),
+            vec![
+                &json!({
+                    "level": "INFO",
+                    "msg": "stack reaches the first commit in the repository",
+                }),
+                &json!({
+                    "level": "WARN",
+                    "msg": "The fixup subject is longer than absorb.maxSubjectLength",
+                    "length": 72,
+                    "max": 30,
+                }),
+                &json!({"level": "INFO", "msg": "committed"}),
+                &json!({
+                    "level": "WARN",
+                    "msg": "The fixup subject is longer than absorb.maxSubjectLength",
+                    "length": 72,
+                    "max": 30,
+                }),
+                &json!({"level": "INFO", "msg": "committed"}),
+                &json!({"level": "INFO", "msg": "To squash the new commits, rebase:"}),
+            ],
+        );
+    }
+
+    #[test]
+    fn truncate_subject_flag() {
+        let ctx = prepare_long_subject();
+
+        let drain = slog::Discard;
+        let logger = slog::Logger::root(drain, o!());
+        let config = Config {
+            truncate_subject: true,
+            ..DEFAULT_CONFIG
+        };
+        run_with_repo(&logger, &config, &ctx.repo).unwrap();
+
+        assert_eq!(
+            extract_commit_messages(&ctx.repo)[0],
+            "fixup! Add a very long subject\n"
+        );
+
+        // autosquash still finds the commit from the start of its subject
+        let status = std::process::Command::new("git")
+            .args([
+                "rebase",
+                "--quiet",
+                "--interactive",
+                "--autosquash",
+                "--root",
+            ])
+            .current_dir(ctx.dir.path())
+            .env("GIT_SEQUENCE_EDITOR", "true")
+            .status()
+            .unwrap();
+        assert!(status.success());
+        assert_eq!(
+            extract_commit_messages(&ctx.repo),
+            vec![
+                "Add a very long subject that goes on and on about what it changes",
+                "Initial commit.",
+            ]
+        );
+    }
+    #[test]
+    fn and_rebase_flag() {
+        let ctx = repo_utils::prepare_and_stage();
+        repo_utils::set_config_option(&ctx.repo, "core.editor", "true");
+        repo_utils::set_config_option(&ctx.repo, "advice.waitingForEditor", "false");
+        let orig_head = ctx.repo.head().unwrap().target().unwrap();
+
+        // run 'git-absorb'
+        let mut capturing_logger = log_utils::CapturingLogger::new();
+        let config = Config {
+            and_rebase: true,
+            ..DEFAULT_CONFIG
+        };
+        run_with_repo(&capturing_logger.logger, &config, &ctx.repo).unwrap();
+
+        let mut revwalk = ctx.repo.revwalk().unwrap();
+        revwalk.push_head().unwrap();
+
+        assert_eq!(revwalk.count(), 1);
+        assert!(nothing_left_in_index(&ctx.repo).unwrap());
+        assert_eq!(ctx.repo.refname_to_id(ORIG_HEAD_REF).unwrap(), orig_head);
+
+        log_utils::assert_log_messages_are(
+            capturing_logger.visible_logs(),
+            vec![
+                &json!({
+                    "level": "INFO",
+                    "msg": "stack reaches the first commit in the repository",
+                }),
+                &json!({"level": "INFO", "msg": "committed"}),
+                &json!({"level": "INFO", "msg": "committed"}),
+            ],
+        );
+    }
+
+    /// The command that a dry run with --and-rebase would have run.
+    fn would_have_rebased_with(ctx: &repo_utils::Context) -> String {
+        let mut capturing_logger = log_utils::CapturingLogger::new();
+        let config = Config {
+            and_rebase: true,
+            dry_run: true,
+            ..DEFAULT_CONFIG
+        };
+        run_with_repo(&capturing_logger.logger, &config, &ctx.repo).unwrap();
+        capturing_logger
+            .visible_logs()
+            .into_iter()
+            .find(|log| log["msg"] == "would have run git rebase, nothing was run")
+            .unwrap()["command"]
+            .as_str()
+            .unwrap()
+            .to_owned()
+    }
+
+    #[test]
+    fn and_rebase_only_rewrites_from_the_oldest_target() {
+        let (ctx, path) = repo_utils::prepare_synthetic_stack(3, 0);
+        repo_utils::set_config_option(&ctx.repo, "core.editor", "true");
+        repo_utils::set_config_option(&ctx.repo, "advice.waitingForEditor", "false");
+        let contents = std::fs::read_to_string(ctx.join(&path)).unwrap();
+        std::fs::write(
+            ctx.join(&path),
+            contents.replace("commit 1\n", "edited 1\n"),
+        )
+        .unwrap();
+        repo_utils::add(&ctx.repo, &path);
+        let mut revwalk = ctx.repo.revwalk().unwrap();
+        revwalk.push_head().unwrap();
+        let before: Vec<_> = revwalk.map(Result::unwrap).collect();
+
+        // the rebase starts right below commit 1
+        let command = would_have_rebased_with(&ctx);
+        assert!(command.ends_with(&format!(" {}", before[2])), "{}", command);
+
+        let drain = slog::Discard;
+        let logger = slog::Logger::root(drain, o!());
+        let config = Config {
+            and_rebase: true,
+            ..DEFAULT_CONFIG
+        };
+        run_with_repo(&logger, &config, &ctx.repo).unwrap();
+
+        let mut revwalk = ctx.repo.revwalk().unwrap();
+        revwalk.push_head().unwrap();
+        let after: Vec<_> = revwalk.map(Result::unwrap).collect();
+        assert_eq!(after.len(), 4);
+        // commit 0 and the initial commit are below the target
+        assert_eq!(after[2..], before[2..]);
+        assert_ne!(after[1], before[1]);
+        assert!(nothing_left_in_index(&ctx.repo).unwrap());
+        let contents = head_file_contents(&ctx.repo, &path);
+        assert!(contents.contains("edited 1\n"), "{}", contents);
+    }
+
+    #[test]
+    fn rebase_whole_stack_config() {
+        let (ctx, path) = repo_utils::prepare_synthetic_stack(3, 0);
+        let contents = std::fs::read_to_string(ctx.join(&path)).unwrap();
+        std::fs::write(
+            ctx.join(&path),
+            contents.replace("commit 2\n", "edited 2\n"),
+        )
+        .unwrap();
+        repo_utils::add(&ctx.repo, &path);
+        repo_utils::set_config_option(&ctx.repo, config::REBASE_WHOLE_STACK_CONFIG_NAME, "true");
+
+        // the stack reaches the initial commit
+        let command = would_have_rebased_with(&ctx);
+        assert!(command.ends_with(" --root"), "{}", command);
+    }
+
+    #[test]
+    fn and_rebase_config() {
+        let ctx = repo_utils::prepare_and_stage();
+        repo_utils::set_config_option(&ctx.repo, "core.editor", "true");
+        repo_utils::set_config_option(&ctx.repo, "advice.waitingForEditor", "false");
+        repo_utils::set_config_option(&ctx.repo, config::AND_REBASE_CONFIG_NAME, "true");
+
+        let drain = slog::Discard;
+        let logger = slog::Logger::root(drain, o!());
+        run_with_repo(&logger, &DEFAULT_CONFIG, &ctx.repo).unwrap();
+
+        assert_eq!(extract_commit_messages(&ctx.repo), vec!["Initial commit."]);
+        assert!(nothing_left_in_index(&ctx.repo).unwrap());
+    }
+
+    #[test]
+    fn no_rebase_flag_overrides_and_rebase_config() {
+        let ctx = repo_utils::prepare_and_stage();
+        repo_utils::set_config_option(&ctx.repo, config::AND_REBASE_CONFIG_NAME, "true");
+
+        let mut capturing_logger = log_utils::CapturingLogger::new();
+        let config = Config {
+            no_rebase: true,
+            ..DEFAULT_CONFIG
+        };
+        run_with_repo(&capturing_logger.logger, &config, &ctx.repo).unwrap();
+
+        assert_eq!(extract_commit_messages(&ctx.repo).len(), 3);
+        log_utils::assert_log_messages_are(
+            capturing_logger.visible_logs(),
+            vec![
+                &json!({
+                    "level": "INFO",
+                    "msg": "stack reaches the first commit in the repository",
+                }),
+                &json!({"level": "INFO", "msg": "committed"}),
+                &json!({"level": "INFO", "msg": "committed"}),
+                &json!({
+                    "level": "INFO",
+                    "msg": "To squash the new commits, rebase:",
+                }),
+            ],
+        );
+    }
+
+    #[test]
+    fn and_rebase_config_with_changes_in_working_tree() {
+        let (ctx, file_path) = repo_utils::prepare_repo();
+        repo_utils::set_config_option(&ctx.repo, config::AND_REBASE_CONFIG_NAME, "true");
+        repo_utils::set_config_option(&ctx.repo, config::REBASE_AUTO_STASH_CONFIG_NAME, "false");
+        repo_utils::stage_file_changes(&ctx, &file_path);
+        let path = ctx.join(&file_path);
+        let contents = std::fs::read_to_string(&path).unwrap();
+        std::fs::write(&path, format!("{contents}\nnot staged")).unwrap();
+
+        let mut capturing_logger = log_utils::CapturingLogger::new();
+        run_with_repo(&capturing_logger.logger, &DEFAULT_CONFIG, &ctx.repo).unwrap();
+
+        // the fixups are made, but left for a rebase later
+        assert_eq!(extract_commit_messages(&ctx.repo).len(), 3);
+        log_utils::assert_log_messages_are(
+            capturing_logger.visible_logs(),
+            vec![
+                &json!({
+                    "level": "INFO",
+                    "msg": "stack reaches the first commit in the repository",
+                }),
+                &json!({"level": "INFO", "msg": "committed"}),
+                &json!({"level": "INFO", "msg": "committed"}),
+                &json!({
+                    "level": "WARN",
+                    "msg": "Will not rebase as absorb.andRebase asks, only creating fixup commits",
+                }),
+                &json!({
+                    "level": "INFO",
+                    "msg": "To squash the new commits, rebase:",
+                }),
+            ],
+        );
+    }
+
+    #[test]
+    fn and_rebase_flag_with_unrelated_unstaged_changes() {
+        let (ctx, file_path) = repo_utils::prepare_repo();
+        repo_utils::set_config_option(&ctx.repo, "core.editor", "true");
+        repo_utils::set_config_option(&ctx.repo, "advice.waitingForEditor", "false");
+        let other_path = PathBuf::from("other.txt");
+        std::fs::write(ctx.join(&other_path), "line\n").unwrap();
+        let tree = repo_utils::add(&ctx.repo, &other_path);
+        let head = ctx.repo.head().unwrap().peel_to_commit().unwrap();
+        repo_utils::commit(&ctx.repo, "HEAD", "Add other file", &tree, &[&head]);
+
+        repo_utils::stage_file_changes(&ctx, &file_path);
+        // would stop a rebase without --autostash
+        std::fs::write(ctx.join(&other_path), "dirty line\n").unwrap();
+
+        let capturing_logger = log_utils::CapturingLogger::new();
+        let config = Config {
+            and_rebase: true,
+            ..DEFAULT_CONFIG
+        };
+        run_with_repo(&capturing_logger.logger, &config, &ctx.repo).unwrap();
+
+        assert_eq!(
+            extract_commit_messages(&ctx.repo),
+            vec!["Add other file", "Initial commit."]
+        );
+        assert!(nothing_left_in_index(&ctx.repo).unwrap());
+        assert_eq!(
+            std::fs::read_to_string(ctx.join(&other_path)).unwrap(),
+            "dirty line\n"
+        );
+        assert_eq!(
+            ctx.repo.status_file(&other_path).unwrap(),
+            git2::Status::WT_MODIFIED
+        );
+    }
+
+    #[test]
+    fn and_rebase_flag_inside_submodule_checkout() {
+        // lay the repository out like a submodule: the git dir lives in the
+        // superproject's git dir, and the work tree only has a .git file
+        let dir = tempfile::tempdir().unwrap();
+        let superproject_path = dir.path().join("super");
+        let superproject = git2::Repository::init(&superproject_path).unwrap();
+        let git_dir = superproject_path.join(".git/modules/sub");
+        let work_tree = superproject_path.join("sub");
+        std::fs::create_dir(&work_tree).unwrap();
+        git2::Repository::init_opts(
+            &git_dir,
+            git2::RepositoryInitOptions::new()
+                .initial_head("master")
+                .workdir_path(&work_tree)
+                .no_dotgit_dir(true),
+        )
+        .unwrap();
+        std::fs::write(work_tree.join(".git"), "gitdir: ../.git/modules/sub\n").unwrap();
+
+        let repo = git2::Repository::open_ext(
+            &work_tree,
+            git2::RepositoryOpenFlags::empty(),
+            &[] as &[&std::ffi::OsStr],
+        )
+        .unwrap();
+        assert_eq!(
+            repo.path().canonicalize().unwrap(),
+            git_dir.canonicalize().unwrap()
+        );
+        repo_utils::become_author(&repo, "nobody", "nobody@example.com");
+        repo_utils::set_config_option(&repo, "core.editor", "true");
+        repo_utils::set_config_option(&repo, "advice.waitingForEditor", "false");
+
+        let path = PathBuf::from("test-file.txt");
+        std::fs::write(work_tree.join(&path), "line\n").unwrap();
+        {
+            let tree = repo_utils::add(&repo, &path);
+            repo_utils::commit(&repo, "HEAD", "Initial commit.", &tree, &[]);
+        }
+        std::fs::write(work_tree.join(&path), "line\nnew_line\n").unwrap();
+        repo_utils::add(&repo, &path);
+
+        let drain = slog::Discard;
+        let logger = slog::Logger::root(drain, o!());
+        let config = Config {
+            and_rebase: true,
+            ..DEFAULT_CONFIG
+        };
+        run_with_repo(&logger, &config, &repo).unwrap();
+
+        // the fixup was squashed in the submodule, not in the superproject
+        assert_eq!(extract_commit_messages(&repo), vec!["Initial commit."]);
+        assert!(nothing_left_in_index(&repo).unwrap());
+        assert_eq!(
+            std::fs::read_to_string(work_tree.join(&path)).unwrap(),
+            "line\nnew_line\n"
+        );
+        assert!(superproject.head().is_err());
+    }
+
+    #[test]
+    fn and_rebase_flag_with_rebase_options() {
+        let ctx = repo_utils::prepare_and_stage();
+        repo_utils::set_config_option(&ctx.repo, "core.editor", "true");
+        repo_utils::set_config_option(&ctx.repo, "advice.waitingForEditor", "false");
+
+        // run 'git-absorb'
+        let mut capturing_logger = log_utils::CapturingLogger::new();
+        let config = Config {
+            and_rebase: true,
+            rebase_options: &vec!["--signoff"],
+            ..DEFAULT_CONFIG
+        };
+        run_with_repo(&capturing_logger.logger, &config, &ctx.repo).unwrap();
+
+        let mut revwalk = ctx.repo.revwalk().unwrap();
+        revwalk.push_head().unwrap();
+        assert_eq!(revwalk.count(), 1);
+
+        let trailers = message_trailers_strs(
+            ctx.repo
+                .head()
+                .unwrap()
+                .peel_to_commit()
+                .unwrap()
+                .message()
+                .unwrap(),
+        )
+        .unwrap();
+        assert_eq!(
+            trailers
+                .iter()
+                .filter(|trailer| trailer.0 == "Signed-off-by")
+                .count(),
+            1
+        );
+
+        assert!(nothing_left_in_index(&ctx.repo).unwrap());
+
+        log_utils::assert_log_messages_are(
+            capturing_logger.visible_logs(),
+            vec![
+                &json!({
+                    "level": "INFO",
+                    "msg": "stack reaches the first commit in the repository",
+                }),
+                &json!({"level": "INFO", "msg": "committed"}),
+                &json!({"level": "INFO", "msg": "committed"}),
+            ],
+        );
+    }
+
+    #[test]
+    fn rebase_options_without_and_rebase_flag() {
+        let ctx = repo_utils::prepare_and_stage();
+
+        // run 'git-absorb'
+        let capturing_logger = log_utils::CapturingLogger::new();
+        let config = Config {
+            rebase_options: &vec!["--some-option"],
+            ..DEFAULT_CONFIG
+        };
+        let result = run_with_repo(&capturing_logger.logger, &config, &ctx.repo);
+
+        assert_eq!(
+            result.err().unwrap().to_string(),
+            "REBASE_OPTIONS were specified without --and-rebase flag"
+        );
+
+        let mut revwalk = ctx.repo.revwalk().unwrap();
+        revwalk.push_head().unwrap();
+        assert_eq!(revwalk.count(), 1);
+        let is_something_in_index = !nothing_left_in_index(&ctx.repo).unwrap();
+        assert!(is_something_in_index);
+    }
+
+    #[test]
+    fn squash_flag() {
+        let ctx = repo_utils::prepare_and_stage();
+
+        // run 'git-absorb'
+        let mut capturing_logger = log_utils::CapturingLogger::new();
+        let config = Config {
+            squash: true,
+            ..DEFAULT_CONFIG
+        };
+        run_with_repo(&capturing_logger.logger, &config, &ctx.repo).unwrap();
+
+        assert_eq!(
+            extract_commit_messages(&ctx.repo),
+            vec![
+                "squash! Initial commit.\n",
+                "squash! Initial commit.\n",
+                "Initial commit.",
+            ]
+        );
+
+        log_utils::assert_log_messages_are(
+            capturing_logger.visible_logs(),
+            vec![
+                &json!({
+                    "level": "INFO",
+                    "msg": "stack reaches the first commit in the repository",
+                }),
+                &json!({"level": "INFO", "msg": "committed"}),
+                &json!({"level": "INFO", "msg": "committed"}),
+                &json!({
+                    "level": "INFO",
+                    "msg": "To squash the new commits, rebase:",
+                    "command": "git rebase --interactive --autosquash --autostash --root",
+                }),
+            ],
+        );
+    }
+
+    #[test]
+    fn run_with_squash_config_option() {
+        let ctx = repo_utils::prepare_and_stage();
+
+        repo_utils::set_config_flag(&ctx.repo, "absorb.createSquashCommits");
+
+        // run 'git-absorb'
+        let mut capturing_logger = log_utils::CapturingLogger::new();
+        run_with_repo(&capturing_logger.logger, &DEFAULT_CONFIG, &ctx.repo).unwrap();
+
+        assert_eq!(
+            extract_commit_messages(&ctx.repo),
+            vec![
+                "squash! Initial commit.\n",
+                "squash! Initial commit.\n",
+                "Initial commit.",
+            ]
+        );
+
+        log_utils::assert_log_messages_are(
+            capturing_logger.visible_logs(),
+            vec![
+                &json!({
+                    "level": "INFO",
+                    "msg": "stack reaches the first commit in the repository",
+                }),
+                &json!({"level": "INFO", "msg": "committed"}),
+                &json!({"level": "INFO", "msg": "committed"}),
+                &json!({
+                    "level": "INFO",
+                    "msg": "To squash the new commits, rebase:",
+                    "command": "git rebase --interactive --autosquash --autostash --root",
+                }),
+            ],
+        );
+    }
+
+    #[test]
+    fn dry_run_flag() {
+        let ctx = repo_utils::prepare_and_stage();
+
+        // run 'git-absorb'
+        let mut capturing_logger = log_utils::CapturingLogger::new();
+        let config = Config {
+            dry_run: true,
+            ..DEFAULT_CONFIG
+        };
+        run_with_repo(&capturing_logger.logger, &config, &ctx.repo).unwrap();
+
+        let mut revwalk = ctx.repo.revwalk().unwrap();
+        revwalk.push_head().unwrap();
+        assert_eq!(revwalk.count(), 1);
+        let is_something_in_index = !nothing_left_in_index(&ctx.repo).unwrap();
+        assert!(is_something_in_index);
+
+        let pre_absorb_ref_commit = ctx.repo.references_glob("PRE_ABSORB_HEAD").unwrap().last();
+        assert!(pre_absorb_ref_commit.is_none());
+
+        log_utils::assert_log_messages_are(
+            capturing_logger.visible_logs(),
+            vec![
+                &json!({
+                    "level": "INFO",
+                    "msg": "stack reaches the first commit in the repository",
+                }),
+                &json!({
+                    "level": "INFO",
+                    "msg": "would have committed", "fixup": "Initial commit.",
+                    "subject": "Initial commit.",
+                }),
+                &json!({
+                    "level": "INFO",
+                    "msg": "would have committed", "fixup": "Initial commit.",
+                    "subject": "Initial commit.",
+                }),
+            ],
+        );
+    }
+
+    #[test]
+    fn long_and_non_utf8_subjects_are_displayed() {
+        let (ctx, _) = repo_utils::prepare_repo();
+        let head = ctx.repo.head().unwrap().peel_to_commit().unwrap();
+
+        // git2 only creates commits with UTF-8 messages, so write one by hand
+        let mut raw = format!(
+            "tree {}\nparent {}\nauthor nobody <nobody@example.com> 0 +0000\n\
+             committer nobody <nobody@example.com> 0 +0000\n\n",
+            head.tree_id(),
+            head.id(),
+        )
+        .into_bytes();
+        raw.extend_from_slice(b"Caf\xe9 menu: add every dish we have ever served, in order\n");
+        let oid = ctx
+            .repo
+            .odb()
+            .unwrap()
+            .write(git2::ObjectType::Commit, &raw)
+            .unwrap();
+        let commit = ctx.repo.find_commit(oid).unwrap();
+
+        let subject = display_subject(&commit);
+        assert_eq!(subject.chars().count(), SUBJECT_WIDTH);
+        assert_eq!(
+            subject,
+            "Caf\u{fffd} menu: add every dish we have ever served, in…"
+        );
+        assert_eq!(display_subject(&head), "Initial commit.");
+    }
+
+    #[test]
+    fn and_rebase_conflicts_use_configured_conflict_style() {
+        let (ctx, file_path) = repo_utils::prepare_repo();
+        repo_utils::set_config_option(&ctx.repo, "core.editor", "true");
+
+        // a hand-made fixup that cannot be moved before the commit after
+        // its target, so the rebase stops with a conflict
+        let other_path = PathBuf::from("other.txt");
+        let mut lines = prepare_numbered_lines(&ctx, &other_path);
+        for (line, subject) in [
+            ("line 3 changed\n", "Change line 3"),
+            ("line 3 fixed\n", "fixup! Add lines"),
+        ] {
+            lines[2] = line.to_owned();
+            std::fs::write(ctx.join(&other_path), lines.concat()).unwrap();
+            let tree = repo_utils::add(&ctx.repo, &other_path);
+            let head = ctx.repo.head().unwrap().peel_to_commit().unwrap();
+            repo_utils::commit(&ctx.repo, "HEAD", subject, &tree, &[&head]);
+        }
+        repo_utils::stage_file_changes(&ctx, &file_path);
+
+        let dir = tempfile::tempdir().unwrap();
+        let config_path = dir.path().join("absorb.config");
+        std::fs::write(&config_path, "[merge]\n\tconflictStyle = diff3\n").unwrap();
+        let config = Config {
+            and_rebase: true,
+            config_file: Some(&config_path),
+            ..DEFAULT_CONFIG
+        };
+        let drain = slog::Discard;
+        let logger = slog::Logger::root(drain, o!());
+        let err = run_with_repo(&logger, &config, &ctx.repo).unwrap_err();
+        assert!(
+            matches!(err, AbsorbError::RebaseFailed { status: Some(1) }),
+            "{:?}",
+            err
+        );
+
+        let conflicted = std::fs::read_to_string(ctx.join(&other_path)).unwrap();
+        assert!(conflicted.contains("<<<<<<< "), "{}", conflicted);
+        // only diff3 shows the common ancestor
+        assert!(conflicted.contains("||||||| "), "{}", conflicted);
+        assert!(conflicted.contains("line 3 changed\n"), "{}", conflicted);
+    }
+
+    #[test]
+    fn dry_run_flag_with_and_rebase_flag() {
+        let (ctx, path) = repo_utils::prepare_repo();
+        repo_utils::set_config_option(&ctx.repo, "core.editor", "true");
+
+        // create a fixup commit that 'git rebase' will act on if called
+        let tree = repo_utils::stage_file_changes(&ctx, &path);
+        let head_commit = ctx.repo.head().unwrap().peel_to_commit().unwrap();
+        let fixup_message = format!("fixup! {}\n", head_commit.id());
+        let fixup = repo_utils::commit(&ctx.repo, "HEAD", &fixup_message, &tree, &[&head_commit]);
+
+        // stage one more change so 'git-absorb' won't exit early
+        repo_utils::stage_file_changes(&ctx, &path);
+
+        // run 'git-absorb'
+        let mut capturing_logger = log_utils::CapturingLogger::new();
+        let config = Config {
+            and_rebase: true,
+            dry_run: true,
+            ..DEFAULT_CONFIG
+        };
+        run_with_repo(&capturing_logger.logger, &config, &ctx.repo).unwrap();
+
+        let mut revwalk = ctx.repo.revwalk().unwrap();
+        revwalk.push_head().unwrap();
+        assert_eq!(revwalk.count(), 2); // git rebase wasn't called so both commits persist
+        let is_something_in_index = !nothing_left_in_index(&ctx.repo).unwrap();
+        assert!(is_something_in_index);
+
+        log_utils::assert_log_messages_are(
+            capturing_logger.visible_logs(),
+            vec![
+                &json!({
+                    "level": "INFO",
+                    "msg": "stack reaches the first commit in the repository",
+                }),
+                &json!({"level": "INFO", "msg": "would have committed",}),
+                &json!({"level": "INFO", "msg": "would have committed",}),
+                &json!({
+                    "level": "INFO",
+                    "msg": "would have run git rebase, nothing was run",
+                    // what a real run would have run
+                    "command": command_line(&rebase_command(
+                        &capturing_logger.logger,
+                        &Config {
+                            and_rebase: true,
+                            ..DEFAULT_CONFIG
+                        },
+                        &ctx.repo,
+                        &[
+                            "rebase",
+                            "--interactive",
+                            "--autosquash",
+                            "--autostash",
+                            "--root",
+                        ],
+                    )),
+                }),
+                // the existing fixup and the new ones are slotted after their targets
+                &json!({
+                    "level": "INFO",
+                    "msg": "would have rebased",
+                    "todo": format!("pick {} Initial commit.", short_id(&head_commit, 7)),
+                }),
+                &json!({
+                    "level": "INFO",
+                    "msg": "would have rebased",
+                    "todo": format!("fixup {} {}", short_id(&fixup, 7), fixup_message.trim_end()),
+                }),
+                &json!({
+                    "level": "INFO",
+                    "msg": "would have rebased",
+                    "todo": format!("fixup (new) fixup! {}", fixup_message.trim_end()),
+                }),
+                &json!({
+                    "level": "INFO",
+                    "msg": "would have rebased",
+                    "todo": format!("fixup (new) fixup! {}", fixup_message.trim_end()),
+                }),
+            ],
+        );
+    }
+
+    fn autostage_common(ctx: &repo_utils::Context, file_path: &PathBuf) -> (PathBuf, PathBuf) {
+        // 1 modification w/o staging
+        let path = ctx.join(file_path);
+        let contents = std::fs::read_to_string(&path).unwrap();
+        let modifications = format!("{contents}\nnew_line2");
+        std::fs::write(&path, &modifications).unwrap();
+
+        // 1 extra file
+        let fp2 = PathBuf::from("unrel.txt");
+        std::fs::write(ctx.join(&fp2), "foo").unwrap();
+
+        (path, fp2)
+    }
+
+    #[test]
+    fn autostage_if_index_was_empty() {
+        let (ctx, file_path) = repo_utils::prepare_repo();
+
+        // requires enabled config var
+        ctx.repo
+            .config()
+            .unwrap()
+            .set_bool(config::AUTO_STAGE_IF_NOTHING_STAGED_CONFIG_NAME, true)
+            .unwrap();
+
+        autostage_common(&ctx, &file_path);
+
+        // run 'git-absorb'
+        let mut capturing_logger = log_utils::CapturingLogger::new();
+        run_with_repo(&capturing_logger.logger, &DEFAULT_CONFIG, &ctx.repo).unwrap();
+
+        let mut revwalk = ctx.repo.revwalk().unwrap();
+        revwalk.push_head().unwrap();
+        assert_eq!(revwalk.count(), 2);
+
+        assert!(nothing_left_in_index(&ctx.repo).unwrap());
+
+        log_utils::assert_log_messages_are(
+            capturing_logger.visible_logs(),
+            vec![
+                &json!({
+                    "level": "INFO",
+                    "msg": "stack reaches the first commit in the repository",
+                }),
+                &json!({"level": "INFO", "msg": "committed"}),
+                &json!({
+                    "level": "INFO",
+                    "msg": "To squash the new commits, rebase:",
+                    "command": "git rebase --interactive --autosquash --autostash --root",
+                }),
+            ],
+        );
+    }
+
+    #[test]
+    fn do_not_autostage_if_index_was_not_empty() {
+        let (ctx, file_path) = repo_utils::prepare_repo();
+
+        // enable config var
+        ctx.repo
+            .config()
+            .unwrap()
+            .set_bool(config::AUTO_STAGE_IF_NOTHING_STAGED_CONFIG_NAME, true)
+            .unwrap();
+
+        let (_, fp2) = autostage_common(&ctx, &file_path);
+        // we stage the extra file - should stay in index
+        repo_utils::add(&ctx.repo, &fp2);
+
+        // run 'git-absorb'
+        let mut capturing_logger = log_utils::CapturingLogger::new();
+        run_with_repo(&capturing_logger.logger, &DEFAULT_CONFIG, &ctx.repo).unwrap();
+
+        let mut revwalk = ctx.repo.revwalk().unwrap();
+        revwalk.push_head().unwrap();
+        assert_eq!(revwalk.count(), 1);
+
+        assert_eq!(index_stats(&ctx.repo).unwrap().files_changed(), 1);
+
+        log_utils::assert_log_messages_are(
+            capturing_logger.visible_logs(),
+            vec![
+                &json!({
+                    "level": "INFO",
+                    "msg": "stack reaches the first commit in the repository",
+                }),
+                &json!({
+                    "level": "WARN",
+                    "msg": "No changes were in-place file modifications. \
+                           Added or renamed files cannot be automatically absorbed, \
+                           and removed files only into the commit that added them."
+                }),
+                &json!({
+                    "level": "WARN",
+                    "msg": "Staged change could not be absorbed",
+                    "location": "unrel.txt",
+                    "reason": "added",
+                }),
+            ],
+        );
+    }
+
+    #[test]
+    fn do_not_autostage_if_not_enabled_by_config_var() {
+        let (ctx, file_path) = repo_utils::prepare_repo();
+
+        // disable config var
+        ctx.repo
+            .config()
+            .unwrap()
+            .set_bool(config::AUTO_STAGE_IF_NOTHING_STAGED_CONFIG_NAME, false)
+            .unwrap();
+
+        autostage_common(&ctx, &file_path);
+
+        // run 'git-absorb'
+        let mut capturing_logger = log_utils::CapturingLogger::new();
+        run_with_repo(&capturing_logger.logger, &DEFAULT_CONFIG, &ctx.repo).unwrap();
+
+        let mut revwalk = ctx.repo.revwalk().unwrap();
+        revwalk.push_head().unwrap();
+        assert_eq!(revwalk.count(), 1);
+
+        assert!(nothing_left_in_index(&ctx.repo).unwrap());
+
+        log_utils::assert_log_messages_are(
+            capturing_logger.visible_logs(),
+            vec![&json!({
+                "level": "WARN",
+                "msg": format!(
+                    "No changes staged. \
+                    Try adding something to the index or set {} = true.",
+                    config::AUTO_STAGE_IF_NOTHING_STAGED_CONFIG_NAME,
+                ),
+            })],
+        );
+    }
+
+    #[test]
+    fn autostage_if_index_was_empty_and_no_changes() {
+        let (ctx, _file_path) = repo_utils::prepare_repo();
+
+        // requires enabled config var
+        ctx.repo
+            .config()
+            .unwrap()
+            .set_bool(config::AUTO_STAGE_IF_NOTHING_STAGED_CONFIG_NAME, true)
+            .unwrap();
+
+        // run 'git-absorb'
+        let mut capturing_logger = log_utils::CapturingLogger::new();
+        run_with_repo(&capturing_logger.logger, &DEFAULT_CONFIG, &ctx.repo).unwrap();
+
+        let mut revwalk = ctx.repo.revwalk().unwrap();
+        revwalk.push_head().unwrap();
+        assert_eq!(revwalk.count(), 1);
+
+        assert!(nothing_left_in_index(&ctx.repo).unwrap());
+
+        log_utils::assert_log_messages_are(
+            capturing_logger.visible_logs(),
+            vec![&json!({
+                    "level": "WARN",
+                    "msg": "No changes staged, even after auto-staging. \
+                           Try adding something to the index."})],
+        );
+    }
+
+    #[test]
+    fn autostage_waits_for_index_lock() {
+        let (ctx, file_path) = repo_utils::prepare_repo();
+        repo_utils::set_config_flag(&ctx.repo, config::AUTO_STAGE_IF_NOTHING_STAGED_CONFIG_NAME);
+        let contents = std::fs::read_to_string(ctx.join(&file_path)).unwrap();
+        std::fs::write(ctx.join(&file_path), format!("new_line1\n{contents}")).unwrap();
+
+        // another git process, which lets go of the index lock after a while
+        let lock = ctx.repo.path().join("index.lock");
+        std::fs::write(&lock, "").unwrap();
+        let unlock = {
+            let lock = lock.clone();
+            std::thread::spawn(move || {
+                std::thread::sleep(LOCK_BACKOFF * 2);
+                std::fs::remove_file(lock).unwrap();
+            })
+        };
+
+        let capturing_logger = log_utils::CapturingLogger::new();
+        run_with_repo(&capturing_logger.logger, &DEFAULT_CONFIG, &ctx.repo).unwrap();
+        unlock.join().unwrap();
+
+        assert_eq!(
+            extract_commit_messages(&ctx.repo),
+            vec!["fixup! Initial commit.\n", "Initial commit."]
+        );
+        assert!(nothing_left_in_index(&ctx.repo).unwrap());
+    }
+
+    #[test]
+    fn autostage_with_index_lock_held() {
+        let (ctx, file_path) = repo_utils::prepare_repo();
+        repo_utils::set_config_flag(&ctx.repo, config::AUTO_STAGE_IF_NOTHING_STAGED_CONFIG_NAME);
+        let contents = std::fs::read_to_string(ctx.join(&file_path)).unwrap();
+        std::fs::write(ctx.join(&file_path), format!("new_line1\n{contents}")).unwrap();
+        let lock = ctx.repo.path().join("index.lock");
+        std::fs::write(&lock, "").unwrap();
+
+        // a dry run does not write the index, so it does not need the lock
+        let capturing_logger = log_utils::CapturingLogger::new();
+        let config = Config {
+            dry_run: true,
+            ..DEFAULT_CONFIG
+        };
+        run_with_repo(&capturing_logger.logger, &config, &ctx.repo).unwrap();
+        assert!(nothing_left_in_index(&ctx.repo).unwrap());
+
+        let err = run_with_repo(&capturing_logger.logger, &DEFAULT_CONFIG, &ctx.repo).unwrap_err();
+        assert!(
+            matches!(&err, AbsorbError::IndexLocked { path } if path.with_extension("lock") == lock),
+            "{:?}",
+            err
+        );
+        assert_eq!(
+            err.to_string(),
+            format!(
+                "the index is locked by another git process, if none is running remove {}",
+                lock.display()
+            )
+        );
+        assert!(lock.exists());
+        assert_eq!(extract_commit_messages(&ctx.repo).len(), 1);
+    }
+
+    /// Commits two files that git is told to leave alone, one marked
+    /// skip-worktree and one assume-unchanged, and changes both in the
+    /// working tree, as sparse checkouts and monorepo tooling do.
+    fn add_flagged_bystanders(ctx: &repo_utils::Context) {
+        std::fs::write(ctx.join(Path::new("skipped.txt")), "skipped\n").unwrap();
+        std::fs::write(ctx.join(Path::new("assumed.txt")), "assumed\n").unwrap();
+        repo_utils::add(&ctx.repo, Path::new("skipped.txt"));
+        let tree = repo_utils::add(&ctx.repo, Path::new("assumed.txt"));
+        let head = ctx.repo.head().unwrap().peel_to_commit().unwrap();
+        repo_utils::commit(&ctx.repo, "HEAD", "Add bystanders", &tree, &[&head]);
+
+        let mut index = ctx.repo.index().unwrap();
+        let mut entry = index.get_path(Path::new("skipped.txt"), 0).unwrap();
+        entry.flags_extended |= git2::IndexEntryExtendedFlag::SKIP_WORKTREE.bits();
+        index.add(&entry).unwrap();
+        let mut entry = index.get_path(Path::new("assumed.txt"), 0).unwrap();
+        entry.flags |= git2::IndexEntryFlag::VALID.bits();
+        index.add(&entry).unwrap();
+        index.write().unwrap();
+        assert_bystanders_flagged(&ctx.repo);
+
+        std::fs::write(ctx.join(Path::new("skipped.txt")), "not staged\n").unwrap();
+        std::fs::write(ctx.join(Path::new("assumed.txt")), "not staged\n").unwrap();
+    }
+
+    fn assert_bystanders_flagged(repo: &git2::Repository) {
+        let index = repo.index().unwrap();
+        let skipped = index.get_path(Path::new("skipped.txt"), 0).unwrap();
+        assert_ne!(
+            skipped.flags_extended & git2::IndexEntryExtendedFlag::SKIP_WORKTREE.bits(),
+            0
+        );
+        let assumed = index.get_path(Path::new("assumed.txt"), 0).unwrap();
+        assert_ne!(assumed.flags & git2::IndexEntryFlag::VALID.bits(), 0);
+        let head = repo.head().unwrap().peel_to_tree().unwrap();
+        for entry in [skipped, assumed] {
+            let path = std::str::from_utf8(&entry.path).unwrap();
+            assert_eq!(entry.id, head.get_path(Path::new(path)).unwrap().id());
+        }
+    }
+
+    #[test]
+    fn index_flags_survive_absorb() {
+        let (ctx, file_path) = repo_utils::prepare_repo();
+        add_flagged_bystanders(&ctx);
+        repo_utils::stage_file_changes(&ctx, &file_path);
+
+        let drain = slog::Discard;
+        let logger = slog::Logger::root(drain, o!());
+        run_with_repo(&logger, &DEFAULT_CONFIG, &ctx.repo).unwrap();
+
+        assert_eq!(extract_commit_messages(&ctx.repo).len(), 4);
+        assert_bystanders_flagged(&ctx.repo);
+    }
+
+    #[test]
+    fn index_flags_survive_autostage() {
+        let (ctx, file_path) = repo_utils::prepare_repo();
+        add_flagged_bystanders(&ctx);
+        repo_utils::set_config_flag(&ctx.repo, config::AUTO_STAGE_IF_NOTHING_STAGED_CONFIG_NAME);
+        // the new file is left over, and unstaged again afterwards
+        let (_, unrelated) = autostage_common(&ctx, &file_path);
+
+        let drain = slog::Discard;
+        let logger = slog::Logger::root(drain, o!());
+        run_with_repo(&logger, &DEFAULT_CONFIG, &ctx.repo).unwrap();
+
+        assert_eq!(
+            extract_commit_messages(&ctx.repo),
+            vec![
+                "fixup! Initial commit.\n",
+                "Add bystanders",
+                "Initial commit."
+            ]
+        );
+        assert!(ctx.repo.index().unwrap().get_path(&unrelated, 0).is_none());
+        assert!(nothing_left_in_index(&ctx.repo).unwrap());
+        assert_bystanders_flagged(&ctx.repo);
+    }
+
+    #[test]
+    #[cfg(unix)]
+    fn non_utf8_path() {
+        use std::os::unix::ffi::OsStrExt;
+
+        let (ctx, _) = repo_utils::prepare_repo();
+        let name = b"caf\xe9.txt";
+        let path = Path::new(std::ffi::OsStr::from_bytes(name));
+        std::fs::write(ctx.join(path), "line\nline\n").unwrap();
+        let tree = repo_utils::add(&ctx.repo, path);
+        let head = ctx.repo.head().unwrap().peel_to_commit().unwrap();
+        repo_utils::commit(&ctx.repo, "HEAD", "Add latin-1 file", &tree, &[&head]);
+        std::fs::write(ctx.join(path), "line\nchanged line\n").unwrap();
+        repo_utils::add(&ctx.repo, path);
+
+        let capturing_logger = log_utils::CapturingLogger::new();
+        let config = Config {
+            // blames the file by its path
+            only_my_lines: true,
+            ..DEFAULT_CONFIG
+        };
+        run_with_repo(&capturing_logger.logger, &config, &ctx.repo).unwrap();
+        assert!(nothing_left_in_index(&ctx.repo).unwrap());
+
+        assert_eq!(
+            extract_commit_messages(&ctx.repo)[0],
+            "fixup! Add latin-1 file\n"
+        );
+        // the fixup changed the same entry, without renaming it
+        let head_tree = ctx.repo.head().unwrap().peel_to_tree().unwrap();
+        let names: Vec<_> = head_tree
+            .iter()
+            .map(|entry| entry.name_bytes().to_vec())
+            .collect();
+        assert_eq!(names, vec![name.to_vec(), b"test-file.txt".to_vec()]);
+        let blob = head_tree
+            .get_path(path)
+            .unwrap()
+            .to_object(&ctx.repo)
+            .unwrap()
+            .peel_to_blob()
+            .unwrap();
+        assert_eq!(blob.content(), b"line\nchanged line\n");
+    }
+
+    #[test]
+    fn fixup_for_subject_in_another_encoding() {
+        let (ctx, _) = repo_utils::prepare_repo();
+        let path = PathBuf::from("other.txt");
+        std::fs::write(ctx.join(&path), "line\n").unwrap();
+        let tree = repo_utils::add(&ctx.repo, &path);
+        let head = ctx.repo.head().unwrap().peel_to_commit().unwrap();
+        // git2 cannot set the encoding header, so add it to the raw commit.
+        // the subject would be valid UTF-8 if it were not latin-1
+        let signature = ctx.repo.signature().unwrap();
+        let buffer = ctx
+            .repo
+            .commit_create_buffer(&signature, &signature, "Caf\u{c3}\u{a9}", &tree, &[&head])
+            .unwrap();
+        let buffer = String::from_utf8(buffer.to_vec()).unwrap();
+        let (headers, _) = buffer.split_once("\n\n").unwrap();
+        let mut raw = format!("{}\nencoding ISO-8859-1\n\n", headers).into_bytes();
+        raw.extend_from_slice(b"Caf\xc3\xa9\n");
+        let oid = ctx
+            .repo
+            .odb()
+            .unwrap()
+            .write(git2::ObjectType::Commit, &raw)
+            .unwrap();
+        ctx.repo
+            .head()
+            .unwrap()
+            .set_target(oid, "encoded commit")
+            .unwrap();
+        let target = ctx.repo.find_commit(oid).unwrap();
+        assert_eq!(target.message_encoding(), Some("ISO-8859-1"));
+        assert_eq!(fixup_subject(&target), None);
+
+        std::fs::write(ctx.join(&path), "line\nnew line\n").unwrap();
+        repo_utils::add(&ctx.repo, &path);
+
+        let capturing_logger = log_utils::CapturingLogger::new();
+        let config = Config {
+            and_rebase: true,
+            ..DEFAULT_CONFIG
+        };
+        run_with_repo(&capturing_logger.logger, &config, &ctx.repo).unwrap();
+
+        // the fixup named the commit by SHA, so autosquash found it
+        let mut revwalk = ctx.repo.revwalk().unwrap();
+        revwalk.push_head().unwrap();
+        assert_eq!(revwalk.count(), 2);
+        let head_tree = ctx.repo.head().unwrap().peel_to_tree().unwrap();
+        let blob = head_tree
+            .get_path(&path)
+            .unwrap()
+            .to_object(&ctx.repo)
+            .unwrap()
+            .peel_to_blob()
+            .unwrap();
+        assert_eq!(blob.content(), b"line\nnew line\n");
+    }
+
+    #[test]
+    fn paths_quoted_for_display() {
+        for quote_path in [true, false] {
+            assert_eq!(display_path(b"src/lib.rs", quote_path), "src/lib.rs");
+            assert_eq!(display_path(b"caf\xe9.txt", quote_path), "\"caf\\351.txt\"");
+            assert_eq!(
+                display_path(b"a\"b\\c\nd", quote_path),
+                "\"a\\\"b\\\\c\\nd\""
+            );
+            assert_eq!(
+                display_path(b"new\nline.txt", quote_path),
+                "\"new\\nline.txt\""
+            );
+            assert_eq!(
+                display_path(b"\x1b[31mred\x07\r", quote_path),
+                "\"\\033[31mred\\a\\r\""
+            );
+        }
+        assert_eq!(
+            display_path("café.txt".as_bytes(), true),
+            "\"caf\\303\\251.txt\""
+        );
+        assert_eq!(display_path("café.txt".as_bytes(), false), "café.txt");
+        assert_eq!(
+            display_path("café\n.txt".as_bytes(), false),
+            "\"café\\n.txt\""
+        );
+        // no control characters get through, wherever they are from
+        assert_eq!(
+            display_path("a\u{85}b".as_bytes(), false),
+            "\"a\\302\\205b\""
+        );
+    }
+
+    #[test]
+    fn unabsorbed_paths_follow_quote_path() {
+        let (ctx, _) = repo_utils::prepare_repo();
+        for name in ["new\nline.txt", "café.txt"] {
+            std::fs::write(ctx.join(Path::new(name)), "new\n").unwrap();
+            repo_utils::add(&ctx.repo, Path::new(name));
+        }
+
+        for (quote_path, expected) in [
+            ("true", ["\"caf\\303\\251.txt\"", "\"new\\nline.txt\""]),
+            ("false", ["\"new\\nline.txt\"", "café.txt"]),
+        ] {
+            repo_utils::set_config_option(&ctx.repo, config::QUOTE_PATH_CONFIG_NAME, quote_path);
+            let mut capturing_logger = log_utils::CapturingLogger::new();
+            let config = Config {
+                dry_run: true,
+                ..DEFAULT_CONFIG
+            };
+            run_with_repo(&capturing_logger.logger, &config, &ctx.repo).unwrap();
+            let locations: Vec<_> = capturing_logger
+                .visible_logs()
+                .into_iter()
+                .filter(|log| log["msg"] == "Staged change could not be absorbed")
+                .map(|log| log["location"].as_str().unwrap().to_owned())
+                .collect();
+            assert_eq!(locations, expected);
+        }
+    }
+
+    #[test]
+    fn unstaged_flag() {
+        let (ctx, file_path) = repo_utils::prepare_repo();
+        let initial_commit = ctx.repo.head().unwrap().peel_to_commit().unwrap();
+
+        // a second commit that the unstaged change to its file can be absorbed into
+        let other_path = PathBuf::from("other-file.txt");
+        std::fs::write(ctx.join(&other_path), "line\n").unwrap();
+        let tree = repo_utils::add(&ctx.repo, &other_path);
+        repo_utils::commit(
+            &ctx.repo,
+            "HEAD",
+            "Add other file",
+            &tree,
+            &[&initial_commit],
+        );
+
+        // modify both files without staging anything.
+        // the initial commit is hidden by the base, so the change to
+        // its file cannot be absorbed
+        std::fs::write(ctx.join(&other_path), "changed line\n").unwrap();
+        let contents = std::fs::read_to_string(ctx.join(&file_path)).unwrap();
+        let test_file_contents = format!("new_line1\n{contents}");
+        std::fs::write(ctx.join(&file_path), &test_file_contents).unwrap();
+
+        // run 'git-absorb'
+        let mut capturing_logger = log_utils::CapturingLogger::new();
+        let base_id = initial_commit.id().to_string();
+        let config = Config {
+            unstaged: true,
+            base: &[&base_id],
+            ..DEFAULT_CONFIG
+        };
+        run_with_repo(&capturing_logger.logger, &config, &ctx.repo).unwrap();
+
+        assert_eq!(
+            extract_commit_messages(&ctx.repo),
+            vec![
+                "fixup! Add other file\n",
+                "Add other file",
+                "Initial commit.",
+            ]
+        );
+
+        // the absorbed change is in the fixup commit
+        let head_tree = ctx.repo.head().unwrap().peel_to_tree().unwrap();
+        let blob = head_tree
+            .get_path(&other_path)
+            .unwrap()
+            .to_object(&ctx.repo)
+            .unwrap()
+            .peel_to_blob()
+            .unwrap();
+        assert_eq!(blob.content(), b"changed line\n");
+
+        // nothing was left staged
+        assert!(nothing_left_in_index(&ctx.repo).unwrap());
+
+        // the change that could not be absorbed is still in the working tree, unstaged
+        let unstaged = ctx.repo.diff_index_to_workdir(None, None).unwrap();
+        let unstaged_paths: Vec<_> = unstaged
+            .deltas()
+            .map(|delta| delta.new_file().path().unwrap().to_path_buf())
+            .collect();
+        assert_eq!(unstaged_paths, vec![file_path.clone()]);
+        assert_eq!(
+            std::fs::read_to_string(ctx.join(&file_path)).unwrap(),
+            test_file_contents
+        );
+
+        log_utils::assert_log_messages_are(
+            capturing_logger.visible_logs(),
+            vec![
+                &json!({"level": "INFO", "msg": "stack base"}),
+                &json!({"level": "INFO", "msg": "committed"}),
+                &json!({
+                    "level": "WARN",
+                    "msg": "Some file modifications did not have an available commit to fix up. \
+                           You will have to manually create fixup commits.",
+                }),
+                &json!({
+                    "level": "WARN",
+                    "msg": "Will not fix up past specified base commit. \
+                           Consider using --base to specify a different base commit",
+                }),
+                &json!({
+                    "level": "WARN",
+                    "msg": "Staged change could not be absorbed",
+                    "location": "test-file.txt:1",
+                    "reason": "boundary",
+                }),
+                &json!({
+                    "level": "INFO",
+                    "msg": "To squash the new commits, rebase:",
+                }),
+            ],
+        );
+    }
+
+    #[test]
+    fn unstaged_flag_with_staged_changes() {
+        let ctx = repo_utils::prepare_and_stage();
+
+        // run 'git-absorb'
+        let capturing_logger = log_utils::CapturingLogger::new();
+        let config = Config {
+            unstaged: true,
+            ..DEFAULT_CONFIG
+        };
+        let result = run_with_repo(&capturing_logger.logger, &config, &ctx.repo);
+        assert_eq!(
+            result.err().unwrap().to_string(),
+            "--unstaged cannot be used while changes are staged"
+        );
+
+        let mut revwalk = ctx.repo.revwalk().unwrap();
+        revwalk.push_head().unwrap();
+        assert_eq!(revwalk.count(), 1);
+    }
+
+    /// Writes a patch of `changes` to the files in HEAD, without leaving
+    /// them changed, and returns where it is.
+    fn write_patch(ctx: &repo_utils::Context, changes: &[(&str, &str)]) -> PathBuf {
+        for (path, content) in changes {
+            std::fs::write(ctx.join(Path::new(path)), content).unwrap();
+        }
+        let head_tree = ctx.repo.head().unwrap().peel_to_tree().unwrap();
+        let mut patch = vec![];
+        ctx.repo
+            .diff_tree_to_workdir(Some(&head_tree), None)
+            .unwrap()
+            .print(git2::DiffFormat::Patch, |_, _, line| {
+                if matches!(line.origin(), '+' | '-' | ' ') {
+                    patch.push(line.origin() as u8);
+                }
+                patch.extend_from_slice(line.content());
+                true
+            })
+            .unwrap();
+        ctx.repo
+            .checkout_head(Some(git2::build::CheckoutBuilder::new().force()))
+            .unwrap();
+        let path = ctx.repo.path().join("suggestion.patch");
+        std::fs::write(&path, patch).unwrap();
+        path
+    }
+
+    /// A repository with a second commit that adds `other.txt`.
+    fn prepare_other_file() -> repo_utils::Context {
+        let (ctx, _) = repo_utils::prepare_repo();
+        std::fs::write(ctx.join(Path::new("other.txt")), "a\nb\nc\n").unwrap();
+        {
+            let tree = repo_utils::add(&ctx.repo, Path::new("other.txt"));
+            let head = ctx.repo.head().unwrap().peel_to_commit().unwrap();
+            repo_utils::commit(&ctx.repo, "HEAD", "Add other file", &tree, &[&head]);
+        }
+        ctx
+    }
+
+    #[test]
+    fn patch_file_with_two_files() {
+        let ctx = prepare_other_file();
+        let patch = write_patch(
+            &ctx,
+            &[
+                ("test-file.txt", "\nline\nline fixed\n\nmore\nlines\n"),
+                ("other.txt", "a\nb fixed\nc\n"),
+            ],
+        );
+
+        let mut capturing_logger = log_utils::CapturingLogger::new();
+        let config = Config {
+            patch_file: Some(&patch),
+            ..DEFAULT_CONFIG
+        };
+        run_with_repo(&capturing_logger.logger, &config, &ctx.repo).unwrap();
+
+        assert_eq!(
+            extract_commit_messages(&ctx.repo),
+            vec![
+                "fixup! Add other file\n",
+                "fixup! Initial commit.\n",
+                "Add other file",
+                "Initial commit.",
+            ]
+        );
+        // the absorbed changes are checked out, and nothing is left staged
+        assert!(nothing_left_in_index(&ctx.repo).unwrap());
+        assert!(nothing_left_in_workdir(&ctx.repo).unwrap());
+        assert_eq!(
+            std::fs::read_to_string(ctx.join(Path::new("other.txt"))).unwrap(),
+            "a\nb fixed\nc\n"
+        );
+        let committed = capturing_logger
+            .visible_logs()
+            .iter()
+            .filter(|log| log["msg"] == "committed")
+            .count();
+        assert_eq!(committed, 2);
+    }
+
+    #[test]
+    fn patch_file_that_does_not_match_head() {
+        let ctx = prepare_other_file();
+        let patch = write_patch(&ctx, &[("other.txt", "a\nb fixed\nc\n")]);
+        // the lines around the hunk change before it is absorbed
+        std::fs::write(ctx.join(Path::new("other.txt")), "a\nb\nc changed\n").unwrap();
+        let tree = repo_utils::add(&ctx.repo, Path::new("other.txt"));
+        let head = ctx.repo.head().unwrap().peel_to_commit().unwrap();
+        repo_utils::commit(&ctx.repo, "HEAD", "Change c", &tree, &[&head]);
+
+        let drain = slog::Discard;
+        let logger = slog::Logger::root(drain, o!());
+        let config = Config {
+            patch_file: Some(&patch),
+            ..DEFAULT_CONFIG
+        };
+        let err = run_with_repo(&logger, &config, &ctx.repo).unwrap_err();
+
+        assert!(
+            matches!(&err, AbsorbError::PatchFailed { path, line: 1 } if path == "other.txt"),
+            "{:?}",
+            err
+        );
+        assert_eq!(
+            err.to_string(),
+            "patch failed: other.txt:1, the lines around it do not match HEAD"
+        );
+        assert_eq!(extract_commit_messages(&ctx.repo).len(), 3);
+    }
+
+    #[test]
+    fn patch_file_with_one_fixup_per_commit() {
+        let (ctx, _) = repo_utils::prepare_repo();
+        let patch = write_patch(
+            &ctx,
+            &[(
+                "test-file.txt",
+                "new_line1\n\nline\nline\n\nmore\nlines\nnew_line2\n",
+            )],
+        );
+
+        let drain = slog::Discard;
+        let logger = slog::Logger::root(drain, o!());
+        let config = Config {
+            patch_file: Some(&patch),
+            one_fixup_per_commit: true,
+            ..DEFAULT_CONFIG
+        };
+        run_with_repo(&logger, &config, &ctx.repo).unwrap();
+
+        assert_eq!(
+            extract_commit_messages(&ctx.repo),
+            vec!["fixup! Initial commit.\n", "Initial commit."]
+        );
+        assert!(nothing_left_in_index(&ctx.repo).unwrap());
+        assert_eq!(
+            head_file_contents(&ctx.repo, Path::new("test-file.txt")),
+            "new_line1\n\nline\nline\n\nmore\nlines\nnew_line2\n"
+        );
+    }
+
+    /// Saves the changes in the index and the working tree as a stash entry.
+    fn stash(ctx: &repo_utils::Context) {
+        let mut repo = git2::Repository::open(ctx.repo.path()).unwrap();
+        let signature = repo.signature().unwrap();
+        repo.stash_save(&signature, "saved", None).unwrap();
+        // the stash was saved through another handle
+        ctx.repo.index().unwrap().read(true).unwrap();
+    }
+
+    #[test]
+    fn from_stash_flag() {
+        let (ctx, file_path) = repo_utils::prepare_repo();
+        repo_utils::stage_file_changes(&ctx, &file_path);
+        let stashed = std::fs::read_to_string(ctx.join(&file_path)).unwrap();
+        stash(&ctx);
+        // a newer stash entry, which is not the one to absorb
+        std::fs::write(ctx.join(&file_path), "something else\n").unwrap();
+        stash(&ctx);
+
+        // an unrelated staged change, which must stay staged
+        let other_path = PathBuf::from("other.txt");
+        std::fs::write(ctx.join(&other_path), "other\n").unwrap();
+        repo_utils::add(&ctx.repo, &other_path);
+
+        let capturing_logger = log_utils::CapturingLogger::new();
+        let config = Config {
+            from_stash: Some("stash@{1}"),
+            ..DEFAULT_CONFIG
+        };
+        run_with_repo(&capturing_logger.logger, &config, &ctx.repo).unwrap();
+
+        assert_eq!(
+            extract_commit_messages(&ctx.repo),
+            vec![
+                "fixup! Initial commit.\n",
+                "fixup! Initial commit.\n",
+                "Initial commit.",
+            ]
+        );
+        // the absorbed changes are in the working tree, and not staged again
+        assert_eq!(
+            std::fs::read_to_string(ctx.join(&file_path)).unwrap(),
+            stashed
+        );
+        assert!(ctx.repo.status_file(&file_path).unwrap().is_empty());
+        assert_eq!(
+            ctx.repo.status_file(&other_path).unwrap(),
+            git2::Status::INDEX_NEW
+        );
+        // the stash entries are left alone
+        let mut repo = git2::Repository::open(ctx.repo.path()).unwrap();
+        let mut entries = 0;
+        repo.stash_foreach(|_, _, _| {
+            entries += 1;
+            true
+        })
+        .unwrap();
+        assert_eq!(entries, 2);
+    }
+
+    #[test]
+    fn from_stash_flag_with_changes_to_the_same_file() {
+        let (ctx, file_path) = repo_utils::prepare_repo();
+        repo_utils::stage_file_changes(&ctx, &file_path);
+        stash(&ctx);
+        std::fs::write(ctx.join(&file_path), "something else\n").unwrap();
+
+        let capturing_logger = log_utils::CapturingLogger::new();
+        let config = Config {
+            from_stash: Some("stash@{0}"),
+            ..DEFAULT_CONFIG
+        };
+        let result = run_with_repo(&capturing_logger.logger, &config, &ctx.repo);
+        assert_eq!(
+            result.err().unwrap().to_string(),
+            "test-file.txt has changes of its own, which absorbing from the stash would overwrite"
+        );
+        assert_eq!(extract_commit_messages(&ctx.repo).len(), 1);
+    }
+
+    #[test]
+    fn from_stash_flag_with_stash_on_another_commit() {
+        let (ctx, file_path) = repo_utils::prepare_repo();
+        repo_utils::stage_file_changes(&ctx, &file_path);
+        stash(&ctx);
+        let base = ctx.repo.head().unwrap().peel_to_commit().unwrap();
+        let tree = base.tree().unwrap();
+        repo_utils::commit(&ctx.repo, "HEAD", "Another commit", &tree, &[&base]);
+
+        let capturing_logger = log_utils::CapturingLogger::new();
+        let config = Config {
+            from_stash: Some("stash@{0}"),
+            ..DEFAULT_CONFIG
+        };
+        let result = run_with_repo(&capturing_logger.logger, &config, &ctx.repo);
+        assert_eq!(
+            result.err().unwrap().to_string(),
+            format!(
+                "stash@{{0}} was saved on top of {}, not on top of HEAD",
+                base.id()
+            )
+        );
+
+        let result = run_with_repo(
+            &capturing_logger.logger,
+            &Config {
+                from_stash: Some("HEAD"),
+                ..DEFAULT_CONFIG
+            },
+            &ctx.repo,
+        );
+        assert_eq!(
+            result.err().unwrap().to_string(),
+            "HEAD is not a stash entry"
+        );
+    }
+
+    #[test]
+    fn commit_ids_shortened_to_core_abbrev() {
+        let ctx = repo_utils::prepare_and_stage();
+        let mut config = ctx.repo.config().unwrap();
+        config.set_i64(config::ABBREV_CONFIG_NAME, 12).unwrap();
+        config
+            .set_bool(config::FIXUP_TARGET_ALWAYS_SHA_CONFIG_NAME, true)
+            .unwrap();
+
+        let mut capturing_logger = log_utils::CapturingLogger::new();
+        let mut out = Vec::new();
+        absorb(
+            &capturing_logger.logger,
+            &Config {
+                stat: true,
+                ..DEFAULT_CONFIG
+            },
+            &ctx.repo,
+            &mut prompt::Terminal,
+            &mut out,
+            &mut stats::Stats::default(),
+            &mut Summary::default(),
+        )
+        .unwrap();
+
+        let target = ctx.repo.revparse_single("HEAD~2").unwrap().id().to_string();
+        assert_eq!(
+            extract_commit_messages(&ctx.repo)[0],
+            format!("fixup! {}\n", &target[..12])
+        );
+        let stat = String::from_utf8(out).unwrap();
+        assert!(stat.starts_with(&format!("{} Initial commit.\n", &target[..12])));
+        log_utils::assert_log_messages_are(
+            capturing_logger.visible_logs(),
+            vec![
+                &json!({
+                    "level": "INFO",
+                    "msg": "stack reaches the first commit in the repository",
+                }),
+                &json!({
+                    "level": "INFO",
+                    "msg": "committed",
+                    "target": &target[..12],
+                    "target_id": &target,
+                }),
+                &json!({
+                    "level": "INFO",
+                    "msg": "committed",
+                    "target": &target[..12],
+                    "target_id": &target,
+                }),
+                &json!({"level": "INFO", "msg": "To squash the new commits, rebase:"}),
+            ],
+        );
+    }
+
+    #[test]
+    fn commit_ids_in_full_when_core_abbrev_is_false() {
+        let ctx = repo_utils::prepare_and_stage();
+        ctx.repo
+            .config()
+            .unwrap()
+            .set_bool(config::ABBREV_CONFIG_NAME, false)
+            .unwrap();
+
+        let mut capturing_logger = log_utils::CapturingLogger::new();
+        let config = Config {
+            dry_run: true,
+            ..DEFAULT_CONFIG
+        };
+        run_with_repo(&capturing_logger.logger, &config, &ctx.repo).unwrap();
+
+        let target = ctx.repo.head().unwrap().peel_to_commit().unwrap().id();
+        log_utils::assert_log_messages_are(
+            capturing_logger.visible_logs(),
+            vec![
+                &json!({
+                    "level": "INFO",
+                    "msg": "stack reaches the first commit in the repository",
+                }),
+                &json!({
+                    "level": "INFO",
+                    "msg": "would have committed",
+                    "fixup": "Initial commit.",
+                    "target": target.to_string(),
+                }),
+                &json!({
+                    "level": "INFO",
+                    "msg": "would have committed",
+                    "fixup": "Initial commit.",
+                    "target": target.to_string(),
+                }),
+            ],
+        );
+    }
+
+    #[test]
+    fn core_abbrev_auto_grows_with_the_repository() {
+        let ctx = repo_utils::prepare_and_stage();
+        ctx.repo
+            .config()
+            .unwrap()
+            .set_str(config::ABBREV_CONFIG_NAME, "auto")
+            .unwrap();
+        assert_eq!(config::abbrev_length(&ctx.repo), 7);
+
+        assert_eq!(config::auto_abbrev_length(0), 7);
+        assert_eq!(config::auto_abbrev_length(1 << 14), 8);
+        assert_eq!(config::auto_abbrev_length(1 << 20), 11);
+        assert_eq!(config::auto_abbrev_length((1 << 20) - 1), 10);
+    }
+
+    #[test]
+    fn fixup_message_always_commit_sha_if_configured() {
+        let ctx = repo_utils::prepare_and_stage();
+
+        ctx.repo
+            .config()
+            .unwrap()
+            .set_bool(config::FIXUP_TARGET_ALWAYS_SHA_CONFIG_NAME, true)
+            .unwrap();
+
+        // run 'git-absorb'
+        let mut capturing_logger = log_utils::CapturingLogger::new();
+        run_with_repo(&capturing_logger.logger, &DEFAULT_CONFIG, &ctx.repo).unwrap();
+        assert!(nothing_left_in_index(&ctx.repo).unwrap());
+
+        let mut revwalk = ctx.repo.revwalk().unwrap();
+        revwalk.push_head().unwrap();
+
+        let oids: Vec<git2::Oid> = revwalk.by_ref().collect::<Result<Vec<_>, _>>().unwrap();
+        assert_eq!(oids.len(), 3);
+
+        let commit = ctx.repo.find_commit(oids[0]).unwrap();
+        let actual_msg = commit.summary().unwrap();
+        let expected_msg = format!("fixup! {}", oids.last().unwrap());
+        assert_eq!(actual_msg, expected_msg);
+
+        log_utils::assert_log_messages_are(
+            capturing_logger.visible_logs(),
+            vec![
+                &json!({
+                    "level": "INFO",
+                    "msg": "stack reaches the first commit in the repository",
+                }),
+                &json!({"level": "INFO", "msg": "committed"}),
+                &json!({"level": "INFO", "msg": "committed"}),
+                &json!({
+                    "level": "INFO",
+                    "msg": "To squash the new commits, rebase:",
+                    "command": "git rebase --interactive --autosquash --autostash --root",
+                }),
+            ],
+        );
+    }
+
+    /// Commits that each add a file, with the given subjects, and staged
+    /// changes to all of those files.
+    fn prepare_files_with_subjects(subjects: &[&str]) -> repo_utils::Context {
+        let (ctx, _) = repo_utils::prepare_repo();
+        for (idx, subject) in subjects.iter().enumerate() {
+            let path = PathBuf::from(format!("file{idx}.txt"));
+            std::fs::write(ctx.join(&path), "contents\n").unwrap();
+            let tree = repo_utils::add(&ctx.repo, &path);
+            let head = ctx.repo.head().unwrap().peel_to_commit().unwrap();
+            repo_utils::commit(&ctx.repo, "HEAD", subject, &tree, &[&head]);
+        }
+        for idx in 0..subjects.len() {
+            let path = PathBuf::from(format!("file{idx}.txt"));
+            std::fs::write(ctx.join(&path), "fixed contents\n").unwrap();
+            repo_utils::add(&ctx.repo, &path);
+        }
+        ctx
+    }
+
+    #[test]
+    fn fixup_message_commit_sha_for_ambiguous_subjects() {
+        let ctx = prepare_files_with_subjects(&["fix tests", "Add parser", "fix tests"]);
+        let head = ctx.repo.head().unwrap().peel_to_commit().unwrap();
+        let first = head.parent(0).unwrap().parent(0).unwrap();
+
+        let mut capturing_logger = log_utils::CapturingLogger::new();
+        run_with_repo(&capturing_logger.logger, &DEFAULT_CONFIG, &ctx.repo).unwrap();
+        assert!(nothing_left_in_index(&ctx.repo).unwrap());
+
+        assert_eq!(
+            extract_commit_messages(&ctx.repo)[..3],
+            [
+                format!("fixup! {}\n", head.id()),
+                "fixup! Add parser\n".to_owned(),
+                format!("fixup! {}\n", first.id()),
+            ]
+        );
+        log_utils::assert_log_messages_are(
+            capturing_logger.visible_logs(),
+            vec![
+                &json!({
+                    "level": "INFO",
+                    "msg": "stack reaches the first commit in the repository",
+                }),
+                &json!({
+                    "level": "INFO",
+                    "msg": "Another commit in the stack has the same subject, so the fixup names this one by SHA",
+                    "commit": short_id(&first, 7),
+                    "subject": "fix tests",
+                }),
+                &json!({"level": "INFO", "msg": "committed"}),
+                &json!({"level": "INFO", "msg": "committed", "subject": "Add parser"}),
+                &json!({
+                    "level": "INFO",
+                    "msg": "Another commit in the stack has the same subject, so the fixup names this one by SHA",
+                    "commit": short_id(&head, 7),
+                }),
+                &json!({"level": "INFO", "msg": "committed"}),
+                &json!({"level": "INFO", "msg": "To squash the new commits, rebase:"}),
+            ],
+        );
+    }
+
+    #[test]
+    fn fixup_message_ambiguous_after_autosquash_prefixes() {
+        let subjects = ["Add lexer", "fixup! Add lexer", "Add parser"];
+
+        // autosquash looks for both of the first two as "Add lexer"
+        let ctx = prepare_files_with_subjects(&subjects);
+        repo_utils::set_config_option(
+            &ctx.repo,
+            config::FIXUP_TARGET_ALWAYS_SHA_CONFIG_NAME,
+            "auto",
+        );
+        let drain = slog::Discard;
+        let logger = slog::Logger::root(drain, o!());
+        run_with_repo(&logger, &DEFAULT_CONFIG, &ctx.repo).unwrap();
+        let messages = extract_commit_messages(&ctx.repo);
+        assert_eq!(messages[0], "fixup! Add parser\n");
+        assert!(!messages[1].contains("Add lexer"));
+        assert!(!messages[2].contains("Add lexer"));
+
+        // with absorb.fixupTargetAlwaysSHA=false, only identical subjects count
+        let ctx = prepare_files_with_subjects(&subjects);
+        ctx.repo
+            .config()
+            .unwrap()
+            .set_bool(config::FIXUP_TARGET_ALWAYS_SHA_CONFIG_NAME, false)
+            .unwrap();
+        run_with_repo(&logger, &DEFAULT_CONFIG, &ctx.repo).unwrap();
+        assert_eq!(
+            extract_commit_messages(&ctx.repo)[..3],
+            [
+                "fixup! Add parser\n",
+                "fixup! fixup! Add lexer\n",
+                "fixup! Add lexer\n"
+            ]
+        );
+    }
+
+    #[test]
+    fn fixup_by_sha_squashes_into_the_right_commits() {
+        let ctx = prepare_files_with_subjects(&["fix tests", "Add parser", "fix tests"]);
+        // the flag wins over the config
+        ctx.repo
+            .config()
+            .unwrap()
+            .set_bool(config::FIXUP_TARGET_ALWAYS_SHA_CONFIG_NAME, false)
+            .unwrap();
+        let parser = ctx.repo.revparse_single("HEAD~1").unwrap().id();
+
+        let drain = slog::Discard;
+        let logger = slog::Logger::root(drain, o!());
+        let config = Config {
+            fixup_by_sha: true,
+            ..DEFAULT_CONFIG
+        };
+        run_with_repo(&logger, &config, &ctx.repo).unwrap();
+        assert!(nothing_left_in_index(&ctx.repo).unwrap());
+        assert_eq!(
+            extract_commit_messages(&ctx.repo)[1],
+            format!("fixup! {}\n", parser)
+        );
+
+        let status = std::process::Command::new("git")
+            .args([
+                "rebase",
+                "--quiet",
+                "--interactive",
+                "--autosquash",
+                "--root",
+            ])
+            .current_dir(ctx.dir.path())
+            .env("GIT_SEQUENCE_EDITOR", "true")
+            .status()
+            .unwrap();
+        assert!(status.success());
+        assert_eq!(
+            extract_commit_messages(&ctx.repo),
+            ["fix tests", "Add parser", "fix tests", "Initial commit."]
+        );
+        // each commit still only adds its own file, now with the fix
+        let mut revwalk = ctx.repo.revwalk().unwrap();
+        revwalk.push_head().unwrap();
+        let mut commits: Vec<_> = revwalk
+            .map(|oid| ctx.repo.find_commit(oid.unwrap()).unwrap())
+            .collect();
+        commits.reverse();
+        for (idx, commit) in commits[1..].iter().enumerate() {
+            let path = format!("file{idx}.txt");
+            let blob = commit.tree().unwrap().get_path(Path::new(&path)).unwrap();
+            let blob = ctx.repo.find_blob(blob.id()).unwrap();
+            assert_eq!(blob.content(), b"fixed contents\n");
+            let parent = commit.parent(0).unwrap().tree().unwrap();
+            assert!(parent.get_path(Path::new(&path)).is_err());
+        }
+    }
+
+    #[test]
+    fn settings_from_config_file() {
+        let ctx = repo_utils::prepare_and_stage();
+
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("absorb.config");
+        std::fs::write(&path, "[absorb]\n\tfixupTargetAlwaysSHA = true\n").unwrap();
+
+        let config = Config {
+            config_file: Some(&path),
+            ..DEFAULT_CONFIG
+        };
+        let drain = slog::Discard;
+        let logger = slog::Logger::root(drain, o!());
+        run_with_repo(&logger, &config, &ctx.repo).unwrap();
+        assert!(nothing_left_in_index(&ctx.repo).unwrap());
+
+        let mut revwalk = ctx.repo.revwalk().unwrap();
+        revwalk.push_head().unwrap();
+        let oids: Vec<git2::Oid> = revwalk.by_ref().collect::<Result<Vec<_>, _>>().unwrap();
+        let commit = ctx.repo.find_commit(oids[0]).unwrap();
+        assert_eq!(
+            commit.summary().unwrap(),
+            format!("fixup! {}", oids.last().unwrap())
+        );
+
+        // the file is layered over the repository config, not written into it
+        let repo_config = ctx
+            .repo
+            .config()
+            .unwrap()
+            .open_level(git2::ConfigLevel::Local)
+            .unwrap();
+        assert!(repo_config
+            .get_bool(config::FIXUP_TARGET_ALWAYS_SHA_CONFIG_NAME)
+            .is_err());
+    }
+
+    #[test]
+    fn missing_config_file_is_an_error() {
+        let ctx = repo_utils::prepare_and_stage();
+
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("missing.config");
+        let config = Config {
+            config_file: Some(&path),
+            ..DEFAULT_CONFIG
+        };
+        let drain = slog::Discard;
+        let logger = slog::Logger::root(drain, o!());
+        assert!(run_with_repo(&logger, &config, &ctx.repo).is_err());
+        assert!(!nothing_left_in_index(&ctx.repo).unwrap());
+    }
+
+    #[test]
+    fn timings_list_every_phase() {
+        let ctx = repo_utils::prepare_and_stage();
+        let mut stats = stats::Stats::default();
+        absorb(
+            &log_utils::CapturingLogger::new().logger,
+            &Config {
+                timings: true,
+                and_rebase: true,
+                dry_run: true,
+                ..DEFAULT_CONFIG
+            },
+            &ctx.repo,
+            &mut prompt::Terminal,
+            &mut Vec::new(),
+            &mut stats,
+            &mut Summary::default(),
+        )
+        .unwrap();
+        let mut timings = stats.timings.unwrap();
+        timings.finish();
+
+        let table = timings.table(&stats);
+        let rows: Vec<Vec<_>> = table
+            .lines()
+            .skip(1)
+            .map(|row| row.split_whitespace().collect())
+            .collect();
+        let phases: Vec<_> = rows.iter().map(|row| row[0]).collect();
+        assert_eq!(
+            phases,
+            ["options", "stack", "diffs", "matching", "fixups", "rebase", "total"],
+            "{}",
+            table
+        );
+        for row in &rows {
+            let ms: f64 = row[1].strip_suffix("ms").unwrap().parse().unwrap();
+            assert!(ms >= 0.0, "{}", table);
+        }
+        // the commits in the stack, the hunks matched and the fixups made
+        assert_eq!(rows[1].get(2), Some(&"1"), "{}", table);
+        assert_eq!(rows[3].get(2), Some(&"2"), "{}", table);
+        assert_eq!(rows[4].get(2), Some(&"2"), "{}", table);
+
+        let line: serde_json::Value =
+            serde_json::from_str(&stats.line(std::time::Duration::ZERO)).unwrap();
+        assert_eq!(line["timings"]["matching"]["count"], 2);
+        assert!(line["timings"]["rebase"]["ms"].is_u64());
+        assert!(line["timings"]["total_ms"].is_u64());
+    }
+
+    #[test]
+    fn timings_are_written_to_the_output() {
+        let ctx = repo_utils::prepare_and_stage();
+        let mut out = Vec::new();
+        absorb_with_stats(
+            &log_utils::CapturingLogger::new().logger,
+            &Config {
+                timings: true,
+                dry_run: true,
+                ..DEFAULT_CONFIG
+            },
+            &ctx.repo,
+            &mut out,
+        )
+        .unwrap();
+
+        let out = String::from_utf8(out).unwrap();
+        assert!(out.starts_with("phase "), "{}", out);
+        assert!(out.lines().last().unwrap().starts_with("total "), "{}", out);
+    }
+
+    #[test]
+    fn stats_line_appended_to_file() {
+        let ctx = repo_utils::prepare_and_stage();
+
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("stats.jsonl");
+        std::fs::write(&path, "{\"earlier\":\"run\"}\n").unwrap();
+        let config = Config {
+            stats_file: Some(&path),
+            ..DEFAULT_CONFIG
+        };
+        let drain = slog::Discard;
+        let logger = slog::Logger::root(drain, o!());
+        run_with_repo(&logger, &config, &ctx.repo).unwrap();
+
+        let contents = std::fs::read_to_string(&path).unwrap();
+        let lines: Vec<_> = contents.lines().collect();
+        assert_eq!(lines.len(), 2);
+        let mut stats: serde_json::Value = serde_json::from_str(lines[1]).unwrap();
+        assert!(stats["time"].as_u64().unwrap() > 0);
+        assert!(stats["elapsed_ms"].is_u64());
+        stats.as_object_mut().unwrap().remove("time");
+        stats.as_object_mut().unwrap().remove("elapsed_ms");
+        assert_eq!(
+            stats,
+            json!({
+                "hunks": 2,
+                "absorbed": 2,
+                "skipped": 0,
+                "fixups": 2,
+                "stack": 1,
+            })
+        );
+    }
+
+    #[test]
+    fn stats_count_skipped_hunks() {
+        let ctx = repo_utils::prepare_and_stage();
+        // a new file cannot be absorbed
+        std::fs::write(ctx.join(Path::new("new_file.txt")), "new\n").unwrap();
+        repo_utils::add(&ctx.repo, Path::new("new_file.txt"));
+
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("stats.jsonl");
+        let config = Config {
+            dry_run: true,
+            stats_file: Some(&path),
+            ..DEFAULT_CONFIG
+        };
+        let drain = slog::Discard;
+        let logger = slog::Logger::root(drain, o!());
+        run_with_repo(&logger, &config, &ctx.repo).unwrap();
+
+        let stats: serde_json::Value =
+            serde_json::from_str(&std::fs::read_to_string(&path).unwrap()).unwrap();
+        assert_eq!(stats["hunks"], 3);
+        assert_eq!(stats["absorbed"], 2);
+        assert_eq!(stats["skipped"], 1);
+        assert_eq!(stats["fixups"], 2);
+    }
+
+    #[test]
+    fn summary_lists_created_fixups() {
+        let ctx = repo_utils::prepare_and_stage();
+        std::fs::write(ctx.join(Path::new("new_file.txt")), "new\n").unwrap();
+        repo_utils::add(&ctx.repo, Path::new("new_file.txt"));
+        let target = ctx.repo.head().unwrap().target().unwrap();
+
+        let drain = slog::Discard;
+        let logger = slog::Logger::root(drain, o!());
+        let summary = run_with_repo(&logger, &DEFAULT_CONFIG, &ctx.repo).unwrap();
+
+        let head = ctx.repo.head().unwrap().peel_to_commit().unwrap();
+        let fixups: Vec<_> = summary.created_fixups.iter().map(|(id, _)| *id).collect();
+        assert_eq!(fixups, vec![head.parent_id(0).unwrap(), head.id()]);
+        for (fixup, fixes_up) in &summary.created_fixups {
+            let fixup = ctx.repo.find_commit(*fixup).unwrap();
+            assert_eq!(fixup.summary(), Some("fixup! Initial commit."));
+            assert_eq!(*fixes_up, target);
+        }
+        assert_eq!(summary.leftover_hunks, 1);
+
+        // nothing is created on a dry run
+        let config = Config {
+            dry_run: true,
+            ..DEFAULT_CONFIG
+        };
+        let summary = run_with_repo(&logger, &config, &ctx.repo).unwrap();
+        assert_eq!(summary.created_fixups, vec![]);
+        assert_eq!(summary.leftover_hunks, 1);
+    }
+
+    #[test]
+    fn absorbing_the_same_hunks_again_makes_no_duplicate_fixup() {
+        let (ctx, _) = repo_utils::prepare_repo();
+        let path = PathBuf::from("list.txt");
+        std::fs::write(ctx.join(&path), "one\n").unwrap();
+        let tree = repo_utils::add(&ctx.repo, &path);
+        let head = ctx.repo.head().unwrap().peel_to_commit().unwrap();
+        repo_utils::commit(&ctx.repo, "HEAD", "Add list", &tree, &[&head]);
+
+        // a script appending the same line each time it is run
+        let mut capturing_logger = log_utils::CapturingLogger::new();
+        for contents in ["one\ntwo\n", "one\ntwo\ntwo\n"] {
+            std::fs::write(ctx.join(&path), contents).unwrap();
+            repo_utils::add(&ctx.repo, &path);
+            run_with_repo(&capturing_logger.logger, &DEFAULT_CONFIG, &ctx.repo).unwrap();
+        }
+
+        let messages = extract_commit_messages(&ctx.repo);
+        assert_eq!(messages[..2], ["fixup! Add list\n", "Add list"]);
+        assert!(!nothing_left_in_index(&ctx.repo).unwrap());
+        let warnings: Vec<_> = capturing_logger
+            .visible_logs()
+            .into_iter()
+            .filter(|log| log["level"] == "WARN")
+            .map(|log| log["msg"].as_str().unwrap().to_owned())
+            .collect();
+        assert_eq!(
+            warnings,
+            ["HEAD is already a fixup with the same changes, leaving them staged instead of committing them again"]
+        );
+    }
+
+    /// Absorbs a change that myers and patience split into hunks
+    /// differently, returning the fixups made and the warnings logged.
+    fn absorb_with_diff_algorithm(
+        settings: &[(&str, &str)],
+        flag: Option<&str>,
+    ) -> (Vec<String>, Vec<String>) {
+        let (ctx, _) = repo_utils::prepare_repo();
+        for (name, value) in settings {
+            repo_utils::set_config_option(&ctx.repo, name, value);
+        }
+        let path = PathBuf::from("code.c");
+        let contents = [
+            ("Add code", "y();\n{\n}\nx();\ny();\n"),
+            ("Edit code", "y();\n{\nedited();\nx();\ny();\n"),
+        ];
+        for (message, contents) in contents {
+            std::fs::write(ctx.join(&path), contents).unwrap();
+            let tree = repo_utils::add(&ctx.repo, &path);
+            let head = ctx.repo.head().unwrap().peel_to_commit().unwrap();
+            repo_utils::commit(&ctx.repo, "HEAD", message, &tree, &[&head]);
+        }
+        std::fs::write(ctx.join(&path), "y();\n{\ny();\nedited();\n").unwrap();
+        repo_utils::add(&ctx.repo, &path);
+
+        let mut capturing_logger = log_utils::CapturingLogger::new();
+        let config = Config {
+            diff_algorithm: flag,
+            ..DEFAULT_CONFIG
+        };
+        run_with_repo(&capturing_logger.logger, &config, &ctx.repo).unwrap();
+
+        let mut fixups: Vec<_> = extract_commit_messages(&ctx.repo)
+            .into_iter()
+            .filter(|message| message.starts_with("fixup! "))
+            .collect();
+        fixups.sort();
+        let warnings = capturing_logger
+            .visible_logs()
+            .into_iter()
+            .filter(|log| log["level"] == "WARN")
+            .map(|log| log["msg"].as_str().unwrap().to_owned())
+            .collect();
+        (fixups, warnings)
+    }
+
+    #[test]
+    fn diff_algorithm_decides_the_hunks() {
+        let myers = vec![
+            "fixup! Add code\n".to_owned(),
+            "fixup! Edit code\n".to_owned(),
+        ];
+        let patience = vec!["fixup! Edit code\n".to_owned()];
+        let no_warnings: Vec<String> = vec![];
+
+        assert_eq!(
+            absorb_with_diff_algorithm(&[], None),
+            (myers.clone(), no_warnings.clone())
+        );
+        assert_eq!(
+            absorb_with_diff_algorithm(&[("diff.algorithm", "patience")], None),
+            (patience.clone(), no_warnings.clone())
+        );
+        // libgit2 has no histogram, the closest is patience
+        assert_eq!(
+            absorb_with_diff_algorithm(&[("diff.algorithm", "histogram")], None),
+            (patience.clone(), no_warnings.clone())
+        );
+        assert_eq!(
+            absorb_with_diff_algorithm(
+                &[
+                    ("diff.algorithm", "patience"),
+                    ("absorb.diffAlgorithm", "myers")
+                ],
+                None
+            ),
+            (myers.clone(), no_warnings.clone())
+        );
+        assert_eq!(
+            absorb_with_diff_algorithm(&[("absorb.diffAlgorithm", "myers")], Some("patience")),
+            (patience, no_warnings)
+        );
+        assert_eq!(
+            absorb_with_diff_algorithm(&[("diff.algorithm", "fancy")], None),
+            (
+                myers,
+                vec!["Unknown diff algorithm, using myers".to_owned()]
+            )
+        );
+    }
+
+    /// Commits each of `edits` to a copy of a ten-line file, then stages
+    /// `staged` and absorbs it with `context`, returning the fixups made.
+    #[test]
+    fn identical_additions_are_matched_by_where_they_are() {
+        let (ctx, _) = repo_utils::prepare_repo();
+        let path = PathBuf::from("lines.txt");
+        let mut lines: Vec<_> = (1..=10).map(|line| format!("line {line}\n")).collect();
+        let commit = |lines: &[String], message: &str| {
+            std::fs::write(ctx.join(&path), lines.concat()).unwrap();
+            let tree = repo_utils::add(&ctx.repo, &path);
+            let head = ctx.repo.head().unwrap().peel_to_commit().unwrap();
+            repo_utils::commit(&ctx.repo, "HEAD", message, &tree, &[&head]);
+        };
+        commit(&lines, "Add lines");
+        lines.insert(8, "x();\n".to_owned());
+        commit(&lines, "Call x at the bottom");
+        lines.insert(2, "x();\n".to_owned());
+        commit(&lines, "Call x at the top");
+
+        // the same line again, right below each of the calls
+        lines.insert(10, "x();\n".to_owned());
+        lines.insert(3, "x();\n".to_owned());
+        std::fs::write(ctx.join(&path), lines.concat()).unwrap();
+        repo_utils::add(&ctx.repo, &path);
+        let drain = slog::Discard;
+        let logger = slog::Logger::root(drain, o!());
+        run_with_repo(&logger, &DEFAULT_CONFIG, &ctx.repo).unwrap();
+
+        assert!(nothing_left_in_index(&ctx.repo).unwrap());
+        assert_eq!(
+            extract_commit_messages(&ctx.repo)[..2],
+            [
+                "fixup! Call x at the top\n",
+                "fixup! Call x at the bottom\n"
+            ]
+        );
+    }
+
+    fn absorb_with_context(
+        edits: &[usize],
+        staged: usize,
+        base: &[&str],
+        context: Option<usize>,
+    ) -> Vec<String> {
+        let (ctx, _) = repo_utils::prepare_repo();
+        let path = PathBuf::from("lines.txt");
+        let mut lines: Vec<_> = (1..=10).map(|line| format!("line {line}\n")).collect();
+        let commit = |lines: &[String], message: &str| {
+            std::fs::write(ctx.join(&path), lines.concat()).unwrap();
+            let tree = repo_utils::add(&ctx.repo, &path);
+            let head = ctx.repo.head().unwrap().peel_to_commit().unwrap();
+            repo_utils::commit(&ctx.repo, "HEAD", message, &tree, &[&head]);
+        };
+        commit(&lines, "Add lines");
+        for &line in edits {
+            lines[line - 1] = format!("line {line} edited\n");
+            commit(&lines, &format!("Edit line {line}"));
+        }
+        lines[staged - 1] = format!("line {staged} edited again\n");
+        std::fs::write(ctx.join(&path), lines.concat()).unwrap();
+        repo_utils::add(&ctx.repo, &path);
+
+        let config = Config {
+            base,
+            diff_context: context,
+            ..DEFAULT_CONFIG
+        };
+        run_with_repo(
+            &log_utils::CapturingLogger::new().logger,
+            &config,
+            &ctx.repo,
+        )
+        .unwrap();
+        extract_commit_messages(&ctx.repo)
+            .into_iter()
+            .filter(|message| message.starts_with("fixup! "))
+            .collect()
+    }
+
+    #[test]
+    fn larger_context_keeps_nearby_hunks_together() {
+        // two lines away from the only change in the stack
+        assert!(absorb_with_context(&[3], 6, &["HEAD~1"], None).is_empty());
+        assert_eq!(
+            absorb_with_context(&[3], 6, &["HEAD~1"], Some(3)),
+            ["fixup! Edit line 3\n"]
+        );
+    }
+
+    #[test]
+    fn smaller_context_lets_nearby_hunks_be_reordered() {
+        // the newer edit is only two lines away from the older one
+        assert_eq!(
+            absorb_with_context(&[3, 6], 3, &[], Some(0)),
+            ["fixup! Edit line 3\n"]
+        );
+        assert_eq!(
+            absorb_with_context(&[3, 6], 3, &[], Some(2)),
+            ["fixup! Edit line 6\n"]
+        );
+    }
+
+    #[test]
+    fn zero_context_splits_hunks_into_readable_fixups() {
+        let (ctx, _) = repo_utils::prepare_repo();
+        let path = PathBuf::from("lines.txt");
+        let mut lines: Vec<_> = (1..=10).map(|line| format!("line {line}\n")).collect();
+        let commit = |lines: &[String], message: &str| {
+            std::fs::write(ctx.join(&path), lines.concat()).unwrap();
+            let tree = repo_utils::add(&ctx.repo, &path);
+            let head = ctx.repo.head().unwrap().peel_to_commit().unwrap();
+            repo_utils::commit(&ctx.repo, "HEAD", message, &tree, &[&head]);
+        };
+        commit(&lines, "Add lines");
+        lines[2] = "line 3 edited\n".to_owned();
+        commit(&lines, "Edit line 3");
+        lines[6] = "line 7 edited\n".to_owned();
+        commit(&lines, "Edit line 7");
+        // a single hunk for `git diff`, with one unchanged line in it
+        lines[3] = "line 4 edited\n".to_owned();
+        lines[5] = "line 6 edited\n".to_owned();
+        std::fs::write(ctx.join(&path), lines.concat()).unwrap();
+        repo_utils::add(&ctx.repo, &path);
+
+        let config = Config {
+            diff_context: Some(0),
+            ..DEFAULT_CONFIG
+        };
+        run_with_repo(
+            &log_utils::CapturingLogger::new().logger,
+            &config,
+            &ctx.repo,
+        )
+        .unwrap();
+        assert!(nothing_left_in_index(&ctx.repo).unwrap());
+        assert_eq!(
+            extract_commit_messages(&ctx.repo)[..2],
+            ["fixup! Edit line 7\n", "fixup! Edit line 3\n"]
+        );
+
+        // each fixup is still shown with the usual context around it
+        let head = ctx.repo.head().unwrap().peel_to_commit().unwrap();
+        let mut shown = vec![];
+        for fixup in [head.parent(0).unwrap(), head] {
+            let mut patch = vec![];
+            ctx.repo
+                .diff_tree_to_tree(
+                    Some(&fixup.parent(0).unwrap().tree().unwrap()),
+                    Some(&fixup.tree().unwrap()),
+                    None,
+                )
+                .unwrap()
+                .print(git2::DiffFormat::Patch, |_, _, line| {
+                    if matches!(line.origin(), '+' | '-' | ' ') {
+                        patch.push(line.origin() as u8);
+                        patch.extend_from_slice(line.content());
+                    }
+                    true
+                })
+                .unwrap();
+            shown.push(String::from_utf8(patch).unwrap());
+        }
+        assert_eq!(
+            shown,
+            [
+                " line 1\n line 2\n line 3 edited\n-line 4\n+line 4 edited\n line 5\n line 6\n line 7 edited\n",
+                " line 3 edited\n line 4 edited\n line 5\n-line 6\n+line 6 edited\n line 7 edited\n line 8\n line 9\n",
+            ]
+        );
+    }
+
+    #[test]
+    fn git_c_options_from_the_environment() {
+        let (ctx, _) = repo_utils::prepare_repo();
+        repo_utils::set_config_option(&ctx.repo, config::MAX_STACK_CONFIG_NAME, "20");
+        repo_utils::set_config_option(&ctx.repo, config::ABBREV_CONFIG_NAME, "9");
+
+        // what `git -c` passes down: the counted pairs come first, so the
+        // quoted parameters win where both set the same key
+        let environment = std::collections::HashMap::from([
+            ("GIT_CONFIG_COUNT", "2"),
+            ("GIT_CONFIG_KEY_0", "Absorb.MaxStack"),
+            ("GIT_CONFIG_VALUE_0", "50"),
+            ("GIT_CONFIG_KEY_1", "absorb.maxSubjectLength"),
+            ("GIT_CONFIG_VALUE_1", "60"),
+            (
+                "GIT_CONFIG_PARAMETERS",
+                r"'absorb.maxStack'='100' 'core.abbrev=12' 'absorb.fixupTargetAlwaysSHA' 'absorb.diffAlgorithm'='it'\''s'",
+            ),
+        ]);
+        let parameters =
+            config::parameters(|name| environment.get(name).map(|value| value.to_string()));
+        assert_eq!(
+            parameters,
+            [
+                ("Absorb.MaxStack".to_owned(), Some("50".to_owned())),
+                ("absorb.maxSubjectLength".to_owned(), Some("60".to_owned())),
+                ("absorb.maxStack".to_owned(), Some("100".to_owned())),
+                ("core.abbrev".to_owned(), Some("12".to_owned())),
+                ("absorb.fixupTargetAlwaysSHA".to_owned(), None),
+                ("absorb.diffAlgorithm".to_owned(), Some("it's".to_owned())),
+            ]
+        );
+
+        let get_i64 = |parameters: &[(String, Option<String>)], name| {
+            config::layered(
+                &ctx.repo,
+                parameters,
+                name,
+                git2::Config::get_i64,
+                |value| git2::Config::parse_i64(value?).ok(),
+            )
+        };
+        assert_eq!(
+            get_i64(&parameters, config::MAX_STACK_CONFIG_NAME),
+            Some(100)
+        );
+        assert_eq!(get_i64(&parameters, config::ABBREV_CONFIG_NAME), Some(12));
+        assert_eq!(get_i64(&[], config::ABBREV_CONFIG_NAME), Some(9));
+        assert_eq!(
+            config::layered(
+                &ctx.repo,
+                &parameters,
+                config::FIXUP_TARGET_ALWAYS_SHA_CONFIG_NAME,
+                git2::Config::get_bool,
+                |value| match value {
+                    None => Some(true),
+                    Some(value) => git2::Config::parse_bool(value).ok(),
+                },
+            ),
+            Some(true)
+        );
+
+        // but --config-file still wins over them
+        let file = ctx.join(Path::new("absorb.config"));
+        std::fs::write(&file, "[absorb]\n\tmaxStack = 5\n").unwrap();
+        config::add_file(&ctx.repo, &file).unwrap();
+        assert_eq!(get_i64(&parameters, config::MAX_STACK_CONFIG_NAME), Some(5));
+        assert_eq!(get_i64(&parameters, config::ABBREV_CONFIG_NAME), Some(12));
+    }
+
+    #[test]
+    fn diff_context_from_config() {
+        let (ctx, _) = repo_utils::prepare_repo();
+        repo_utils::set_config_option(&ctx.repo, config::DIFF_CONTEXT_CONFIG_NAME, "2");
+        assert_eq!(config::diff_context(&ctx.repo).unwrap(), Some(2));
+        repo_utils::set_config_option(&ctx.repo, config::DIFF_CONTEXT_CONFIG_NAME, "-1");
+        assert!(config::diff_context(&ctx.repo).is_err());
+        std::fs::write(ctx.join(Path::new("test-file.txt")), "changed\n").unwrap();
+        repo_utils::add(&ctx.repo, Path::new("test-file.txt"));
+        assert!(run_with_repo(
+            &log_utils::CapturingLogger::new().logger,
+            &DEFAULT_CONFIG,
+            &ctx.repo
+        )
+        .is_err());
+    }
+
+    #[test]
+    fn many_files_with_many_hunks() {
+        let (ctx, _) = repo_utils::prepare_repo();
+        let paths: Vec<_> = (0..100)
+            .map(|idx| PathBuf::from(format!("file{idx}.txt")))
+            .collect();
+        let mut lines: Vec<_> = (0..200).map(|idx| format!("line {idx}\n")).collect();
+        let commit_all = |lines: &[String], message: &str| {
+            for path in &paths {
+                std::fs::write(ctx.join(path), lines.concat()).unwrap();
+                repo_utils::add(&ctx.repo, path);
+            }
+            let tree = ctx
+                .repo
+                .find_tree(ctx.repo.index().unwrap().write_tree().unwrap());
+            let head = ctx.repo.head().unwrap().peel_to_commit().unwrap();
+            repo_utils::commit(&ctx.repo, "HEAD", message, &tree.unwrap(), &[&head]);
+        };
+        commit_all(&lines, "Add the files");
+        for idx in (0..200).step_by(10) {
+            lines[idx] = format!("line {idx} edited\n");
+        }
+        commit_all(&lines, "Edit every tenth line");
+        for idx in (5..200).step_by(10) {
+            lines[idx] = format!("line {idx} edited\n");
+        }
+        commit_all(&lines, "Edit the lines between");
+
+        // one line from each of the commits, in every file
+        lines[100] = "line 100 edited again\n".to_owned();
+        lines[105] = "line 105 edited again\n".to_owned();
+        lines[108] = "line 108 edited\n".to_owned();
+        for path in &paths {
+            std::fs::write(ctx.join(path), lines.concat()).unwrap();
+            repo_utils::add(&ctx.repo, path);
+        }
+
+        let capturing_logger = log_utils::CapturingLogger::new();
+        let config = Config {
+            one_fixup_per_commit: true,
+            ..DEFAULT_CONFIG
+        };
+        run_with_repo(&capturing_logger.logger, &config, &ctx.repo).unwrap();
+
+        let mut fixups = extract_commit_messages(&ctx.repo)[..3].to_vec();
+        fixups.sort();
+        assert_eq!(
+            fixups,
+            [
+                "fixup! Add the files\n",
+                "fixup! Edit every tenth line\n",
+                "fixup! Edit the lines between\n"
+            ]
+        );
+        for path in &paths {
+            assert_eq!(head_file_contents(&ctx.repo, path), lines.concat());
+        }
+        assert!(nothing_left_in_index(&ctx.repo).unwrap());
+    }
+
+    #[test]
+    fn large_files_are_not_copied_whole() {
+        let (ctx, _) = repo_utils::prepare_repo();
+        let path = PathBuf::from("generated.txt");
+        let mut lines: Vec<_> = (0..200_000).map(|idx| format!("line {idx}\n")).collect();
+        std::fs::write(ctx.join(&path), lines.concat()).unwrap();
+        let tree = repo_utils::add(&ctx.repo, &path);
+        let head = ctx.repo.head().unwrap().peel_to_commit().unwrap();
+        let base = repo_utils::commit(&ctx.repo, "HEAD", "Generate", &tree, &[&head]);
+        lines[100_000] = "line 100000 regenerated\n".to_owned();
+        std::fs::write(ctx.join(&path), lines.concat()).unwrap();
+        let tree = repo_utils::add(&ctx.repo, &path);
+        repo_utils::commit(&ctx.repo, "HEAD", "Regenerate", &tree, &[&base]);
+        lines[100_001] = "line 100001 regenerated\n".to_owned();
+        std::fs::write(ctx.join(&path), lines.concat()).unwrap();
+        repo_utils::add(&ctx.repo, &path);
+
+        let mut capturing_logger = log_utils::CapturingLogger::new();
+        let base = base.id().to_string();
+        let config = Config {
+            base: &[&base],
+            ..DEFAULT_CONFIG
+        };
+        run_with_repo(&capturing_logger.logger, &config, &ctx.repo).unwrap();
+
+        assert_eq!(
+            extract_commit_messages(&ctx.repo),
+            vec![
+                "fixup! Regenerate\n",
+                "Regenerate",
+                "Generate",
+                "Initial commit."
+            ]
+        );
+        assert_eq!(head_file_contents(&ctx.repo, &path), lines.concat());
+        // only the line the commit changed, on both sides
+        let copied: Vec<_> = capturing_logger
+            .logs()
+            .into_iter()
+            .filter(|log| log["msg"] == "parsed commit diff")
+            .map(|log| log["bytes"].as_u64().unwrap())
+            .collect();
+        assert_eq!(
+            copied,
+            vec![("line 100000\n".len() + "line 100000 regenerated\n".len()) as u64]
+        );
+    }
+
+    #[test]
+    fn fixup_message_option_left_out_sets_only_summary() {
+        let ctx = repo_utils::prepare_and_stage();
+
+        // run 'git-absorb'
+        let drain = slog::Discard;
+        let logger = slog::Logger::root(drain, o!());
+        run_with_repo(&logger, &DEFAULT_CONFIG, &ctx.repo).unwrap();
+        assert!(nothing_left_in_index(&ctx.repo).unwrap());
+
+        let mut revwalk = ctx.repo.revwalk().unwrap();
+        revwalk.push_head().unwrap();
+
+        let oids: Vec<git2::Oid> = revwalk.by_ref().collect::<Result<Vec<_>, _>>().unwrap();
+        assert_eq!(oids.len(), 3);
+
+        let fixup_commit = ctx.repo.find_commit(oids[0]).unwrap();
+        let fixed_up_commit = ctx.repo.find_commit(*oids.last().unwrap()).unwrap();
+        let actual_msg = fixup_commit.message().unwrap();
+        let expected_msg = fixed_up_commit.message().unwrap();
+        let expected_msg = format!("fixup! {}\n", expected_msg);
+        assert_eq!(actual_msg, expected_msg);
+    }
+
+    #[test]
+    fn fixup_message_option_provided_sets_message() {
+        let ctx = repo_utils::prepare_and_stage();
+
+        // run 'git-absorb'
+        let drain = slog::Discard;
+        let logger = slog::Logger::root(drain, o!());
+        let fixup_message_body = "git-absorb is my favorite git tool!";
+        let config = Config {
+            message: Some(fixup_message_body),
+            ..DEFAULT_CONFIG
+        };
+        run_with_repo(&logger, &config, &ctx.repo).unwrap();
+        assert!(nothing_left_in_index(&ctx.repo).unwrap());
+
+        let mut revwalk = ctx.repo.revwalk().unwrap();
+        revwalk.push_head().unwrap();
+
+        let oids: Vec<git2::Oid> = revwalk.by_ref().collect::<Result<Vec<_>, _>>().unwrap();
+        assert_eq!(oids.len(), 3);
+
+        let fixup_commit = ctx.repo.find_commit(oids[0]).unwrap();
+        let fixed_up_commit = ctx.repo.find_commit(*oids.last().unwrap()).unwrap();
+        let actual_msg = fixup_commit.message().unwrap();
+        let expected_msg = fixed_up_commit.message().unwrap();
+        let expected_msg = format!("fixup! {}\n\n{}\n", expected_msg, fixup_message_body);
+        assert_eq!(actual_msg, expected_msg);
+    }
+
+    fn prepare_repo_with_subjects() -> repo_utils::Context {
+        let (ctx, file_path) = repo_utils::prepare_repo();
+        {
+            let head = ctx.repo.head().unwrap().peel_to_commit().unwrap();
+            let parser = repo_utils::empty_commit(&ctx.repo, "HEAD", "Add parser", &[&head]);
+            repo_utils::empty_commit(&ctx.repo, "HEAD", "Add lexer", &[&parser]);
+        }
+        repo_utils::stage_file_changes(&ctx, &file_path);
+        ctx
+    }
+
+    #[test]
+    fn into_relative_revision() {
+        let ctx = prepare_repo_with_subjects();
+        {
+            let head = ctx.repo.head().unwrap().peel_to_commit().unwrap();
+            repo_utils::empty_commit(&ctx.repo, "HEAD", "Add docs", &[&head]);
+        }
+
+        let drain = slog::Discard;
+        let logger = slog::Logger::root(drain, o!());
+        let config = Config {
+            into: Some("HEAD~2"),
+            ..DEFAULT_CONFIG
+        };
+        run_with_repo(&logger, &config, &ctx.repo).unwrap();
+        assert!(nothing_left_in_index(&ctx.repo).unwrap());
+
+        let messages = extract_commit_messages(&ctx.repo);
+        assert_eq!(messages.len(), 6);
+        assert!(messages[0].starts_with("fixup! Add parser"));
+        assert!(messages[1].starts_with("fixup! Add parser"));
+        assert_eq!(messages[4], "Add parser");
+    }
+
+    #[test]
+    fn into_revision_outside_the_stack() {
+        let ctx = prepare_repo_with_subjects();
+
+        let drain = slog::Discard;
+        let logger = slog::Logger::root(drain, o!());
+        let config = Config {
+            into: Some("HEAD~1"),
+            base: &["HEAD~1"],
+            ..DEFAULT_CONFIG
+        };
+        let err = run_with_repo(&logger, &config, &ctx.repo).unwrap_err();
+        assert!(
+            err.to_string().starts_with("HEAD~1 (")
+                && err
+                    .to_string()
+                    .ends_with("is not in the stack of commits to absorb into"),
+            "{}",
+            err
+        );
+        assert!(!nothing_left_in_index(&ctx.repo).unwrap());
+    }
+
+    #[test]
+    fn into_unique_subject_substring() {
+        let ctx = prepare_repo_with_subjects();
+
+        let drain = slog::Discard;
+        let logger = slog::Logger::root(drain, o!());
+        let config = Config {
+            into: Some("parser"),
+            ..DEFAULT_CONFIG
+        };
+        run_with_repo(&logger, &config, &ctx.repo).unwrap();
+        assert!(nothing_left_in_index(&ctx.repo).unwrap());
+
+        let messages = extract_commit_messages(&ctx.repo);
+        assert_eq!(messages.len(), 5);
+        assert!(messages[0].starts_with("fixup! Add parser"));
+        assert!(messages[1].starts_with("fixup! Add parser"));
+        assert_eq!(messages[2], "Add lexer");
+    }
+
+    #[test]
+    fn into_ambiguous_subject_substring() {
+        let ctx = prepare_repo_with_subjects();
+
+        let drain = slog::Discard;
+        let logger = slog::Logger::root(drain, o!());
+        let config = Config {
+            into: Some("Add"),
+            ..DEFAULT_CONFIG
+        };
+        let result = run_with_repo(&logger, &config, &ctx.repo);
+        let message = result.unwrap_err().to_string();
+        assert!(
+            message.starts_with("2 commits in the stack have a subject containing \"Add\""),
+            "unexpected error: {}",
+            message
+        );
+        assert_eq!(extract_commit_messages(&ctx.repo).len(), 3);
+    }
+
+    #[test]
+    fn into_unmatched_subject_substring() {
+        let ctx = prepare_repo_with_subjects();
+
+        let drain = slog::Discard;
+        let logger = slog::Logger::root(drain, o!());
+        let config = Config {
+            into: Some("formatter"),
+            ..DEFAULT_CONFIG
+        };
+        let result = run_with_repo(&logger, &config, &ctx.repo);
+        assert_eq!(
+            result.unwrap_err().to_string(),
+            "no commit in the stack has a subject containing \"formatter\""
+        );
+    }
+
+    fn run_with_answers(ctx: &repo_utils::Context, answers: &str) -> String {
+        let drain = slog::Discard;
+        let logger = slog::Logger::root(drain, o!());
+        let config = Config {
+            select: true,
+            ..DEFAULT_CONFIG
+        };
+        let mut output = Vec::new();
+        let mut prompt = prompt::Interactive::new(answers.as_bytes(), &mut output);
+        absorb(
+            &logger,
+            &config,
+            &ctx.repo,
+            &mut prompt,
+            &mut std::io::sink(),
+            &mut stats::Stats::default(),
+            &mut Summary::default(),
+        )
+        .unwrap();
+        String::from_utf8(output).unwrap()
+    }
+
+    #[test]
+    fn select_with_yes_does_not_prompt() {
+        let ctx = repo_utils::prepare_and_stage();
+
+        let drain = slog::Discard;
+        let logger = slog::Logger::root(drain, o!());
+        let config = Config {
+            select: true,
+            yes: true,
+            ..DEFAULT_CONFIG
+        };
+        // nobody is there to answer, which would otherwise mean quitting
+        let mut output = Vec::new();
+        let mut prompt = prompt::Interactive::new("".as_bytes(), &mut output);
+        absorb(
+            &logger,
+            &config,
+            &ctx.repo,
+            &mut prompt,
+            &mut std::io::sink(),
+            &mut stats::Stats::default(),
+            &mut Summary::default(),
+        )
+        .unwrap();
+
+        assert!(output.is_empty());
+        assert!(nothing_left_in_index(&ctx.repo).unwrap());
+        assert_eq!(extract_commit_messages(&ctx.repo).len(), 3);
+    }
+
+    #[test]
+    fn select_some_hunks() {
+        let ctx = repo_utils::prepare_and_stage();
+
+        let output = run_with_answers(&ctx, "n\ny\n");
+        assert!(output.contains("+new_line1\n"));
+        assert!(output.contains("+new_line2\n"));
+
+        // only the second hunk was absorbed, the first one is still staged
+        assert_eq!(extract_commit_messages(&ctx.repo).len(), 2);
+        let stats = index_stats(&ctx.repo).unwrap();
+        assert_eq!(stats.insertions(), 1);
+        assert_eq!(stats.deletions(), 0);
+
+        let contents = head_file_contents(&ctx.repo, Path::new("test-file.txt"));
+        assert!(!contents.contains("new_line1"));
+        assert!(contents.ends_with("new_line2"));
+    }
+
+    #[test]
+    fn select_all_hunks() {
+        let ctx = repo_utils::prepare_and_stage();
+
+        run_with_answers(&ctx, "a\n");
+        assert_eq!(extract_commit_messages(&ctx.repo).len(), 3);
+        assert!(nothing_left_in_index(&ctx.repo).unwrap());
+    }
+
+    #[test]
+    fn select_quit() {
+        let ctx = repo_utils::prepare_and_stage();
+        let staged = index_stats(&ctx.repo).unwrap().insertions();
+
+        run_with_answers(&ctx, "q\n");
+        assert_eq!(extract_commit_messages(&ctx.repo).len(), 1);
+        let stats = index_stats(&ctx.repo).unwrap();
+        assert_eq!(stats.insertions(), staged);
+    }
+
+    #[test]
+    fn check_flag_with_everything_absorbed() {
+        let ctx = repo_utils::prepare_and_stage();
+
+        let mut capturing_logger = log_utils::CapturingLogger::new();
+        let config = Config {
+            check: true,
+            ..DEFAULT_CONFIG
+        };
+        run_with_repo(&capturing_logger.logger, &config, &ctx.repo).unwrap();
+
+        assert_eq!(extract_commit_messages(&ctx.repo).len(), 1);
+        assert!(!nothing_left_in_index(&ctx.repo).unwrap());
+        assert!(capturing_logger
+            .visible_logs()
+            .iter()
+            .all(|log| log["msg"] != "Staged change could not be absorbed"));
+    }
+
+    #[test]
+    fn check_flag_with_some_changes_not_absorbed() {
+        let (ctx, file_path) = repo_utils::prepare_repo();
+        let other_file_path = PathBuf::from("other-file.txt");
+        std::fs::write(ctx.join(&other_file_path), "line\n").unwrap();
+        let tree = repo_utils::add(&ctx.repo, &other_file_path);
+        let head = ctx.repo.head().unwrap().peel_to_commit().unwrap();
+        repo_utils::commit(&ctx.repo, "HEAD", "Add other file", &tree, &[&head]);
+        repo_utils::stage_file_changes(&ctx, &other_file_path);
+        repo_utils::stage_file_changes(&ctx, &file_path);
+
+        let mut capturing_logger = log_utils::CapturingLogger::new();
+        let config = Config {
+            check: true,
+            base: &["HEAD~1"],
+            ..DEFAULT_CONFIG
+        };
+        let result = run_with_repo(&capturing_logger.logger, &config, &ctx.repo);
+
+        let err = result.unwrap_err();
+        assert!(
+            matches!(err, AbsorbError::CheckFailed { unabsorbed: 2 }),
+            "{:?}",
+            err
+        );
+        assert_eq!(extract_commit_messages(&ctx.repo).len(), 2);
+
+        let locations: Vec<_> = capturing_logger
+            .visible_logs()
+            .into_iter()
+            .filter(|log| log["msg"] == "Staged change could not be absorbed")
+            .map(|log| log["location"].as_str().unwrap().to_owned())
+            .collect();
+        assert_eq!(locations, vec!["test-file.txt:1", "test-file.txt:8-9"]);
+    }
+
+    #[test]
+    fn all_or_nothing_with_some_changes_not_absorbed() {
+        let (ctx, file_path) = repo_utils::prepare_repo();
+        let other_file_path = PathBuf::from("other-file.txt");
+        std::fs::write(ctx.join(&other_file_path), "line\n").unwrap();
+        let tree = repo_utils::add(&ctx.repo, &other_file_path);
+        let head = ctx.repo.head().unwrap().peel_to_commit().unwrap();
+        repo_utils::commit(&ctx.repo, "HEAD", "Add other file", &tree, &[&head]);
+        repo_utils::stage_file_changes(&ctx, &other_file_path);
+        repo_utils::stage_file_changes(&ctx, &file_path);
+        let staged = ctx.repo.index().unwrap().write_tree().unwrap();
+
+        // the changes to the other file could be absorbed on their own
+        let mut capturing_logger = log_utils::CapturingLogger::new();
+        let config = Config {
+            all_or_nothing: true,
+            base: &["HEAD~1"],
+            ..DEFAULT_CONFIG
+        };
+        let err = run_with_repo(&capturing_logger.logger, &config, &ctx.repo).unwrap_err();
+        assert!(
+            matches!(err, AbsorbError::CheckFailed { unabsorbed: 2 }),
+            "{:?}",
+            err
+        );
+        assert_eq!(extract_commit_messages(&ctx.repo).len(), 2);
+        assert_eq!(ctx.repo.index().unwrap().write_tree().unwrap(), staged);
+
+        let locations: Vec<_> = capturing_logger
+            .visible_logs()
+            .into_iter()
+            .filter(|log| log["msg"] == "Staged change could not be absorbed")
+            .map(|log| log["location"].as_str().unwrap().to_owned())
+            .collect();
+        assert_eq!(locations, vec!["test-file.txt:1", "test-file.txt:8-9"]);
+    }
+
+    #[test]
+    fn all_or_nothing_with_everything_absorbed() {
+        let ctx = repo_utils::prepare_and_stage();
+        repo_utils::set_config_option(&ctx.repo, config::ALL_OR_NOTHING_CONFIG_NAME, "true");
+
+        let drain = slog::Discard;
+        let logger = slog::Logger::root(drain, o!());
+        run_with_repo(&logger, &DEFAULT_CONFIG, &ctx.repo).unwrap();
+        assert_eq!(extract_commit_messages(&ctx.repo).len(), 3);
+        assert!(nothing_left_in_index(&ctx.repo).unwrap());
+    }
+
+    #[test]
+    fn unabsorbed_changes_are_listed_with_reasons() {
+        let (ctx, file_path) = repo_utils::prepare_repo();
+        let image_path = PathBuf::from("image.bin");
+        std::fs::write(ctx.join(&image_path), b"\x89PNG\0\x01").unwrap();
+        {
+            let tree = repo_utils::add(&ctx.repo, &image_path);
+            let head = ctx.repo.head().unwrap().peel_to_commit().unwrap();
+            repo_utils::commit(&ctx.repo, "HEAD", "Add image", &tree, &[&head]);
+        }
+        std::fs::write(ctx.join(&image_path), b"\x89PNG\0\x02").unwrap();
+        repo_utils::add(&ctx.repo, &image_path);
+        std::fs::write(ctx.join(Path::new("a-new-file.txt")), "new\n").unwrap();
+        repo_utils::add(&ctx.repo, Path::new("a-new-file.txt"));
+        repo_utils::stage_file_changes(&ctx, &file_path);
+
+        // the commit the text hunks belong to is hidden by the base
+        let mut capturing_logger = log_utils::CapturingLogger::new();
+        let config = Config {
+            dry_run: true,
+            base: &["HEAD~1"],
+            ..DEFAULT_CONFIG
+        };
+        run_with_repo(&capturing_logger.logger, &config, &ctx.repo).unwrap();
+
+        let unabsorbed: Vec<_> = capturing_logger
+            .visible_logs()
+            .into_iter()
+            .filter(|log| log["msg"] == "Staged change could not be absorbed")
+            .map(|log| {
+                (
+                    log["location"].as_str().unwrap().to_owned(),
+                    log["reason"].as_str().unwrap().to_owned(),
+                )
+            })
+            .collect();
+        assert_eq!(
+            unabsorbed,
+            [
+                ("a-new-file.txt", "added"),
+                ("image.bin", "binary"),
+                ("test-file.txt:1", "boundary"),
+                ("test-file.txt:8-9", "boundary"),
+            ]
+            .map(|(location, reason)| (location.to_owned(), reason.to_owned()))
+        );
+    }
+
+    #[test]
+    fn files_above_max_file_size_are_skipped() {
+        let (ctx, file_path) = repo_utils::prepare_repo();
+        let big_path = PathBuf::from("big.txt");
+        std::fs::write(ctx.join(&big_path), "big line\n".repeat(100)).unwrap();
+        {
+            let tree = repo_utils::add(&ctx.repo, &big_path);
+            let head = ctx.repo.head().unwrap().peel_to_commit().unwrap();
+            repo_utils::commit(&ctx.repo, "HEAD", "Add big file", &tree, &[&head]);
+        }
+        std::fs::write(ctx.join(&big_path), "big line\n".repeat(101)).unwrap();
+        repo_utils::add(&ctx.repo, &big_path);
+        repo_utils::stage_file_changes(&ctx, &file_path);
+        repo_utils::set_config_option(&ctx.repo, config::MAX_FILE_SIZE_CONFIG_NAME, "512");
+
+        let mut capturing_logger = log_utils::CapturingLogger::new();
+        run_with_repo(&capturing_logger.logger, &DEFAULT_CONFIG, &ctx.repo).unwrap();
+
+        let unabsorbed: Vec<_> = capturing_logger
+            .visible_logs()
+            .into_iter()
+            .filter(|log| log["msg"] == "Staged change could not be absorbed")
+            .map(|log| {
+                (
+                    log["location"].as_str().unwrap().to_owned(),
+                    log["reason"].as_str().unwrap().to_owned(),
+                )
+            })
+            .collect();
+        assert_eq!(unabsorbed, [("big.txt".to_owned(), "too-large".to_owned())]);
+        // the other file is absorbed as usual
+        assert_eq!(
+            extract_commit_messages(&ctx.repo)[..3],
+            [
+                "fixup! Initial commit.\n",
+                "fixup! Initial commit.\n",
+                "Add big file"
+            ]
+        );
+    }
+
+    /// The reasons each staged change that was left in the index was given.
+    fn unabsorbed_reasons(logger: &mut log_utils::CapturingLogger) -> Vec<(String, String)> {
+        logger
+            .visible_logs()
+            .into_iter()
+            .filter(|log| log["msg"] == "Staged change could not be absorbed")
+            .map(|log| {
+                (
+                    log["location"].as_str().unwrap().to_owned(),
+                    log["reason"].as_str().unwrap().to_owned(),
+                )
+            })
+            .collect()
+    }
+
+    #[test]
+    fn hunks_are_filtered_by_their_added_lines() {
+        let (ctx, _) = repo_utils::prepare_synthetic_stack(3, 3);
+
+        let mut capturing_logger = log_utils::CapturingLogger::new();
+        let config = Config {
+            exclude_hunks_matching: &["^hunk 0$"],
+            include_hunks_matching: &["hunk [01]"],
+            ..DEFAULT_CONFIG
+        };
+        run_with_repo(&capturing_logger.logger, &config, &ctx.repo).unwrap();
+
+        // the first is excluded, and the last is not included
+        assert_eq!(
+            extract_commit_messages(&ctx.repo)[..2],
+            ["fixup! Commit 2\n", "Commit 2"]
+        );
+        assert_eq!(
+            unabsorbed_reasons(&mut capturing_logger),
+            [
+                ("test-file.txt:2".to_owned(), "filtered".to_owned()),
+                ("test-file.txt:6".to_owned(), "filtered".to_owned()),
+            ]
+        );
+    }
+
+    #[test]
+    fn exclude_hunk_patterns_from_every_config_value_only_match_added_lines() {
+        let (ctx, _) = repo_utils::prepare_synthetic_stack(3, 3);
+        let mut config = ctx.repo.config().unwrap();
+        for pattern in ["^hunk 0$", "^line 3$", "^commit 2$"] {
+            config
+                .set_multivar(config::EXCLUDE_HUNK_PATTERN_CONFIG_NAME, "^$", pattern)
+                .unwrap();
+        }
+
+        let mut capturing_logger = log_utils::CapturingLogger::new();
+        run_with_repo(&capturing_logger.logger, &DEFAULT_CONFIG, &ctx.repo).unwrap();
+
+        // line 3 was removed by the second hunk, and commit 2 is context
+        assert_eq!(
+            extract_commit_messages(&ctx.repo)[..3],
+            ["fixup! Commit 2\n", "fixup! Commit 2\n", "Commit 2"]
+        );
+        assert_eq!(
+            unabsorbed_reasons(&mut capturing_logger),
+            [("test-file.txt:2".to_owned(), "filtered".to_owned())]
+        );
+
+        // an empty value drops the patterns before it
+        config
+            .set_multivar(config::EXCLUDE_HUNK_PATTERN_CONFIG_NAME, "^$", "")
+            .unwrap();
+        assert!(config::exclude_hunk_patterns(&ctx.repo).is_empty());
+    }
+
+    #[test]
+    fn vendored_files_are_excluded_unless_the_list_is_cleared() {
+        let (ctx, _) = repo_utils::prepare_repo();
+        let vendored = PathBuf::from("vendor/lib/lib.txt");
+        std::fs::create_dir_all(ctx.join(Path::new("vendor/lib"))).unwrap();
+        std::fs::write(ctx.join(&vendored), "one\ntwo\n").unwrap();
+        {
+            let tree = repo_utils::add(&ctx.repo, &vendored);
+            let head = ctx.repo.head().unwrap().peel_to_commit().unwrap();
+            repo_utils::commit(&ctx.repo, "HEAD", "Vendor lib", &tree, &[&head]);
+        }
+        std::fs::write(ctx.join(&vendored), "one\ntwo fixed\n").unwrap();
+        repo_utils::add(&ctx.repo, &vendored);
+
+        let mut capturing_logger = log_utils::CapturingLogger::new();
+        run_with_repo(&capturing_logger.logger, &DEFAULT_CONFIG, &ctx.repo).unwrap();
+
+        let unabsorbed: Vec<_> = capturing_logger
+            .visible_logs()
+            .into_iter()
+            .filter(|log| log["msg"] == "Staged change could not be absorbed")
+            .map(|log| {
+                (
+                    log["location"].as_str().unwrap().to_owned(),
+                    log["reason"].as_str().unwrap().to_owned(),
+                )
+            })
+            .collect();
+        assert_eq!(
+            unabsorbed,
+            [("vendor/lib/lib.txt".to_owned(), "excluded".to_owned())]
+        );
+        assert_eq!(extract_commit_messages(&ctx.repo).len(), 2);
+
+        repo_utils::set_config_option(&ctx.repo, config::EXCLUDE_PATHS_CONFIG_NAME, "");
+        run_with_repo(&capturing_logger.logger, &DEFAULT_CONFIG, &ctx.repo).unwrap();
+
+        assert!(nothing_left_in_index(&ctx.repo).unwrap());
+        assert_eq!(
+            extract_commit_messages(&ctx.repo)[..2],
+            ["fixup! Vendor lib\n", "Vendor lib"]
+        );
+    }
+
+    #[test]
+    fn excluded_directories_match_at_any_depth() {
+        let dirs = ["vendor".to_owned(), "third_party/js".to_owned()];
+        assert!(is_excluded(&dirs, b"vendor/lib.rs"));
+        assert!(is_excluded(&dirs, b"web/vendor/lib.js"));
+        assert!(is_excluded(&dirs, b"third_party/js/a.js"));
+        assert!(!is_excluded(&dirs, b"vendored/lib.rs"));
+        assert!(!is_excluded(&dirs, b"src/vendor"));
+        assert!(!is_excluded(&dirs, b"third_party/jsx/a.js"));
+    }
+
+    #[test]
+    fn max_report_truncates_unabsorbed_changes() {
+        let ctx = repo_utils::prepare_and_stage();
+
+        let mut capturing_logger = log_utils::CapturingLogger::new();
+        let config = Config {
+            dry_run: true,
+            base: &["HEAD"],
+            max_report: Some(1),
+            ..DEFAULT_CONFIG
+        };
+        run_with_repo(&capturing_logger.logger, &config, &ctx.repo).unwrap();
+
+        let logs = capturing_logger.visible_logs();
+        let report: Vec<_> = logs
+            .iter()
+            .filter(|log| log["level"] == "WARN")
+            .skip_while(|log| log["msg"] != "Staged change could not be absorbed")
+            .collect();
+        log_utils::assert_log_messages_are(
+            report.into_iter().cloned().collect(),
+            vec![
+                &json!({
+                    "level": "WARN",
+                    "msg": "Staged change could not be absorbed",
+                    "location": "test-file.txt:1",
+                }),
+                &json!({"level": "WARN", "msg": "...and 1 more.", "count": 1}),
+            ],
+        );
+    }
+
+    #[test]
+    fn unabsorbed_hunks_blocked_by_into() {
+        let (ctx, _) = repo_utils::prepare_repo();
+        let path = PathBuf::from("other.txt");
+        let mut lines = prepare_numbered_lines(&ctx, &path);
+        {
+            lines[2] = "line 3 changed\n".to_owned();
+            std::fs::write(ctx.join(&path), lines.concat()).unwrap();
+            let tree = repo_utils::add(&ctx.repo, &path);
+            let head = ctx.repo.head().unwrap().peel_to_commit().unwrap();
+            repo_utils::commit(&ctx.repo, "HEAD", "Change line 3", &tree, &[&head]);
+        }
+        lines[2] = "line 3 fixed\n".to_owned();
+        std::fs::write(ctx.join(&path), lines.concat()).unwrap();
+        repo_utils::add(&ctx.repo, &path);
+
+        let mut capturing_logger = log_utils::CapturingLogger::new();
+        let config = Config {
+            dry_run: true,
+            into: Some("Add lines"),
+            ..DEFAULT_CONFIG
+        };
+        run_with_repo(&capturing_logger.logger, &config, &ctx.repo).unwrap();
+
+        let reasons: Vec<_> = capturing_logger
+            .visible_logs()
+            .into_iter()
+            .filter(|log| log["msg"] == "Staged change could not be absorbed")
+            .map(|log| log["reason"].as_str().unwrap().to_owned())
+            .collect();
+        assert_eq!(reasons, ["blocked"]);
+    }
+
+    #[test]
+    fn target_touched_only_absorbs_into_commits_that_changed_the_path() {
+        let (ctx, _) = repo_utils::prepare_repo();
+        std::fs::create_dir(ctx.join(Path::new("src"))).unwrap();
+        for name in ["a", "b"] {
+            let path = PathBuf::from(format!("src/{name}.rs"));
+            std::fs::write(ctx.join(&path), format!("fn {name}() {{}}\n")).unwrap();
+            let tree = repo_utils::add(&ctx.repo, &path);
+            let head = ctx.repo.head().unwrap().peel_to_commit().unwrap();
+            repo_utils::commit(&ctx.repo, "HEAD", &format!("Add {name}"), &tree, &[&head]);
+        }
+        for name in ["a", "b"] {
+            let path = PathBuf::from(format!("src/{name}.rs"));
+            std::fs::write(ctx.join(&path), format!("fn {name}() {{ todo!() }}\n")).unwrap();
+            repo_utils::add(&ctx.repo, &path);
+        }
+
+        let mut capturing_logger = log_utils::CapturingLogger::new();
+        let config = Config {
+            target_touched: Some("src/a.rs"),
+            ..DEFAULT_CONFIG
+        };
+        run_with_repo(&capturing_logger.logger, &config, &ctx.repo).unwrap();
+
+        let messages = extract_commit_messages(&ctx.repo);
+        assert_eq!(messages[0], "fixup! Add a\n");
+        assert_eq!(messages[1], "Add b");
+        let unabsorbed: Vec<_> = capturing_logger
+            .visible_logs()
+            .into_iter()
+            .filter(|log| log["msg"] == "Staged change could not be absorbed")
+            .map(|log| {
+                (
+                    log["location"].as_str().unwrap().to_owned(),
+                    log["reason"].as_str().unwrap().to_owned(),
+                )
+            })
+            .collect();
+        assert_eq!(
+            unabsorbed,
+            [("src/b.rs:1".to_owned(), "untouched".to_owned())]
+        );
+    }
+
+    #[test]
+    fn paths_restrict_the_staged_changes() {
+        let (ctx, _) = repo_utils::prepare_repo();
+        std::fs::create_dir(ctx.join(Path::new("src"))).unwrap();
+        let names = ["src/a b.rs", "src/c.rs", "d.rs"];
+        for name in names {
+            let path = PathBuf::from(name);
+            std::fs::write(ctx.join(&path), "fn f() {}\n").unwrap();
+            let tree = repo_utils::add(&ctx.repo, &path);
+            let head = ctx.repo.head().unwrap().peel_to_commit().unwrap();
+            repo_utils::commit(&ctx.repo, "HEAD", &format!("Add {name}"), &tree, &[&head]);
+        }
+        for name in names {
+            let path = PathBuf::from(name);
+            std::fs::write(ctx.join(&path), "fn f() { todo!() }\n").unwrap();
+            repo_utils::add(&ctx.repo, &path);
+        }
+
+        let first = ctx.join(Path::new("src/a b.rs"));
+        let second = ctx.join(Path::new("src/../d.rs"));
+        let paths = [first.to_str().unwrap(), second.to_str().unwrap()];
+        let config = Config {
+            paths: &paths,
+            ..DEFAULT_CONFIG
+        };
+        let drain = slog::Discard;
+        let logger = slog::Logger::root(drain, o!());
+        run_with_repo(&logger, &config, &ctx.repo).unwrap();
+
+        assert_eq!(
+            extract_commit_messages(&ctx.repo)[..2],
+            ["fixup! Add d.rs\n", "fixup! Add src/a b.rs\n"]
+        );
+        let head_tree = ctx.repo.head().unwrap().peel_to_tree().unwrap();
+        let staged = ctx
+            .repo
+            .diff_tree_to_index(Some(&head_tree), None, None)
+            .unwrap();
+        let staged: Vec<_> = staged
+            .deltas()
+            .map(|delta| delta.new_file().path().unwrap().to_owned())
+            .collect();
+        assert_eq!(staged, [PathBuf::from("src/c.rs")]);
+
+        let outside = ctx
+            .dir
+            .path()
+            .parent()
+            .unwrap()
+            .to_str()
+            .unwrap()
+            .to_owned();
+        let config = Config {
+            paths: &[&outside],
+            ..DEFAULT_CONFIG
+        };
+        let err = run_with_repo(&logger, &config, &ctx.repo).unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            format!("{} is outside the repository", outside)
+        );
+    }
+
+    #[test]
+    fn range_only_absorbs_into_the_commits_in_it() {
+        let (ctx, _) = repo_utils::prepare_repo();
+        for name in ["a", "b", "c", "d"] {
+            let path = PathBuf::from(format!("{name}.txt"));
+            std::fs::write(ctx.join(&path), format!("{name}\n")).unwrap();
+            let tree = repo_utils::add(&ctx.repo, &path);
+            let head = ctx.repo.head().unwrap().peel_to_commit().unwrap();
+            repo_utils::commit(&ctx.repo, "HEAD", &format!("Add {name}"), &tree, &[&head]);
+        }
+        for name in ["a", "b", "c", "d"] {
+            let path = PathBuf::from(format!("{name}.txt"));
+            std::fs::write(ctx.join(&path), format!("{name} changed\n")).unwrap();
+            repo_utils::add(&ctx.repo, &path);
+        }
+
+        let mut capturing_logger = log_utils::CapturingLogger::new();
+        let config = Config {
+            range: Some("HEAD~3..HEAD~1"),
+            ..DEFAULT_CONFIG
+        };
+        run_with_repo(&capturing_logger.logger, &config, &ctx.repo).unwrap();
+
+        let messages = extract_commit_messages(&ctx.repo);
+        assert_eq!(messages[..3], ["fixup! Add c\n", "fixup! Add b\n", "Add d"]);
+        let unabsorbed: Vec<_> = capturing_logger
+            .visible_logs()
+            .into_iter()
+            .filter(|log| log["msg"] == "Staged change could not be absorbed")
+            .map(|log| {
+                (
+                    log["location"].as_str().unwrap().to_owned(),
+                    log["reason"].as_str().unwrap().to_owned(),
+                )
+            })
+            .collect();
+        assert_eq!(
+            unabsorbed,
+            [
+                ("a.txt:1".to_owned(), "boundary".to_owned()),
+                ("d.txt:1".to_owned(), "out-of-range".to_owned())
+            ]
+        );
+    }
+
+    #[test]
+    fn check_flag_with_nothing_staged() {
+        let (ctx, _) = repo_utils::prepare_repo();
+        repo_utils::set_config_flag(&ctx.repo, "absorb.autoStageIfNothingStaged");
+        std::fs::write(ctx.join(Path::new("test-file.txt")), "changed\n").unwrap();
+
+        let capturing_logger = log_utils::CapturingLogger::new();
+        let config = Config {
+            check: true,
+            ..DEFAULT_CONFIG
+        };
+        run_with_repo(&capturing_logger.logger, &config, &ctx.repo).unwrap();
+
+        // the change was not auto-staged
+        assert!(nothing_left_in_index(&ctx.repo).unwrap());
+        assert_eq!(extract_commit_messages(&ctx.repo).len(), 1);
+    }
+
+    #[test]
+    fn synthetic_stack() {
+        let (ctx, _) = repo_utils::prepare_synthetic_stack(3, 4);
+
+        let drain = slog::Discard;
+        let logger = slog::Logger::root(drain, o!());
+        let config = Config {
+            one_fixup_per_commit: true,
+            ..DEFAULT_CONFIG
+        };
+        run_with_repo(&logger, &config, &ctx.repo).unwrap();
+        assert!(nothing_left_in_index(&ctx.repo).unwrap());
+
+        // each hunk goes to the newer of the two commits around it, and the
+        // one past the end of the stack to the commit that added the file
+        let messages = extract_commit_messages(&ctx.repo);
+        assert_eq!(
+            messages[..4],
+            [
+                "fixup! Commit 2\n",
+                "fixup! Commit 1\n",
+                "fixup! Initial commit.\n",
+                "Commit 2",
+            ]
+        );
+    }
+
+    #[test]
+    fn fixups_in_stack_order() {
+        for one_fixup_per_commit in [false, true] {
+            let (ctx, file_path) = repo_utils::prepare_repo();
+            let path = ctx.join(&file_path);
+            std::fs::write(&path, "1\n2\n3\n4\n5\n6\n7\n").unwrap();
+            let tree = repo_utils::add(&ctx.repo, &file_path);
+            let head = ctx.repo.head().unwrap().peel_to_commit().unwrap();
+            let base = repo_utils::commit(&ctx.repo, "HEAD", "Numbers", &tree, &[&head]);
+            // each commit changes a line above the one before it
+            let mut parent = base;
+            for (lines, name) in [
+                ("1\n2\n3\n4\n5\n6\na\n", "A"),
+                ("1\n2\n3\nb\n5\n6\na\n", "B"),
+                ("c\n2\n3\nb\n5\n6\na\n", "C"),
+            ] {
+                std::fs::write(&path, lines).unwrap();
+                let tree = repo_utils::add(&ctx.repo, &file_path);
+                parent = repo_utils::commit(&ctx.repo, "HEAD", name, &tree, &[&parent]);
+            }
+            // in file order, the hunks are for C, B and then A, and the ones
+            // above add lines, which moves the ones below
+            let staged = "c1\nc2\nc3\n2\n3\nb1\nb2\n5\n6\na changed\n";
+            std::fs::write(&path, staged).unwrap();
+            repo_utils::add(&ctx.repo, &file_path);
+
+            let drain = slog::Discard;
+            let logger = slog::Logger::root(drain, o!());
+            let config = Config {
+                one_fixup_per_commit,
+                ..DEFAULT_CONFIG
+            };
+            run_with_repo(&logger, &config, &ctx.repo).unwrap();
+            assert!(nothing_left_in_index(&ctx.repo).unwrap());
+
+            // the fixup for the oldest commit comes first
+            let messages = extract_commit_messages(&ctx.repo);
+            assert_eq!(
+                messages[..4],
+                ["fixup! C\n", "fixup! B\n", "fixup! A\n", "C"]
+            );
+            // and each one only changes the lines of its own commit
+            let file_at = |revision: &str| {
+                let tree = ctx
+                    .repo
+                    .revparse_single(revision)
+                    .unwrap()
+                    .peel_to_tree()
+                    .unwrap();
+                let blob = tree
+                    .get_path(&file_path)
+                    .unwrap()
+                    .to_object(&ctx.repo)
+                    .unwrap();
+                String::from_utf8(blob.peel_to_blob().unwrap().content().to_vec()).unwrap()
+            };
+            assert_eq!(file_at("HEAD~2"), "c\n2\n3\nb\n5\n6\na changed\n");
+            assert_eq!(file_at("HEAD~1"), "c\n2\n3\nb1\nb2\n5\n6\na changed\n");
+            assert_eq!(file_at("HEAD"), staged);
+        }
+    }
+
+    #[test]
+    fn unstaged_edits_on_absorbed_lines_are_not_absorbed() {
+        let (ctx, file_path) = repo_utils::prepare_repo();
+        let path = ctx.join(&file_path);
+        std::fs::write(&path, "\nline\nline\n\nmore staged\nlines\n").unwrap();
+        repo_utils::add(&ctx.repo, &file_path);
+        let worktree = "\nline\nline\n\nmore unstaged\nlines\nextra\n";
+        std::fs::write(&path, worktree).unwrap();
+
+        let drain = slog::Discard;
+        let logger = slog::Logger::root(drain, o!());
+        run_with_repo(&logger, &DEFAULT_CONFIG, &ctx.repo).unwrap();
+
+        assert_eq!(extract_commit_messages(&ctx.repo).len(), 2);
+        assert_eq!(
+            head_file_contents(&ctx.repo, &file_path),
+            "\nline\nline\n\nmore staged\nlines\n"
+        );
+        assert!(nothing_left_in_index(&ctx.repo).unwrap());
+        assert_eq!(std::fs::read_to_string(&path).unwrap(), worktree);
+    }
+
+    #[test]
+    fn unstaged_edits_on_removed_lines_are_not_absorbed() {
+        let (ctx, file_path) = repo_utils::prepare_repo();
+        let path = ctx.join(&file_path);
+        std::fs::write(&path, "\nline\nline\n\nlines\n").unwrap();
+        repo_utils::add(&ctx.repo, &file_path);
+        let worktree = "\nline\nline\n\nmore unstaged\nlines\n";
+        std::fs::write(&path, worktree).unwrap();
+
+        let drain = slog::Discard;
+        let logger = slog::Logger::root(drain, o!());
+        run_with_repo(&logger, &DEFAULT_CONFIG, &ctx.repo).unwrap();
+
+        assert_eq!(extract_commit_messages(&ctx.repo).len(), 2);
+        assert_eq!(
+            head_file_contents(&ctx.repo, &file_path),
+            "\nline\nline\n\nlines\n"
+        );
+        assert!(nothing_left_in_index(&ctx.repo).unwrap());
+        assert_eq!(std::fs::read_to_string(&path).unwrap(), worktree);
+    }
+
+    #[test]
+    fn target_below_deletion_only_commit() {
+        let (ctx, file_path) = repo_utils::prepare_repo();
+        let path = ctx.join(&file_path);
+        let lines: Vec<_> = (1..=12).map(|idx| format!("line {idx}\n")).collect();
+        std::fs::write(&path, lines.concat()).unwrap();
+        {
+            let tree = repo_utils::add(&ctx.repo, &file_path);
+            let head = ctx.repo.head().unwrap().peel_to_commit().unwrap();
+            repo_utils::commit(&ctx.repo, "HEAD", "Add lines", &tree, &[&head]);
+        }
+
+        // remove line 3, then lines 8 and 9, so that the second hunk's
+        // sides are shifted apart
+        let mut remaining = lines.clone();
+        remaining.drain(7..9);
+        remaining.remove(2);
+        std::fs::write(&path, remaining.concat()).unwrap();
+        {
+            let tree = repo_utils::add(&ctx.repo, &file_path);
+            let head = ctx.repo.head().unwrap().peel_to_commit().unwrap();
+            repo_utils::commit(&ctx.repo, "HEAD", "Remove lines", &tree, &[&head]);
+        }
+
+        // change line 11, which is not next to any of the removed ones
+        let mut changed = remaining.clone();
+        changed[7] = "line 11 changed\n".to_owned();
+        std::fs::write(&path, changed.concat()).unwrap();
+        repo_utils::add(&ctx.repo, &file_path);
+
+        let drain = slog::Discard;
+        let logger = slog::Logger::root(drain, o!());
+        run_with_repo(&logger, &DEFAULT_CONFIG, &ctx.repo).unwrap();
+        assert!(nothing_left_in_index(&ctx.repo).unwrap());
+
+        let messages = extract_commit_messages(&ctx.repo);
+        assert_eq!(messages[0], "fixup! Add lines\n");
+    }
+
+    #[test]
+    fn unstaged_flag_with_deleted_file() {
+        let (ctx, file_path) = repo_utils::prepare_repo();
+        let other_path = PathBuf::from("other-file.txt");
+        std::fs::write(ctx.join(&other_path), "line\n").unwrap();
+        {
+            let tree = repo_utils::add(&ctx.repo, &other_path);
+            let head = ctx.repo.head().unwrap().peel_to_commit().unwrap();
+            repo_utils::commit(&ctx.repo, "HEAD", "Add other file", &tree, &[&head]);
+        }
+
+        // delete the file without staging the deletion
+        std::fs::remove_file(ctx.join(&other_path)).unwrap();
+
+        let drain = slog::Discard;
+        let logger = slog::Logger::root(drain, o!());
+        let config = Config {
+            unstaged: true,
+            ..DEFAULT_CONFIG
+        };
+        run_with_repo(&logger, &config, &ctx.repo).unwrap();
+
+        assert_eq!(
+            extract_commit_messages(&ctx.repo),
+            vec![
+                "fixup! Add other file\n",
+                "Add other file",
+                "Initial commit."
+            ]
+        );
+        let head_tree = ctx.repo.head().unwrap().peel_to_tree().unwrap();
+        assert!(head_tree.get_path(&other_path).is_err());
+        assert!(head_tree.get_path(&file_path).is_ok());
+        assert!(nothing_left_in_index(&ctx.repo).unwrap());
+        assert!(nothing_left_in_workdir(&ctx.repo).unwrap());
+    }
+
+    #[test]
+    fn deleted_file_in_subdirectory() {
+        let (ctx, _) = repo_utils::prepare_repo();
+        let nested_path = PathBuf::from("subdir/nested-file.txt");
+        std::fs::create_dir(ctx.join(Path::new("subdir"))).unwrap();
+        std::fs::write(ctx.join(&nested_path), "line\n").unwrap();
+        {
+            let tree = repo_utils::add(&ctx.repo, &nested_path);
+            let head = ctx.repo.head().unwrap().peel_to_commit().unwrap();
+            repo_utils::commit(&ctx.repo, "HEAD", "Add nested file", &tree, &[&head]);
+        }
+
+        std::fs::remove_file(ctx.join(&nested_path)).unwrap();
+        let mut index = ctx.repo.index().unwrap();
+        index.remove_path(&nested_path).unwrap();
+        index.write().unwrap();
+
+        let drain = slog::Discard;
+        let logger = slog::Logger::root(drain, o!());
+        run_with_repo(&logger, &DEFAULT_CONFIG, &ctx.repo).unwrap();
+
+        assert_eq!(
+            extract_commit_messages(&ctx.repo)[0],
+            "fixup! Add nested file\n"
+        );
+        let head_tree = ctx.repo.head().unwrap().peel_to_tree().unwrap();
+        assert!(head_tree.get_path(Path::new("subdir")).is_err());
+        assert!(nothing_left_in_index(&ctx.repo).unwrap());
+    }
+
+    #[test]
+    fn deleted_file_changed_after_it_was_added() {
+        let (ctx, file_path) = repo_utils::prepare_repo();
+        std::fs::write(ctx.join(&file_path), "changed\n").unwrap();
+        {
+            let tree = repo_utils::add(&ctx.repo, &file_path);
+            let head = ctx.repo.head().unwrap().peel_to_commit().unwrap();
+            repo_utils::commit(&ctx.repo, "HEAD", "Change file", &tree, &[&head]);
+        }
+
+        std::fs::remove_file(ctx.join(&file_path)).unwrap();
+        let mut index = ctx.repo.index().unwrap();
+        index.remove_path(&file_path).unwrap();
+        index.write().unwrap();
+
+        let drain = slog::Discard;
+        let logger = slog::Logger::root(drain, o!());
+        run_with_repo(&logger, &DEFAULT_CONFIG, &ctx.repo).unwrap();
+
+        // the deletion can't be moved before the change, so it stays staged
+        assert_eq!(extract_commit_messages(&ctx.repo).len(), 2);
+        assert!(!nothing_left_in_index(&ctx.repo).unwrap());
+    }
+
+    /// Commit a file of twelve numbered lines on top of the initial commit.
+    fn prepare_numbered_lines(ctx: &repo_utils::Context, file_path: &Path) -> Vec<String> {
+        let lines: Vec<_> = (1..=12).map(|idx| format!("line {idx}\n")).collect();
+        std::fs::write(ctx.join(file_path), lines.concat()).unwrap();
+        let tree = repo_utils::add(&ctx.repo, file_path);
+        let head = ctx.repo.head().unwrap().peel_to_commit().unwrap();
+        repo_utils::commit(&ctx.repo, "HEAD", "Add lines", &tree, &[&head]);
+        lines
+    }
+
+    #[test]
+    fn changes_to_renamed_files_go_into_the_rename() {
+        let (ctx, _) = repo_utils::prepare_repo();
+        let old_path = PathBuf::from("other.txt");
+        let mut lines = prepare_numbered_lines(&ctx, &old_path);
+        let path = PathBuf::from("renamed.txt");
+        {
+            std::fs::rename(ctx.join(&old_path), ctx.join(&path)).unwrap();
+            let mut index = ctx.repo.index().unwrap();
+            index.remove_path(&old_path).unwrap();
+            index.write().unwrap();
+            let tree = repo_utils::add(&ctx.repo, &path);
+            let head = ctx.repo.head().unwrap().peel_to_commit().unwrap();
+            repo_utils::commit(&ctx.repo, "HEAD", "Rename lines", &tree, &[&head]);
+        }
+        lines[2] = "line 3 changed\n".to_owned();
+        std::fs::write(ctx.join(&path), lines.concat()).unwrap();
+        repo_utils::add(&ctx.repo, &path);
+
+        // renames are not detected, so to the stack the file is new in the
+        // commit that renamed it, which changed every one of its lines
+        let drain = slog::Discard;
+        let logger = slog::Logger::root(drain, o!());
+        run_with_repo(&logger, &DEFAULT_CONFIG, &ctx.repo).unwrap();
+
+        assert_eq!(
+            extract_commit_messages(&ctx.repo),
+            vec![
+                "fixup! Rename lines\n",
+                "Rename lines",
+                "Add lines",
+                "Initial commit."
+            ]
+        );
+        assert!(nothing_left_in_index(&ctx.repo).unwrap());
+    }
+
+    /// Commits `Util.rs` and then renames it to `util.rs`, changing only
+    /// the case of its name, as anyone on a case-insensitive filesystem
+    /// has to with `git mv`.
+    fn prepare_case_only_rename(ctx: &repo_utils::Context) -> (PathBuf, Vec<String>) {
+        let old_path = PathBuf::from("Util.rs");
+        let lines = prepare_numbered_lines(ctx, &old_path);
+        let path = PathBuf::from("util.rs");
+        std::fs::rename(ctx.join(&old_path), ctx.join(&path)).unwrap();
+        let mut index = ctx.repo.index().unwrap();
+        index.remove_path(&old_path).unwrap();
+        index.write().unwrap();
+        let tree = repo_utils::add(&ctx.repo, &path);
+        assert!(tree.get_path(&old_path).is_err());
+        let head = ctx.repo.head().unwrap().peel_to_commit().unwrap();
+        repo_utils::commit(&ctx.repo, "HEAD", "Lowercase util", &tree, &[&head]);
+        (path, lines)
+    }
+
+    #[test]
+    fn changes_after_case_only_rename() {
+        let (ctx, _) = repo_utils::prepare_repo();
+        let (path, mut lines) = prepare_case_only_rename(&ctx);
+        lines[2] = "line 3 changed\n".to_owned();
+        std::fs::write(ctx.join(&path), lines.concat()).unwrap();
+        repo_utils::add(&ctx.repo, &path);
+
+        // the paths in the trees are told apart by case, whatever the
+        // filesystem does, so the change is in the file the rename added
+        let drain = slog::Discard;
+        let logger = slog::Logger::root(drain, o!());
+        run_with_repo(&logger, &DEFAULT_CONFIG, &ctx.repo).unwrap();
+
+        assert_eq!(
+            extract_commit_messages(&ctx.repo),
+            vec![
+                "fixup! Lowercase util\n",
+                "Lowercase util",
+                "Add lines",
+                "Initial commit."
+            ]
+        );
+        assert!(nothing_left_in_index(&ctx.repo).unwrap());
+        let head = ctx.repo.head().unwrap().peel_to_tree().unwrap();
+        assert!(head.get_path(Path::new("Util.rs")).is_err());
+        assert!(head_file_contents(&ctx.repo, &path).contains("line 3 changed\n"));
+    }
+
+    #[test]
+    fn unstaged_changes_after_case_only_rename() {
+        let (ctx, _) = repo_utils::prepare_repo();
+        let (path, mut lines) = prepare_case_only_rename(&ctx);
+        lines[2] = "line 3 changed\n".to_owned();
+        std::fs::write(ctx.join(&path), lines.concat()).unwrap();
+
+        let drain = slog::Discard;
+        let logger = slog::Logger::root(drain, o!());
+        let config = Config {
+            unstaged: true,
+            ..DEFAULT_CONFIG
+        };
+        run_with_repo(&logger, &config, &ctx.repo).unwrap();
+
+        // the index keeps the name the tree has, not some other case of it
+        let index = ctx.repo.index().unwrap();
+        let paths: Vec<_> = index.iter().map(|entry| entry.path).collect();
+        assert!(paths.contains(&b"util.rs".to_vec()));
+        assert!(!paths.contains(&b"Util.rs".to_vec()));
+        assert!(nothing_left_in_index(&ctx.repo).unwrap());
+    }
+
+    #[test]
+    #[cfg(target_os = "linux")]
+    fn autostage_folds_case_on_ignorecase_repos() {
+        let (ctx, file_path) = repo_utils::prepare_repo();
+        std::fs::write(ctx.join(Path::new("Skipped.txt")), "skipped\n").unwrap();
+        let tree = repo_utils::add(&ctx.repo, Path::new("Skipped.txt"));
+        let head = ctx.repo.head().unwrap().peel_to_commit().unwrap();
+        repo_utils::commit(&ctx.repo, "HEAD", "Add skipped", &tree, &[&head]);
+        let mut index = ctx.repo.index().unwrap();
+        let mut entry = index.get_path(Path::new("Skipped.txt"), 0).unwrap();
+        entry.flags_extended |= git2::IndexEntryExtendedFlag::SKIP_WORKTREE.bits();
+        index.add(&entry).unwrap();
+        index.write().unwrap();
+
+        // on a case-insensitive filesystem, this is the same file as the
+        // one git was told not to check out
+        std::fs::remove_file(ctx.join(Path::new("Skipped.txt"))).unwrap();
+        std::fs::write(ctx.join(Path::new("skipped.txt")), "not staged\n").unwrap();
+        repo_utils::set_config_option(&ctx.repo, "core.ignorecase", "true");
+        repo_utils::set_config_flag(&ctx.repo, config::AUTO_STAGE_IF_NOTHING_STAGED_CONFIG_NAME);
+        let contents = std::fs::read_to_string(ctx.join(&file_path)).unwrap();
+        std::fs::write(ctx.join(&file_path), format!("new_line1\n{contents}")).unwrap();
+
+        let drain = slog::Discard;
+        let logger = slog::Logger::root(drain, o!());
+        run_with_repo(&logger, &DEFAULT_CONFIG, &ctx.repo).unwrap();
+
+        assert_eq!(
+            extract_commit_messages(&ctx.repo),
+            vec!["fixup! Initial commit.\n", "Add skipped", "Initial commit."]
+        );
+        let index = ctx.repo.index().unwrap();
+        let paths: Vec<_> = index.iter().map(|entry| entry.path).collect();
+        assert_eq!(
+            paths,
+            vec![b"Skipped.txt".to_vec(), b"test-file.txt".to_vec()]
+        );
+        let skipped = index.get_path(Path::new("Skipped.txt"), 0).unwrap();
+        assert_ne!(
+            skipped.flags_extended & git2::IndexEntryExtendedFlag::SKIP_WORKTREE.bits(),
+            0
+        );
+    }
+
+    fn prepare_lines_by_two_authors() -> (repo_utils::Context, PathBuf) {
+        let (ctx, _) = repo_utils::prepare_repo();
+        let path = PathBuf::from("other.txt");
+        repo_utils::become_author(&ctx.repo, "somebody", "somebody@example.com");
+        let mut lines = prepare_numbered_lines(&ctx, &path);
+        repo_utils::become_author(&ctx.repo, "nobody", "nobody@example.com");
+        {
+            lines[9] = "line 10 changed\n".to_owned();
+            std::fs::write(ctx.join(&path), lines.concat()).unwrap();
+            let tree = repo_utils::add(&ctx.repo, &path);
+            let head = ctx.repo.head().unwrap().peel_to_commit().unwrap();
+            repo_utils::commit(&ctx.repo, "HEAD", "Change line 10", &tree, &[&head]);
+        }
+
+        // one change in lines by somebody, and one in lines by nobody
+        lines[2] = "line 3 fixed\n".to_owned();
+        lines[9] = "line 10 fixed\n".to_owned();
+        std::fs::write(ctx.join(&path), lines.concat()).unwrap();
+        repo_utils::add(&ctx.repo, &path);
+        (ctx, path)
+    }
+
+    #[test]
+    fn only_my_lines_skips_hunks_by_other_authors() {
+        let (ctx, path) = prepare_lines_by_two_authors();
+
+        let drain = slog::Discard;
+        let logger = slog::Logger::root(drain, o!());
+        let config = Config {
+            force_author: true,
+            only_my_lines: true,
+            ..DEFAULT_CONFIG
+        };
+        run_with_repo(&logger, &config, &ctx.repo).unwrap();
+
+        let messages = extract_commit_messages(&ctx.repo);
+        assert_eq!(messages.len(), 4);
+        assert_eq!(messages[0], "fixup! Change line 10\n");
+        assert!(head_file_contents(&ctx.repo, &path).contains("line 10 fixed\n"));
+        assert!(head_file_contents(&ctx.repo, &path).contains("line 3\n"));
+
+        // the change to somebody's line is still staged
+        let stats = index_stats(&ctx.repo).unwrap();
+        assert_eq!(stats.insertions(), 1);
+        assert_eq!(stats.deletions(), 1);
+    }
+
+    #[test]
+    fn without_only_my_lines_hunks_by_other_authors_are_absorbed() {
+        let (ctx, _) = prepare_lines_by_two_authors();
+
+        let drain = slog::Discard;
+        let logger = slog::Logger::root(drain, o!());
+        let config = Config {
+            force_author: true,
+            ..DEFAULT_CONFIG
+        };
+        run_with_repo(&logger, &config, &ctx.repo).unwrap();
+
+        assert!(nothing_left_in_index(&ctx.repo).unwrap());
+        let messages = extract_commit_messages(&ctx.repo);
+        assert_eq!(messages[0], "fixup! Change line 10\n");
+        assert_eq!(messages[1], "fixup! Add lines\n");
+    }
+
+    /// Stages a hunk that deletes line 2, one that adds a line after line 6
+    /// and one that changes line 9 of the lines added by "Add lines", and
+    /// absorbs them with `config`, returning the lines committed at HEAD.
+    fn absorb_kinds_of_hunks(config: &Config) -> (repo_utils::Context, String) {
+        let (ctx, _) = repo_utils::prepare_repo();
+        let path = PathBuf::from("lines.txt");
+        let mut lines: Vec<_> = (1..=10).map(|line| format!("line {line}\n")).collect();
+        std::fs::write(ctx.join(&path), lines.concat()).unwrap();
+        {
+            let tree = repo_utils::add(&ctx.repo, &path);
+            let head = ctx.repo.head().unwrap().peel_to_commit().unwrap();
+            repo_utils::commit(&ctx.repo, "HEAD", "Add lines", &tree, &[&head]);
+        }
+
+        lines[8] = "line 9 changed\n".to_owned();
+        lines.insert(6, "added\n".to_owned());
+        lines.remove(1);
+        std::fs::write(ctx.join(&path), lines.concat()).unwrap();
+        repo_utils::add(&ctx.repo, &path);
+
+        let drain = slog::Discard;
+        let logger = slog::Logger::root(drain, o!());
+        run_with_repo(&logger, config, &ctx.repo).unwrap();
+        let contents = head_file_contents(&ctx.repo, &path);
+        (ctx, contents)
+    }
+
+    #[test]
+    fn additions_only_leaves_out_pure_deletions() {
+        let config = Config {
+            additions_only: true,
+            ..DEFAULT_CONFIG
+        };
+        let (ctx, contents) = absorb_kinds_of_hunks(&config);
+
+        assert_eq!(extract_commit_messages(&ctx.repo)[0], "fixup! Add lines\n");
+        assert!(contents.contains("line 2\n"), "{}", contents);
+        assert!(contents.contains("added\n"), "{}", contents);
+        assert!(contents.contains("line 9 changed\n"), "{}", contents);
+        let stats = index_stats(&ctx.repo).unwrap();
+        assert_eq!((stats.insertions(), stats.deletions()), (0, 1));
+    }
+
+    #[test]
+    fn deletions_only_leaves_out_pure_additions() {
+        let config = Config {
+            deletions_only: true,
+            ..DEFAULT_CONFIG
+        };
+        let (ctx, contents) = absorb_kinds_of_hunks(&config);
+
+        assert_eq!(extract_commit_messages(&ctx.repo)[0], "fixup! Add lines\n");
+        assert!(!contents.contains("line 2\n"), "{}", contents);
+        assert!(!contents.contains("added\n"), "{}", contents);
+        assert!(contents.contains("line 9 changed\n"), "{}", contents);
+        let stats = index_stats(&ctx.repo).unwrap();
+        assert_eq!((stats.insertions(), stats.deletions()), (1, 0));
+    }
+
+    /// Stages one change for "Initial commit." and one for "Add lines",
+    /// with "Change line 10" on top, and a new file that cannot be absorbed.
+    fn prepare_stack_to_rewrite() -> (repo_utils::Context, PathBuf) {
+        let (ctx, file_path) = repo_utils::prepare_repo();
+        let path = PathBuf::from("other.txt");
+        let mut lines = prepare_numbered_lines(&ctx, &path);
+        {
+            lines[9] = "line 10 changed\n".to_owned();
+            std::fs::write(ctx.join(&path), lines.concat()).unwrap();
+            let tree = repo_utils::add(&ctx.repo, &path);
+            let head = ctx.repo.head().unwrap().peel_to_commit().unwrap();
+            repo_utils::commit(&ctx.repo, "HEAD", "Change line 10", &tree, &[&head]);
+        }
+        lines[2] = "line 3 fixed\n".to_owned();
+        std::fs::write(ctx.join(&path), lines.concat()).unwrap();
+        repo_utils::add(&ctx.repo, &path);
+        std::fs::write(ctx.join(Path::new("new-file.txt")), "new\n").unwrap();
+        repo_utils::add(&ctx.repo, Path::new("new-file.txt"));
+        repo_utils::stage_file_changes(&ctx, &file_path);
+        (ctx, path)
+    }
+
+    /// The note that absorb.writeNotes left on `commit`.
+    fn absorb_note(repo: &git2::Repository, commit: git2::Oid) -> Option<String> {
+        let note = repo.find_note(Some(notes::NOTES_REF), commit).ok()?;
+        note.message().map(str::to_owned)
+    }
+
+    #[test]
+    fn notes_are_only_written_when_configured() {
+        let ctx = repo_utils::prepare_and_stage();
+
+        let drain = slog::Discard;
+        let logger = slog::Logger::root(drain, o!());
+        run_with_repo(&logger, &DEFAULT_CONFIG, &ctx.repo).unwrap();
+
+        assert!(ctx.repo.find_reference(notes::NOTES_REF).is_err());
+    }
+
+    #[test]
+    fn notes_record_the_target_of_each_fixup() {
+        let ctx = repo_utils::prepare_and_stage();
+        repo_utils::set_config_option(&ctx.repo, config::WRITE_NOTES_CONFIG_NAME, "true");
+        let target = ctx.repo.head().unwrap().peel_to_commit().unwrap().id();
+
+        let drain = slog::Discard;
+        let logger = slog::Logger::root(drain, o!());
+        let summary = run_with_repo(&logger, &DEFAULT_CONFIG, &ctx.repo).unwrap();
+
+        assert_eq!(summary.created_fixups.len(), 2);
+        for (fixup, fixes_up) in &summary.created_fixups {
+            assert_eq!(*fixes_up, target);
+            let note = absorb_note(&ctx.repo, *fixup).unwrap();
+            let lines: Vec<_> = note.lines().collect();
+            assert_eq!(lines[0], format!("target: {}", target));
+            assert_eq!(lines[1], "subject: Initial commit.");
+            assert!(lines[2].starts_with("time: "));
+            assert_eq!(
+                lines[3],
+                format!("version: git-absorb {}", env!("CARGO_PKG_VERSION"))
+            );
+        }
+    }
+
+    #[test]
+    fn notes_are_copied_onto_rewritten_commits() {
+        let ctx = repo_utils::prepare_and_stage();
+        repo_utils::set_config_option(&ctx.repo, config::WRITE_NOTES_CONFIG_NAME, "true");
+        let target = ctx.repo.head().unwrap().peel_to_commit().unwrap().id();
+
+        let drain = slog::Discard;
+        let logger = slog::Logger::root(drain, o!());
+        run_with_repo(&logger, &rewrite_config(), &ctx.repo).unwrap();
+
+        let rewritten = ctx.repo.head().unwrap().peel_to_commit().unwrap().id();
+        assert_ne!(rewritten, target);
+        let note = absorb_note(&ctx.repo, rewritten).unwrap();
+        let targets: Vec<_> = note
+            .lines()
+            .filter_map(|line| line.strip_prefix("target: "))
+            .collect();
+        assert_eq!(targets, [target.to_string()]);
+    }
+
+    #[test]
+    fn notes_are_copied_by_the_rebase() {
+        let ctx = repo_utils::prepare_and_stage();
+        repo_utils::set_config_option(&ctx.repo, "core.editor", "true");
+        repo_utils::set_config_option(&ctx.repo, "advice.waitingForEditor", "false");
+        repo_utils::set_config_option(&ctx.repo, config::WRITE_NOTES_CONFIG_NAME, "true");
+        let target = ctx.repo.head().unwrap().peel_to_commit().unwrap().id();
+
+        let drain = slog::Discard;
+        let logger = slog::Logger::root(drain, o!());
+        let config = Config {
+            and_rebase: true,
+            ..DEFAULT_CONFIG
+        };
+        run_with_repo(&logger, &config, &ctx.repo).unwrap();
+
+        let rebased = ctx.repo.head().unwrap().peel_to_commit().unwrap().id();
+        assert_eq!(extract_commit_messages(&ctx.repo), vec!["Initial commit."]);
+        let note = absorb_note(&ctx.repo, rebased).unwrap();
+        let targets: Vec<_> = note
+            .lines()
+            .filter_map(|line| line.strip_prefix("target: "))
+            .collect();
+        assert_eq!(targets, [target.to_string()]);
+    }
+
+    fn rewrite_config() -> Config<'static> {
+        Config {
+            rewrite: true,
+            ..DEFAULT_CONFIG
+        }
+    }
+
+    #[test]
+    fn extract_takes_reverted_lines_out_of_their_commit() {
+        let (ctx, path) = repo_utils::prepare_repo();
+        std::fs::write(ctx.join(&path), "one\ntwo\nthree\nfour\nfive\n").unwrap();
+        {
+            let tree = repo_utils::add(&ctx.repo, &path);
+            let head = ctx.repo.head().unwrap().peel_to_commit().unwrap();
+            repo_utils::commit(&ctx.repo, "HEAD", "Rewrite lines", &tree, &[&head]);
+        }
+        std::fs::write(
+            ctx.join(&path),
+            "one\nadded a\ntwo\nthree\nfour\nadded b\nfive\n",
+        )
+        .unwrap();
+        {
+            let tree = repo_utils::add(&ctx.repo, &path);
+            let head = ctx.repo.head().unwrap().peel_to_commit().unwrap();
+            repo_utils::commit(&ctx.repo, "HEAD", "Add lines", &tree, &[&head]);
+        }
+        std::fs::write(ctx.join(Path::new("other.txt")), "other\n").unwrap();
+        {
+            let tree = repo_utils::add(&ctx.repo, Path::new("other.txt"));
+            let head = ctx.repo.head().unwrap().peel_to_commit().unwrap();
+            repo_utils::commit(&ctx.repo, "HEAD", "Add another file", &tree, &[&head]);
+        }
+
+        // revert one of the added lines, and change the other one
+        std::fs::write(ctx.join(&path), "one\ntwo\nthree\nfour\nchanged b\nfive\n").unwrap();
+        repo_utils::add(&ctx.repo, &path);
+
+        let mut capturing_logger = log_utils::CapturingLogger::new();
+        let config = Config {
+            extract: true,
+            ..DEFAULT_CONFIG
+        };
+        run_with_repo(&capturing_logger.logger, &config, &ctx.repo).unwrap();
+
+        assert_eq!(
+            extract_commit_messages(&ctx.repo),
+            vec![
+                "Add another file",
+                "Add lines",
+                "Rewrite lines",
+                "Initial commit."
+            ]
+        );
+        let head = ctx.repo.head().unwrap().peel_to_commit().unwrap();
+        let add_lines = head.parent(0).unwrap();
+        let contents = |commit: &git2::Commit| {
+            let entry = commit.tree().unwrap().get_path(&path).unwrap();
+            let blob = ctx.repo.find_blob(entry.id()).unwrap();
+            String::from_utf8(blob.content().to_vec()).unwrap()
+        };
+        assert_eq!(
+            contents(&add_lines),
+            "one\ntwo\nthree\nfour\nadded b\nfive\n"
+        );
+        assert_eq!(contents(&head), "one\ntwo\nthree\nfour\nadded b\nfive\n");
+
+        // the extracted line is back in the working tree, while the change
+        // that does not revert anything is still staged
+        assert_eq!(
+            std::fs::read_to_string(ctx.join(&path)).unwrap(),
+            "one\nadded a\ntwo\nthree\nfour\nchanged b\nfive\n"
+        );
+        let stats = index_stats(&ctx.repo).unwrap();
+        assert_eq!((stats.insertions(), stats.deletions()), (1, 1));
+        assert!(capturing_logger.visible_logs().iter().any(|log| {
+            log["msg"] == "Staged change could not be absorbed"
+                && log["location"] == "test-file.txt:5"
+                && log["reason"] == "not-inverse"
+        }));
+    }
+
+    #[test]
+    fn rewrite_folds_changes_into_target_commits() {
+        let (ctx, path) = prepare_stack_to_rewrite();
+        let old_head = ctx.repo.head().unwrap().peel_to_commit().unwrap();
+
+        let mut capturing_logger = log_utils::CapturingLogger::new();
+        run_with_repo(&capturing_logger.logger, &rewrite_config(), &ctx.repo).unwrap();
+
+        assert_eq!(
+            extract_commit_messages(&ctx.repo),
+            vec!["Change line 10", "Add lines", "Initial commit."]
+        );
+        assert_eq!(
+            ctx.repo.refname_to_id("PRE_ABSORB_HEAD").unwrap(),
+            old_head.id()
+        );
+        assert_eq!(
+            ctx.repo.refname_to_id(ORIG_HEAD_REF).unwrap(),
+            old_head.id()
+        );
+
+        let new_head = ctx.repo.head().unwrap().peel_to_commit().unwrap();
+        assert_eq!(new_head.author().when(), old_head.author().when());
+        assert_eq!(new_head.committer().when(), old_head.committer().when());
+
+        // each change went into the commit it belongs to
+        let commit_diff = |commit: &git2::Commit| {
+            let parent = commit.parent(0).ok().map(|parent| parent.tree().unwrap());
+            let diff = ctx
+                .repo
+                .diff_tree_to_tree(parent.as_ref(), Some(&commit.tree().unwrap()), None)
+                .unwrap();
+            let mut text = vec![];
+            diff.print(git2::DiffFormat::Patch, |_, _, line| {
+                if matches!(line.origin(), '+' | '-') {
+                    text.push(line.origin() as u8);
+                }
+                text.extend_from_slice(line.content());
+                true
+            })
+            .unwrap();
+            String::from_utf8(text).unwrap()
+        };
+        let add_lines = new_head.parent(0).unwrap();
+        assert!(commit_diff(&add_lines).contains("+line 3 fixed\n"));
+        assert!(!commit_diff(&add_lines).contains("line 10 changed"));
+        let initial = add_lines.parent(0).unwrap();
+        assert!(commit_diff(&initial).contains("+new_line1\n"));
+        assert!(commit_diff(&new_head).contains("+line 10 changed\n"));
+        assert!(head_file_contents(&ctx.repo, &path).contains("line 3 fixed\n"));
+
+        // only the new file is still staged
+        let stats = index_stats(&ctx.repo).unwrap();
+        assert_eq!(stats.files_changed(), 1);
+        assert_eq!(stats.insertions(), 1);
+        assert_eq!(stats.deletions(), 0);
+
+        log_utils::assert_log_messages_are(
+            capturing_logger.visible_logs(),
+            vec![
+                &json!({
+                    "level": "INFO",
+                    "msg": "stack reaches the first commit in the repository",
+                }),
+                &json!({"level": "INFO", "msg": "rewrote", "subject": "Add lines"}),
+                &json!({"level": "INFO", "msg": "rewrote", "subject": "Initial commit."}),
+                &json!({"level": "WARN", "msg": "Some changes were not in-place file modifications. \
+                    Added or renamed files cannot be automatically absorbed, \
+                    and removed files only into the commit that added them."}),
+                &json!({
+                    "level": "WARN",
+                    "msg": "Staged change could not be absorbed",
+                    "location": "new-file.txt",
+                    "reason": "added",
+                }),
+            ],
+        );
     }
-}
 
-#[cfg(test)]
-mod tests {
-    use git2::message_trailers_strs;
-    use serde_json::json;
-    use std::path::PathBuf;
+    #[test]
+    fn orig_head_ref_keeps_every_rewritten_tip() {
+        let (ctx, path) = prepare_stack_to_rewrite();
+        let first_head = ctx.repo.head().unwrap().peel_to_commit().unwrap().id();
+        let drain = slog::Discard;
+        let logger = slog::Logger::root(drain, o!());
+        run_with_repo(&logger, &rewrite_config(), &ctx.repo).unwrap();
+        assert_eq!(ctx.repo.refname_to_id(ORIG_HEAD_REF).unwrap(), first_head);
+
+        // ORIG_HEAD moves on with any later rebase, this ref does not
+        let contents = std::fs::read_to_string(ctx.join(&path)).unwrap();
+        let contents = contents.replace("line 10 changed", "line 10 changed again");
+        std::fs::write(ctx.join(&path), contents).unwrap();
+        repo_utils::add(&ctx.repo, &path);
+        let second_head = ctx.repo.head().unwrap().peel_to_commit().unwrap().id();
+        run_with_repo(&logger, &rewrite_config(), &ctx.repo).unwrap();
+        assert_ne!(ctx.repo.head().unwrap().target().unwrap(), second_head);
+        assert_eq!(ctx.repo.refname_to_id(ORIG_HEAD_REF).unwrap(), second_head);
+
+        let reflog = ctx.repo.reflog(ORIG_HEAD_REF).unwrap();
+        let tips: Vec<_> = reflog.iter().map(|entry| entry.id_new()).collect();
+        assert_eq!(tips, vec![second_head, first_head]);
+        assert_eq!(
+            reflog.get(0).unwrap().message(),
+            Some("absorb: before rewrite stack")
+        );
+    }
 
-    use super::*;
-    mod log_utils;
-    pub mod repo_utils;
+    #[test]
+    fn rewrite_refuses_commits_on_remote_branches() {
+        let (ctx, _) = prepare_stack_to_rewrite();
+        let old_head = ctx.repo.head().unwrap().peel_to_commit().unwrap();
+        ctx.repo
+            .reference(
+                "refs/remotes/origin/master",
+                old_head.parent_id(0).unwrap(),
+                false,
+                "",
+            )
+            .unwrap();
+
+        let drain = slog::Discard;
+        let logger = slog::Logger::root(drain, o!());
+        let err = run_with_repo(&logger, &rewrite_config(), &ctx.repo).unwrap_err();
+        assert!(
+            err.to_string()
+                .contains("reachable from refs/remotes/origin/master"),
+            "{}",
+            err
+        );
+        assert_eq!(
+            ctx.repo.head().unwrap().peel_to_commit().unwrap().id(),
+            old_head.id()
+        );
+
+        let config = Config {
+            force_rewrite: true,
+            ..rewrite_config()
+        };
+        run_with_repo(&logger, &config, &ctx.repo).unwrap();
+        assert_eq!(extract_commit_messages(&ctx.repo).len(), 3);
+    }
 
     #[test]
-    fn no_commits_in_repo() {
-        let dir = tempfile::tempdir().unwrap();
-        let repo = git2::Repository::init_opts(
-            dir.path(),
-            git2::RepositoryInitOptions::new().initial_head("master"),
+    fn remotes_to_protect_config() {
+        let (ctx, path) = prepare_stack_to_rewrite();
+        let old_head = ctx.repo.head().unwrap().peel_to_commit().unwrap();
+        ctx.repo
+            .reference("refs/remotes/fork/master", old_head.id(), false, "")
+            .unwrap();
+        repo_utils::set_config_option(&ctx.repo, config::REMOTES_TO_PROTECT_CONFIG_NAME, "origin");
+
+        // only origin is protected, and the stack was only pushed to fork
+        let drain = slog::Discard;
+        let logger = slog::Logger::root(drain, o!());
+        run_with_repo(&logger, &rewrite_config(), &ctx.repo).unwrap();
+        assert_eq!(extract_commit_messages(&ctx.repo).len(), 3);
+        let new_head = ctx.repo.head().unwrap().peel_to_commit().unwrap();
+        assert_ne!(new_head.id(), old_head.id());
+
+        // a remote whose name starts like a protected one is not protected
+        ctx.repo
+            .reference(
+                "refs/remotes/origin-mirror/master",
+                new_head.id(),
+                false,
+                "",
+            )
+            .unwrap();
+        ctx.repo
+            .reference(
+                "refs/remotes/origin/master",
+                new_head.parent_id(0).unwrap(),
+                false,
+                "",
+            )
+            .unwrap();
+        let contents = std::fs::read_to_string(ctx.join(&path)).unwrap();
+        std::fs::write(
+            ctx.join(&path),
+            contents.replace("line 3 fixed", "line 3 fixed again"),
         )
         .unwrap();
-        let capturing_logger = log_utils::CapturingLogger::new();
-        let result = run_with_repo(&capturing_logger.logger, &DEFAULT_CONFIG, &repo);
-        assert!(result
-            .err()
-            .unwrap()
-            .to_string()
-            .starts_with("reference 'refs/heads/master' not found"));
+        repo_utils::add(&ctx.repo, &path);
+        let err = run_with_repo(&logger, &rewrite_config(), &ctx.repo).unwrap_err();
+        assert!(
+            matches!(
+                &err,
+                AbsorbError::PushedCommit { remote_ref, .. } if remote_ref == "refs/remotes/origin/master"
+            ),
+            "{}",
+            err
+        );
     }
 
     #[test]
-    fn multiple_fixups_per_commit() {
+    fn amend_head_when_every_hunk_belongs_to_it() {
         let ctx = repo_utils::prepare_and_stage();
+        let old_head = ctx.repo.head().unwrap().peel_to_commit().unwrap();
 
-        let actual_pre_absorb_commit = ctx.repo.head().unwrap().peel_to_commit().unwrap().id();
-
-        // run 'git-absorb'
         let mut capturing_logger = log_utils::CapturingLogger::new();
-        run_with_repo(&capturing_logger.logger, &DEFAULT_CONFIG, &ctx.repo).unwrap();
+        let config = Config {
+            amend_head: true,
+            ..DEFAULT_CONFIG
+        };
+        run_with_repo(&capturing_logger.logger, &config, &ctx.repo).unwrap();
 
-        let mut revwalk = ctx.repo.revwalk().unwrap();
-        revwalk.push_head().unwrap();
-        assert_eq!(revwalk.count(), 3);
+        assert_eq!(extract_commit_messages(&ctx.repo), vec!["Initial commit."]);
+        assert!(nothing_left_in_index(&ctx.repo).unwrap());
+        let new_head = ctx.repo.head().unwrap().peel_to_commit().unwrap();
+        assert_ne!(new_head.id(), old_head.id());
+        assert_eq!(new_head.author().to_string(), old_head.author().to_string());
+        assert_eq!(new_head.author().when(), old_head.author().when());
+        assert_eq!(new_head.parent_count(), 0);
+        assert_eq!(
+            ctx.repo.refname_to_id("PRE_ABSORB_HEAD").unwrap(),
+            old_head.id()
+        );
+        log_utils::assert_log_messages_are(
+            capturing_logger.visible_logs(),
+            vec![
+                &json!({
+                    "level": "INFO",
+                    "msg": "stack reaches the first commit in the repository",
+                }),
+                &json!({
+                    "level": "INFO",
+                    "msg": "amended HEAD",
+                    "commit_id": new_head.id().to_string(),
+                    "subject": "Initial commit.",
+                }),
+            ],
+        );
+    }
+
+    #[test]
+    fn amend_head_when_possible_config() {
+        let ctx = repo_utils::prepare_and_stage();
+        ctx.repo
+            .config()
+            .unwrap()
+            .set_bool(config::AMEND_HEAD_WHEN_POSSIBLE_CONFIG_NAME, true)
+            .unwrap();
 
+        let drain = slog::Discard;
+        let logger = slog::Logger::root(drain, o!());
+        run_with_repo(&logger, &DEFAULT_CONFIG, &ctx.repo).unwrap();
+
+        assert_eq!(extract_commit_messages(&ctx.repo), vec!["Initial commit."]);
         assert!(nothing_left_in_index(&ctx.repo).unwrap());
+    }
 
-        let pre_absorb_ref_commit = ctx.repo.refname_to_id("PRE_ABSORB_HEAD").unwrap();
-        assert_eq!(pre_absorb_ref_commit, actual_pre_absorb_commit);
+    #[test]
+    fn amend_head_with_hunks_for_older_commits() {
+        let (ctx, _) = prepare_stack_to_rewrite();
+        let old_head = ctx.repo.head().unwrap().peel_to_commit().unwrap();
+
+        let drain = slog::Discard;
+        let logger = slog::Logger::root(drain, o!());
+        let config = Config {
+            amend_head: true,
+            ..DEFAULT_CONFIG
+        };
+        run_with_repo(&logger, &config, &ctx.repo).unwrap();
+
+        // nothing was amended, every hunk got a fixup as usual
+        assert_eq!(
+            extract_commit_messages(&ctx.repo),
+            vec![
+                "fixup! Add lines\n",
+                "fixup! Initial commit.\n",
+                "fixup! Initial commit.\n",
+                "Change line 10",
+                "Add lines",
+                "Initial commit.",
+            ]
+        );
+        assert_eq!(
+            ctx.repo.revparse_single("HEAD~3").unwrap().id(),
+            old_head.id()
+        );
+    }
+
+    #[test]
+    fn amend_head_refuses_head_on_remote_branch() {
+        let ctx = repo_utils::prepare_and_stage();
+        let old_head = ctx.repo.head().unwrap().peel_to_commit().unwrap();
+        ctx.repo
+            .reference("refs/remotes/origin/master", old_head.id(), false, "")
+            .unwrap();
+
+        let mut capturing_logger = log_utils::CapturingLogger::new();
+        let config = Config {
+            amend_head: true,
+            ..DEFAULT_CONFIG
+        };
+        run_with_repo(&capturing_logger.logger, &config, &ctx.repo).unwrap();
 
         assert_eq!(
             extract_commit_messages(&ctx.repo),
@@ -728,1169 +9457,2247 @@ mod tests {
                 "Initial commit.",
             ]
         );
+        let logs = capturing_logger.visible_logs();
+        let refusal = logs
+            .iter()
+            .find(|log| log["msg"] == "Will not amend HEAD, creating fixup commits instead")
+            .unwrap();
+        assert!(
+            refusal["reason"]
+                .as_str()
+                .unwrap()
+                .contains("reachable from refs/remotes/origin/master"),
+            "{}",
+            refusal
+        );
+    }
+
+    #[test]
+    fn rewrite_refuses_signed_commits() {
+        let (ctx, _) = prepare_stack_to_rewrite();
+        {
+            // sign the newest commit, which is rewritten too
+            let head = ctx.repo.head().unwrap().peel_to_commit().unwrap();
+            let buffer = ctx
+                .repo
+                .commit_create_buffer(
+                    &head.author(),
+                    &head.committer(),
+                    head.message().unwrap(),
+                    &head.tree().unwrap(),
+                    &[&head.parent(0).unwrap()],
+                )
+                .unwrap();
+            let signed = ctx
+                .repo
+                .commit_signed(buffer.as_str().unwrap(), "not really a signature", None)
+                .unwrap();
+            ctx.repo.head().unwrap().set_target(signed, "sign").unwrap();
+        }
+
+        let drain = slog::Discard;
+        let logger = slog::Logger::root(drain, o!());
+        let err = run_with_repo(&logger, &rewrite_config(), &ctx.repo).unwrap_err();
+        assert!(matches!(err, AbsorbError::SignedCommit { .. }), "{:?}", err);
+        assert!(
+            err.to_string().contains("cannot rewrite signed commit"),
+            "{}",
+            err
+        );
+        assert!(!nothing_left_in_index(&ctx.repo).unwrap());
+        assert_eq!(extract_commit_messages(&ctx.repo).len(), 3);
+    }
+
+    #[test]
+    fn rewrite_keeps_messages_in_other_encodings() {
+        let (ctx, _) = prepare_stack_to_rewrite();
+        let message = b"Change line 10 \xe0 la main\n";
+        {
+            // the newest commit, which is rewritten too, in latin1
+            let head = ctx.repo.head().unwrap().peel_to_commit().unwrap();
+            let mut buffer = format!(
+                "tree {}\nparent {}\n",
+                head.tree_id(),
+                head.parent_id(0).unwrap()
+            )
+            .into_bytes();
+            for name in ["author", "committer"] {
+                buffer.extend_from_slice(name.as_bytes());
+                buffer.push(b' ');
+                buffer.extend_from_slice(&head.header_field_bytes(name).unwrap());
+                buffer.push(b'\n');
+            }
+            buffer.extend_from_slice(b"encoding ISO-8859-1\n\n");
+            buffer.extend_from_slice(message);
+            let latin1 = ctx
+                .repo
+                .odb()
+                .unwrap()
+                .write(git2::ObjectType::Commit, &buffer)
+                .unwrap();
+            ctx.repo
+                .head()
+                .unwrap()
+                .set_target(latin1, "latin1")
+                .unwrap();
+        }
+
+        let drain = slog::Discard;
+        let logger = slog::Logger::root(drain, o!());
+        run_with_repo(&logger, &rewrite_config(), &ctx.repo).unwrap();
+
+        let head = ctx.repo.head().unwrap().peel_to_commit().unwrap();
+        assert_eq!(head.message_raw_bytes(), message);
+        assert_eq!(
+            &*head.header_field_bytes("encoding").unwrap(),
+            b"ISO-8859-1"
+        );
+    }
+
+    #[test]
+    fn rewrite_conflicts_with_and_rebase() {
+        let (ctx, _) = prepare_stack_to_rewrite();
+
+        let drain = slog::Discard;
+        let logger = slog::Logger::root(drain, o!());
+        let config = Config {
+            and_rebase: true,
+            ..rewrite_config()
+        };
+        assert!(run_with_repo(&logger, &config, &ctx.repo).is_err());
+    }
+
+    #[test]
+    fn plan_round_trip() {
+        let ctx = repo_utils::prepare_and_stage();
+        let head = ctx.repo.head().unwrap().peel_to_commit().unwrap().id();
+
+        let dir = tempfile::tempdir().unwrap();
+        let plan_path = dir.path().join("plan.json");
+        let mut capturing_logger = log_utils::CapturingLogger::new();
+        let config = Config {
+            plan_only: Some(&plan_path),
+            ..DEFAULT_CONFIG
+        };
+        run_with_repo(&capturing_logger.logger, &config, &ctx.repo).unwrap();
 
+        // nothing changed yet
+        assert_eq!(extract_commit_messages(&ctx.repo).len(), 1);
+        assert!(!nothing_left_in_index(&ctx.repo).unwrap());
+        assert!(ctx.repo.refname_to_id("PRE_ABSORB_HEAD").is_err());
         log_utils::assert_log_messages_are(
             capturing_logger.visible_logs(),
             vec![
-                &json!({"level": "INFO", "msg": "committed"}),
-                &json!({"level": "INFO", "msg": "committed"}),
                 &json!({
                     "level": "INFO",
-                    "msg": "To squash the new commits, rebase:",
-                    "command": "git rebase --interactive --autosquash --autostash --root",
+                    "msg": "stack reaches the first commit in the repository",
                 }),
+                &json!({"level": "INFO", "msg": "wrote plan", "hunks": 2}),
             ],
         );
+
+        let plan: serde_json::Value =
+            serde_json::from_slice(&std::fs::read(&plan_path).unwrap()).unwrap();
+        assert_eq!(plan["head"], head.to_string());
+        assert_eq!(plan["stack"][0]["subject"], "Initial commit.");
+        let fixups = plan["fixups"].as_array().unwrap();
+        assert_eq!(fixups.len(), 2);
+        for fixup in fixups {
+            assert_eq!(fixup["target"], head.to_string());
+            assert_eq!(fixup["path"], "test-file.txt");
+        }
+        let added: Vec<_> = fixups
+            .iter()
+            .flat_map(|fixup| fixup["hunk"]["added"]["lines"].as_array().unwrap())
+            .collect();
+        assert!(added.contains(&&json!("new_line1\n")), "{:?}", added);
+
+        let config = Config {
+            apply_plan: Some(&plan_path),
+            ..DEFAULT_CONFIG
+        };
+        let drain = slog::Discard;
+        let logger = slog::Logger::root(drain, o!());
+        run_with_repo(&logger, &config, &ctx.repo).unwrap();
+
+        assert!(nothing_left_in_index(&ctx.repo).unwrap());
+        assert_eq!(
+            extract_commit_messages(&ctx.repo),
+            vec![
+                "fixup! Initial commit.\n",
+                "fixup! Initial commit.\n",
+                "Initial commit.",
+            ]
+        );
+    }
+
+    /// Plans the fixups for edits to the lines of commits 0 and 2 of a stack
+    /// of three, and returns the plan along with the commits.
+    fn plan_for_edited_lines(
+        plan_path: &Path,
+    ) -> (repo_utils::Context, serde_json::Value, Vec<git2::Oid>) {
+        let (ctx, path) = repo_utils::prepare_synthetic_stack(3, 0);
+        let contents = std::fs::read_to_string(ctx.join(&path)).unwrap();
+        let contents = contents
+            .replace("commit 0\n", "edited 0\n")
+            .replace("commit 2\n", "edited 2\n");
+        std::fs::write(ctx.join(&path), contents).unwrap();
+        repo_utils::add(&ctx.repo, &path);
+
+        let drain = slog::Discard;
+        let logger = slog::Logger::root(drain, o!());
+        let config = Config {
+            plan_only: Some(plan_path),
+            ..DEFAULT_CONFIG
+        };
+        run_with_repo(&logger, &config, &ctx.repo).unwrap();
+        let plan = serde_json::from_slice(&std::fs::read(plan_path).unwrap()).unwrap();
+
+        let mut revwalk = ctx.repo.revwalk().unwrap();
+        revwalk.push_head().unwrap();
+        let mut commits: Vec<_> = revwalk.map(Result::unwrap).collect();
+        // oldest first, without the initial commit
+        commits.reverse();
+        commits.remove(0);
+        (ctx, plan, commits)
+    }
+
+    /// Points the planned hunk that adds `line` at `target`.
+    fn retarget(plan: &mut serde_json::Value, line: &str, target: git2::Oid) {
+        let fixup = plan["fixups"]
+            .as_array_mut()
+            .unwrap()
+            .iter_mut()
+            .find(|fixup| fixup["hunk"]["added"]["lines"][0] == line)
+            .unwrap();
+        fixup["target"] = json!(target.to_string());
+    }
+
+    #[test]
+    fn edited_plan_absorbs_into_the_new_target() {
+        let dir = tempfile::tempdir().unwrap();
+        let plan_path = dir.path().join("plan.json");
+        let (ctx, mut plan, commits) = plan_for_edited_lines(&plan_path);
+        let targets: Vec<_> = plan["fixups"]
+            .as_array()
+            .unwrap()
+            .iter()
+            .map(|fixup| fixup["target"].clone())
+            .collect();
+        assert_eq!(
+            targets,
+            vec![json!(commits[0].to_string()), json!(commits[2].to_string())]
+        );
+
+        // the edit to commit 0 can be moved down to commit 1 as well
+        retarget(&mut plan, "edited 0\n", commits[1]);
+        std::fs::write(&plan_path, plan.to_string()).unwrap();
+        let config = Config {
+            apply_plan: Some(&plan_path),
+            ..DEFAULT_CONFIG
+        };
+        let drain = slog::Discard;
+        let logger = slog::Logger::root(drain, o!());
+        run_with_repo(&logger, &config, &ctx.repo).unwrap();
+
+        assert!(nothing_left_in_index(&ctx.repo).unwrap());
+        assert_eq!(
+            extract_commit_messages(&ctx.repo)[..2],
+            ["fixup! Commit 2\n", "fixup! Commit 1\n"]
+        );
+        let contents = head_file_contents(&ctx.repo, Path::new("test-file.txt"));
+        assert!(contents.starts_with("edited 0\n"), "{}", contents);
+        assert!(contents.contains("edited 2\n"), "{}", contents);
+    }
+
+    #[test]
+    fn edited_plan_target_must_be_reachable() {
+        let dir = tempfile::tempdir().unwrap();
+        let plan_path = dir.path().join("plan.json");
+        let (ctx, mut plan, commits) = plan_for_edited_lines(&plan_path);
+
+        // the edit to commit 2 cannot be moved past it
+        retarget(&mut plan, "edited 2\n", commits[0]);
+        std::fs::write(&plan_path, plan.to_string()).unwrap();
+        let config = Config {
+            apply_plan: Some(&plan_path),
+            ..DEFAULT_CONFIG
+        };
+        let drain = slog::Discard;
+        let logger = slog::Logger::root(drain, o!());
+        let err = run_with_repo(&logger, &config, &ctx.repo).unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            format!(
+                "the plan absorbs the hunk at test-file.txt:5 into {}, \
+                 but it cannot be moved past {}",
+                commits[0], commits[2]
+            )
+        );
+        assert_eq!(extract_commit_messages(&ctx.repo).len(), 4);
     }
 
     #[test]
-    fn exceed_stack_limit_with_modified_hunk() {
+    fn stale_plan_is_rejected() {
         let (ctx, file_path) = repo_utils::prepare_repo();
-
-        let parent_commit = ctx.repo.head().unwrap().peel_to_commit().unwrap();
-        repo_utils::empty_commit_chain(&ctx.repo, "HEAD", &[&parent_commit], config::MAX_STACK);
         repo_utils::stage_file_changes(&ctx, &file_path);
 
-        // run 'git-absorb'
-        let mut capturing_logger = log_utils::CapturingLogger::new();
-        run_with_repo(&capturing_logger.logger, &DEFAULT_CONFIG, &ctx.repo).unwrap();
+        let dir = tempfile::tempdir().unwrap();
+        let plan_path = dir.path().join("plan.json");
+        let drain = slog::Discard;
+        let logger = slog::Logger::root(drain, o!());
+        let config = Config {
+            plan_only: Some(&plan_path),
+            ..DEFAULT_CONFIG
+        };
+        run_with_repo(&logger, &config, &ctx.repo).unwrap();
+        let config = Config {
+            apply_plan: Some(&plan_path),
+            ..DEFAULT_CONFIG
+        };
 
-        let mut revwalk = ctx.repo.revwalk().unwrap();
-        revwalk.push_head().unwrap();
+        // staging something else
+        std::fs::write(ctx.join(&file_path), "something else\n").unwrap();
+        repo_utils::add(&ctx.repo, &file_path);
+        let err = run_with_repo(&logger, &config, &ctx.repo).unwrap_err();
+        assert!(matches!(err, AbsorbError::PlanMismatch(_)), "{:?}", err);
+        assert!(
+            err.to_string()
+                .starts_with("the staged changes are not the ones the plan was made for"),
+            "{}",
+            err
+        );
+
+        // committing something
+        let old_head = ctx.repo.head().unwrap().peel_to_commit().unwrap();
+        let new_head = repo_utils::empty_commit(&ctx.repo, "HEAD", "Another commit", &[&old_head]);
+        repo_utils::stage_file_changes(&ctx, &file_path);
+        let err = run_with_repo(&logger, &config, &ctx.repo).unwrap_err();
         assert_eq!(
-            revwalk.count(),
-            config::MAX_STACK + 1,
-            "Wrong number of commits."
+            err.to_string(),
+            format!(
+                "the plan was made when HEAD was at {}, but HEAD is now at {}",
+                old_head.id(),
+                new_head.id()
+            )
         );
+        assert_eq!(extract_commit_messages(&ctx.repo).len(), 2);
+    }
 
-        let is_something_in_index = !nothing_left_in_index(&ctx.repo).unwrap();
-        assert!(is_something_in_index);
+    /// Makes a repository in the `sub` directory of `ctx`, with a commit of
+    /// its own, and adds it to the superproject as a submodule.
+    fn add_submodule(ctx: &repo_utils::Context) -> git2::Repository {
+        let dir = ctx.join(Path::new("sub"));
+        let submodule = git2::Repository::init(&dir).unwrap();
+        repo_utils::become_author(&submodule, "nobody", "nobody@example.com");
+        std::fs::write(dir.join("sub-file.txt"), "line\nline\n\nmore\nlines\n").unwrap();
+        {
+            let tree = repo_utils::add(&submodule, Path::new("sub-file.txt"));
+            repo_utils::commit(&submodule, "HEAD", "Submodule commit.", &tree, &[]);
+        }
 
-        log_utils::assert_log_messages_are(
-            capturing_logger.visible_logs(),
-            vec![
-                &json!({
-                    "level": "WARN",
-                    "msg": "Some file modifications did not have an available commit to fix up. \
-                           You will have to manually create fixup commits.",
-                }),
-                &json!({
-                    "level": "WARN",
-                    "msg": format!(
-                        "Will not fix up past maximum stack limit. \
-                        Use --base or configure {} to override",
-                        config::MAX_STACK_CONFIG_NAME
-                    ),
-                    "limit": config::MAX_STACK,
-                }),
-            ],
-        );
+        let added = std::process::Command::new("git")
+            .args(["submodule", "add", "./sub", "sub"])
+            .stdout(std::process::Stdio::null())
+            .current_dir(ctx.dir.path())
+            .status()
+            .unwrap();
+        assert!(added.success());
+        let mut index = ctx.repo.index().unwrap();
+        index.read(true).unwrap();
+        let tree = ctx.repo.find_tree(index.write_tree().unwrap()).unwrap();
+        let head = ctx.repo.head().unwrap().peel_to_commit().unwrap();
+        repo_utils::commit(&ctx.repo, "HEAD", "Add submodule.", &tree, &[&head]);
+        submodule
     }
 
     #[test]
-    fn exceed_stack_limit_with_non_modified_patch() {
-        // non-modified patches commute with everything, and
-        // have special handling above, so make sure we test with one
-        let (ctx, _) = repo_utils::prepare_repo();
-        let parent_commit = ctx.repo.head().unwrap().peel_to_commit().unwrap();
-        repo_utils::empty_commit_chain(&ctx.repo, "HEAD", &[&parent_commit], config::MAX_STACK);
-        let a_new_file_path = PathBuf::from("a_whole_new_file.txt");
-        std::fs::write(ctx.join(&a_new_file_path), "contents").unwrap();
-        repo_utils::stage_file_changes(&ctx, &a_new_file_path);
-        let another_new_file_path = PathBuf::from("another_whole_new_file.txt");
-        std::fs::write(ctx.join(&another_new_file_path), "contents").unwrap();
-        repo_utils::stage_file_changes(&ctx, &another_new_file_path);
+    fn recurse_submodules_absorbs_into_each_stack() {
+        let (ctx, path) = repo_utils::prepare_repo();
+        let submodule = add_submodule(&ctx);
+        repo_utils::stage_file_changes(&ctx, &path);
+        let sub_path = ctx.join(Path::new("sub/sub-file.txt"));
+        std::fs::write(&sub_path, "line\nline\n\nmore\nlines\nand more\n").unwrap();
+        repo_utils::add(&submodule, Path::new("sub-file.txt"));
 
-        // run 'git-absorb'
         let mut capturing_logger = log_utils::CapturingLogger::new();
-        run_with_repo(&capturing_logger.logger, &DEFAULT_CONFIG, &ctx.repo).unwrap();
+        let config = Config {
+            recurse_submodules: true,
+            ..DEFAULT_CONFIG
+        };
+        run_with_repo(&capturing_logger.logger, &config, &ctx.repo).unwrap();
 
-        let mut revwalk = ctx.repo.revwalk().unwrap();
-        revwalk.push_head().unwrap();
+        assert!(nothing_left_in_index(&ctx.repo).unwrap());
         assert_eq!(
-            revwalk.count(),
-            config::MAX_STACK + 1,
-            "Wrong number of commits."
+            extract_commit_messages(&ctx.repo),
+            vec![
+                "fixup! Initial commit.\n",
+                "fixup! Initial commit.\n",
+                "Add submodule.",
+                "Initial commit.",
+            ]
         );
-
-        let is_something_in_index = !nothing_left_in_index(&ctx.repo).unwrap();
-        assert!(is_something_in_index);
-
-        log_utils::assert_log_messages_are(
-            capturing_logger.visible_logs(),
-            vec![&json!({
-                "level": "WARN",
-                "msg": "No changes were in-place file modifications. \
-                       Added, removed, or renamed files cannot be automatically absorbed.",
-            })],
+        assert!(nothing_left_in_index(&submodule).unwrap());
+        assert_eq!(
+            extract_commit_messages(&submodule),
+            vec!["fixup! Submodule commit.\n", "Submodule commit."]
         );
+        assert!(capturing_logger
+            .visible_logs()
+            .iter()
+            .any(|log| log["msg"] == "committed" && log["submodule"] == "sub"));
     }
 
     #[test]
-    fn exceed_stack_limit_with_modified_patch_and_non_modified_hunks() {
-        // non-modified patches commute with everything, and
-        // have special handling above. Test with both modified hunks (a patch is made of hunks)
-        // and non-modified patches to ensure we don't confuse the messaging to the user.
-        let (ctx, file_path) = repo_utils::prepare_repo();
-        let new_file_path = PathBuf::from("a_whole_new_file.txt");
-        let parent_commit = ctx.repo.head().unwrap().peel_to_commit().unwrap();
-        repo_utils::empty_commit_chain(&ctx.repo, "HEAD", &[&parent_commit], config::MAX_STACK);
-        std::fs::write(ctx.join(&new_file_path), "contents").unwrap();
-        repo_utils::stage_file_changes(&ctx, &new_file_path);
-        repo_utils::stage_file_changes(&ctx, &file_path);
+    fn recurse_submodules_reports_every_failure() {
+        let (ctx, _) = repo_utils::prepare_repo();
+        let submodule = add_submodule(&ctx);
+        std::fs::write(ctx.join(Path::new("new-file.txt")), "new\n").unwrap();
+        repo_utils::add(&ctx.repo, Path::new("new-file.txt"));
+        std::fs::write(ctx.join(Path::new("sub/new-file.txt")), "new\n").unwrap();
+        repo_utils::add(&submodule, Path::new("new-file.txt"));
 
-        // run 'git-absorb'
         let mut capturing_logger = log_utils::CapturingLogger::new();
-        run_with_repo(&capturing_logger.logger, &DEFAULT_CONFIG, &ctx.repo).unwrap();
-
-        let mut revwalk = ctx.repo.revwalk().unwrap();
-        revwalk.push_head().unwrap();
-        assert_eq!(
-            revwalk.count(),
-            config::MAX_STACK + 1,
-            "Wrong number of commits."
-        );
-
-        let is_something_in_index = !nothing_left_in_index(&ctx.repo).unwrap();
-        assert!(is_something_in_index);
+        let config = Config {
+            check: true,
+            recurse_submodules: true,
+            ..DEFAULT_CONFIG
+        };
+        let err = run_with_repo(&capturing_logger.logger, &config, &ctx.repo).unwrap_err();
 
-        log_utils::assert_log_messages_are(
-            capturing_logger.visible_logs(),
-            vec![
-                &json!({
-                    "level": "WARN",
-                    "msg": "Some changes were not in-place file modifications. \
-                           Added, removed, or renamed files cannot be automatically absorbed.",
-                }),
-                &json!({
-                    "level": "WARN",
-                    "msg": "Some file modifications did not have an available commit to fix up. \
-                           You will have to manually create fixup commits.",
-                }),
-                &json!({
-                    "level": "WARN",
-                    "msg": format!(
-                        "Will not fix up past maximum stack limit. \
-                        Use --base or configure {} to override",
-                        config::MAX_STACK_CONFIG_NAME
-                    ),
-                }),
-            ],
+        // neither failure stopped the other, and both are counted
+        assert!(
+            matches!(err, AbsorbError::CheckFailed { unabsorbed: 2 }),
+            "{:?}",
+            err
         );
+        assert!(capturing_logger
+            .visible_logs()
+            .iter()
+            .any(|log| log["msg"] == "absorb failed in submodule" && log["submodule"] == "sub"));
     }
 
     #[test]
-    fn reached_root() {
-        let (ctx, _) = repo_utils::prepare_repo();
-        let file_path = PathBuf::from("a_whole_new_file.txt");
-        std::fs::write(ctx.join(&file_path), "contents").unwrap();
-        repo_utils::stage_file_changes(&ctx, &file_path);
-
-        // run 'git-absorb'
-        let mut capturing_logger = log_utils::CapturingLogger::new();
-        run_with_repo(&capturing_logger.logger, &DEFAULT_CONFIG, &ctx.repo).unwrap();
+    fn undo_restores_the_branch_and_the_index() {
+        let ctx = repo_utils::prepare_and_stage();
+        let head = ctx.repo.head().unwrap().peel_to_commit().unwrap().id();
+        let staged = ctx.repo.index().unwrap().write_tree().unwrap();
 
-        let mut revwalk = ctx.repo.revwalk().unwrap();
-        revwalk.push_head().unwrap();
-        assert_eq!(revwalk.count(), 1, "Wrong number of commits.");
+        let drain = slog::Discard;
+        let logger = slog::Logger::root(drain, o!());
+        run_with_repo(&logger, &DEFAULT_CONFIG, &ctx.repo).unwrap();
+        assert_eq!(extract_commit_messages(&ctx.repo).len(), 3);
+        assert!(nothing_left_in_index(&ctx.repo).unwrap());
 
-        let is_something_in_index = !nothing_left_in_index(&ctx.repo).unwrap();
-        assert!(is_something_in_index);
+        let absorbed = ctx.repo.head().unwrap().peel_to_commit().unwrap().id();
+        let mut capturing_logger = log_utils::CapturingLogger::new();
+        let config = Config {
+            undo: true,
+            ..DEFAULT_CONFIG
+        };
+        run_with_repo(&capturing_logger.logger, &config, &ctx.repo).unwrap();
 
+        assert_eq!(
+            ctx.repo.head().unwrap().peel_to_commit().unwrap().id(),
+            head
+        );
+        assert!(ctx.repo.head().unwrap().is_branch());
+        assert_eq!(ctx.repo.index().unwrap().write_tree().unwrap(), staged);
+        assert_eq!(ctx.repo.refname_to_id(ORIG_HEAD_REF).unwrap(), absorbed);
+        assert!(ctx.repo.find_reference(undo::UNDO_REF).is_err());
         log_utils::assert_log_messages_are(
             capturing_logger.visible_logs(),
             vec![&json!({
-                "level": "WARN",
-                "msg": "No changes were in-place file modifications. \
-                       Added, removed, or renamed files cannot be automatically absorbed."
+                "level": "INFO",
+                "msg": "undid the last absorb",
+                "head": head.to_string()[..7],
             })],
         );
+
+        // it was undone already
+        let err = run_with_repo(&logger, &config, &ctx.repo).unwrap_err();
+        assert_eq!(err.to_string(), "cannot undo: there is no absorb to undo");
     }
 
     #[test]
-    fn user_defined_base_hides_target_commit() {
-        let ctx = repo_utils::prepare_and_stage();
+    fn undo_keeps_the_flags_of_the_other_index_entries() {
+        let (ctx, file_path) = repo_utils::prepare_repo();
+        add_flagged_bystanders(&ctx);
+        repo_utils::stage_file_changes(&ctx, &file_path);
+        let staged = ctx.repo.index().unwrap().write_tree().unwrap();
 
-        // run 'git-absorb'
-        let mut capturing_logger = log_utils::CapturingLogger::new();
+        let drain = slog::Discard;
+        let logger = slog::Logger::root(drain, o!());
+        run_with_repo(&logger, &DEFAULT_CONFIG, &ctx.repo).unwrap();
         let config = Config {
-            base: Some("HEAD"),
+            undo: true,
             ..DEFAULT_CONFIG
         };
-        run_with_repo(&capturing_logger.logger, &config, &ctx.repo).unwrap();
+        run_with_repo(&logger, &config, &ctx.repo).unwrap();
 
-        let mut revwalk = ctx.repo.revwalk().unwrap();
-        revwalk.push_head().unwrap();
-        assert_eq!(revwalk.count(), 1, "Wrong number of commits.");
+        assert_eq!(ctx.repo.index().unwrap().write_tree().unwrap(), staged);
+        assert_bystanders_flagged(&ctx.repo);
+    }
 
-        let is_something_in_index = !nothing_left_in_index(&ctx.repo).unwrap();
-        assert!(is_something_in_index);
+    #[test]
+    fn undo_with_index_lock_held() {
+        let ctx = repo_utils::prepare_and_stage();
 
-        log_utils::assert_log_messages_are(
-            capturing_logger.visible_logs(),
-            vec![
-                &json!({
-                    "level": "WARN",
-                    "msg": "Some file modifications did not have an available commit to fix up. \
-                           You will have to manually create fixup commits.",
-                }),
-                &json!({
-                    "level": "WARN",
-                    "msg": "Will not fix up past specified base commit. \
-                           Consider using --base to specify a different base commit",
-                    "base": "HEAD",
-                }),
-            ],
+        let drain = slog::Discard;
+        let logger = slog::Logger::root(drain, o!());
+        run_with_repo(&logger, &DEFAULT_CONFIG, &ctx.repo).unwrap();
+        let absorbed = ctx.repo.head().unwrap().peel_to_commit().unwrap().id();
+        let lock = ctx.repo.path().join("index.lock");
+        std::fs::write(&lock, "").unwrap();
+
+        let config = Config {
+            undo: true,
+            ..DEFAULT_CONFIG
+        };
+        let err = run_with_repo(&logger, &config, &ctx.repo).unwrap_err();
+        assert!(
+            matches!(&err, AbsorbError::IndexLocked { path } if path.with_extension("lock") == lock),
+            "{:?}",
+            err
+        );
+        // nothing was undone, so it can still be once the lock is gone
+        assert_eq!(
+            ctx.repo.head().unwrap().peel_to_commit().unwrap().id(),
+            absorbed
         );
+        std::fs::remove_file(&lock).unwrap();
+        run_with_repo(&logger, &config, &ctx.repo).unwrap();
+        assert!(ctx.repo.find_reference(undo::UNDO_REF).is_err());
     }
 
     #[test]
-    fn merge_commit_found() {
-        let (ctx, file_path) = repo_utils::prepare_repo();
-        repo_utils::merge_commit(
-            &ctx.repo,
-            &[&ctx.repo.head().unwrap().peel_to_commit().unwrap()],
-        );
-        repo_utils::stage_file_changes(&ctx, &file_path);
+    fn run_with_undo_ref_locked() {
+        let ctx = repo_utils::prepare_and_stage();
+        let lock = ctx.repo.path().join("refs/absorb/undo.lock");
+        std::fs::create_dir_all(lock.parent().unwrap()).unwrap();
+        std::fs::write(&lock, "").unwrap();
 
-        // run 'git-absorb'
         let mut capturing_logger = log_utils::CapturingLogger::new();
         run_with_repo(&capturing_logger.logger, &DEFAULT_CONFIG, &ctx.repo).unwrap();
 
-        let mut revwalk = ctx.repo.revwalk().unwrap();
-        revwalk.push_head().unwrap();
-        assert_eq!(revwalk.count(), 4, "Wrong number of commits.");
-
-        let is_something_in_index = !nothing_left_in_index(&ctx.repo).unwrap();
-        assert!(is_something_in_index);
-
+        assert_eq!(extract_commit_messages(&ctx.repo).len(), 3);
+        assert!(ctx.repo.find_reference(undo::UNDO_REF).is_err());
+        let warnings: Vec<_> = capturing_logger
+            .visible_logs()
+            .into_iter()
+            .filter(|log| log["level"] == "WARN")
+            .collect();
         log_utils::assert_log_messages_are(
-            capturing_logger.visible_logs(),
-            vec![
-                &json!({
-                    "level": "WARN",
-                    "msg": "Some file modifications did not have an available commit to fix up. \
-                           You will have to manually create fixup commits.",
-                }),
-                &json!({
-                    "level": "WARN",
-                    "msg": "Cannot fix up past a merge commit",
-                }),
-            ],
+            warnings,
+            vec![&json!({
+                "level": "WARN",
+                "msg": "Could not record how to undo the run, so --undo cannot undo it",
+            })],
         );
     }
 
     #[test]
-    fn merge_commit_before_target_commit() {
-        let (ctx, file_path) = repo_utils::prepare_repo();
-        let merge_commit = repo_utils::merge_commit(
-            &ctx.repo,
-            &[&ctx.repo.head().unwrap().peel_to_commit().unwrap()],
-        );
+    fn undo_refuses_once_the_branch_moved() {
+        let ctx = repo_utils::prepare_and_stage();
 
-        std::fs::write(&ctx.join(&file_path), "new content").unwrap();
-        let tree = repo_utils::add(&ctx.repo, &file_path);
+        let drain = slog::Discard;
+        let logger = slog::Logger::root(drain, o!());
+        run_with_repo(&logger, &DEFAULT_CONFIG, &ctx.repo).unwrap();
+        let head = ctx.repo.head().unwrap().peel_to_commit().unwrap();
         repo_utils::commit(
             &ctx.repo,
             "HEAD",
-            "Change after merge",
-            &tree,
-            &[&merge_commit],
+            "Another commit.",
+            &head.tree().unwrap(),
+            &[&head],
         );
 
-        repo_utils::stage_file_changes(&ctx, &file_path);
+        let config = Config {
+            undo: true,
+            ..DEFAULT_CONFIG
+        };
+        let err = run_with_repo(&logger, &config, &ctx.repo).unwrap_err();
 
-        // run 'git-absorb'
-        let mut capturing_logger = log_utils::CapturingLogger::new();
-        run_with_repo(&capturing_logger.logger, &DEFAULT_CONFIG, &ctx.repo).unwrap();
+        assert!(matches!(err, AbsorbError::CannotUndo(_)), "{:?}", err);
+        assert_eq!(
+            err.to_string(),
+            "cannot undo: HEAD moved since the last absorb"
+        );
+        assert_eq!(extract_commit_messages(&ctx.repo).len(), 4);
+    }
 
-        let mut revwalk = ctx.repo.revwalk().unwrap();
-        revwalk.push_head().unwrap();
-        assert_eq!(revwalk.count(), 6, "Wrong number of commits.");
+    #[test]
+    fn refuses_with_unmerged_paths() {
+        let ctx = repo_utils::prepare_and_stage();
+        let head = ctx.repo.head().unwrap().peel_to_commit().unwrap().id();
+        // libgit2 resolves the conflicts at a path when adding an entry for
+        // it, so the stages are added the way git would
+        let mut info = String::new();
+        for (stage, content) in [(1, "base\n"), (2, "ours\n"), (3, "theirs\n")] {
+            let blob = ctx.repo.blob(content.as_bytes()).unwrap();
+            info += &format!("100644 {} {}\tconflicted.txt\n", blob, stage);
+        }
+        let mut child = std::process::Command::new("git")
+            .args(["update-index", "--index-info"])
+            .current_dir(ctx.dir.path())
+            .stdin(std::process::Stdio::piped())
+            .spawn()
+            .unwrap();
+        child
+            .stdin
+            .take()
+            .unwrap()
+            .write_all(info.as_bytes())
+            .unwrap();
+        assert!(child.wait().unwrap().success());
+        let mut index = ctx.repo.index().unwrap();
+        index.read(true).unwrap();
+        assert!(index.has_conflicts());
 
-        assert!(nothing_left_in_index(&ctx.repo).unwrap());
+        let drain = slog::Discard;
+        let logger = slog::Logger::root(drain, o!());
+        let error = run_with_repo(&logger, &DEFAULT_CONFIG, &ctx.repo).unwrap_err();
 
-        log_utils::assert_log_messages_are(
-            capturing_logger.visible_logs(),
-            vec![
-                &json!({"level": "INFO", "msg": "committed",}),
-                &json!({
-                    "level": "INFO",
-                    "msg": "To squash the new commits, rebase:",
-                    "command": format!(
-                        "git rebase --interactive --autosquash --autostash {}",
-                        merge_commit.id()),
-                }),
-            ],
+        assert!(
+            matches!(&error, AbsorbError::UnmergedPaths(paths) if paths == &["conflicted.txt"]),
+            "{:?}",
+            error
+        );
+        assert_eq!(
+            error.to_string(),
+            "cannot absorb while the index has unmerged paths, resolve them or abort \
+             the merge first: conflicted.txt"
+        );
+        assert_eq!(
+            ctx.repo.head().unwrap().peel_to_commit().unwrap().id(),
+            head
         );
     }
 
     #[test]
-    fn first_hidden_commit_is_merge() {
-        let (ctx, file_path) = repo_utils::prepare_repo();
-        let merge_commit = repo_utils::merge_commit(
-            &ctx.repo,
-            &[&ctx.repo.head().unwrap().peel_to_commit().unwrap()],
+    fn refuses_during_rebase() {
+        let ctx = repo_utils::prepare_and_stage();
+        let head = ctx.repo.head().unwrap().peel_to_commit().unwrap().id();
+        std::fs::create_dir(ctx.repo.path().join("rebase-merge")).unwrap();
+        std::fs::write(ctx.repo.path().join("rebase-merge").join("interactive"), "").unwrap();
+
+        let drain = slog::Discard;
+        let logger = slog::Logger::root(drain, o!());
+        let error = run_with_repo(&logger, &DEFAULT_CONFIG, &ctx.repo).unwrap_err();
+
+        assert!(
+            matches!(error, AbsorbError::OperationInProgress("rebase")),
+            "{:?}",
+            error
         );
-        repo_utils::empty_commit(&ctx.repo, "HEAD", "empty commit", &[&merge_commit]);
-        repo_utils::stage_file_changes(&ctx, &file_path);
+        assert_eq!(
+            error.to_string(),
+            "cannot absorb while a rebase is in progress, finish or abort it first"
+        );
+        assert_eq!(
+            ctx.repo.head().unwrap().peel_to_commit().unwrap().id(),
+            head
+        );
+        assert!(!nothing_left_in_index(&ctx.repo).unwrap());
+    }
+
+    #[test]
+    fn refuses_during_merge_but_not_dry_run() {
+        let ctx = repo_utils::prepare_and_stage();
+        let head = ctx.repo.head().unwrap().peel_to_commit().unwrap().id();
+        std::fs::write(ctx.repo.path().join("MERGE_HEAD"), format!("{}\n", head)).unwrap();
+
+        let drain = slog::Discard;
+        let logger = slog::Logger::root(drain, o!());
+        let error = run_with_repo(&logger, &DEFAULT_CONFIG, &ctx.repo).unwrap_err();
+        assert!(error.to_string().contains("a merge is in progress"));
 
-        // run 'git-absorb'
-        let mut capturing_logger = log_utils::CapturingLogger::new();
-        let base_id = merge_commit.id().to_string();
         let config = Config {
-            base: Some(&base_id),
+            dry_run: true,
             ..DEFAULT_CONFIG
         };
-        run_with_repo(&capturing_logger.logger, &config, &ctx.repo).unwrap();
+        run_with_repo(&logger, &config, &ctx.repo).unwrap();
+        assert_eq!(
+            ctx.repo.head().unwrap().peel_to_commit().unwrap().id(),
+            head
+        );
+    }
 
-        let mut revwalk = ctx.repo.revwalk().unwrap();
-        revwalk.push_head().unwrap();
-        assert_eq!(revwalk.count(), 5, "Wrong number of commits.");
+    #[test]
+    fn fixup_timestamps_increase() {
+        let (ctx, _) = repo_utils::prepare_repo();
+        for name in ["a", "b", "c"] {
+            let path = PathBuf::from(format!("{name}.txt"));
+            std::fs::write(ctx.join(&path), format!("{name}\n")).unwrap();
+            let tree = repo_utils::add(&ctx.repo, &path);
+            let head = ctx.repo.head().unwrap().peel_to_commit().unwrap();
+            repo_utils::commit(&ctx.repo, "HEAD", &format!("Add {name}"), &tree, &[&head]);
+        }
+        for name in ["a", "b", "c"] {
+            let path = PathBuf::from(format!("{name}.txt"));
+            std::fs::write(ctx.join(&path), format!("{name} fixed\n")).unwrap();
+            repo_utils::add(&ctx.repo, &path);
+        }
 
-        let is_something_in_index = !nothing_left_in_index(&ctx.repo).unwrap();
-        assert!(is_something_in_index);
+        let drain = slog::Discard;
+        let logger = slog::Logger::root(drain, o!());
+        run_with_repo(&logger, &DEFAULT_CONFIG, &ctx.repo).unwrap();
 
-        log_utils::assert_log_messages_are(
-            capturing_logger.visible_logs(),
-            vec![
-                &json!({
-                    "level": "WARN",
-                    "msg": "Some file modifications did not have an available commit to fix up. \
-                           You will have to manually create fixup commits.",
-                }),
-                &json!({
-                    "level": "WARN",
-                    "msg": "Cannot fix up past a merge commit",
-                }),
-            ],
+        let head = ctx.repo.head().unwrap().peel_to_commit().unwrap();
+        let fixups = [
+            head.parent(0).unwrap().parent(0).unwrap(),
+            head.parent(0).unwrap(),
+            head,
+        ];
+        let times: Vec<_> = fixups
+            .iter()
+            .map(|fixup| fixup.committer().when().seconds())
+            .collect();
+        assert!(times[0] < times[1] && times[1] < times[2], "{:?}", times);
+
+        // so sorting them by time keeps the order they were made in
+        let mut by_time: Vec<_> = fixups.iter().rev().collect();
+        by_time.sort_by_key(|fixup| fixup.committer().when());
+        let by_time: Vec<_> = by_time.iter().map(|fixup| fixup.id()).collect();
+        let made: Vec<_> = fixups.iter().map(git2::Commit::id).collect();
+        assert_eq!(by_time, made);
+        assert_eq!(
+            extract_commit_messages(&ctx.repo)[..3],
+            ["fixup! Add c\n", "fixup! Add b\n", "fixup! Add a\n"]
         );
     }
 
     #[test]
-    fn first_hidden_commit_is_by_another_author() {
-        let (ctx, file_path) = repo_utils::prepare_repo();
-        let first_commit = ctx.repo.head().unwrap().peel_to_commit().unwrap();
-        ctx.repo
-            .branch("some-branch", &first_commit, false)
-            .unwrap();
-        repo_utils::become_author(&ctx.repo, "nobody2", "nobody2@example.com");
-        repo_utils::empty_commit(&ctx.repo, "HEAD", "empty commit", &[&first_commit]);
-        repo_utils::stage_file_changes(&ctx, &file_path);
+    fn time_limit_absorbs_what_was_matched() {
+        let ctx = repo_utils::prepare_and_stage();
 
-        // run 'git-absorb'
+        // reached right after matching the first hunk
         let mut capturing_logger = log_utils::CapturingLogger::new();
-        run_with_repo(&capturing_logger.logger, &DEFAULT_CONFIG, &ctx.repo).unwrap();
-
-        let mut revwalk = ctx.repo.revwalk().unwrap();
-        revwalk.push_head().unwrap();
-        assert_eq!(revwalk.count(), 2, "Wrong number of commits.");
-
-        let is_something_in_index = !nothing_left_in_index(&ctx.repo).unwrap();
-        assert!(is_something_in_index);
+        let config = Config {
+            time_limit: Some(std::time::Duration::ZERO),
+            ..DEFAULT_CONFIG
+        };
+        let mut stats = stats::Stats::default();
+        absorb(
+            &capturing_logger.logger,
+            &config,
+            &ctx.repo,
+            &mut prompt::Terminal,
+            &mut std::io::sink(),
+            &mut stats,
+            &mut Summary::default(),
+        )
+        .unwrap();
 
+        assert_eq!(
+            extract_commit_messages(&ctx.repo),
+            vec!["fixup! Initial commit.\n", "Initial commit."]
+        );
+        assert!(!nothing_left_in_index(&ctx.repo).unwrap());
+        assert_eq!((stats.hunks, stats.absorbed), (2, 1));
         log_utils::assert_log_messages_are(
             capturing_logger.visible_logs(),
             vec![
+                &json!({
+                    "level": "INFO",
+                    "msg": "stack reaches the first commit in the repository",
+                }),
                 &json!({
                     "level": "WARN",
-                    "msg": "Some file modifications did not have an available commit to fix up. \
-                           You will have to manually create fixup commits.",
+                    "msg": "Time limit reached, the remaining changes were left staged without matching them",
+                    "limit": "0s",
                 }),
+                &json!({"level": "INFO", "msg": "committed"}),
                 &json!({
                     "level": "WARN",
-                    "msg": "Will not fix up past commits by another author. \
-                           Use --force-author to override",
+                    "msg": "Staged change could not be absorbed",
+                    "reason": "time-limit",
                 }),
+                &json!({"level": "INFO", "msg": "To squash the new commits, rebase:"}),
             ],
         );
     }
 
-    #[test]
-    fn first_hidden_commit_is_regular_commit() {
-        let (ctx, file_path) = repo_utils::prepare_repo();
-        let first_commit = ctx.repo.head().unwrap().peel_to_commit().unwrap();
-        ctx.repo
-            .branch("some-branch", &first_commit, false)
-            .unwrap();
-        repo_utils::empty_commit(&ctx.repo, "HEAD", "empty commit", &[&first_commit]);
-        repo_utils::stage_file_changes(&ctx, &file_path);
+    fn prepare_hunk_over_two_commits() -> repo_utils::Context {
+        let (ctx, _) = repo_utils::prepare_repo();
+        let path = Path::new("lines.txt");
+        let mut lines: Vec<_> = (1..=8).map(|idx| format!("line {idx}\n")).collect();
+        for (subject, end) in [("Add first lines", 4), ("Add last lines", 8)] {
+            std::fs::write(ctx.join(path), lines[..end].concat()).unwrap();
+            let tree = repo_utils::add(&ctx.repo, path);
+            let head = ctx.repo.head().unwrap().peel_to_commit().unwrap();
+            repo_utils::commit(&ctx.repo, "HEAD", subject, &tree, &[&head]);
+        }
 
-        // run 'git-absorb'
-        let mut capturing_logger = log_utils::CapturingLogger::new();
-        run_with_repo(&capturing_logger.logger, &DEFAULT_CONFIG, &ctx.repo).unwrap();
+        // one hunk, over lines 2 to 7
+        for line in &mut lines[1..7] {
+            *line = line.replace('\n', " fixed\n");
+        }
+        std::fs::write(ctx.join(path), lines.concat()).unwrap();
+        repo_utils::add(&ctx.repo, path);
+        ctx
+    }
 
-        let mut revwalk = ctx.repo.revwalk().unwrap();
-        revwalk.push_head().unwrap();
-        assert_eq!(revwalk.count(), 2, "Wrong number of commits.");
+    #[test]
+    fn hunk_over_two_commits_goes_to_the_newest() {
+        let ctx = prepare_hunk_over_two_commits();
 
-        let is_something_in_index = !nothing_left_in_index(&ctx.repo).unwrap();
-        assert!(is_something_in_index);
+        let drain = slog::Discard;
+        let logger = slog::Logger::root(drain, o!());
+        run_with_repo(&logger, &DEFAULT_CONFIG, &ctx.repo).unwrap();
 
-        log_utils::assert_log_messages_are(
-            capturing_logger.visible_logs(),
-            vec![
-                &json!({
-                    "level": "WARN",
-                    "msg": "Some file modifications did not have an available commit to fix up. \
-                           You will have to manually create fixup commits.",
-                }),
-                &json!({
-                    "level": "WARN",
-                    "msg": "Will not fix up commits reachable by other branches. \
-                           Use --base to specify a base commit.",
-                }),
-            ],
-        );
+        assert!(nothing_left_in_index(&ctx.repo).unwrap());
+        let messages = extract_commit_messages(&ctx.repo);
+        assert_eq!(messages[0], "fixup! Add last lines\n");
+        assert_eq!(messages[1], "Add last lines");
     }
 
     #[test]
-    fn one_fixup_per_commit() {
-        let ctx = repo_utils::prepare_and_stage();
+    fn split_hunks_over_two_commits() {
+        let ctx = prepare_hunk_over_two_commits();
+        let staged = ctx.repo.index().unwrap().write_tree().unwrap();
 
-        // run 'git-absorb'
-        let mut capturing_logger = log_utils::CapturingLogger::new();
+        let drain = slog::Discard;
+        let logger = slog::Logger::root(drain, o!());
         let config = Config {
-            one_fixup_per_commit: true,
+            split_hunks: true,
             ..DEFAULT_CONFIG
         };
-        run_with_repo(&capturing_logger.logger, &config, &ctx.repo).unwrap();
-
-        let mut revwalk = ctx.repo.revwalk().unwrap();
-        revwalk.push_head().unwrap();
-        assert_eq!(revwalk.count(), 2);
+        run_with_repo(&logger, &config, &ctx.repo).unwrap();
 
         assert!(nothing_left_in_index(&ctx.repo).unwrap());
-
-        log_utils::assert_log_messages_are(
-            capturing_logger.visible_logs(),
+        assert_eq!(
+            ctx.repo.head().unwrap().peel_to_tree().unwrap().id(),
+            staged
+        );
+        let messages = extract_commit_messages(&ctx.repo);
+        assert_eq!(messages[0], "fixup! Add last lines\n");
+        assert_eq!(messages[1], "fixup! Add first lines\n");
+
+        // line 4 is right above the lines added later, so it cannot be
+        // moved past them
+        let head = ctx.repo.head().unwrap().peel_to_commit().unwrap();
+        let changed = |commit: &git2::Commit| {
+            let mut changed = vec![];
+            ctx.repo
+                .diff_tree_to_tree(
+                    Some(&commit.parent(0).unwrap().tree().unwrap()),
+                    Some(&commit.tree().unwrap()),
+                    None,
+                )
+                .unwrap()
+                .print(git2::DiffFormat::Patch, |_, _, line| {
+                    if line.origin() == '+' {
+                        changed.push(String::from_utf8_lossy(line.content()).into_owned());
+                    }
+                    true
+                })
+                .unwrap();
+            changed
+        };
+        assert_eq!(
+            changed(&head.parent(0).unwrap()),
+            vec!["line 2 fixed\n", "line 3 fixed\n"]
+        );
+        assert_eq!(
+            changed(&head),
             vec![
-                &json!({"level": "INFO", "msg": "committed"}),
-                &json!({
-                    "level": "INFO",
-                    "msg": "To squash the new commits, rebase:",
-                    "command": "git rebase --interactive --autosquash --autostash --root",
-                }),
-            ],
+                "line 4 fixed\n",
+                "line 5 fixed\n",
+                "line 6 fixed\n",
+                "line 7 fixed\n"
+            ]
         );
     }
 
-    #[test]
-    fn another_author() {
+    fn prepare_bookmark_mid_stack() -> repo_utils::Context {
         let ctx = repo_utils::prepare_and_stage();
+        {
+            let initial = ctx.repo.head().unwrap().peel_to_commit().unwrap();
+            repo_utils::empty_commit(&ctx.repo, "HEAD", "Add docs", &[&initial]);
+            ctx.repo.branch("bookmark", &initial, false).unwrap();
+        }
+        ctx
+    }
 
-        repo_utils::become_author(&ctx.repo, "nobody2", "nobody2@example.com");
-
-        // run 'git-absorb'
-        let mut capturing_logger = log_utils::CapturingLogger::new();
-        run_with_repo(&capturing_logger.logger, &DEFAULT_CONFIG, &ctx.repo).unwrap();
+    #[test]
+    fn branches_end_the_stack() {
+        let ctx = prepare_bookmark_mid_stack();
 
-        let mut revwalk = ctx.repo.revwalk().unwrap();
-        revwalk.push_head().unwrap();
-        assert_eq!(revwalk.count(), 1);
-        let is_something_in_index = !nothing_left_in_index(&ctx.repo).unwrap();
-        assert!(is_something_in_index);
+        let drain = slog::Discard;
+        let logger = slog::Logger::root(drain, o!());
+        run_with_repo(&logger, &DEFAULT_CONFIG, &ctx.repo).unwrap();
 
-        log_utils::assert_log_messages_are(
-            capturing_logger.visible_logs(),
-            vec![
-                &json!({
-                    "level": "WARN",
-                    "msg": "Some file modifications did not have an available commit to fix up. \
-                           You will have to manually create fixup commits.",
-                }),
-                &json!({
-                    "level": "WARN",
-                    "msg": "Will not fix up past commits by another author. \
-                           Use --force-author to override"
-                }),
-            ],
-        );
+        assert!(!nothing_left_in_index(&ctx.repo).unwrap());
+        assert_eq!(extract_commit_messages(&ctx.repo).len(), 2);
     }
 
     #[test]
-    fn another_author_with_force_author_flag() {
-        let ctx = repo_utils::prepare_and_stage();
-
-        repo_utils::become_author(&ctx.repo, "nobody2", "nobody2@example.com");
+    fn cross_branch_boundaries() {
+        let ctx = prepare_bookmark_mid_stack();
 
-        // run 'git-absorb'
         let mut capturing_logger = log_utils::CapturingLogger::new();
         let config = Config {
-            force_author: true,
-            ..DEFAULT_CONFIG
-        };
-        run_with_repo(&capturing_logger.logger, &config, &ctx.repo).unwrap();
-
-        let mut revwalk = ctx.repo.revwalk().unwrap();
-        revwalk.push_head().unwrap();
-        assert_eq!(revwalk.count(), 3);
+            cross_branch_boundaries: true,
+            ..DEFAULT_CONFIG
+        };
+        run_with_repo(&capturing_logger.logger, &config, &ctx.repo).unwrap();
 
         assert!(nothing_left_in_index(&ctx.repo).unwrap());
-
+        assert_eq!(
+            extract_commit_messages(&ctx.repo),
+            vec![
+                "fixup! Initial commit.\n",
+                "fixup! Initial commit.\n",
+                "Add docs",
+                "Initial commit.",
+            ]
+        );
         log_utils::assert_log_messages_are(
             capturing_logger.visible_logs(),
             vec![
-                &json!({"level": "INFO", "msg": "committed"}),
-                &json!({"level": "INFO", "msg": "committed"}),
+                &json!({
+                    "level": "WARN",
+                    "msg": "Stack crosses other branches, which a rebase will leave behind unless given --update-refs",
+                    "branches": "bookmark",
+                }),
                 &json!({
                     "level": "INFO",
-                    "msg": "To squash the new commits, rebase:",
-                    "command": "git rebase --interactive --autosquash --autostash --root",
+                    "msg": "stack reaches the first commit in the repository",
                 }),
+                &json!({"level": "INFO", "msg": "committed"}),
+                &json!({"level": "INFO", "msg": "committed"}),
+                &json!({"level": "INFO", "msg": "To squash the new commits, rebase:"}),
             ],
         );
     }
 
     #[test]
-    fn another_author_with_force_author_config() {
-        let ctx = repo_utils::prepare_and_stage();
+    fn cross_branch_boundaries_from_config() {
+        let ctx = prepare_bookmark_mid_stack();
+        ctx.repo
+            .config()
+            .unwrap()
+            .set_bool(config::CROSS_BRANCH_BOUNDARIES_CONFIG_NAME, true)
+            .unwrap();
 
-        repo_utils::become_author(&ctx.repo, "nobody2", "nobody2@example.com");
+        let drain = slog::Discard;
+        let logger = slog::Logger::root(drain, o!());
+        run_with_repo(&logger, &DEFAULT_CONFIG, &ctx.repo).unwrap();
 
-        repo_utils::set_config_flag(&ctx.repo, "absorb.forceAuthor");
+        assert!(nothing_left_in_index(&ctx.repo).unwrap());
+        assert_eq!(extract_commit_messages(&ctx.repo).len(), 4);
+    }
+
+    #[test]
+    fn absorbs_into_a_cherry_picked_copy() {
+        let (ctx, path) = repo_utils::prepare_repo();
+        let full_path = ctx.join(&path);
+        let initial = ctx.repo.head().unwrap().peel_to_commit().unwrap();
+
+        // the original, on another branch
+        std::fs::write(&full_path, "\nline\nline\n\nfeature\nlines\n").unwrap();
+        let tree = repo_utils::add(&ctx.repo, &path);
+        let original = repo_utils::commit(
+            &ctx.repo,
+            "refs/heads/feature",
+            "Add the feature",
+            &tree,
+            &[&initial],
+        );
+
+        // a commit close enough to it on this branch that the copy's diff,
+        // and so its patch-id, differs from the original's
+        std::fs::write(&full_path, "\nchanged line\nline\n\nmore\nlines\n").unwrap();
+        let tree = repo_utils::add(&ctx.repo, &path);
+        let head = repo_utils::commit(&ctx.repo, "HEAD", "Change a line", &tree, &[&initial]);
+
+        let mut index = ctx
+            .repo
+            .cherrypick_commit(&original, &head, 0, None)
+            .unwrap();
+        assert!(!index.has_conflicts());
+        let tree = ctx
+            .repo
+            .find_tree(index.write_tree_to(&ctx.repo).unwrap())
+            .unwrap();
+        let copy = repo_utils::commit(&ctx.repo, "HEAD", "Add the feature", &tree, &[&head]);
+        ctx.repo
+            .checkout_head(Some(git2::build::CheckoutBuilder::new().force()))
+            .unwrap();
+
+        std::fs::write(&full_path, "\nchanged line\nline\n\nfixed feature\nlines\n").unwrap();
+        repo_utils::add(&ctx.repo, &path);
 
-        // run 'git-absorb'
         let mut capturing_logger = log_utils::CapturingLogger::new();
         run_with_repo(&capturing_logger.logger, &DEFAULT_CONFIG, &ctx.repo).unwrap();
 
-        let mut revwalk = ctx.repo.revwalk().unwrap();
-        revwalk.push_head().unwrap();
-        assert_eq!(revwalk.count(), 3);
-
         assert!(nothing_left_in_index(&ctx.repo).unwrap());
-
-        log_utils::assert_log_messages_are(
-            capturing_logger.visible_logs(),
+        assert_eq!(
+            extract_commit_messages(&ctx.repo),
             vec![
-                &json!({"level": "INFO", "msg": "committed"}),
-                &json!({"level": "INFO", "msg": "committed"}),
-                &json!({
-                    "level": "INFO",
-                    "msg": "To squash the new commits, rebase:",
-                    "command": "git rebase --interactive --autosquash --autostash --root",
-                }),
-            ],
+                "fixup! Add the feature\n",
+                "Add the feature",
+                "Change a line",
+                "Initial commit.",
+            ]
         );
+        let logs = capturing_logger.visible_logs();
+        let committed = logs.iter().find(|log| log["msg"] == "committed").unwrap();
+        assert_eq!(committed["target_id"], copy.id().to_string());
+        assert_ne!(committed["target_id"], original.id().to_string());
     }
 
     #[test]
-    fn detached_head() {
+    fn porcelain_lists_each_fixup() {
         let ctx = repo_utils::prepare_and_stage();
-        repo_utils::detach_head(&ctx.repo);
+        let initial = ctx.repo.head().unwrap().target().unwrap();
 
-        // run 'git-absorb'
-        let capturing_logger = log_utils::CapturingLogger::new();
-        let result = run_with_repo(&capturing_logger.logger, &DEFAULT_CONFIG, &ctx.repo);
-        assert_eq!(
-            result.err().unwrap().to_string(),
-            "HEAD is not a branch, use --force-detach to override"
-        );
+        let drain = slog::Discard;
+        let logger = slog::Logger::root(drain, o!());
+        let config = Config {
+            porcelain: true,
+            ..DEFAULT_CONFIG
+        };
+        let mut out = Vec::new();
+        absorb(
+            &logger,
+            &config,
+            &ctx.repo,
+            &mut prompt::Terminal,
+            &mut out,
+            &mut stats::Stats::default(),
+            &mut Summary::default(),
+        )
+        .unwrap();
 
         let mut revwalk = ctx.repo.revwalk().unwrap();
         revwalk.push_head().unwrap();
-        assert_eq!(revwalk.count(), 1);
-        let is_something_in_index = !nothing_left_in_index(&ctx.repo).unwrap();
-        assert!(is_something_in_index);
+        let ids: Vec<_> = revwalk.map(|id| id.unwrap()).collect();
+        assert_eq!(ids.len(), 3);
+        assert_eq!(
+            String::from_utf8(out).unwrap(),
+            format!(
+                "fixup\t{}\t{}\ttest-file.txt\nfixup\t{}\t{}\ttest-file.txt\n",
+                ids[1], initial, ids[0], initial
+            )
+        );
     }
 
     #[test]
-    fn detached_head_pointing_at_branch_with_force_detach_flag() {
+    fn annotate_shows_the_target_of_each_change() {
+        let (ctx, _) = repo_utils::prepare_synthetic_stack(3, 4);
+        let index_tree = ctx.repo.index().unwrap().write_tree().unwrap();
+        let head = ctx.repo.head().unwrap().peel_to_commit().unwrap();
+        let commit_1 = head.parent(0).unwrap();
+
+        // without the first commit, nothing changed the last line
+        let drain = slog::Discard;
+        let logger = slog::Logger::root(drain, o!());
+        let config = Config {
+            annotate: true,
+            base: &["HEAD~3"],
+            ..DEFAULT_CONFIG
+        };
+        let mut out = Vec::new();
+        absorb(
+            &logger,
+            &config,
+            &ctx.repo,
+            &mut prompt::Terminal,
+            &mut out,
+            &mut stats::Stats::default(),
+            &mut Summary::default(),
+        )
+        .unwrap();
+        let commit_1 = format!("{:<8} {:<20}", &commit_1.id().to_string()[..7], "Commit 1");
+        let commit_2 = format!("{:<8} {:<20}", &head.id().to_string()[..7], "Commit 2");
+        let none = format!("{:<8} {:<20}", "????????", "");
+        assert_eq!(
+            String::from_utf8(out).unwrap(),
+            [
+                "diff --git a/test-file.txt b/test-file.txt".to_owned(),
+                "@@ -2,1 +2,1 @@ commit 0".to_owned(),
+                format!("{commit_1} -line 1"),
+                format!("{commit_1} +hunk 0"),
+                "@@ -4,1 +4,1 @@ commit 1".to_owned(),
+                format!("{commit_2} -line 3"),
+                format!("{commit_2} +hunk 1"),
+                "@@ -6,1 +6,1 @@ commit 2".to_owned(),
+                format!("{commit_2} -line 5"),
+                format!("{commit_2} +hunk 2"),
+                "@@ -8,1 +8,1 @@ line 6".to_owned(),
+                format!("{none} -line 7"),
+                format!("{none} +hunk 3"),
+                String::new(),
+            ]
+            .join("\n")
+        );
+
+        // nothing was committed, and the index is as it was
+        assert_eq!(ctx.repo.head().unwrap().target().unwrap(), head.id());
+        assert_eq!(ctx.repo.index().unwrap().write_tree().unwrap(), index_tree);
+    }
+
+    #[test]
+    fn dry_run_reports_the_signing_key() {
         let ctx = repo_utils::prepare_and_stage();
-        repo_utils::detach_head(&ctx.repo);
+        repo_utils::set_config_option(&ctx.repo, "commit.gpgSign", "true");
+        repo_utils::set_config_option(&ctx.repo, "user.signingKey", "0123456789ABCDEF");
 
-        // run 'git-absorb'
         let mut capturing_logger = log_utils::CapturingLogger::new();
         let config = Config {
-            force_detach: true,
+            dry_run: true,
             ..DEFAULT_CONFIG
         };
         run_with_repo(&capturing_logger.logger, &config, &ctx.repo).unwrap();
-        let mut revwalk = ctx.repo.revwalk().unwrap();
-        revwalk.push_head().unwrap();
-
-        assert_eq!(revwalk.count(), 1); // nothing was committed
-        let is_something_in_index = !nothing_left_in_index(&ctx.repo).unwrap();
-        assert!(is_something_in_index);
 
         log_utils::assert_log_messages_are(
             capturing_logger.visible_logs(),
             vec![
                 &json!({
-                    "level": "WARN",
-                    "msg": "HEAD is not a branch, but --force-detach used to continue."}),
+                    "level": "INFO",
+                    "msg": "stack reaches the first commit in the repository",
+                }),
+                &json!({"level": "INFO", "msg": "would have committed"}),
                 &json!({
-                    "level": "WARN",
-                    "msg": "Some file modifications did not have an available commit to fix up. \
-                           You will have to manually create fixup commits.",
+                    "level": "INFO",
+                    "msg": "would sign with key",
+                    "key": "0123456789ABCDEF",
                 }),
+                &json!({"level": "INFO", "msg": "would have committed"}),
                 &json!({
-                    "level": "WARN",
-                    "msg": "Will not fix up commits reachable by other branches. \
-                    Use --base to specify a base commit."
+                    "level": "INFO",
+                    "msg": "would sign with key",
+                    "key": "0123456789ABCDEF",
                 }),
             ],
         );
     }
 
     #[test]
-    fn detached_head_with_force_detach_flag() {
+    fn dry_run_without_signing_reports_nothing_about_it() {
         let ctx = repo_utils::prepare_and_stage();
-        repo_utils::detach_head(&ctx.repo);
-        repo_utils::delete_branch(&ctx.repo, "master");
+        repo_utils::set_config_option(&ctx.repo, "user.signingKey", "0123456789ABCDEF");
 
-        // run 'git-absorb'
         let mut capturing_logger = log_utils::CapturingLogger::new();
         let config = Config {
-            force_detach: true,
+            dry_run: true,
             ..DEFAULT_CONFIG
         };
         run_with_repo(&capturing_logger.logger, &config, &ctx.repo).unwrap();
-        let mut revwalk = ctx.repo.revwalk().unwrap();
-        revwalk.push_head().unwrap();
-
-        assert_eq!(revwalk.count(), 3);
-        assert!(nothing_left_in_index(&ctx.repo).unwrap());
 
-        log_utils::assert_log_messages_are(
-            capturing_logger.visible_logs(),
-            vec![
-                &json!({
-                    "level": "WARN",
-                    "msg": "HEAD is not a branch, but --force-detach used to continue.",
-                }),
-                &json!({"level": "INFO", "msg": "committed"}),
-                &json!({"level": "INFO", "msg": "committed"}),
-                &json!({
-                    "level": "INFO",
-                    "msg": "To squash the new commits, rebase:",
-                    "command": "git rebase --interactive --autosquash --autostash --root",
-                }),
-            ],
-        );
+        assert!(!capturing_logger
+            .visible_logs()
+            .iter()
+            .any(|log| log["msg"] == "would sign with key"));
     }
 
     #[test]
-    fn detached_head_with_force_detach_config() {
-        let ctx = repo_utils::prepare_and_stage();
-        repo_utils::detach_head(&ctx.repo);
-        repo_utils::delete_branch(&ctx.repo, "master");
+    #[cfg(unix)]
+    fn fixups_are_signed_with_commit_gpg_sign() {
+        use std::os::unix::fs::PermissionsExt;
 
-        repo_utils::set_config_flag(&ctx.repo, "absorb.forceDetach");
+        let ctx = repo_utils::prepare_and_stage();
+        // stands in for gpg, with the arguments it was given as the signature
+        let program = ctx.dir.path().join("fake-gpg");
+        std::fs::write(
+            &program,
+            "#!/bin/sh\ncat >/dev/null\necho '-----BEGIN PGP SIGNATURE-----'\necho \"$@\"\necho '-----END PGP SIGNATURE-----'\n",
+        )
+        .unwrap();
+        std::fs::set_permissions(&program, std::fs::Permissions::from_mode(0o755)).unwrap();
+        repo_utils::set_config_option(&ctx.repo, "commit.gpgSign", "true");
+        repo_utils::set_config_option(&ctx.repo, "gpg.program", program.to_str().unwrap());
 
-        // run 'git-absorb'
-        let mut capturing_logger = log_utils::CapturingLogger::new();
+        let capturing_logger = log_utils::CapturingLogger::new();
         run_with_repo(&capturing_logger.logger, &DEFAULT_CONFIG, &ctx.repo).unwrap();
-        let mut revwalk = ctx.repo.revwalk().unwrap();
-        revwalk.push_head().unwrap();
 
-        assert_eq!(revwalk.count(), 3);
+        let head = ctx.repo.head().unwrap().peel_to_commit().unwrap();
+        assert_eq!(head.summary(), Some("fixup! Initial commit."));
+        let signature = head.header_field_bytes("gpgsig").unwrap();
+        assert_eq!(
+            signature.as_str().unwrap(),
+            "-----BEGIN PGP SIGNATURE-----\n--status-fd=2 -bsau nobody <nobody@example.com>\n-----END PGP SIGNATURE-----\n"
+        );
         assert!(nothing_left_in_index(&ctx.repo).unwrap());
+    }
 
-        log_utils::assert_log_messages_are(
-            capturing_logger.visible_logs(),
-            vec![
-                &json!({
-                    "level": "WARN",
-                    "msg": "HEAD is not a branch, but --force-detach used to continue.",
-                }),
-                &json!({"level": "INFO", "msg": "committed"}),
-                &json!({"level": "INFO", "msg": "committed"}),
-                &json!({
-                    "level": "INFO",
-                    "msg": "To squash the new commits, rebase:",
-                    "command": "git rebase --interactive --autosquash --autostash --root",
-                }),
-            ],
+    #[test]
+    fn failing_to_sign_the_fixups() {
+        let ctx = repo_utils::prepare_and_stage();
+        let head = ctx.repo.head().unwrap().target().unwrap();
+        repo_utils::set_config_option(&ctx.repo, "commit.gpgSign", "true");
+        repo_utils::set_config_option(&ctx.repo, "gpg.program", "false");
+
+        let capturing_logger = log_utils::CapturingLogger::new();
+        let result = run_with_repo(&capturing_logger.logger, &DEFAULT_CONFIG, &ctx.repo);
+        assert!(
+            matches!(result, Err(AbsorbError::SigningFailed(_))),
+            "{:?}",
+            result
         );
+        assert_eq!(ctx.repo.head().unwrap().target().unwrap(), head);
+        assert!(!nothing_left_in_index(&ctx.repo).unwrap());
     }
 
     #[test]
-    fn and_rebase_flag() {
+    fn signing_settings_from_the_config_file() {
         let ctx = repo_utils::prepare_and_stage();
-        repo_utils::set_config_option(&ctx.repo, "core.editor", "true");
-        repo_utils::set_config_option(&ctx.repo, "advice.waitingForEditor", "false");
+        let head = ctx.repo.head().unwrap().target().unwrap();
+        let file = ctx.join(Path::new("absorb.config"));
+        std::fs::write(
+            &file,
+            "[commit]\n\tgpgSign = true\n[gpg]\n\tprogram = false\n",
+        )
+        .unwrap();
 
-        // run 'git-absorb'
-        let mut capturing_logger = log_utils::CapturingLogger::new();
+        let capturing_logger = log_utils::CapturingLogger::new();
         let config = Config {
-            and_rebase: true,
+            config_file: Some(&file),
             ..DEFAULT_CONFIG
         };
-        run_with_repo(&capturing_logger.logger, &config, &ctx.repo).unwrap();
-
-        let mut revwalk = ctx.repo.revwalk().unwrap();
-        revwalk.push_head().unwrap();
-
-        assert_eq!(revwalk.count(), 1);
-        assert!(nothing_left_in_index(&ctx.repo).unwrap());
-
-        log_utils::assert_log_messages_are(
-            capturing_logger.visible_logs(),
-            vec![
-                &json!({"level": "INFO", "msg": "committed"}),
-                &json!({"level": "INFO", "msg": "committed"}),
-            ],
+        let result = run_with_repo(&capturing_logger.logger, &config, &ctx.repo);
+        assert!(
+            matches!(&result, Err(AbsorbError::SigningFailed(message)) if message.starts_with("false ")),
+            "{:?}",
+            result
         );
+        assert_eq!(ctx.repo.head().unwrap().target().unwrap(), head);
+    }
+
+    /// The diff in a patch that `git2::Email` wrote, without the headers,
+    /// the message and the diffstat before it, nor the signature after it.
+    fn patch_diff(patch: &str) -> &str {
+        let start = patch.find("diff --git").unwrap();
+        let end = patch.rfind("\n--\n").unwrap();
+        &patch[start..=end]
     }
 
     #[test]
-    fn and_rebase_flag_with_rebase_options() {
-        let ctx = repo_utils::prepare_and_stage();
+    fn export_series_matches_the_rebased_stack() {
+        // the last commit gets no fixup
+        let (ctx, _) = repo_utils::prepare_synthetic_stack(3, 2);
         repo_utils::set_config_option(&ctx.repo, "core.editor", "true");
         repo_utils::set_config_option(&ctx.repo, "advice.waitingForEditor", "false");
+        let head = ctx.repo.head().unwrap().target().unwrap();
+        let index_tree = ctx.repo.index().unwrap().write_tree().unwrap();
+        let refs = ctx.repo.references().unwrap().count();
 
-        // run 'git-absorb'
-        let mut capturing_logger = log_utils::CapturingLogger::new();
+        let capturing_logger = log_utils::CapturingLogger::new();
+        let series = ctx.dir.path().join("series");
         let config = Config {
-            and_rebase: true,
-            rebase_options: &vec!["--signoff"],
+            export_series: Some(&series),
             ..DEFAULT_CONFIG
         };
         run_with_repo(&capturing_logger.logger, &config, &ctx.repo).unwrap();
 
-        let mut revwalk = ctx.repo.revwalk().unwrap();
-        revwalk.push_head().unwrap();
-        assert_eq!(revwalk.count(), 1);
-
-        let trailers = message_trailers_strs(
-            ctx.repo
-                .head()
-                .unwrap()
-                .peel_to_commit()
-                .unwrap()
-                .message()
-                .unwrap(),
-        )
-        .unwrap();
+        assert_eq!(ctx.repo.head().unwrap().target().unwrap(), head);
+        assert_eq!(ctx.repo.index().unwrap().write_tree().unwrap(), index_tree);
+        assert_eq!(ctx.repo.references().unwrap().count(), refs);
+        let mut names: Vec<_> = std::fs::read_dir(&series)
+            .unwrap()
+            .map(|entry| entry.unwrap().file_name().into_string().unwrap())
+            .collect();
+        names.sort();
         assert_eq!(
-            trailers
-                .iter()
-                .filter(|trailer| trailer.0 == "Signed-off-by")
-                .count(),
-            1
-        );
-
-        assert!(nothing_left_in_index(&ctx.repo).unwrap());
-
-        log_utils::assert_log_messages_are(
-            capturing_logger.visible_logs(),
-            vec![
-                &json!({"level": "INFO", "msg": "committed"}),
-                &json!({"level": "INFO", "msg": "committed"}),
-            ],
+            names,
+            vec![
+                "0001-Initial-commit.patch",
+                "0002-Commit-0.patch",
+                "0003-Commit-1.patch",
+                "0004-Commit-2.patch",
+            ]
         );
+
+        let config = Config {
+            and_rebase: true,
+            ..DEFAULT_CONFIG
+        };
+        run_with_repo(&capturing_logger.logger, &config, &ctx.repo).unwrap();
+
+        let mut rebased = vec![ctx.repo.head().unwrap().peel_to_commit().unwrap()];
+        while let Ok(parent) = rebased[rebased.len() - 1].parent(0) {
+            rebased.push(parent);
+        }
+        rebased.reverse();
+        assert_eq!(rebased.len(), names.len());
+        for (name, commit) in names.iter().zip(&rebased) {
+            let patch = std::fs::read_to_string(series.join(name)).unwrap();
+            let parent_tree = commit.parent(0).ok().map(|parent| parent.tree().unwrap());
+            let mut expected = vec![];
+            ctx.repo
+                .diff_tree_to_tree(parent_tree.as_ref(), Some(&commit.tree().unwrap()), None)
+                .unwrap()
+                .print(git2::DiffFormat::Patch, |_, _, line| {
+                    if matches!(line.origin(), '+' | '-' | ' ') {
+                        expected.push(line.origin() as u8);
+                    }
+                    expected.extend_from_slice(line.content());
+                    true
+                })
+                .unwrap();
+            assert_eq!(patch_diff(&patch), String::from_utf8(expected).unwrap());
+            assert!(patch.contains("\nFrom: nobody <nobody@example.com>\n"));
+            assert!(patch.contains(&format!(
+                "\nSubject: [PATCH {}/4] {}\n",
+                name[..4].trim_start_matches('0'),
+                commit.summary().unwrap()
+            )));
+        }
     }
 
     #[test]
-    fn rebase_options_without_and_rebase_flag() {
-        let ctx = repo_utils::prepare_and_stage();
-
-        // run 'git-absorb'
+    fn export_series_to_stdout() {
+        let (ctx, _) = repo_utils::prepare_synthetic_stack(2, 2);
         let capturing_logger = log_utils::CapturingLogger::new();
         let config = Config {
-            rebase_options: &vec!["--some-option"],
+            export_series: Some(Path::new("-")),
             ..DEFAULT_CONFIG
         };
-        let result = run_with_repo(&capturing_logger.logger, &config, &ctx.repo);
+        let mut out = Vec::new();
+        absorb(
+            &capturing_logger.logger,
+            &config,
+            &ctx.repo,
+            &mut prompt::Terminal,
+            &mut out,
+            &mut stats::Stats::default(),
+            &mut Summary::default(),
+        )
+        .unwrap();
 
+        // one mbox, with a message for each commit in the stack
+        let out = String::from_utf8(out).unwrap();
+        let subjects: Vec<_> = out
+            .lines()
+            .filter(|line| line.starts_with("Subject: "))
+            .collect();
         assert_eq!(
-            result.err().unwrap().to_string(),
-            "REBASE_OPTIONS were specified without --and-rebase flag"
+            subjects,
+            vec![
+                "Subject: [PATCH 1/3] Initial commit.",
+                "Subject: [PATCH 2/3] Commit 0",
+                "Subject: [PATCH 3/3] Commit 1",
+            ]
         );
-
-        let mut revwalk = ctx.repo.revwalk().unwrap();
-        revwalk.push_head().unwrap();
-        assert_eq!(revwalk.count(), 1);
-        let is_something_in_index = !nothing_left_in_index(&ctx.repo).unwrap();
-        assert!(is_something_in_index);
+        assert_eq!(out.matches("\nFrom ").count() + 1, 3);
+        assert!(out.contains("+hunk 0\n"));
+        assert!(out.contains("+hunk 1\n"));
     }
 
     #[test]
-    fn squash_flag() {
-        let ctx = repo_utils::prepare_and_stage();
+    fn show_final_leaves_the_net_change_alone() {
+        let (ctx, _) = repo_utils::prepare_synthetic_stack(3, 3);
+        let head = ctx.repo.head().unwrap().target().unwrap();
+        let index_tree = ctx
+            .repo
+            .find_tree(ctx.repo.index().unwrap().write_tree().unwrap())
+            .unwrap();
 
-        // run 'git-absorb'
         let mut capturing_logger = log_utils::CapturingLogger::new();
         let config = Config {
+            dry_run: true,
             squash: true,
+            show_final: true,
             ..DEFAULT_CONFIG
         };
-        run_with_repo(&capturing_logger.logger, &config, &ctx.repo).unwrap();
+        let mut out = Vec::new();
+        absorb(
+            &capturing_logger.logger,
+            &config,
+            &ctx.repo,
+            &mut prompt::Terminal,
+            &mut out,
+            &mut stats::Stats::default(),
+            &mut Summary::default(),
+        )
+        .unwrap();
 
+        // the stack reaches the first commit, so the whole branch is shown,
+        // just as it would be with the changes committed on top of it
+        let mut expected = vec![];
+        ctx.repo
+            .diff_tree_to_tree(None, Some(&index_tree), None)
+            .unwrap()
+            .print(git2::DiffFormat::Patch, |_, _, line| {
+                if matches!(line.origin(), '+' | '-' | ' ') {
+                    expected.push(line.origin() as u8);
+                }
+                expected.extend_from_slice(line.content());
+                true
+            })
+            .unwrap();
         assert_eq!(
-            extract_commit_messages(&ctx.repo),
-            vec![
-                "squash! Initial commit.\n",
-                "squash! Initial commit.\n",
-                "Initial commit.",
-            ]
+            String::from_utf8(out).unwrap(),
+            String::from_utf8(expected).unwrap()
         );
+        assert!(!capturing_logger
+            .visible_logs()
+            .iter()
+            .any(|log| log["level"] == "WARN"));
 
-        log_utils::assert_log_messages_are(
-            capturing_logger.visible_logs(),
-            vec![
-                &json!({"level": "INFO", "msg": "committed"}),
-                &json!({"level": "INFO", "msg": "committed"}),
-                &json!({
-                    "level": "INFO",
-                    "msg": "To squash the new commits, rebase:",
-                    "command": "git rebase --interactive --autosquash --autostash --root",
-                }),
-            ],
+        // nothing was committed
+        assert_eq!(ctx.repo.head().unwrap().target().unwrap(), head);
+        assert_eq!(
+            ctx.repo.index().unwrap().write_tree().unwrap(),
+            index_tree.id()
         );
     }
 
     #[test]
-    fn run_with_squash_config_option() {
-        let ctx = repo_utils::prepare_and_stage();
+    fn fixups_that_would_conflict_with_a_newer_commit_are_found() {
+        let (ctx, _) = repo_utils::prepare_synthetic_stack(2, 0);
+        let head = ctx.repo.head().unwrap().peel_to_commit().unwrap();
+        let stack: Vec<_> = [head.clone(), head.parent(0).unwrap()]
+            .into_iter()
+            .map(|commit| {
+                let diff = ctx
+                    .repo
+                    .diff_tree_to_tree(
+                        Some(&commit.parent(0).unwrap().tree().unwrap()),
+                        Some(&commit.tree().unwrap()),
+                        Some(git2::DiffOptions::new().context_lines(0)),
+                    )
+                    .unwrap();
+                let diff = owned::Diff::new(&diff, None).unwrap();
+                (commit, diff)
+            })
+            .collect();
+        let hunk_at = |line: usize| owned::Hunk {
+            added: owned::Block {
+                start: line,
+                lines: std::rc::Rc::new([b"changed\n".as_slice()].into_iter().collect()),
+            },
+            removed: owned::Block {
+                start: line,
+                lines: std::rc::Rc::new(
+                    [format!("line {}\n", line - 1).as_bytes()]
+                        .into_iter()
+                        .collect(),
+                ),
+            },
+            funcname: None,
+        };
+        let path = b"test-file.txt".as_slice();
+        let (newer, older) = (&stack[0].0, &stack[1].0);
 
-        repo_utils::set_config_flag(&ctx.repo, "absorb.createSquashCommits");
+        // the line between the ones that Commit 0 and Commit 1 changed
+        // cannot go into Commit 0 without conflicting with Commit 1
+        let next_to_both = hunk_at(2);
+        assert_eq!(
+            conflicting_descendant(&stack, path, &next_to_both, older, 0).map(|c| c.id()),
+            Some(newer.id())
+        );
+        assert!(conflicting_descendant(&stack, path, &next_to_both, newer, 0).is_none());
 
-        // run 'git-absorb'
-        let mut capturing_logger = log_utils::CapturingLogger::new();
-        run_with_repo(&capturing_logger.logger, &DEFAULT_CONFIG, &ctx.repo).unwrap();
+        // the last line is far enough from the line Commit 1 changed
+        let apart = hunk_at(5);
+        assert!(conflicting_descendant(&stack, path, &apart, older, 0).is_none());
+    }
+
+    #[test]
+    fn reverting_a_line_goes_into_the_commit_that_last_changed_it() {
+        let (ctx, path) = repo_utils::prepare_repo();
+        for (content, message) in [("x=1\n", "Set x to 1"), ("x=2\n", "Set x to 2")] {
+            std::fs::write(ctx.join(&path), format!("first\n{}last\n", content)).unwrap();
+            let tree = repo_utils::add(&ctx.repo, &path);
+            let head = ctx.repo.head().unwrap().peel_to_commit().unwrap();
+            repo_utils::commit(&ctx.repo, "HEAD", message, &tree, &[&head]);
+        }
+        // back to what the older commit set
+        std::fs::write(ctx.join(&path), "first\nx=1\nlast\n").unwrap();
+        repo_utils::add(&ctx.repo, &path);
+
+        let drain = slog::Discard;
+        let logger = slog::Logger::root(drain, o!());
+        run_with_repo(&logger, &DEFAULT_CONFIG, &ctx.repo).unwrap();
 
+        assert!(nothing_left_in_index(&ctx.repo).unwrap());
         assert_eq!(
             extract_commit_messages(&ctx.repo),
             vec![
-                "squash! Initial commit.\n",
-                "squash! Initial commit.\n",
+                "fixup! Set x to 2\n",
+                "Set x to 2",
+                "Set x to 1",
                 "Initial commit.",
             ]
         );
+    }
 
-        log_utils::assert_log_messages_are(
-            capturing_logger.visible_logs(),
-            vec![
-                &json!({"level": "INFO", "msg": "committed"}),
-                &json!({"level": "INFO", "msg": "committed"}),
-                &json!({
-                    "level": "INFO",
-                    "msg": "To squash the new commits, rebase:",
-                    "command": "git rebase --interactive --autosquash --autostash --root",
-                }),
-            ],
+    #[test]
+    fn overlapping_hunks_for_different_commits_are_refused() {
+        let dir = tempfile::tempdir().unwrap();
+        let plan_path = dir.path().join("plan.json");
+        let (ctx, mut plan, commits) = plan_for_edited_lines(&plan_path);
+
+        // the edit to commit 0 is planned for commit 1 as well
+        let fixups = plan["fixups"].as_array_mut().unwrap();
+        let mut copy = fixups[0].clone();
+        copy["target"] = json!(commits[1].to_string());
+        fixups.push(copy);
+        std::fs::write(&plan_path, plan.to_string()).unwrap();
+        let drain = slog::Discard;
+        let logger = slog::Logger::root(drain, o!());
+        let config = Config {
+            apply_plan: Some(&plan_path),
+            ..DEFAULT_CONFIG
+        };
+        let err = run_with_repo(&logger, &config, &ctx.repo).unwrap_err();
+        assert!(
+            matches!(err, AbsorbError::OverlappingHunks { first_target, second_target, .. }
+                if first_target == commits[0] && second_target == commits[1]),
+            "{:?}",
+            err
+        );
+        assert_eq!(
+            err.to_string(),
+            format!(
+                "the hunks at test-file.txt:1 and test-file.txt:1 change the same lines, \
+                 but go into {} and {}, use --force-overlap to only absorb the one for \
+                 the older commit",
+                commits[0], commits[1]
+            )
+        );
+        assert_eq!(extract_commit_messages(&ctx.repo).len(), 4);
+
+        let config = Config {
+            force_overlap: true,
+            ..config
+        };
+        run_with_repo(&logger, &config, &ctx.repo).unwrap();
+        assert_eq!(
+            extract_commit_messages(&ctx.repo)[..2],
+            ["fixup! Commit 2\n", "fixup! Commit 0\n"]
         );
     }
 
     #[test]
-    fn dry_run_flag() {
-        let ctx = repo_utils::prepare_and_stage();
+    fn verify_build_picks_the_oldest_passing_commit() {
+        let (ctx, path) = repo_utils::prepare_synthetic_stack(3, 0);
+        let contents = std::fs::read_to_string(ctx.join(&path)).unwrap();
+        std::fs::write(
+            ctx.join(&path),
+            contents.replace("commit 0\n", "edited 0\n"),
+        )
+        .unwrap();
+        repo_utils::add(&ctx.repo, &path);
+
+        let drain = slog::Discard;
+        let logger = slog::Logger::root(drain, o!());
+        // only passes once commit 2 is there, not in commits 0 and 1
+        let config = Config {
+            verify_build: Some("grep -q 'commit 2' test-file.txt"),
+            ..DEFAULT_CONFIG
+        };
+        run_with_repo(&logger, &config, &ctx.repo).unwrap();
+        assert!(nothing_left_in_index(&ctx.repo).unwrap());
+        assert_eq!(
+            extract_commit_messages(&ctx.repo)[..2],
+            ["fixup! Commit 2\n", "Commit 2"]
+        );
+    }
+
+    #[test]
+    fn verify_build_that_never_passes() {
+        let (ctx, path) = repo_utils::prepare_synthetic_stack(3, 0);
+        let contents = std::fs::read_to_string(ctx.join(&path)).unwrap();
+        std::fs::write(
+            ctx.join(&path),
+            contents.replace("commit 0\n", "edited 0\n"),
+        )
+        .unwrap();
+        repo_utils::add(&ctx.repo, &path);
 
-        // run 'git-absorb'
         let mut capturing_logger = log_utils::CapturingLogger::new();
         let config = Config {
-            dry_run: true,
+            verify_build: Some("false"),
             ..DEFAULT_CONFIG
         };
         run_with_repo(&capturing_logger.logger, &config, &ctx.repo).unwrap();
+        assert_eq!(extract_commit_messages(&ctx.repo).len(), 4);
+        let reasons: Vec<_> = capturing_logger
+            .visible_logs()
+            .into_iter()
+            .filter(|log| log["msg"] == "Staged change could not be absorbed")
+            .map(|log| log["reason"].as_str().unwrap().to_owned())
+            .collect();
+        assert_eq!(reasons, ["verify-build"]);
+        assert!(!nothing_left_in_index(&ctx.repo).unwrap());
+    }
+
+    #[test]
+    fn stage_only_leaves_fixups_on_scratch_ref() {
+        let ctx = repo_utils::prepare_and_stage();
+        let head = ctx.repo.head().unwrap().peel_to_commit().unwrap();
+        let index_tree = ctx.repo.index().unwrap().write_tree().unwrap();
+
+        let mut capturing_logger = log_utils::CapturingLogger::new();
+        let config = Config {
+            stage_only: true,
+            ..DEFAULT_CONFIG
+        };
+        let mut out = Vec::new();
+        absorb(
+            &capturing_logger.logger,
+            &config,
+            &ctx.repo,
+            &mut prompt::Terminal,
+            &mut out,
+            &mut stats::Stats::default(),
+            &mut Summary::default(),
+        )
+        .unwrap();
+
+        // the branch and the index are as they were
+        assert_eq!(
+            ctx.repo.head().unwrap().peel_to_commit().unwrap().id(),
+            head.id()
+        );
+        assert_eq!(ctx.repo.index().unwrap().write_tree().unwrap(), index_tree);
+        assert!(ctx.repo.refname_to_id("PRE_ABSORB_HEAD").is_err());
 
+        // the fixups are on the scratch ref, on top of HEAD, newest first
         let mut revwalk = ctx.repo.revwalk().unwrap();
-        revwalk.push_head().unwrap();
-        assert_eq!(revwalk.count(), 1);
-        let is_something_in_index = !nothing_left_in_index(&ctx.repo).unwrap();
-        assert!(is_something_in_index);
+        revwalk.push_ref(STAGED_FIXUPS_REF).unwrap();
+        revwalk.hide(head.id()).unwrap();
+        let fixups: Vec<_> = revwalk.map(|oid| oid.unwrap()).collect();
+        assert_eq!(fixups.len(), 2);
+        for fixup in &fixups {
+            let fixup = ctx.repo.find_commit(*fixup).unwrap();
+            assert_eq!(fixup.message(), Some("fixup! Initial commit.\n"));
+        }
+        let tip = ctx.repo.find_commit(fixups[0]).unwrap();
+        assert_eq!(tip.tree_id(), index_tree);
 
-        let pre_absorb_ref_commit = ctx.repo.references_glob("PRE_ABSORB_HEAD").unwrap().last();
-        assert!(pre_absorb_ref_commit.is_none());
+        // and their ids are printed, oldest first
+        let printed: Vec<_> = fixups
+            .iter()
+            .rev()
+            .map(|oid| format!("{}\n", oid))
+            .collect();
+        assert_eq!(String::from_utf8(out).unwrap(), printed.concat());
 
         log_utils::assert_log_messages_are(
             capturing_logger.visible_logs(),
             vec![
                 &json!({
                     "level": "INFO",
-                    "msg": "would have committed", "fixup": "Initial commit.",
+                    "msg": "stack reaches the first commit in the repository",
                 }),
+                &json!({"level": "INFO", "msg": "committed"}),
+                &json!({"level": "INFO", "msg": "committed"}),
                 &json!({
                     "level": "INFO",
-                    "msg": "would have committed", "fixup": "Initial commit.",
+                    "msg": "The fixups were left out of the branch. To add them, cherry-pick:",
+                    "command": "git cherry-pick HEAD..refs/absorb/fixups",
                 }),
             ],
         );
     }
 
     #[test]
-    fn dry_run_flag_with_and_rebase_flag() {
-        let (ctx, path) = repo_utils::prepare_repo();
-        repo_utils::set_config_option(&ctx.repo, "core.editor", "true");
-
-        // create a fixup commit that 'git rebase' will act on if called
-        let tree = repo_utils::stage_file_changes(&ctx, &path);
-        let head_commit = ctx.repo.head().unwrap().peel_to_commit().unwrap();
-        let fixup_message = format!("fixup! {}\n", head_commit.id());
-        repo_utils::commit(&ctx.repo, "HEAD", &fixup_message, &tree, &[&head_commit]);
-
-        // stage one more change so 'git-absorb' won't exit early
-        repo_utils::stage_file_changes(&ctx, &path);
+    fn stage_only_conflicts_with_and_rebase() {
+        let ctx = repo_utils::prepare_and_stage();
 
-        // run 'git-absorb'
-        let mut capturing_logger = log_utils::CapturingLogger::new();
+        let drain = slog::Discard;
+        let logger = slog::Logger::root(drain, o!());
         let config = Config {
+            stage_only: true,
             and_rebase: true,
-            dry_run: true,
             ..DEFAULT_CONFIG
         };
-        run_with_repo(&capturing_logger.logger, &config, &ctx.repo).unwrap();
+        assert!(run_with_repo(&logger, &config, &ctx.repo).is_err());
+        assert!(ctx.repo.refname_to_id(STAGED_FIXUPS_REF).is_err());
+    }
 
-        let mut revwalk = ctx.repo.revwalk().unwrap();
-        revwalk.push_head().unwrap();
-        assert_eq!(revwalk.count(), 2); // git rebase wasn't called so both commits persist
-        let is_something_in_index = !nothing_left_in_index(&ctx.repo).unwrap();
-        assert!(is_something_in_index);
+    fn prepare_for_script() -> repo_utils::Context {
+        let ctx = repo_utils::prepare_and_stage();
+        // stays staged
+        std::fs::write(ctx.join(Path::new("new-file.txt")), "new\n").unwrap();
+        repo_utils::add(&ctx.repo, Path::new("new-file.txt"));
+        ctx
+    }
 
-        log_utils::assert_log_messages_are(
-            capturing_logger.visible_logs(),
-            vec![
-                &json!({"level": "INFO", "msg": "would have committed",}),
-                &json!({"level": "INFO", "msg": "would have committed",}),
-                &json!({"level": "INFO", "msg": "would have run git rebase",}),
-            ],
-        );
+    fn emit_script(ctx: &repo_utils::Context, config: &Config) -> String {
+        let drain = slog::Discard;
+        let logger = slog::Logger::root(drain, o!());
+        let config = Config {
+            emit_script: true,
+            ..*config
+        };
+        let mut out = Vec::new();
+        absorb(
+            &logger,
+            &config,
+            &ctx.repo,
+            &mut prompt::Terminal,
+            &mut out,
+            &mut stats::Stats::default(),
+            &mut Summary::default(),
+        )
+        .unwrap();
+        String::from_utf8(out).unwrap()
     }
 
-    fn autostage_common(ctx: &repo_utils::Context, file_path: &PathBuf) -> (PathBuf, PathBuf) {
-        // 1 modification w/o staging
-        let path = ctx.join(file_path);
-        let contents = std::fs::read_to_string(&path).unwrap();
-        let modifications = format!("{contents}\nnew_line2");
-        std::fs::write(&path, &modifications).unwrap();
+    #[test]
+    fn emitted_script_makes_the_same_commits() {
+        let direct = prepare_for_script();
+        let drain = slog::Discard;
+        let logger = slog::Logger::root(drain, o!());
+        run_with_repo(&logger, &DEFAULT_CONFIG, &direct.repo).unwrap();
 
-        // 1 extra file
-        let fp2 = PathBuf::from("unrel.txt");
-        std::fs::write(ctx.join(&fp2), "foo").unwrap();
+        let scripted = prepare_for_script();
+        let head = scripted.repo.head().unwrap().peel_to_commit().unwrap().id();
+        let index_tree = scripted.repo.index().unwrap().write_tree().unwrap();
+        let script = emit_script(&scripted, &DEFAULT_CONFIG);
 
-        (path, fp2)
+        // emitting the script changes nothing
+        assert_eq!(
+            scripted.repo.head().unwrap().peel_to_commit().unwrap().id(),
+            head
+        );
+        assert_eq!(
+            scripted.repo.index().unwrap().write_tree().unwrap(),
+            index_tree
+        );
+        assert!(scripted.repo.refname_to_id("PRE_ABSORB_HEAD").is_err());
+        assert!(script.starts_with("#!/bin/sh\n"));
+        assert!(script.contains(&format!("#   {} Initial commit.\n", head)));
+        assert!(!script.contains("git rebase"));
+
+        let script_path = scripted.dir.path().join(".git").join("absorb.sh");
+        std::fs::write(&script_path, &script).unwrap();
+        let status = std::process::Command::new("sh")
+            .arg(&script_path)
+            .current_dir(scripted.dir.path())
+            .status()
+            .unwrap();
+        assert!(status.success(), "{}", script);
+
+        let mut index = scripted.repo.index().unwrap();
+        index.read(true).unwrap();
+        assert_eq!(
+            extract_commit_messages(&scripted.repo),
+            extract_commit_messages(&direct.repo)
+        );
+        assert_eq!(
+            scripted.repo.head().unwrap().peel_to_tree().unwrap().id(),
+            direct.repo.head().unwrap().peel_to_tree().unwrap().id()
+        );
+        // the new file is still staged, and nothing else
+        assert_eq!(index.write_tree().unwrap(), index_tree);
+        let stats = index_stats(&scripted.repo).unwrap();
+        assert_eq!(stats.files_changed(), 1);
     }
 
     #[test]
-    fn autostage_if_index_was_empty() {
-        let (ctx, file_path) = repo_utils::prepare_repo();
+    fn emitted_script_ends_with_rebase() {
+        let ctx = prepare_for_script();
+        let rebase_options = vec!["--exec", "make test"];
+        let config = Config {
+            and_rebase: true,
+            rebase_options: &rebase_options,
+            message: Some("it's fine"),
+            ..DEFAULT_CONFIG
+        };
+        let script = emit_script(&ctx, &config);
 
-        // requires enabled config var
-        ctx.repo
-            .config()
-            .unwrap()
-            .set_bool(config::AUTO_STAGE_IF_NOTHING_STAGED_CONFIG_NAME, true)
-            .unwrap();
+        assert!(script.contains("\nfixup! Initial commit.\n\nit's fine\n"));
+        assert!(script.ends_with(
+            "\ngit read-tree \"$staged\"\n\
+             git rebase --interactive --autosquash --autostash --root --exec 'make test'\n"
+        ));
+        assert!(!nothing_left_in_index(&ctx.repo).unwrap());
+        assert_eq!(extract_commit_messages(&ctx.repo).len(), 1);
+    }
 
-        autostage_common(&ctx, &file_path);
+    #[test]
+    fn stat_per_fixup() {
+        let (ctx, file_path) = repo_utils::prepare_repo();
+        let other_path = PathBuf::from("other.txt");
+        let mut lines = prepare_numbered_lines(&ctx, &other_path);
+        lines[2] = "line 3 changed\n".to_owned();
+        lines[7] = "line 8 changed\n".to_owned();
+        std::fs::write(ctx.join(&other_path), lines.concat()).unwrap();
+        repo_utils::add(&ctx.repo, &other_path);
+        repo_utils::stage_file_changes(&ctx, &file_path);
 
-        // run 'git-absorb'
         let mut capturing_logger = log_utils::CapturingLogger::new();
-        run_with_repo(&capturing_logger.logger, &DEFAULT_CONFIG, &ctx.repo).unwrap();
+        let config = Config {
+            stat: true,
+            one_fixup_per_commit: true,
+            ..DEFAULT_CONFIG
+        };
+        let mut out = Vec::new();
+        absorb(
+            &capturing_logger.logger,
+            &config,
+            &ctx.repo,
+            &mut prompt::Terminal,
+            &mut out,
+            &mut stats::Stats::default(),
+            &mut Summary::default(),
+        )
+        .unwrap();
+        assert!(nothing_left_in_index(&ctx.repo).unwrap());
 
-        let mut revwalk = ctx.repo.revwalk().unwrap();
-        revwalk.push_head().unwrap();
-        assert_eq!(revwalk.count(), 2);
+        let messages = extract_commit_messages(&ctx.repo);
+        assert_eq!(messages[0], "fixup! Add lines\n");
+        assert_eq!(messages[1], "fixup! Initial commit.\n");
+        let add_lines = ctx.repo.revparse_single("HEAD~2").unwrap();
+        let initial = ctx.repo.revparse_single("HEAD~3").unwrap();
+        let short = |object: &git2::Object| object.short_id().unwrap().as_str().unwrap().to_owned();
 
-        assert!(nothing_left_in_index(&ctx.repo).unwrap());
+        assert_eq!(
+            String::from_utf8(out).unwrap(),
+            format!(
+                "{} Initial commit.\n\
+                 \x20test-file.txt | 3 +++\n\
+                 \x201 file changed, 3 insertions(+)\n\
+                 \n\
+                 {} Add lines\n\
+                 \x20other.txt | 4 ++--\n\
+                 \x201 file changed, 2 insertions(+), 2 deletions(-)\n",
+                short(&initial),
+                short(&add_lines),
+            )
+        );
 
         log_utils::assert_log_messages_are(
             capturing_logger.visible_logs(),
             vec![
-                &json!({"level": "INFO", "msg": "committed"}),
                 &json!({
                     "level": "INFO",
-                    "msg": "To squash the new commits, rebase:",
-                    "command": "git rebase --interactive --autosquash --autostash --root",
+                    "msg": "stack reaches the first commit in the repository",
+                }),
+                &json!({
+                    "level": "INFO",
+                    "msg": "committed",
+                    "subject": "Initial commit.",
+                    "files_changed": 1,
+                    "insertions": 3,
+                    "deletions": 0,
+                }),
+                &json!({
+                    "level": "INFO",
+                    "msg": "committed",
+                    "subject": "Add lines",
+                    "files_changed": 1,
+                    "insertions": 2,
+                    "deletions": 2,
                 }),
+                &json!({"level": "INFO", "msg": "To squash the new commits, rebase:"}),
             ],
         );
     }
 
     #[test]
-    fn do_not_autostage_if_index_was_not_empty() {
+    fn nearby_hunks_are_absorbed_together() {
         let (ctx, file_path) = repo_utils::prepare_repo();
+        let mut lines = prepare_numbered_lines(&ctx, &file_path);
 
-        // enable config var
-        ctx.repo
-            .config()
-            .unwrap()
-            .set_bool(config::AUTO_STAGE_IF_NOTHING_STAGED_CONFIG_NAME, true)
-            .unwrap();
+        // two separate hunks, with only four unchanged lines between them
+        lines[2] = "line 3 changed\n".to_owned();
+        lines[7] = "line 8 changed\n".to_owned();
+        std::fs::write(ctx.join(&file_path), lines.concat()).unwrap();
+        repo_utils::add(&ctx.repo, &file_path);
 
-        let (_, fp2) = autostage_common(&ctx, &file_path);
-        // we stage the extra file - should stay in index
-        repo_utils::add(&ctx.repo, &fp2);
+        let drain = slog::Discard;
+        let logger = slog::Logger::root(drain, o!());
+        run_with_repo(&logger, &DEFAULT_CONFIG, &ctx.repo).unwrap();
+        assert!(nothing_left_in_index(&ctx.repo).unwrap());
 
-        // run 'git-absorb'
-        let mut capturing_logger = log_utils::CapturingLogger::new();
-        run_with_repo(&capturing_logger.logger, &DEFAULT_CONFIG, &ctx.repo).unwrap();
+        let messages = extract_commit_messages(&ctx.repo);
+        assert_eq!(messages[..2], ["fixup! Add lines\n", "Add lines"]);
+
+        // the fixup shows both changes as a single hunk
+        let fixup = ctx.repo.head().unwrap().peel_to_commit().unwrap();
+        let diff = ctx
+            .repo
+            .diff_tree_to_tree(
+                Some(&fixup.parent(0).unwrap().tree().unwrap()),
+                Some(&fixup.tree().unwrap()),
+                None,
+            )
+            .unwrap();
+        let patch = git2::Patch::from_diff(&diff, 0).unwrap().unwrap();
+        assert_eq!(patch.num_hunks(), 1);
+        let (_, additions, deletions) = patch.line_stats().unwrap();
+        assert_eq!((additions, deletions), (2, 2));
+    }
 
-        let mut revwalk = ctx.repo.revwalk().unwrap();
-        revwalk.push_head().unwrap();
-        assert_eq!(revwalk.count(), 1);
+    #[test]
+    fn nearby_hunks_are_absorbed_separately_with_less_context() {
+        let (ctx, file_path) = repo_utils::prepare_repo();
+        let mut lines = prepare_numbered_lines(&ctx, &file_path);
 
-        assert_eq!(index_stats(&ctx.repo).unwrap().files_changed(), 1);
+        lines[2] = "line 3 changed\n".to_owned();
+        lines[7] = "line 8 changed\n".to_owned();
+        std::fs::write(ctx.join(&file_path), lines.concat()).unwrap();
+        repo_utils::add(&ctx.repo, &file_path);
 
-        log_utils::assert_log_messages_are(
-            capturing_logger.visible_logs(),
-            vec![&json!({
-                    "level": "WARN",
-                    "msg": "No changes were in-place file modifications. \
-                           Added, removed, or renamed files cannot be automatically absorbed."
-            })],
+        let drain = slog::Discard;
+        let logger = slog::Logger::root(drain, o!());
+        let config = Config {
+            diff_context: Some(1),
+            ..DEFAULT_CONFIG
+        };
+        run_with_repo(&logger, &config, &ctx.repo).unwrap();
+        assert!(nothing_left_in_index(&ctx.repo).unwrap());
+
+        let messages = extract_commit_messages(&ctx.repo);
+        assert_eq!(
+            messages[..3],
+            ["fixup! Add lines\n", "fixup! Add lines\n", "Add lines"]
         );
     }
 
     #[test]
-    fn do_not_autostage_if_not_enabled_by_config_var() {
+    fn distant_hunks_are_absorbed_separately() {
         let (ctx, file_path) = repo_utils::prepare_repo();
+        let mut lines = prepare_numbered_lines(&ctx, &file_path);
 
-        // disable config var
-        ctx.repo
-            .config()
-            .unwrap()
-            .set_bool(config::AUTO_STAGE_IF_NOTHING_STAGED_CONFIG_NAME, false)
-            .unwrap();
+        lines[1] = "line 2 changed\n".to_owned();
+        lines[8] = "line 9 changed\n".to_owned();
+        std::fs::write(ctx.join(&file_path), lines.concat()).unwrap();
+        repo_utils::add(&ctx.repo, &file_path);
 
-        autostage_common(&ctx, &file_path);
+        let drain = slog::Discard;
+        let logger = slog::Logger::root(drain, o!());
+        run_with_repo(&logger, &DEFAULT_CONFIG, &ctx.repo).unwrap();
+        assert!(nothing_left_in_index(&ctx.repo).unwrap());
 
-        // run 'git-absorb'
-        let mut capturing_logger = log_utils::CapturingLogger::new();
-        run_with_repo(&capturing_logger.logger, &DEFAULT_CONFIG, &ctx.repo).unwrap();
+        let messages = extract_commit_messages(&ctx.repo);
+        assert_eq!(
+            messages[..3],
+            ["fixup! Add lines\n", "fixup! Add lines\n", "Add lines"]
+        );
+    }
 
-        let mut revwalk = ctx.repo.revwalk().unwrap();
-        revwalk.push_head().unwrap();
-        assert_eq!(revwalk.count(), 1);
+    #[test]
+    fn nearby_hunks_for_different_commits_are_absorbed_separately() {
+        let (ctx, file_path) = repo_utils::prepare_repo();
+        let mut lines = prepare_numbered_lines(&ctx, &file_path);
+        lines[6] = "line 7 changed\n".to_owned();
+        std::fs::write(ctx.join(&file_path), lines.concat()).unwrap();
+        {
+            let tree = repo_utils::add(&ctx.repo, &file_path);
+            let head = ctx.repo.head().unwrap().peel_to_commit().unwrap();
+            repo_utils::commit(&ctx.repo, "HEAD", "Change line 7", &tree, &[&head]);
+        }
 
+        // line 4 belongs to the first commit, line 7 to the second one
+        lines[3] = "line 4 changed\n".to_owned();
+        lines[6] = "line 7 changed again\n".to_owned();
+        std::fs::write(ctx.join(&file_path), lines.concat()).unwrap();
+        repo_utils::add(&ctx.repo, &file_path);
+
+        let drain = slog::Discard;
+        let logger = slog::Logger::root(drain, o!());
+        run_with_repo(&logger, &DEFAULT_CONFIG, &ctx.repo).unwrap();
         assert!(nothing_left_in_index(&ctx.repo).unwrap());
 
-        log_utils::assert_log_messages_are(
-            capturing_logger.visible_logs(),
-            vec![&json!({
-                "level": "WARN",
-                "msg": format!(
-                    "No changes staged. \
-                    Try adding something to the index or set {} = true.",
-                    config::AUTO_STAGE_IF_NOTHING_STAGED_CONFIG_NAME,
-                ),
-            })],
+        let messages = extract_commit_messages(&ctx.repo);
+        assert_eq!(
+            messages[..3],
+            [
+                "fixup! Change line 7\n",
+                "fixup! Add lines\n",
+                "Change line 7"
+            ]
         );
     }
 
     #[test]
-    fn autostage_if_index_was_empty_and_no_changes() {
-        let (ctx, _file_path) = repo_utils::prepare_repo();
-
-        // requires enabled config var
-        ctx.repo
-            .config()
-            .unwrap()
-            .set_bool(config::AUTO_STAGE_IF_NOTHING_STAGED_CONFIG_NAME, true)
-            .unwrap();
+    fn print_stack_flag() {
+        let (ctx, _) = repo_utils::prepare_repo();
+        {
+            let initial = ctx.repo.head().unwrap().peel_to_commit().unwrap();
+            repo_utils::empty_commit_chain(&ctx.repo, "HEAD", &[&initial], 2);
+        }
+        let commits: Vec<_> = ["HEAD", "HEAD~1", "HEAD~2"]
+            .iter()
+            .map(|rev| {
+                let commit = ctx.repo.revparse_single(rev).unwrap();
+                commit.short_id().unwrap().as_str().unwrap().to_owned()
+            })
+            .collect();
 
-        // run 'git-absorb'
         let mut capturing_logger = log_utils::CapturingLogger::new();
-        run_with_repo(&capturing_logger.logger, &DEFAULT_CONFIG, &ctx.repo).unwrap();
-
-        let mut revwalk = ctx.repo.revwalk().unwrap();
-        revwalk.push_head().unwrap();
-        assert_eq!(revwalk.count(), 1);
-
-        assert!(nothing_left_in_index(&ctx.repo).unwrap());
+        let config = Config {
+            print_stack: true,
+            base: &["HEAD~2"],
+            ..DEFAULT_CONFIG
+        };
+        run_with_repo(&capturing_logger.logger, &config, &ctx.repo).unwrap();
 
         log_utils::assert_log_messages_are(
             capturing_logger.visible_logs(),
-            vec![&json!({
-                    "level": "WARN",
-                    "msg": "No changes staged, even after auto-staging. \
-                           Try adding something to the index."})],
+            vec![
+                &json!({
+                    "level": "INFO",
+                    "msg": "stack commit",
+                    "commit": commits[0],
+                    "subject": "1",
+                    "author": "nobody <nobody@example.com>",
+                }),
+                &json!({
+                    "level": "INFO",
+                    "msg": "stack commit",
+                    "commit": commits[1],
+                    "subject": "0",
+                    "author": "nobody <nobody@example.com>",
+                }),
+                &json!({
+                    "level": "INFO",
+                    "msg": "stack base",
+                    "commit": commits[2],
+                    "subject": "Initial commit.",
+                }),
+            ],
         );
+        assert_eq!(extract_commit_messages(&ctx.repo).len(), 3);
     }
 
     #[test]
-    fn fixup_message_always_commit_sha_if_configured() {
-        let ctx = repo_utils::prepare_and_stage();
-
-        ctx.repo
-            .config()
-            .unwrap()
-            .set_bool(config::FIXUP_TARGET_ALWAYS_SHA_CONFIG_NAME, true)
-            .unwrap();
+    fn print_stack_flag_down_to_root() {
+        let (ctx, _) = repo_utils::prepare_repo();
 
-        // run 'git-absorb'
         let mut capturing_logger = log_utils::CapturingLogger::new();
-        run_with_repo(&capturing_logger.logger, &DEFAULT_CONFIG, &ctx.repo).unwrap();
-        assert!(nothing_left_in_index(&ctx.repo).unwrap());
-
-        let mut revwalk = ctx.repo.revwalk().unwrap();
-        revwalk.push_head().unwrap();
-
-        let oids: Vec<git2::Oid> = revwalk.by_ref().collect::<Result<Vec<_>, _>>().unwrap();
-        assert_eq!(oids.len(), 3);
-
-        let commit = ctx.repo.find_commit(oids[0]).unwrap();
-        let actual_msg = commit.summary().unwrap();
-        let expected_msg = format!("fixup! {}", oids.last().unwrap());
-        assert_eq!(actual_msg, expected_msg);
+        let config = Config {
+            print_stack: true,
+            ..DEFAULT_CONFIG
+        };
+        run_with_repo(&capturing_logger.logger, &config, &ctx.repo).unwrap();
 
         log_utils::assert_log_messages_are(
             capturing_logger.visible_logs(),
             vec![
-                &json!({"level": "INFO", "msg": "committed"}),
-                &json!({"level": "INFO", "msg": "committed"}),
                 &json!({
                     "level": "INFO",
-                    "msg": "To squash the new commits, rebase:",
-                    "command": "git rebase --interactive --autosquash --autostash --root",
+                    "msg": "stack commit",
+                    "subject": "Initial commit.",
+                }),
+                &json!({
+                    "level": "INFO",
+                    "msg": "stack reaches the first commit in the repository",
                 }),
             ],
         );
     }
 
     #[test]
-    fn fixup_message_option_left_out_sets_only_summary() {
-        let ctx = repo_utils::prepare_and_stage();
+    fn whole_file_prefers_commit_touching_same_function() {
+        let (ctx, _) = repo_utils::prepare_repo();
+        let path = PathBuf::from("code.rs");
+        let mut lines = vec![
+            "fn foo() {\n",
+            "    one();\n",
+            "    two();\n",
+            "    three();\n",
+            "    four();\n",
+            "}\n",
+            "\n",
+            "fn bar() {\n",
+            "    five();\n",
+            "}\n",
+        ];
+        let commit_lines = |lines: &[&str], message: &str| {
+            std::fs::write(ctx.join(&path), lines.concat()).unwrap();
+            let tree = repo_utils::add(&ctx.repo, &path);
+            let head = ctx.repo.head().unwrap().peel_to_commit().unwrap();
+            repo_utils::commit(&ctx.repo, "HEAD", message, &tree, &[&head]);
+        };
+        commit_lines(&lines, "Add code");
+        lines.insert(2, "    helper();\n");
+        commit_lines(&lines, "Call helper in foo");
+        lines[9] = "    six();\n";
+        commit_lines(&lines, "Change bar");
+
+        // not next to the helper call, so it would normally go to the
+        // commit that added the file
+        lines[5] = "    four_changed();\n";
+        std::fs::write(ctx.join(&path), lines.concat()).unwrap();
+        repo_utils::add(&ctx.repo, &path);
 
-        // run 'git-absorb'
         let drain = slog::Discard;
         let logger = slog::Logger::root(drain, o!());
-        run_with_repo(&logger, &DEFAULT_CONFIG, &ctx.repo).unwrap();
+        let config = Config {
+            whole_file: true,
+            ..DEFAULT_CONFIG
+        };
+        run_with_repo(&logger, &config, &ctx.repo).unwrap();
         assert!(nothing_left_in_index(&ctx.repo).unwrap());
 
-        let mut revwalk = ctx.repo.revwalk().unwrap();
-        revwalk.push_head().unwrap();
+        let messages = extract_commit_messages(&ctx.repo);
+        assert_eq!(messages[0], "fixup! Call helper in foo\n");
+    }
 
-        let oids: Vec<git2::Oid> = revwalk.by_ref().collect::<Result<Vec<_>, _>>().unwrap();
-        assert_eq!(oids.len(), 3);
+    #[test]
+    fn whole_file_skips_files_with_merge_attributes() {
+        let (ctx, file_path) = repo_utils::prepare_repo();
+        std::fs::write(
+            ctx.join(Path::new(".gitattributes")),
+            "*.lock merge=ours\n*.dat binary\n",
+        )
+        .unwrap();
+        let paths = [Path::new("deps.lock"), Path::new("table.dat")];
+        for path in paths {
+            std::fs::write(ctx.join(path), "one\ntwo\n").unwrap();
+            let tree = repo_utils::add(&ctx.repo, path);
+            let head = ctx.repo.head().unwrap().peel_to_commit().unwrap();
+            repo_utils::commit(&ctx.repo, "HEAD", "Add file", &tree, &[&head]);
+        }
+        for path in paths {
+            std::fs::write(ctx.join(path), "one\nthree\n").unwrap();
+            repo_utils::add(&ctx.repo, path);
+        }
+        repo_utils::stage_file_changes(&ctx, &file_path);
 
-        let fixup_commit = ctx.repo.find_commit(oids[0]).unwrap();
-        let fixed_up_commit = ctx.repo.find_commit(*oids.last().unwrap()).unwrap();
-        let actual_msg = fixup_commit.message().unwrap();
-        let expected_msg = fixed_up_commit.message().unwrap();
-        let expected_msg = format!("fixup! {}\n", expected_msg);
-        assert_eq!(actual_msg, expected_msg);
+        let mut capturing_logger = log_utils::CapturingLogger::new();
+        let config = Config {
+            whole_file: true,
+            ..DEFAULT_CONFIG
+        };
+        run_with_repo(&capturing_logger.logger, &config, &ctx.repo).unwrap();
+
+        let messages = extract_commit_messages(&ctx.repo);
+        assert_eq!(
+            messages[..3],
+            [
+                "fixup! Initial commit.\n",
+                "fixup! Initial commit.\n",
+                "Add file"
+            ]
+        );
+        let unabsorbed: Vec<_> = capturing_logger
+            .visible_logs()
+            .into_iter()
+            .filter(|log| log["msg"] == "Staged change could not be absorbed")
+            .map(|log| {
+                (
+                    log["location"].as_str().unwrap().to_owned(),
+                    log["reason"].as_str().unwrap().to_owned(),
+                )
+            })
+            .collect();
+        assert_eq!(
+            unabsorbed,
+            [("deps.lock", "attributes"), ("table.dat", "binary")]
+                .map(|(location, reason)| (location.to_owned(), reason.to_owned()))
+        );
+    }
+
+    /// Commits "a.txt" and "unrelated.txt" in "Add files", then changes
+    /// both in "Change files", and stages a fix to the change in "a.txt".
+    fn prepare_two_files(ctx: &repo_utils::Context) {
+        for (message, a, unrelated) in [
+            ("Add files", "a\nb\nc\n", "one\n"),
+            ("Change files", "a\nB\nc\n", "two\n"),
+        ] {
+            std::fs::write(ctx.join(Path::new("a.txt")), a).unwrap();
+            std::fs::write(ctx.join(Path::new("unrelated.txt")), unrelated).unwrap();
+            repo_utils::add(&ctx.repo, Path::new("a.txt"));
+            let tree = repo_utils::add(&ctx.repo, Path::new("unrelated.txt"));
+            let head = ctx.repo.head().unwrap().peel_to_commit().unwrap();
+            repo_utils::commit(&ctx.repo, "HEAD", message, &tree, &[&head]);
+        }
+        std::fs::write(ctx.join(Path::new("a.txt")), "a\nB!\nc\n").unwrap();
+        repo_utils::add(&ctx.repo, Path::new("a.txt"));
+    }
+
+    fn blob_id(repo: &git2::Repository, rev: &str, path: &str) -> git2::Oid {
+        let tree = repo.revparse_single(rev).unwrap().peel_to_tree().unwrap();
+        tree.get_path(Path::new(path)).unwrap().id()
+    }
+
+    /// Removes a loose object, as if it was never fetched.
+    fn prune_object(repo: &git2::Repository, id: git2::Oid) {
+        let id = id.to_string();
+        std::fs::remove_file(repo.path().join("objects").join(&id[..2]).join(&id[2..])).unwrap();
+        assert!(!repo.odb().unwrap().exists(id.parse().unwrap()));
     }
 
     #[test]
-    fn fixup_message_option_provided_sets_message() {
-        let ctx = repo_utils::prepare_and_stage();
+    fn blobs_for_unrelated_paths_are_never_read() {
+        let (ctx, _) = repo_utils::prepare_repo();
+        prepare_two_files(&ctx);
+        prune_object(&ctx.repo, blob_id(&ctx.repo, "HEAD~1", "unrelated.txt"));
+        prune_object(&ctx.repo, blob_id(&ctx.repo, "HEAD", "unrelated.txt"));
 
-        // run 'git-absorb'
         let drain = slog::Discard;
         let logger = slog::Logger::root(drain, o!());
-        let fixup_message_body = "git-absorb is my favorite git tool!";
-        let config = Config {
-            message: Some(fixup_message_body),
-            ..DEFAULT_CONFIG
-        };
-        run_with_repo(&logger, &config, &ctx.repo).unwrap();
+        run_with_repo(&logger, &DEFAULT_CONFIG, &ctx.repo).unwrap();
+
         assert!(nothing_left_in_index(&ctx.repo).unwrap());
+        assert_eq!(
+            extract_commit_messages(&ctx.repo)[0],
+            "fixup! Change files\n"
+        );
+    }
 
-        let mut revwalk = ctx.repo.revwalk().unwrap();
-        revwalk.push_head().unwrap();
+    #[test]
+    fn missing_blob_without_promisor_remote() {
+        let (ctx, _) = repo_utils::prepare_repo();
+        prepare_two_files(&ctx);
+        let missing = blob_id(&ctx.repo, "HEAD~1", "a.txt");
+        prune_object(&ctx.repo, missing);
 
-        let oids: Vec<git2::Oid> = revwalk.by_ref().collect::<Result<Vec<_>, _>>().unwrap();
-        assert_eq!(oids.len(), 3);
+        let drain = slog::Discard;
+        let logger = slog::Logger::root(drain, o!());
+        let err = run_with_repo(&logger, &DEFAULT_CONFIG, &ctx.repo).unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            format!("objects {} are missing from the repository", missing)
+        );
+    }
 
-        let fixup_commit = ctx.repo.find_commit(oids[0]).unwrap();
-        let fixed_up_commit = ctx.repo.find_commit(*oids.last().unwrap()).unwrap();
-        let actual_msg = fixup_commit.message().unwrap();
-        let expected_msg = fixed_up_commit.message().unwrap();
-        let expected_msg = format!("fixup! {}\n\n{}\n", expected_msg, fixup_message_body);
-        assert_eq!(actual_msg, expected_msg);
+    #[test]
+    fn missing_blob_is_fetched_from_promisor_remote() {
+        let (origin, _) = repo_utils::prepare_repo();
+        prepare_two_files(&origin);
+        repo_utils::set_config_option(&origin.repo, "uploadpack.allowFilter", "true");
+        repo_utils::set_config_option(&origin.repo, "uploadpack.allowAnySHA1InWant", "true");
+
+        let dir = tempfile::tempdir().unwrap();
+        let status = std::process::Command::new("git")
+            .args(["clone", "--quiet", "--filter=blob:none"])
+            .arg(format!("file://{}", origin.dir.path().display()))
+            .arg(dir.path())
+            .status()
+            .unwrap();
+        assert!(status.success());
+        let repo = git2::Repository::open(dir.path()).unwrap();
+        repo_utils::become_author(&repo, "nobody", "nobody@example.com");
+        // only what was checked out was fetched, none of the history
+        std::fs::write(dir.path().join("a.txt"), "a\nB!\nc\n").unwrap();
+        repo_utils::add(&repo, Path::new("a.txt"));
+        let unrelated = blob_id(&repo, "HEAD~1", "unrelated.txt");
+        assert!(!repo.odb().unwrap().exists(unrelated));
+        let missing = blob_id(&repo, "HEAD~1", "a.txt");
+        assert!(!repo.odb().unwrap().exists(missing));
+
+        let drain = slog::Discard;
+        let logger = slog::Logger::root(drain, o!());
+        run_with_repo(&logger, &DEFAULT_CONFIG, &repo).unwrap();
+
+        assert!(repo.odb().unwrap().exists(missing));
+        assert!(!repo.odb().unwrap().exists(unrelated));
+        assert_eq!(extract_commit_messages(&repo)[0], "fixup! Change files\n");
+    }
+
+    /// Read the contents of a file as committed in HEAD.
+    fn head_file_contents(repo: &git2::Repository, path: &Path) -> String {
+        let head_tree = repo.head().unwrap().peel_to_tree().unwrap();
+        let blob = head_tree
+            .get_path(path)
+            .unwrap()
+            .to_object(repo)
+            .unwrap()
+            .peel_to_blob()
+            .unwrap();
+        String::from_utf8_lossy(blob.content()).into_owned()
     }
 
     /// Perform a revwalk from HEAD, extracting the commit messages.
@@ -1914,12 +11721,58 @@ mod tests {
         dry_run: false,
         force_author: false,
         force_detach: false,
-        base: None,
+        cross_branch_boundaries: false,
+        force_rewrite: false,
+        force_overlap: false,
+        base: &[],
+        range: None,
+        diff_algorithm: None,
+        diff_context: None,
         and_rebase: false,
+        no_rebase: false,
         rebase_options: &Vec::new(),
         whole_file: false,
+        split_hunks: false,
         one_fixup_per_commit: false,
         squash: false,
         message: None,
+        unstaged: false,
+        from_stash: None,
+        patch_file: None,
+        into: None,
+        target_touched: None,
+        verify_build: None,
+        paths: &[],
+        exclude_hunks_matching: &[],
+        include_hunks_matching: &[],
+        recurse_submodules: false,
+        select: false,
+        yes: false,
+        check: false,
+        all_or_nothing: false,
+        print_stack: false,
+        undo: false,
+        emit_script: false,
+        annotate: false,
+        show_final: false,
+        export_series: None,
+        stage_only: false,
+        rewrite: false,
+        extract: false,
+        amend_head: false,
+        only_my_lines: false,
+        additions_only: false,
+        deletions_only: false,
+        stat: false,
+        config_file: None,
+        stats_file: None,
+        porcelain: false,
+        timings: false,
+        truncate_subject: false,
+        fixup_by_sha: false,
+        time_limit: None,
+        max_report: None,
+        plan_only: None,
+        apply_plan: None,
     };
 }
@@ -11,9 +11,24 @@ use std::io;
 #[derive(Debug, clap::Parser)]
 #[command(version)]
 struct Cli {
-    /// Use this commit as the base of the absorb stack
+    /// Use this commit as the base of the absorb stack, can be repeated to
+    /// take the commits above any of them
     #[clap(long, short)]
-    base: Option<String>,
+    base: Vec<String>,
+    /// Only absorb into the commits in RANGE, given as BASE..TIP, where TIP is HEAD or one of its ancestors
+    #[clap(long, value_name = "RANGE", conflicts_with = "base")]
+    range: Option<String>,
+    /// Split changes into hunks with this diff algorithm, instead of the one from absorb.diffAlgorithm or diff.algorithm
+    #[clap(long, value_name = "ALGORITHM", value_parser = ["myers", "default", "minimal", "patience", "histogram"])]
+    diff_algorithm: Option<String>,
+    /// Never reorder hunks with N or fewer unchanged lines between them, instead of absorb.diffContext
+    #[clap(
+        long,
+        short = 'U',
+        visible_alias = "context-lines-for-match",
+        value_name = "N"
+    )]
+    unified: Option<usize>,
     /// Don't make any actual changes
     #[clap(long, short = 'n')]
     dry_run: bool,
@@ -23,15 +38,58 @@ struct Cli {
     /// Generate fixups even when on a non-branch (detached) HEAD
     #[clap(long)]
     force_detach: bool,
+    /// Rewrite commits with --rewrite even if they are signed or were pushed
+    #[clap(long)]
+    force_rewrite: bool,
+    /// When two hunks that change the same lines go into different commits, only absorb the one for the older commit
+    #[clap(long)]
+    force_overlap: bool,
     /// Skip all safety checks as if all --force-* flags were given
     #[clap(long, short)]
     force: bool,
-    /// Display more output
-    #[clap(long, short)]
-    verbose: bool,
+    /// Let the stack reach past commits that other local branches point at
+    #[clap(long)]
+    cross_branch_boundaries: bool,
+    /// Display more output, repeat for even more
+    #[clap(long, short, action = clap::ArgAction::Count)]
+    verbose: u8,
+    /// Only display warnings and errors
+    #[clap(long, short, conflicts_with = "verbose")]
+    quiet: bool,
+    /// List at most N of the staged changes that could not be absorbed
+    #[clap(long, value_name = "N")]
+    max_report: Option<usize>,
+    /// How to format log output
+    #[clap(long, value_enum, env = "GIT_ABSORB_LOG_FORMAT", default_value_t = LogFormat::Text)]
+    log_format: LogFormat,
+    /// Also append the log to the file at PATH, one JSON object per line
+    #[clap(long, value_name = "PATH")]
+    log_file: Option<std::path::PathBuf>,
     /// Run rebase if successful
     #[clap(long, short = 'r')]
     and_rebase: bool,
+    /// Do not run rebase, even if absorb.andRebase is set
+    #[clap(long, conflicts_with = "and_rebase")]
+    no_rebase: bool,
+    /// Only absorb the changes to these files or directories
+    #[clap(value_name = "PATH")]
+    paths: Vec<String>,
+    /// Leave hunks with an added line that matches REGEX in the index, can be repeated
+    #[clap(long, value_name = "REGEX")]
+    exclude_hunks_matching: Vec<String>,
+    /// Only absorb hunks with an added line that matches REGEX, can be repeated
+    #[clap(long, value_name = "REGEX")]
+    include_hunks_matching: Vec<String>,
+    /// Also read paths to absorb the changes to from stdin, one per line
+    #[clap(long, conflicts_with = "select")]
+    stdin: bool,
+    /// Separate the paths read with --stdin by NUL instead of newlines
+    #[clap(short = 'z', long = "null", requires = "stdin")]
+    null: bool,
+    /// Also absorb the changes staged in each initialized submodule, into
+    /// its own stack
+    #[clap(long, conflicts_with_all = ["plan_only", "apply_plan", "emit_script", "from_stash", "patch_file"])]
+    recurse_submodules: bool,
     /// Extra arguments to pass to git rebase. Only valid if --and-rebase is set
     #[clap(last = true)]
     rebase_options: Vec<String>,
@@ -41,6 +99,9 @@ struct Cli {
     /// Match the change against the complete file
     #[clap(long, short)]
     whole_file: bool,
+    /// Split hunks over lines from different commits, and absorb each part into its own commit
+    #[clap(long, conflicts_with = "whole_file")]
+    split_hunks: bool,
     /// Only generate one fixup per commit
     #[clap(long, short = 'F')]
     one_fixup_per_commit: bool,
@@ -50,23 +111,186 @@ struct Cli {
     /// Commit message body that is given to all fixup commits
     #[clap(long, short)]
     message: Option<String>,
+    /// Absorb unstaged changes to tracked files, staging only what was absorbed
+    #[clap(long)]
+    unstaged: bool,
+    /// Absorb the changes saved in STASH, such as stash@{1}, instead of the staged ones
+    #[clap(long, value_name = "STASH", conflicts_with = "unstaged")]
+    from_stash: Option<String>,
+    /// Absorb the changes in the patch file at PATH, made on top of HEAD, instead of the staged ones
+    #[clap(long, value_name = "PATH", conflicts_with_all = ["unstaged", "from_stash", "plan_only", "apply_plan"])]
+    patch_file: Option<std::path::PathBuf>,
+    /// Absorb every hunk that can be moved there into COMMIT, given as a revision or a substring of its subject
+    #[clap(long, value_name = "COMMIT")]
+    into: Option<String>,
+    /// Only absorb into commits that changed PATH, a file or directory relative to the top of the repository
+    #[clap(long, value_name = "PATH")]
+    target_touched: Option<String>,
+    /// Absorb each hunk into the oldest commit it can go into whose tree, with the hunk, passes the shell COMMAND
+    #[clap(long, value_name = "COMMAND", conflicts_with_all = ["into", "extract", "apply_plan"])]
+    verify_build: Option<String>,
+    /// Interactively choose which staged hunks to consider for absorbing
+    #[clap(long)]
+    select: bool,
+    /// Answer yes to every question, for use in scripts
+    #[clap(long, short = 'y', visible_alias = "assume-yes")]
+    yes: bool,
+    /// Don't make any actual changes, but exit with status 2 if some staged changes cannot be absorbed
+    #[clap(long)]
+    check: bool,
+    /// Only absorb anything if every staged change can be absorbed, and otherwise exit with status 2
+    #[clap(long)]
+    all_or_nothing: bool,
+    /// Show the commits that would be considered for absorbing, then exit
+    #[clap(long)]
+    print_stack: bool,
+    /// Move the branch back to before the last absorb, and stage again what was staged then
+    #[clap(long, conflicts_with_all = ["dry_run", "print_stack", "check", "plan_only", "apply_plan", "emit_script", "paths"])]
+    undo: bool,
+    /// Print a shell script of git commands that would create the fixups, instead of creating them
+    #[clap(long, conflicts_with = "stat")]
+    emit_script: bool,
+    /// Show the staged diff with the commit each change would be absorbed into, then exit without changing anything
+    #[clap(long, conflicts_with_all = ["plan_only", "apply_plan", "emit_script", "stage_only", "rewrite", "extract", "and_rebase", "porcelain", "stat"])]
+    annotate: bool,
+    /// With --dry-run, show what the whole stack would change once the fixups were squashed
+    #[clap(long, requires = "dry_run", conflicts_with = "emit_script")]
+    show_final: bool,
+    /// Write the stack as it would be once the fixups were squashed, as numbered patches, into this directory, or to stdout with -
+    #[clap(long, value_name = "DIR", conflicts_with_all = ["plan_only", "apply_plan", "emit_script", "stage_only", "and_rebase", "rewrite", "annotate", "undo", "recurse_submodules"])]
+    export_series: Option<std::path::PathBuf>,
+    /// Create the fixups on refs/absorb/fixups instead of the current branch, leaving HEAD and the index alone
+    #[clap(long, conflicts_with_all = ["and_rebase", "rewrite"])]
+    stage_only: bool,
+    /// Fold the changes straight into the commits they belong to, instead of creating fixup commits
+    #[clap(long, conflicts_with_all = ["and_rebase", "squash"])]
+    rewrite: bool,
+    /// Take the lines that the staged changes revert out of the commits that added them, leaving them in the working tree
+    #[clap(long, conflicts_with_all = ["and_rebase", "squash", "stage_only", "amend_head"])]
+    extract: bool,
+    /// Amend HEAD instead of creating fixups, when every change belongs to it
+    #[clap(long, conflicts_with_all = ["rewrite", "stage_only", "emit_script"])]
+    amend_head: bool,
+    /// Only absorb hunks that change lines you last changed yourself, according to git blame
+    #[clap(long)]
+    only_my_lines: bool,
+    /// Leave out the hunks that only delete lines
+    #[clap(long, conflicts_with = "deletions_only")]
+    additions_only: bool,
+    /// Leave out the hunks that only add lines
+    #[clap(long)]
+    deletions_only: bool,
+    /// Show a diffstat of each fixup on stdout, under the commit it fixes up
+    #[clap(long)]
+    stat: bool,
+    /// List each fixup created on stdout, in a format that stays the same across versions, for scripts
+    #[clap(long, conflicts_with_all = ["stat", "timings", "emit_script", "stage_only", "rewrite", "extract"])]
+    porcelain: bool,
+    /// Read absorb.* settings from this file, on top of the usual git config
+    #[clap(long, value_name = "PATH")]
+    config_file: Option<std::path::PathBuf>,
+    /// Append a line of JSON with counts from this run to the file at PATH
+    #[clap(long, value_name = "PATH")]
+    stats_file: Option<std::path::PathBuf>,
+    /// Shorten fixup subjects longer than absorb.maxSubjectLength, instead of only warning
+    #[clap(long)]
+    truncate_subject: bool,
+    /// Name the target of every fixup by its SHA rather than its subject, as if absorb.fixupTargetAlwaysSHA were true
+    #[clap(long)]
+    fixup_by_sha: bool,
+    /// Print how long each phase of the run took, at the end
+    #[clap(long)]
+    timings: bool,
+    /// Stop matching hunks after SECONDS, absorbing what was matched so far
+    #[clap(long, value_name = "SECONDS", value_parser = parse_seconds)]
+    time_limit: Option<std::time::Duration>,
+    /// Write where each staged hunk would be absorbed to the file at PATH, then exit without changing anything
+    #[clap(
+        long,
+        visible_alias = "emit-plan",
+        value_name = "PATH",
+        conflicts_with_all = ["apply_plan", "and_rebase"]
+    )]
+    plan_only: Option<std::path::PathBuf>,
+    /// Create the fixups described by a plan from --plan-only, if HEAD and the index are unchanged
+    #[clap(long, value_name = "PATH")]
+    apply_plan: Option<std::path::PathBuf>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, clap::ValueEnum)]
+enum LogFormat {
+    /// Human readable messages
+    Text,
+    /// One JSON object per line
+    Json,
 }
 
 fn main() {
     let Cli {
         base,
+        range,
+        diff_algorithm,
+        unified,
         dry_run,
         force_author,
         force_detach,
+        cross_branch_boundaries,
+        force_rewrite,
+        force_overlap,
         force,
         verbose,
+        quiet,
+        max_report,
+        log_format,
+        log_file,
         and_rebase,
+        no_rebase,
+        mut paths,
+        exclude_hunks_matching,
+        include_hunks_matching,
+        stdin,
+        null,
+        recurse_submodules,
         rebase_options,
         gen_completions,
         whole_file,
+        split_hunks,
         one_fixup_per_commit,
         squash,
         message,
+        unstaged,
+        from_stash,
+        patch_file,
+        into,
+        target_touched,
+        verify_build,
+        select,
+        yes,
+        check,
+        all_or_nothing,
+        print_stack,
+        undo,
+        emit_script,
+        annotate,
+        show_final,
+        export_series,
+        stage_only,
+        rewrite,
+        extract,
+        amend_head,
+        only_my_lines,
+        additions_only,
+        deletions_only,
+        stat,
+        porcelain,
+        config_file,
+        stats_file,
+        truncate_subject,
+        fixup_by_sha,
+        timings,
+        time_limit,
+        plan_only,
+        apply_plan,
     } = Cli::parse();
 
     if let Some(shell) = gen_completions {
@@ -84,27 +308,51 @@ fn main() {
         return;
     }
 
-    let decorator = slog_term::TermDecorator::new().build();
-    let drain = slog_term::FullFormat::new(decorator).build().fuse();
-    let drain = std::sync::Mutex::new(drain).fuse();
+    let drain: Box<dyn Drain<Ok = (), Err = slog::Never> + Send> = match log_format {
+        LogFormat::Text => {
+            let decorator = slog_term::TermDecorator::new().build();
+            Box::new(slog_term::FullFormat::new(decorator).build().fuse())
+        }
+        LogFormat::Json => Box::new(json_drain(io::stderr())),
+    };
+    let log_file = log_file.map(|path| {
+        let file = std::fs::OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&path);
+        (path, file)
+    });
+    let logger = match log_file {
+        Some((_, Ok(file))) => tee_logger(drain, file, quiet, verbose),
+        Some((path, Err(e))) => {
+            let logger = filtered_logger(drain, quiet, verbose);
+            crit!(logger, "could not open log file";
+                  "path" => path.display().to_string(),
+                  "err" => e.to_string(),
+            );
+            drop(logger);
+            ::std::process::exit(1);
+        }
+        None => filtered_logger(drain, quiet, verbose),
+    };
 
-    let drain = slog::LevelFilter::new(
-        drain,
-        if verbose {
-            slog::Level::Debug
-        } else {
-            slog::Level::Info
-        },
-    )
-    .fuse();
-    let mut logger = slog::Logger::root(drain, o!());
-    if verbose {
-        logger = logger.new(o!(
-            "module" => slog::FnValue(|record| record.module()),
-            "line" => slog::FnValue(|record| record.line()),
-        ));
+    if stdin {
+        match read_paths(io::stdin().lock(), null) {
+            Ok(read) => paths.extend(read),
+            Err(e) => {
+                crit!(logger, "could not read paths from stdin"; "err" => e.to_string());
+                drop(logger);
+                ::std::process::exit(1);
+            }
+        }
     }
 
+    let base: Vec<&str> = base.iter().map(AsRef::as_ref).collect();
+    let paths: Vec<&str> = paths.iter().map(AsRef::as_ref).collect();
+    let exclude_hunks_matching: Vec<&str> =
+        exclude_hunks_matching.iter().map(AsRef::as_ref).collect();
+    let include_hunks_matching: Vec<&str> =
+        include_hunks_matching.iter().map(AsRef::as_ref).collect();
     let rebase_options: Vec<&str> = rebase_options.iter().map(AsRef::as_ref).collect();
     if let Err(e) = git_absorb::run(
         &logger,
@@ -112,18 +360,306 @@ fn main() {
             dry_run,
             force_author: force_author || force,
             force_detach: force_detach || force,
-            base: base.as_deref(),
+            cross_branch_boundaries,
+            force_rewrite: force_rewrite || force,
+            force_overlap: force_overlap || force,
+            base: &base,
+            range: range.as_deref(),
+            diff_algorithm: diff_algorithm.as_deref(),
+            diff_context: unified,
             and_rebase,
+            no_rebase,
             rebase_options: &rebase_options,
             whole_file,
+            split_hunks,
             one_fixup_per_commit,
             squash,
             message: message.as_deref(),
+            unstaged,
+            from_stash: from_stash.as_deref(),
+            patch_file: patch_file.as_deref(),
+            into: into.as_deref(),
+            target_touched: target_touched.as_deref(),
+            verify_build: verify_build.as_deref(),
+            paths: &paths,
+            exclude_hunks_matching: &exclude_hunks_matching,
+            include_hunks_matching: &include_hunks_matching,
+            recurse_submodules,
+            select,
+            yes,
+            check,
+            all_or_nothing,
+            print_stack,
+            undo,
+            emit_script,
+            annotate,
+            show_final,
+            export_series: export_series.as_deref(),
+            stage_only,
+            rewrite,
+            extract,
+            amend_head,
+            only_my_lines,
+            additions_only,
+            deletions_only,
+            stat,
+            porcelain,
+            config_file: config_file.as_deref(),
+            stats_file: stats_file.as_deref(),
+            timings,
+            truncate_subject,
+            fixup_by_sha,
+            time_limit,
+            max_report,
+            plan_only: plan_only.as_deref(),
+            apply_plan: apply_plan.as_deref(),
         },
     ) {
-        crit!(logger, "absorb failed"; "err" => e.to_string());
+        let (code, message) = exit_code(&e);
+        crit!(logger, "{}", message; "err" => e.to_string());
         // wait for async logger to finish writing messages
         drop(logger);
-        ::std::process::exit(1);
+        ::std::process::exit(code);
+    }
+}
+
+/// The exit code for `e`, and what to log it as.
+fn exit_code(e: &git_absorb::AbsorbError) -> (i32, &'static str) {
+    use git_absorb::AbsorbError;
+    match e {
+        // a failed check is an expected outcome, so scripts must be able to
+        // tell it apart from a real error
+        AbsorbError::CheckFailed { .. } => (2, "check failed"),
+        // git already said what went wrong, and its exit code is as good
+        // as any
+        AbsorbError::RebaseFailed {
+            status: Some(status),
+        } => (*status, "rebase failed"),
+        AbsorbError::RebaseFailed { status: None } => (1, "rebase failed"),
+        AbsorbError::SigningFailed(_) => (1, "signing failed"),
+        _ => (1, "absorb failed"),
+    }
+}
+
+/// The paths in `input`, one per line, or separated by NUL if `null`,
+/// leaving out blank ones.
+fn read_paths(mut input: impl io::Read, null: bool) -> io::Result<Vec<String>> {
+    let mut contents = vec![];
+    input.read_to_end(&mut contents)?;
+    let separator = if null { b'\0' } else { b'\n' };
+    contents
+        .split(|&b| b == separator)
+        .filter(|path| !path.iter().all(u8::is_ascii_whitespace))
+        .map(|path| {
+            String::from_utf8(path.to_vec())
+                .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))
+        })
+        .collect()
+}
+
+fn parse_seconds(value: &str) -> Result<std::time::Duration, String> {
+    match value.parse::<f64>() {
+        Ok(seconds) if seconds.is_finite() && seconds >= 0.0 => {
+            Ok(std::time::Duration::from_secs_f64(seconds))
+        }
+        _ => Err(format!("{} is not a number of seconds", value)),
+    }
+}
+
+fn json_drain<W: io::Write + Send + 'static>(writer: W) -> impl Drain<Ok = (), Err = slog::Never> {
+    slog_json::Json::new(writer)
+        .add_default_keys()
+        .build()
+        .fuse()
+}
+
+/// Logs to `drain`, and the same records as JSON to `log_file`.
+fn tee_logger<D, W>(drain: D, log_file: W, quiet: bool, verbose: u8) -> slog::Logger
+where
+    D: Drain<Ok = (), Err = slog::Never> + Send + 'static,
+    W: io::Write + Send + 'static,
+{
+    filtered_logger(
+        slog::Duplicate::new(drain, json_drain(log_file)).ignore_res(),
+        quiet,
+        verbose,
+    )
+}
+
+fn filtered_logger<D>(drain: D, quiet: bool, verbose: u8) -> slog::Logger
+where
+    D: Drain<Ok = (), Err = slog::Never> + Send + 'static,
+{
+    let drain = std::sync::Mutex::new(drain).fuse();
+    let drain = slog::LevelFilter::new(
+        drain,
+        match (quiet, verbose) {
+            (true, _) => slog::Level::Warning,
+            (false, 0) => slog::Level::Info,
+            (false, 1) => slog::Level::Debug,
+            (false, _) => slog::Level::Trace,
+        },
+    )
+    .fuse();
+    let mut logger = slog::Logger::root(drain, o!());
+    if verbose > 0 {
+        logger = logger.new(o!(
+            "module" => slog::FnValue(|record| record.module()),
+            "line" => slog::FnValue(|record| record.line()),
+        ));
+    }
+    logger
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn logged_levels(quiet: bool, verbose: u8) -> Vec<String> {
+        let buffer = iobuffer::IoBuffer::new();
+        let logger = filtered_logger(json_drain(buffer.clone()), quiet, verbose);
+        warn!(logger, "warning");
+        info!(logger, "info"; "commit" => "abc123");
+        debug!(logger, "debug"; "path" => "src/lib.rs");
+        trace!(logger, "trace"; "offset" => -1);
+        drop(logger);
+
+        let mut buffer = buffer;
+        buffer
+            .lines()
+            .map(|line| {
+                let log: serde_json::Value = serde_json::from_slice(&line).unwrap();
+                log["level"].as_str().unwrap().to_owned()
+            })
+            .collect()
+    }
+
+    #[test]
+    fn verbosity_selects_log_level() {
+        assert_eq!(logged_levels(false, 0), ["WARN", "INFO"]);
+        assert_eq!(logged_levels(false, 1), ["WARN", "INFO", "DEBG"]);
+        assert_eq!(logged_levels(false, 2), ["WARN", "INFO", "DEBG", "TRCE"]);
+        assert_eq!(logged_levels(false, 3), ["WARN", "INFO", "DEBG", "TRCE"]);
+    }
+
+    #[test]
+    fn paths_are_read_by_line_or_by_nul() {
+        assert_eq!(
+            read_paths(&b"src/lib.rs\n\nREADME.md\n"[..], false).unwrap(),
+            ["src/lib.rs", "README.md"]
+        );
+        assert_eq!(
+            read_paths(&b"with space.txt\0line\nbreak.txt\0"[..], true).unwrap(),
+            ["with space.txt", "line\nbreak.txt"]
+        );
+        assert!(Cli::try_parse_from(["git-absorb", "-z"]).is_err());
+        let cli = Cli::try_parse_from(["git-absorb", "--stdin", "-z", "a", "b"]).unwrap();
+        assert_eq!(cli.paths, ["a", "b"]);
+        let cli = Cli::try_parse_from(["git-absorb", "-r", "a", "--", "--autostash"]).unwrap();
+        assert_eq!(cli.paths, ["a"]);
+        assert_eq!(cli.rebase_options, ["--autostash"]);
+    }
+
+    #[test]
+    fn context_lines_for_match_is_unified() {
+        let cli = Cli::try_parse_from(["git-absorb", "--context-lines-for-match", "0"]).unwrap();
+        assert_eq!(cli.unified, Some(0));
+        let cli = Cli::try_parse_from(["git-absorb", "-U", "2"]).unwrap();
+        assert_eq!(cli.unified, Some(2));
+    }
+
+    #[test]
+    fn errors_have_their_own_exit_codes() {
+        use git_absorb::AbsorbError;
+        assert_eq!(
+            exit_code(&AbsorbError::CheckFailed { unabsorbed: 1 }),
+            (2, "check failed")
+        );
+        assert_eq!(
+            exit_code(&AbsorbError::RebaseFailed { status: Some(128) }),
+            (128, "rebase failed")
+        );
+        assert_eq!(
+            exit_code(&AbsorbError::SigningFailed("gpg failed".to_owned())),
+            (1, "signing failed")
+        );
+        assert_eq!(exit_code(&AbsorbError::DetachedHead), (1, "absorb failed"));
+    }
+
+    #[test]
+    fn quiet_only_logs_warnings() {
+        assert_eq!(logged_levels(true, 0), ["WARN"]);
+        assert!(Cli::try_parse_from(["git-absorb", "-q", "-v"]).is_err());
+    }
+
+    #[test]
+    fn json_log_lines_have_structured_fields() {
+        let buffer = iobuffer::IoBuffer::new();
+        let logger = filtered_logger(json_drain(buffer.clone()), false, 1);
+        debug!(logger, "commuted"; "commit" => "abc123", "offset" => -1);
+        drop(logger);
+
+        let mut buffer = buffer;
+        let lines: Vec<_> = buffer
+            .lines()
+            .map(|line| serde_json::from_slice::<serde_json::Value>(&line).unwrap())
+            .collect();
+        assert_eq!(lines.len(), 1);
+        assert_eq!(lines[0]["msg"], "commuted");
+        assert_eq!(lines[0]["commit"], "abc123");
+        assert_eq!(lines[0]["offset"], -1);
+        assert!(lines[0]["line"].is_number());
+    }
+
+    #[test]
+    fn log_file_gets_the_same_lines() {
+        let buffer = iobuffer::IoBuffer::new();
+        let log_file = tempfile::NamedTempFile::new().unwrap();
+        let logger = tee_logger(
+            json_drain(buffer.clone()),
+            log_file.reopen().unwrap(),
+            false,
+            0,
+        );
+        info!(logger, "stack base"; "commit" => "abc123");
+        debug!(logger, "commuted"; "commit" => "abc123");
+        warn!(logger, "No changes staged.");
+        drop(logger);
+
+        let mut buffer = buffer;
+        let terminal: Vec<_> = buffer
+            .lines()
+            .map(|line| serde_json::from_slice::<serde_json::Value>(&line).unwrap())
+            .collect();
+        let logged: Vec<_> = std::fs::read_to_string(log_file.path())
+            .unwrap()
+            .lines()
+            .map(|line| serde_json::from_str::<serde_json::Value>(line).unwrap())
+            .collect();
+        let messages = |logs: &[serde_json::Value]| -> Vec<_> {
+            logs.iter().map(|log| log["msg"].clone()).collect()
+        };
+        assert_eq!(messages(&logged), ["stack base", "No changes staged."]);
+        assert_eq!(messages(&logged), messages(&terminal));
+        assert_eq!(logged[0]["commit"], "abc123");
+    }
+
+    #[test]
+    fn verbose_and_log_format_flags() {
+        let cli = Cli::try_parse_from(["git-absorb", "--log-format", "json"]).unwrap();
+        assert_eq!(cli.log_format, LogFormat::Json);
+        let cli = Cli::try_parse_from(["git-absorb", "-vv"]).unwrap();
+        assert_eq!(cli.verbose, 2);
+    }
+
+    #[test]
+    fn time_limit_in_seconds() {
+        assert_eq!(parse_seconds("2"), Ok(std::time::Duration::from_secs(2)));
+        assert_eq!(
+            parse_seconds("0.5"),
+            Ok(std::time::Duration::from_millis(500))
+        );
+        assert!(parse_seconds("-1").is_err());
+        assert!(parse_seconds("soon").is_err());
     }
 }
@@ -0,0 +1,70 @@
+//! With `absorb.writeNotes`, a note on each fixup commit says what it was
+//! made for, which outlives the fixup once it is squashed.
+
+use anyhow::Result;
+
+/// Where the notes are kept, apart from the ones `git notes` shows.
+pub const NOTES_REF: &str = "refs/notes/absorb";
+
+/// Notes on `fixup` that it fixes up `target`, and which run of git-absorb
+/// made it, at `time`, in seconds since the epoch.
+pub fn note_fixup(
+    repo: &git2::Repository,
+    fixup: &git2::Commit,
+    target: &git2::Commit,
+    time: u64,
+) -> Result<()> {
+    let note = format!(
+        "target: {}\nsubject: {}\ntime: {}\nversion: git-absorb {}\n",
+        target.id(),
+        String::from_utf8_lossy(target.summary_bytes().unwrap_or_default()),
+        time,
+        env!("CARGO_PKG_VERSION"),
+    );
+    let signature = crate::default_signature(repo)?;
+    repo.note(
+        &signature,
+        &signature,
+        Some(NOTES_REF),
+        fixup.id(),
+        &note,
+        true,
+    )?;
+    Ok(())
+}
+
+/// Notes on `rewritten` what the notes on `fixups`, which were squashed
+/// into it, said, one after the other and each only once, as `git rebase`
+/// does when it is told to copy the notes.
+pub fn copy_notes(
+    repo: &git2::Repository,
+    rewritten: git2::Oid,
+    fixups: &[git2::Oid],
+) -> Result<()> {
+    let mut notes: Vec<String> = vec![];
+    for note in fixups
+        .iter()
+        .filter_map(|fixup| repo.find_note(Some(NOTES_REF), *fixup).ok())
+    {
+        // fixups for the same commit in the same run have the same note
+        match note.message() {
+            Some(message) if !notes.iter().any(|note| note == message) => {
+                notes.push(message.to_owned())
+            }
+            _ => {}
+        }
+    }
+    if notes.is_empty() {
+        return Ok(());
+    }
+    let signature = crate::default_signature(repo)?;
+    repo.note(
+        &signature,
+        &signature,
+        Some(NOTES_REF),
+        rewritten,
+        &notes.join("\n"),
+        true,
+    )?;
+    Ok(())
+}
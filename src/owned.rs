@@ -16,18 +16,31 @@ impl ::std::ops::Deref for Diff {
     }
 }
 impl Diff {
-    pub fn new(diff: &git2::Diff) -> Result<Self> {
+    /// Copies the patches out of `diff`, except for those of files larger
+    /// than `max_file_size`, whose blobs are never read, and whose patches
+    /// are marked as too large and have no hunks. The sizes come from `odb`
+    /// without reading the blobs either.
+    pub fn new(diff: &git2::Diff, max_file_size: Option<(&git2::Odb, u64)>) -> Result<Self> {
         let mut ret = Diff {
             patches: Vec::new(),
             by_old: HashMap::new(),
             by_new: HashMap::new(),
         };
 
-        for (delta_idx, _delta) in diff.deltas().enumerate() {
-            let patch = Patch::new(
-                &mut git2::Patch::from_diff(diff, delta_idx)?
-                    .ok_or_else(|| anyhow!("got empty delta"))?,
-            )?;
+        for (delta_idx, delta) in diff.deltas().enumerate() {
+            let too_large = max_file_size.is_some_and(|(odb, max)| {
+                [delta.old_file(), delta.new_file()]
+                    .iter()
+                    .any(|file| file_size(odb, file) > max)
+            });
+            let patch = if too_large {
+                Patch::without_hunks(&delta, true)?
+            } else {
+                Patch::new(
+                    &mut git2::Patch::from_diff(diff, delta_idx)?
+                        .ok_or_else(|| anyhow!("got empty delta"))?,
+                )?
+            };
             if ret.by_old.contains_key(&patch.old_path) {
                 // TODO: would this case be hit if the diff was put through copy detection?
                 return Err(anyhow!("old path already occupied"));
@@ -42,30 +55,150 @@ impl Diff {
 
         Ok(ret)
     }
+    /// How many bytes of changed lines were copied out of the patches, which
+    /// is all the diff holds of the files' contents.
+    pub fn copied_bytes(&self) -> usize {
+        self.patches
+            .iter()
+            .flat_map(|patch| &patch.hunks)
+            .map(|hunk| hunk.added.lines.byte_len() + hunk.removed.lines.byte_len())
+            .sum()
+    }
     pub fn by_new(&self, path: &[u8]) -> Option<&Patch> {
         self.by_new.get(path).map(|&idx| &self.patches[idx])
     }
+    /// Whether any patch changes the file at `path`, or a file anywhere
+    /// below the directory at `path`.
+    pub fn touches(&self, path: &[u8]) -> bool {
+        let within = |patch_path: &[u8]| {
+            patch_path.starts_with(path)
+                && (patch_path.len() == path.len() || patch_path[path.len()] == b'/')
+        };
+        self.patches
+            .iter()
+            .any(|patch| within(&patch.old_path) || within(&patch.new_path))
+    }
 }
 
 #[derive(Debug, Clone)]
 pub struct Block {
     pub start: usize,
-    pub lines: Rc<Vec<Vec<u8>>>,
+    pub lines: Rc<Lines>,
+}
+
+/// The lines of a block, all in one buffer. A commit that regenerated a
+/// large file changes most of its lines, and a separate allocation for each
+/// of them would take more memory than the lines themselves.
+///
+/// The lines are copied out of the patch, which does not live as long as
+/// the diff that holds them.
+#[derive(Clone, Default, PartialEq, Eq)]
+pub struct Lines {
+    bytes: Vec<u8>,
+    /// Where each line ends in `bytes`.
+    ends: Vec<usize>,
+}
+impl Lines {
+    pub fn with_capacity(lines: usize) -> Self {
+        Lines {
+            bytes: Vec::new(),
+            ends: Vec::with_capacity(lines),
+        }
+    }
+    pub fn push(&mut self, line: &[u8]) {
+        self.bytes.extend_from_slice(line);
+        self.ends.push(self.bytes.len());
+    }
+    pub fn len(&self) -> usize {
+        self.ends.len()
+    }
+    pub fn is_empty(&self) -> bool {
+        self.ends.is_empty()
+    }
+    pub fn iter(&self) -> LinesIter<'_> {
+        LinesIter {
+            lines: self,
+            range: 0..self.len(),
+        }
+    }
+    /// The lines in `range`, copied.
+    pub fn slice(&self, range: std::ops::Range<usize>) -> Lines {
+        range.map(|idx| &self[idx]).collect()
+    }
+    /// How many bytes the lines take up together.
+    pub fn byte_len(&self) -> usize {
+        self.bytes.len()
+    }
+}
+impl std::ops::Index<usize> for Lines {
+    type Output = [u8];
+    fn index(&self, idx: usize) -> &[u8] {
+        let start = match idx {
+            0 => 0,
+            _ => self.ends[idx - 1],
+        };
+        &self.bytes[start..self.ends[idx]]
+    }
+}
+impl<'a> IntoIterator for &'a Lines {
+    type Item = &'a [u8];
+    type IntoIter = LinesIter<'a>;
+    fn into_iter(self) -> LinesIter<'a> {
+        self.iter()
+    }
+}
+
+pub struct LinesIter<'a> {
+    lines: &'a Lines,
+    range: std::ops::Range<usize>,
+}
+impl<'a> Iterator for LinesIter<'a> {
+    type Item = &'a [u8];
+    fn next(&mut self) -> Option<&'a [u8]> {
+        self.range.next().map(|idx| &self.lines[idx])
+    }
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        self.range.size_hint()
+    }
+}
+impl<'a> DoubleEndedIterator for LinesIter<'a> {
+    fn next_back(&mut self) -> Option<&'a [u8]> {
+        self.range.next_back().map(|idx| &self.lines[idx])
+    }
+}
+impl ExactSizeIterator for LinesIter<'_> {}
+impl<'a> FromIterator<&'a [u8]> for Lines {
+    fn from_iter<I: IntoIterator<Item = &'a [u8]>>(iter: I) -> Self {
+        let mut ret = Lines::default();
+        for line in iter {
+            ret.push(line);
+        }
+        ret
+    }
+}
+impl std::fmt::Debug for Lines {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_list().entries(self.iter()).finish()
+    }
 }
 #[derive(Debug, Clone)]
 pub struct Hunk {
     pub added: Block,
     pub removed: Block,
+    /// The function context git found for this hunk, ie. the text after
+    /// the second `@@` of the hunk header.
+    pub funcname: Option<Rc<Vec<u8>>>,
 }
 impl Hunk {
     pub fn new(patch: &mut git2::Patch, idx: usize) -> Result<Self> {
-        let (added_start, removed_start, mut added_lines, mut removed_lines) = {
+        let (added_start, removed_start, mut added_lines, mut removed_lines, funcname) = {
             let (hunk, _size) = patch.hunk(idx)?;
             (
                 hunk.new_start() as usize,
                 hunk.old_start() as usize,
-                Vec::with_capacity(hunk.new_lines() as usize),
-                Vec::with_capacity(hunk.old_lines() as usize),
+                Lines::with_capacity(hunk.new_lines() as usize),
+                Lines::with_capacity(hunk.old_lines() as usize),
+                parse_funcname(hunk.header()),
             )
         };
         let mut added_trailing_newline = true;
@@ -86,7 +219,7 @@ impl Hunk {
                     {
                         return Err(anyhow!("added line did not reach expected lineno"));
                     }
-                    added_lines.push(Vec::from(line.content()))
+                    added_lines.push(line.content())
                 }
                 '-' => {
                     if line.num_lines() > 1 {
@@ -100,7 +233,7 @@ impl Hunk {
                     {
                         return Err(anyhow!("removed line did not reach expected lineno",));
                     }
-                    removed_lines.push(Vec::from(line.content()))
+                    removed_lines.push(line.content())
                 }
                 '>' => {
                     if !removed_trailing_newline {
@@ -137,6 +270,7 @@ impl Hunk {
                 start: removed_start,
                 lines: Rc::new(removed_lines),
             },
+            funcname: funcname.map(Rc::new),
         })
     }
 
@@ -153,15 +287,19 @@ impl Hunk {
     pub fn anchors(&self) -> (usize, usize, usize, usize) {
         match (self.removed.lines.len(), self.added.lines.len()) {
             (0, 0) => (0, 1, 0, 1),
+            // for an empty block, git gives the start of the line right
+            // before the change instead, which is already its upper anchor.
+            // the other side can't be used to compute it, because earlier
+            // hunks may have shifted the two sides apart
             (removed_len, 0) => (
                 self.removed.start - 1,
                 self.removed.start + removed_len,
-                self.removed.start - 1,
-                self.removed.start,
+                self.added.start,
+                self.added.start + 1,
             ),
             (0, added_len) => (
-                self.added.start - 1,
-                self.added.start,
+                self.removed.start,
+                self.removed.start + 1,
                 self.added.start - 1,
                 self.added.start + added_len,
             ),
@@ -200,39 +338,116 @@ impl Hunk {
     }
 }
 
+/// Extracts the function context from a hunk header like
+/// `@@ -1,2 +1,3 @@ fn main() {`.
+fn parse_funcname(header: &[u8]) -> Option<Vec<u8>> {
+    let rest = header.strip_prefix(b"@@")?;
+    let end = rest.windows(2).position(|w| w == b"@@")?;
+    let funcname = trim(&rest[end + 2..]);
+    if funcname.is_empty() {
+        None
+    } else {
+        Some(funcname.to_vec())
+    }
+}
+
+fn trim(mut line: &[u8]) -> &[u8] {
+    while let [first, rest @ ..] = line {
+        if !first.is_ascii_whitespace() {
+            break;
+        }
+        line = rest;
+    }
+    while let [rest @ .., last] = line {
+        if !last.is_ascii_whitespace() {
+            break;
+        }
+        line = rest;
+    }
+    line
+}
+
+/// The size of one side of a delta, which the diff may not have filled in
+/// for blobs it has not read yet.
+fn file_size(odb: &git2::Odb, file: &git2::DiffFile) -> u64 {
+    if file.size() > 0 || file.id().is_zero() || file.mode() == git2::FileMode::Commit {
+        return file.size();
+    }
+    odb.read_header(file.id())
+        .map_or(0, |(size, _)| size as u64)
+}
+
 #[derive(Debug)]
 pub struct Patch {
     pub old_path: Vec<u8>,
     pub new_path: Vec<u8>,
     pub status: git2::Delta,
+    pub binary: bool,
+    /// The file is larger than `absorb.maxFileSize`, so its blobs were
+    /// never read, and there are no hunks.
+    pub too_large: bool,
     pub hunks: Vec<Hunk>,
 }
 impl Patch {
     pub fn new(patch: &mut git2::Patch) -> Result<Self> {
-        let mut ret = Patch {
-            old_path: patch
-                .delta()
+        let mut ret = Patch::without_hunks(&patch.delta(), false)?;
+        ret.hunks.reserve(patch.num_hunks());
+        if patch.delta().nfiles() < 1 || patch.delta().nfiles() > 2 {
+            return Err(anyhow!("delta with multiple files"));
+        }
+
+        for idx in 0..patch.num_hunks() {
+            ret.hunks.push(Hunk::new(patch, idx)?);
+        }
+
+        Ok(ret)
+    }
+    /// The patch for `delta`, without its hunks.
+    fn without_hunks(delta: &git2::DiffDelta, too_large: bool) -> Result<Self> {
+        Ok(Patch {
+            old_path: delta
                 .old_file()
                 .path_bytes()
                 .map(Vec::from)
                 .ok_or_else(|| anyhow!("delta with empty old path"))?,
-            new_path: patch
-                .delta()
+            new_path: delta
                 .new_file()
                 .path_bytes()
                 .map(Vec::from)
                 .ok_or_else(|| anyhow!("delta with empty new path"))?,
-            status: patch.delta().status(),
-            hunks: Vec::with_capacity(patch.num_hunks()),
-        };
-        if patch.delta().nfiles() < 1 || patch.delta().nfiles() > 2 {
-            return Err(anyhow!("delta with multiple files"));
-        }
+            status: delta.status(),
+            binary: delta.flags().is_binary(),
+            too_large,
+            hunks: vec![],
+        })
+    }
+    /// Whether any of the hunks is in the function named by `funcname`, or
+    /// adds the line that starts it.
+    pub fn touches_function(&self, funcname: &[u8]) -> bool {
+        self.hunks.iter().any(|hunk| {
+            hunk.funcname.as_deref().map(Vec::as_slice) == Some(funcname)
+                || hunk.added.lines.iter().any(|line| trim(line) == funcname)
+        })
+    }
 
-        for idx in 0..patch.num_hunks() {
-            ret.hunks.push(Hunk::new(patch, idx)?);
+    /// Numbers the hunks so that consecutive hunks get the same number when
+    /// fewer than twice `context` unchanged lines separate them, ie. when a
+    /// diff with `context` lines of context would show them overlapping.
+    pub fn nearby_hunk_groups(&self, context: usize) -> Vec<usize> {
+        let mut ret = Vec::with_capacity(self.hunks.len());
+        let mut group = 0;
+        for (idx, hunk) in self.hunks.iter().enumerate() {
+            if let Some(previous) = idx.checked_sub(1).map(|idx| &self.hunks[idx]) {
+                let (_, previous_lower, _, _) = previous.anchors();
+                let (upper, _, _, _) = hunk.anchors();
+                // the unchanged lines between the two, on the removed side
+                let gap = (upper + 1).saturating_sub(previous_lower);
+                if gap >= 2 * context {
+                    group += 1;
+                }
+            }
+            ret.push(group);
         }
-
-        Ok(ret)
+        ret
     }
 }
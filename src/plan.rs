@@ -0,0 +1,259 @@
+use anyhow::{anyhow, Result};
+
+use std::path::Path;
+use std::rc::Rc;
+
+use crate::json::Value;
+use crate::owned;
+use crate::AbsorbError;
+
+const VERSION: u64 = 2;
+
+/// Where every absorbed hunk goes, ready to be applied later, as long as
+/// neither HEAD nor the index changed in between.
+#[derive(Debug)]
+pub struct Plan {
+    pub head: git2::Oid,
+    pub index_tree: git2::Oid,
+    pub fixups: Vec<PlannedHunk>,
+}
+
+#[derive(Debug)]
+pub struct PlannedHunk {
+    pub target: git2::Oid,
+    /// `None` if the whole file is deleted.
+    pub hunk: Option<owned::Hunk>,
+    /// The file the hunk applies to, without any hunks.
+    pub patch: owned::Patch,
+    pub group: Option<usize>,
+}
+
+impl Plan {
+    pub fn new(
+        head: git2::Oid,
+        index_tree: git2::Oid,
+        hunks_with_commit: &[crate::HunkWithCommit],
+        unshifted: &[Option<owned::Hunk>],
+    ) -> Self {
+        Plan {
+            head,
+            index_tree,
+            fixups: hunks_with_commit
+                .iter()
+                .zip(unshifted)
+                .map(|(hunk, unshifted)| PlannedHunk {
+                    target: hunk.dest_commit.id(),
+                    hunk: unshifted.clone(),
+                    patch: owned::Patch {
+                        old_path: hunk.index_patch.old_path.clone(),
+                        new_path: hunk.index_patch.new_path.clone(),
+                        status: hunk.index_patch.status,
+                        binary: false,
+                        too_large: false,
+                        hunks: vec![],
+                    },
+                    group: hunk.group,
+                })
+                .collect(),
+        }
+    }
+
+    /// Writes the plan to `path`, along with the commits in `stack` to
+    /// show whoever reviews it.
+    pub fn write(&self, path: &Path, stack: &[&git2::Commit]) -> Result<()> {
+        let fixups = self
+            .fixups
+            .iter()
+            .map(|planned| {
+                Ok(Value::object([
+                    ("target", Value::String(planned.target.to_string())),
+                    (
+                        "path",
+                        Value::String(text(&planned.patch.new_path, "path")?.to_owned()),
+                    ),
+                    ("group", planned.group.map_or(Value::Null, Value::number)),
+                    (
+                        "hunk",
+                        match &planned.hunk {
+                            Some(hunk) => Value::object([
+                                ("removed", block_to_json(&hunk.removed)?),
+                                ("added", block_to_json(&hunk.added)?),
+                            ]),
+                            None => Value::Null,
+                        },
+                    ),
+                ]))
+            })
+            .collect::<Result<Vec<_>>>()?;
+        let stack = stack
+            .iter()
+            .map(|commit| {
+                let subject = String::from_utf8_lossy(commit.summary_bytes().unwrap_or_default());
+                Value::object([
+                    ("commit", Value::String(commit.id().to_string())),
+                    ("subject", Value::String(subject.into_owned())),
+                ])
+            })
+            .collect();
+        let plan = Value::object([
+            ("version", Value::number(VERSION)),
+            ("head", Value::String(self.head.to_string())),
+            ("index_tree", Value::String(self.index_tree.to_string())),
+            ("stack", Value::Array(stack)),
+            ("fixups", Value::Array(fixups)),
+        ]);
+        let mut contents = plan.pretty();
+        contents.push('\n');
+        std::fs::write(path, contents)?;
+        Ok(())
+    }
+
+    pub fn read(path: &Path) -> Result<Self> {
+        let contents = std::fs::read(path)?;
+        let plan = Value::parse(&contents)
+            .map_err(|e| anyhow!("{} is not a plan: {}", path.display(), e))?;
+        if plan["version"].as_u64() != Some(VERSION) {
+            return Err(anyhow!(
+                "{} is not a version {} plan",
+                path.display(),
+                VERSION
+            ));
+        }
+        let fixups = array(&plan["fixups"], "fixups")?
+            .iter()
+            .map(|planned| {
+                let path = string(&planned["path"], "path")?.as_bytes().to_vec();
+                let hunk = match &planned["hunk"] {
+                    Value::Null => None,
+                    hunk => Some(owned::Hunk {
+                        removed: block_from_json(&hunk["removed"])?,
+                        added: block_from_json(&hunk["added"])?,
+                        funcname: None,
+                    }),
+                };
+                Ok(PlannedHunk {
+                    target: oid(&planned["target"], "target")?,
+                    patch: owned::Patch {
+                        old_path: path.clone(),
+                        new_path: path,
+                        status: match hunk {
+                            Some(_) => git2::Delta::Modified,
+                            None => git2::Delta::Deleted,
+                        },
+                        binary: false,
+                        too_large: false,
+                        hunks: vec![],
+                    },
+                    hunk,
+                    group: match &planned["group"] {
+                        Value::Null => None,
+                        group => Some(number(group, "group")?),
+                    },
+                })
+            })
+            .collect::<Result<Vec<_>>>()?;
+        Ok(Plan {
+            head: oid(&plan["head"], "head")?,
+            index_tree: oid(&plan["index_tree"], "index_tree")?,
+            fixups,
+        })
+    }
+
+    /// Fails, explaining why, unless the plan can still be applied to a
+    /// repository where HEAD is at `head`, the index has the tree
+    /// `index_tree`, and `stack` is the working stack.
+    pub fn check(
+        &self,
+        head: git2::Oid,
+        index_tree: git2::Oid,
+        stack: &[&git2::Commit],
+    ) -> Result<()> {
+        if head != self.head {
+            return Err(AbsorbError::PlanMismatch(format!(
+                "the plan was made when HEAD was at {}, but HEAD is now at {}",
+                self.head, head
+            ))
+            .into());
+        }
+        if index_tree != self.index_tree {
+            return Err(AbsorbError::PlanMismatch(format!(
+                "the staged changes are not the ones the plan was made for \
+                 (index tree {}, but the plan expects {})",
+                index_tree, self.index_tree
+            ))
+            .into());
+        }
+        for planned in &self.fixups {
+            if !stack.iter().any(|commit| commit.id() == planned.target) {
+                return Err(AbsorbError::PlanMismatch(format!(
+                    "the plan absorbs into {}, which is not in the stack",
+                    planned.target
+                ))
+                .into());
+            }
+        }
+        Ok(())
+    }
+}
+
+fn block_to_json(block: &owned::Block) -> Result<Value> {
+    Ok(Value::object([
+        ("start", Value::number(block.start)),
+        (
+            "lines",
+            Value::Array(
+                block
+                    .lines
+                    .iter()
+                    .map(|line| Ok(Value::String(text(line, "line")?.to_owned())))
+                    .collect::<Result<_>>()?,
+            ),
+        ),
+    ]))
+}
+
+fn block_from_json(block: &Value) -> Result<owned::Block> {
+    Ok(owned::Block {
+        start: number(&block["start"], "start")?,
+        lines: Rc::new(
+            array(&block["lines"], "lines")?
+                .iter()
+                .map(|line| Ok(string(line, "line")?.as_bytes()))
+                .collect::<Result<owned::Lines>>()?,
+        ),
+    })
+}
+
+/// JSON can only hold UTF-8 text.
+fn text<'a>(bytes: &'a [u8], what: &str) -> Result<&'a str> {
+    std::str::from_utf8(bytes).map_err(|_| {
+        anyhow!(
+            "cannot write {} that is not UTF-8 to a plan: {:?}",
+            what,
+            String::from_utf8_lossy(bytes)
+        )
+    })
+}
+
+fn array<'a>(value: &'a Value, what: &str) -> Result<&'a Vec<Value>> {
+    value
+        .as_array()
+        .ok_or_else(|| anyhow!("plan has no valid {}", what))
+}
+
+fn string<'a>(value: &'a Value, what: &str) -> Result<&'a str> {
+    value
+        .as_str()
+        .ok_or_else(|| anyhow!("plan has no valid {}", what))
+}
+
+fn number(value: &Value, what: &str) -> Result<usize> {
+    value
+        .as_u64()
+        .map(|number| number as usize)
+        .ok_or_else(|| anyhow!("plan has no valid {}", what))
+}
+
+fn oid(value: &Value, what: &str) -> Result<git2::Oid> {
+    git2::Oid::from_str(string(value, what)?).map_err(|_| anyhow!("plan has no valid {}", what))
+}
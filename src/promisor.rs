@@ -0,0 +1,102 @@
+//! Partial clones leave out objects that a promisor remote can send later,
+//! which git fetches as it needs them, but libgit2 does not.
+
+use anyhow::Result;
+
+use std::path::Path;
+
+use crate::AbsorbError;
+
+/// Makes sure every blob that `diff` needs to make its patches is in the
+/// repository, fetching the missing ones from the promisor remote if there
+/// is one, and failing with the ids of those that are still missing.
+pub fn fetch_missing_blobs(
+    repo: &git2::Repository,
+    diff: &git2::Diff,
+    logger: &slog::Logger,
+) -> Result<()> {
+    let missing = missing_blobs(repo, diff)?;
+    if missing.is_empty() {
+        return Ok(());
+    }
+    let ids: Vec<_> = missing.iter().map(git2::Oid::to_string).collect();
+
+    let remote = match promisor_remote(repo)? {
+        Some(remote) => remote,
+        None => {
+            return Err(AbsorbError::MissingObjects {
+                ids: missing,
+                remote: None,
+            }
+            .into())
+        }
+    };
+    debug!(logger, "fetching missing blobs";
+           "remote" => &remote,
+           "blobs" => ids.join(" "),
+    );
+    let mut command = std::process::Command::new("git");
+    if let Some(workdir) = repo.workdir().and_then(Path::to_str) {
+        command.args(["-C", workdir]);
+    }
+    command.env("GIT_DIR", repo.path());
+    if let Some(workdir) = repo.workdir() {
+        command.env("GIT_WORK_TREE", workdir);
+    }
+    command.args([
+        "fetch",
+        "--quiet",
+        "--no-tags",
+        "--no-write-fetch-head",
+        "--recurse-submodules=no",
+        "--filter=blob:none",
+        &remote,
+    ]);
+    command.args(&ids);
+    let fetched = command.output().is_ok_and(|output| output.status.success());
+
+    if !fetched || !missing_blobs(repo, diff)?.is_empty() {
+        return Err(AbsorbError::MissingObjects {
+            ids: missing,
+            remote: Some(remote),
+        }
+        .into());
+    }
+    Ok(())
+}
+
+fn missing_blobs(repo: &git2::Repository, diff: &git2::Diff) -> Result<Vec<git2::Oid>> {
+    let odb = repo.odb()?;
+    let mut missing = vec![];
+    for delta in diff.deltas() {
+        for file in [delta.old_file(), delta.new_file()] {
+            let id = file.id();
+            if id.is_zero()
+                || file.mode() == git2::FileMode::Commit
+                || odb.exists(id)
+                || missing.contains(&id)
+            {
+                continue;
+            }
+            missing.push(id);
+        }
+    }
+    Ok(missing)
+}
+
+/// The remote that a partial clone can fetch what it left out from.
+fn promisor_remote(repo: &git2::Repository) -> Result<Option<String>> {
+    let config = repo.config()?;
+    if let Ok(remote) = config.get_string("extensions.partialClone") {
+        return Ok(Some(remote));
+    }
+    for remote in repo.remotes()?.iter().flatten() {
+        if config
+            .get_bool(&format!("remote.{}.promisor", remote))
+            .unwrap_or(false)
+        {
+            return Ok(Some(remote.to_owned()));
+        }
+    }
+    Ok(None)
+}
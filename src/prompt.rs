@@ -0,0 +1,143 @@
+use anyhow::{anyhow, Result};
+
+use std::io::{BufRead, IsTerminal, Write};
+
+/// The answer to a question asked about one item in a sequence.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Answer {
+    /// Accept this item.
+    Yes,
+    /// Skip this item.
+    No,
+    /// Skip this item and all the remaining ones.
+    Quit,
+    /// Accept this item and all the remaining ones.
+    All,
+}
+
+const HELP: &str = "\
+y - yes
+n - no
+q - quit; no to this and all the remaining ones
+a - all; yes to this and all the remaining ones
+? - print help
+";
+
+pub trait Prompt {
+    /// Fails if nobody is around to answer questions.
+    fn check_interactive(&self) -> Result<()>;
+
+    /// Shows `text`, then asks `question` until a valid answer is given.
+    fn ask(&mut self, text: &str, question: &str) -> Result<Answer>;
+}
+
+/// Asks questions on `output` and reads the answers from `input`.
+pub struct Interactive<R, W> {
+    input: R,
+    output: W,
+}
+
+impl<R: BufRead, W: Write> Interactive<R, W> {
+    pub fn new(input: R, output: W) -> Self {
+        Interactive { input, output }
+    }
+}
+
+impl<R: BufRead, W: Write> Prompt for Interactive<R, W> {
+    fn check_interactive(&self) -> Result<()> {
+        Ok(())
+    }
+
+    fn ask(&mut self, text: &str, question: &str) -> Result<Answer> {
+        self.output.write_all(text.as_bytes())?;
+        loop {
+            write!(self.output, "{} [y,n,q,a,?]? ", question)?;
+            self.output.flush()?;
+
+            let mut line = String::new();
+            // running out of input is taken as the user walking away
+            if self.input.read_line(&mut line)? == 0 {
+                writeln!(self.output)?;
+                return Ok(Answer::Quit);
+            }
+            match line.trim() {
+                "y" => return Ok(Answer::Yes),
+                "n" => return Ok(Answer::No),
+                "q" => return Ok(Answer::Quit),
+                "a" => return Ok(Answer::All),
+                _ => self.output.write_all(HELP.as_bytes())?,
+            }
+        }
+    }
+}
+
+/// Answers yes to every question without asking anyone.
+pub struct AssumeYes;
+
+impl Prompt for AssumeYes {
+    fn check_interactive(&self) -> Result<()> {
+        Ok(())
+    }
+
+    fn ask(&mut self, _text: &str, _question: &str) -> Result<Answer> {
+        Ok(Answer::Yes)
+    }
+}
+
+/// Asks questions on stderr and reads the answers from stdin.
+pub struct Terminal;
+
+impl Prompt for Terminal {
+    fn check_interactive(&self) -> Result<()> {
+        if std::io::stdin().is_terminal() {
+            Ok(())
+        } else {
+            Err(anyhow!("cannot prompt for input, stdin is not a terminal"))
+        }
+    }
+
+    fn ask(&mut self, text: &str, question: &str) -> Result<Answer> {
+        Interactive::new(std::io::stdin().lock(), std::io::stderr()).ask(text, question)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn answers_are_parsed() {
+        let mut output = Vec::new();
+        let mut prompt = Interactive::new("y\nn\nq\na\n".as_bytes(), &mut output);
+        assert_eq!(prompt.ask("", "Take it").unwrap(), Answer::Yes);
+        assert_eq!(prompt.ask("", "Take it").unwrap(), Answer::No);
+        assert_eq!(prompt.ask("", "Take it").unwrap(), Answer::Quit);
+        assert_eq!(prompt.ask("", "Take it").unwrap(), Answer::All);
+    }
+
+    #[test]
+    fn invalid_answers_print_help_and_ask_again() {
+        let mut output = Vec::new();
+        let mut prompt = Interactive::new("maybe\ny\n".as_bytes(), &mut output);
+        assert_eq!(prompt.ask("some text\n", "Take it").unwrap(), Answer::Yes);
+        let output = String::from_utf8(output).unwrap();
+        assert_eq!(
+            output,
+            format!("some text\nTake it [y,n,q,a,?]? {HELP}Take it [y,n,q,a,?]? ")
+        );
+    }
+
+    #[test]
+    fn assume_yes_never_reads_input() {
+        let mut prompt = AssumeYes;
+        assert!(prompt.check_interactive().is_ok());
+        assert_eq!(prompt.ask("some text\n", "Take it").unwrap(), Answer::Yes);
+    }
+
+    #[test]
+    fn end_of_input_quits() {
+        let mut output = Vec::new();
+        let mut prompt = Interactive::new("".as_bytes(), &mut output);
+        assert_eq!(prompt.ask("", "Take it").unwrap(), Answer::Quit);
+    }
+}
@@ -0,0 +1,187 @@
+use anyhow::{anyhow, Result};
+
+use crate::config;
+use crate::AbsorbError;
+
+/// Folds each fixup into the commit it fixes up, and re-creates all the
+/// commits after it on top, keeping their authors, committers, messages and
+/// encodings byte for byte.
+///
+/// `stack` is the working stack, newest commit first, and `fixups` the
+/// fixup commits on top of it, oldest first, each with the id of the commit
+/// it fixes up. Returns the rewritten HEAD commit, without moving any refs.
+pub fn rewrite_stack<'repo>(
+    repo: &'repo git2::Repository,
+    stack: &[&git2::Commit<'repo>],
+    fixups: &[(git2::Commit<'repo>, git2::Oid)],
+    force: bool,
+    logger: &slog::Logger,
+) -> Result<git2::Commit<'repo>> {
+    let oldest = match stack
+        .iter()
+        .rposition(|commit| fixups.iter().any(|(_, target)| *target == commit.id()))
+    {
+        Some(oldest) => oldest,
+        None => return Err(anyhow!("no fixups for any commit in the stack")),
+    };
+    let rewritten = &stack[..=oldest];
+    if !force {
+        check_rewritable(repo, rewritten)?;
+    }
+
+    let mut parent = match rewritten[oldest].parent_count() {
+        0 => None,
+        _ => Some(rewritten[oldest].parent(0)?),
+    };
+    for commit in rewritten.iter().rev() {
+        let parent_tree = match &parent {
+            Some(parent) => parent.tree()?,
+            None => repo.find_tree(repo.treebuilder(None)?.write()?)?,
+        };
+        let mut tree = if parent.as_ref().map(|parent| parent.id()) == commit.parent_id(0).ok() {
+            commit.tree()?
+        } else {
+            merge(
+                repo,
+                commit,
+                &commit.parent(0)?.tree()?,
+                &parent_tree,
+                &commit.tree()?,
+            )?
+        };
+        for (fixup, _) in fixups.iter().filter(|(_, target)| *target == commit.id()) {
+            tree = merge(
+                repo,
+                commit,
+                &fixup.parent(0)?.tree()?,
+                &tree,
+                &fixup.tree()?,
+            )?;
+        }
+
+        let new_commit = repo.find_commit(recreate(repo, commit, &tree, parent.as_ref())?)?;
+        debug!(logger, "rewrote commit";
+               "commit" => commit.id().to_string(),
+               "new" => new_commit.id().to_string(),
+        );
+        parent = Some(new_commit);
+    }
+
+    // there is at least one rewritten commit
+    Ok(parent.unwrap())
+}
+
+/// Writes a copy of `commit` with `tree` and `parent`, whose other headers
+/// and message are the same bytes, so that a message in another encoding
+/// than utf8 keeps both its bytes and its `encoding` header. Only a
+/// signature is left out, since it would no longer match.
+fn recreate(
+    repo: &git2::Repository,
+    commit: &git2::Commit,
+    tree: &git2::Tree,
+    parent: Option<&git2::Commit>,
+) -> Result<git2::Oid> {
+    let mut buffer = format!("tree {}\n", tree.id()).into_bytes();
+    if let Some(parent) = parent {
+        buffer.extend_from_slice(format!("parent {}\n", parent.id()).as_bytes());
+    }
+    let mut skipping = false;
+    for line in commit.raw_header_bytes().split_inclusive(|&b| b == b'\n') {
+        // the lines after the first of a header start with a space
+        if !line.starts_with(b" ") {
+            skipping = [&b"tree "[..], b"parent ", b"gpgsig ", b"gpgsig-sha256 "]
+                .iter()
+                .any(|name| line.starts_with(name));
+        }
+        if !skipping {
+            buffer.extend_from_slice(line);
+        }
+    }
+    if !buffer.ends_with(b"\n") {
+        buffer.push(b'\n');
+    }
+    buffer.push(b'\n');
+    buffer.extend_from_slice(commit.message_raw_bytes());
+    Ok(repo.odb()?.write(git2::ObjectType::Commit, &buffer)?)
+}
+
+/// Fails if any of `commits` should not be rewritten: because it is
+/// signed, or because it was already pushed to one of the remotes that
+/// `absorb.remotesToProtect` names, or to any remote by default.
+pub fn check_rewritable(repo: &git2::Repository, commits: &[&git2::Commit]) -> Result<()> {
+    let protected = config::remotes_to_protect(repo);
+    let mut remote_tracking = vec![];
+    for reference in repo.references_glob("refs/remotes/*")? {
+        let reference = reference?;
+        if let (Some(name), Some(oid)) = (reference.name(), reference.target()) {
+            let is_protected = protected.as_ref().map_or(true, |remotes| {
+                remotes.iter().any(|remote| {
+                    name.strip_prefix("refs/remotes/")
+                        .and_then(|name| name.strip_prefix(remote.as_str()))
+                        .is_some_and(|branch| branch.starts_with('/'))
+                })
+            });
+            if is_protected {
+                remote_tracking.push((name.to_owned(), oid));
+            }
+        }
+    }
+
+    // everything that is reachable from a remote-tracking branch, down to
+    // the commit below the oldest one to rewrite, in a single walk
+    let mut pushed = std::collections::HashSet::new();
+    if !remote_tracking.is_empty() {
+        let mut revwalk = repo.revwalk()?;
+        for (_, oid) in &remote_tracking {
+            revwalk.push(*oid)?;
+        }
+        if let Some(Ok(base)) = commits.last().map(|oldest| oldest.parent_id(0)) {
+            revwalk.hide(base)?;
+        }
+        for oid in revwalk {
+            pushed.insert(oid?);
+        }
+    }
+
+    for commit in commits {
+        if commit.header_field_bytes("gpgsig").is_ok()
+            || commit.header_field_bytes("gpgsig-sha256").is_ok()
+        {
+            return Err(AbsorbError::SignedCommit {
+                commit: commit.id(),
+            }
+            .into());
+        }
+        if !pushed.contains(&commit.id()) {
+            continue;
+        }
+        for (name, oid) in &remote_tracking {
+            if *oid == commit.id() || repo.graph_descendant_of(*oid, commit.id())? {
+                return Err(AbsorbError::PushedCommit {
+                    commit: commit.id(),
+                    remote_ref: name.clone(),
+                }
+                .into());
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Applies the changes from `ancestor` to `theirs` on top of `ours`.
+fn merge<'repo>(
+    repo: &'repo git2::Repository,
+    commit: &git2::Commit,
+    ancestor: &git2::Tree,
+    ours: &git2::Tree,
+    theirs: &git2::Tree,
+) -> Result<git2::Tree<'repo>> {
+    let mut index = repo.merge_trees(ancestor, ours, theirs, None)?;
+    if index.has_conflicts() {
+        return Err(AbsorbError::RewriteConflict {
+            commit: commit.id(),
+        }
+        .into());
+    }
+    Ok(repo.find_tree(index.write_tree_to(repo)?)?)
+}
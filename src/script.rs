@@ -0,0 +1,134 @@
+//! A shell script that creates the same fixup commits git-absorb would,
+//! using only plain git commands, for anyone who would rather review the
+//! commands than trust git-absorb to run them.
+
+use anyhow::Result;
+
+use std::io::Write;
+
+/// Starts the script: describes the stack, and unstages everything so that
+/// each fixup can be staged and committed on its own.
+pub fn write_header(out: &mut dyn Write, stack: &[&git2::Commit]) -> Result<()> {
+    writeln!(out, "#!/bin/sh")?;
+    writeln!(
+        out,
+        "# Created by git-absorb, to make the fixup commits it would have made."
+    )?;
+    writeln!(out, "#")?;
+    writeln!(out, "# stack:")?;
+    for commit in stack {
+        writeln!(
+            out,
+            "#   {} {}",
+            commit.id(),
+            String::from_utf8_lossy(commit.summary_bytes().unwrap_or_default())
+        )?;
+    }
+    match stack.last().map(|commit| commit.parent_id(0)) {
+        Some(Ok(base)) => writeln!(out, "# base: {}", base)?,
+        _ => writeln!(out, "# base: none, the stack reaches the first commit")?,
+    }
+    writeln!(out)?;
+    writeln!(out, "set -e")?;
+    writeln!(out, "cd \"$(git rev-parse --show-toplevel)\"")?;
+    writeln!(out, "staged=$(git write-tree)")?;
+    writeln!(out, "git read-tree HEAD")?;
+    Ok(())
+}
+
+/// Stages the changes from `before` to `after`, and commits them with
+/// `message`.
+pub fn write_fixup(
+    out: &mut dyn Write,
+    repo: &git2::Repository,
+    before: &git2::Tree,
+    after: &git2::Tree,
+    message: &str,
+) -> Result<()> {
+    let mut patch = vec![];
+    repo.diff_tree_to_tree(Some(before), Some(after), None)?
+        .print(git2::DiffFormat::Patch, |_, _, line| {
+            if matches!(line.origin(), '+' | '-' | ' ') {
+                patch.push(line.origin() as u8);
+            }
+            patch.extend_from_slice(line.content());
+            true
+        })?;
+
+    writeln!(out)?;
+    writeln!(out, "git apply --cached <<'{}'", delimiter(&patch))?;
+    out.write_all(&patch)?;
+    writeln!(out, "{}", delimiter(&patch))?;
+    writeln!(
+        out,
+        "git commit --quiet --no-verify --cleanup=verbatim --file=- <<'{}'",
+        delimiter(message.as_bytes())
+    )?;
+    out.write_all(message.as_bytes())?;
+    if !message.ends_with('\n') {
+        writeln!(out)?;
+    }
+    writeln!(out, "{}", delimiter(message.as_bytes()))?;
+    Ok(())
+}
+
+/// Ends the script by staging again whatever was not absorbed.
+pub fn write_footer(out: &mut dyn Write) -> Result<()> {
+    writeln!(out)?;
+    writeln!(out, "git read-tree \"$staged\"")?;
+    Ok(())
+}
+
+pub fn write_command(out: &mut dyn Write, args: &[&str]) -> Result<()> {
+    let args: Vec<_> = args.iter().map(|arg| quote(arg)).collect();
+    writeln!(out, "git {}", args.join(" "))?;
+    Ok(())
+}
+
+/// Quotes `arg` for the shell, if it needs it.
+pub fn quote(arg: &str) -> String {
+    let plain = !arg.is_empty()
+        && arg
+            .bytes()
+            .all(|b| b.is_ascii_alphanumeric() || b"-_./=@:+,".contains(&b));
+    if plain {
+        arg.to_owned()
+    } else {
+        format!("'{}'", arg.replace('\'', "'\\''"))
+    }
+}
+
+/// A here-document delimiter that is not a line of `text`.
+fn delimiter(text: &[u8]) -> String {
+    let mut delimiter = "GIT_ABSORB_EOF".to_owned();
+    while text
+        .split(|&b| b == b'\n')
+        .any(|line| line == delimiter.as_bytes())
+    {
+        delimiter.push('_');
+    }
+    delimiter
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn quoting() {
+        assert_eq!(quote("--autosquash"), "--autosquash");
+        assert_eq!(quote("HEAD~1"), "'HEAD~1'");
+        assert_eq!(quote("with space"), "'with space'");
+        assert_eq!(quote("it's"), "'it'\\''s'");
+        assert_eq!(quote(""), "''");
+    }
+
+    #[test]
+    fn delimiter_is_not_in_the_text() {
+        assert_eq!(delimiter(b"line\n"), "GIT_ABSORB_EOF");
+        assert_eq!(
+            delimiter(b"GIT_ABSORB_EOF\nGIT_ABSORB_EOF_\n"),
+            "GIT_ABSORB_EOF__"
+        );
+    }
+}
@@ -0,0 +1,95 @@
+//! The stack as `git format-patch` would write it out once the fixups are
+//! squashed, for sending by mail without rewriting the branch first.
+
+use anyhow::Result;
+
+use std::io::Write;
+use std::path::Path;
+
+/// How long a patch file name can be, as with `git format-patch`, counting
+/// the number in front of it and the suffix.
+const NAME_MAX: usize = 64;
+
+/// Writes the `count` commits that end with `tip` as numbered patches,
+/// oldest first: into a file each in the directory `to`, or one after the
+/// other to `out` if `to` is `-`.
+pub fn export(
+    out: &mut dyn Write,
+    repo: &git2::Repository,
+    tip: &git2::Commit,
+    count: usize,
+    to: &Path,
+) -> Result<()> {
+    let mut commits = vec![tip.clone()];
+    while commits.len() < count {
+        let parent = commits[commits.len() - 1].parent(0)?;
+        commits.push(parent);
+    }
+    commits.reverse();
+
+    if to != Path::new("-") {
+        std::fs::create_dir_all(to)?;
+    }
+    for (i, commit) in commits.iter().enumerate() {
+        let parent_tree = match commit.parent_count() {
+            0 => None,
+            _ => Some(commit.parent(0)?.tree()?),
+        };
+        let diff = repo.diff_tree_to_tree(parent_tree.as_ref(), Some(&commit.tree()?), None)?;
+        let summary = String::from_utf8_lossy(commit.summary_bytes().unwrap_or_default());
+        let body = String::from_utf8_lossy(commit.body_bytes().unwrap_or_default());
+        let email = git2::Email::from_diff(
+            &diff,
+            i + 1,
+            commits.len(),
+            &commit.id(),
+            summary.as_ref(),
+            body.as_ref(),
+            &commit.author(),
+            &mut git2::EmailCreateOptions::new(),
+        )?;
+        if to == Path::new("-") {
+            out.write_all(email.as_slice())?;
+        } else {
+            std::fs::write(to.join(file_name(i + 1, &summary)), email.as_slice())?;
+        }
+    }
+    Ok(())
+}
+
+/// The name `git format-patch` gives the patch numbered `number`: the
+/// subject with anything but letters, digits, dots and underscores turned
+/// into dashes.
+fn file_name(number: usize, summary: &str) -> String {
+    let prefix = format!("{:04}-", number);
+    let suffix = ".patch";
+    let mut name = String::new();
+    for c in summary.chars() {
+        if c.is_ascii_alphanumeric() || c == '.' || c == '_' {
+            name.push(c);
+        } else if !name.is_empty() && !name.ends_with('-') {
+            name.push('-');
+        }
+    }
+    name.truncate(NAME_MAX - prefix.len() - suffix.len() - 1);
+    let name = name.trim_end_matches(['.', '-']);
+    format!("{}{}{}", prefix, name, suffix)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn file_names() {
+        assert_eq!(
+            file_name(1, "Fix the build, again"),
+            "0001-Fix-the-build-again.patch"
+        );
+        assert_eq!(file_name(12, "[wip] v1.2..."), "0012-wip-v1.2.patch");
+        assert_eq!(
+            file_name(3, &"long ".repeat(20)).len(),
+            "0003-".len() + 52 + ".patch".len()
+        );
+    }
+}
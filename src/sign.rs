@@ -0,0 +1,154 @@
+//! Signing the fixup commits like `git commit` does with `commit.gpgSign`,
+//! which libgit2 leaves to whoever makes the commits.
+
+use anyhow::Result;
+
+use std::io::Write;
+use std::process::{Command, Stdio};
+
+use crate::config;
+use crate::AbsorbError;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Format {
+    OpenPgp,
+    X509,
+    Ssh,
+}
+
+/// How commits are signed in a repository that asks for it.
+#[derive(Debug, PartialEq, Eq)]
+pub struct Signing {
+    format: Format,
+    /// The key to sign with, as `user.signingKey` names it, or else the
+    /// committer, whose key gpg looks up by name and email.
+    pub key: String,
+    program: String,
+}
+
+/// How `commit.gpgSign` and the `gpg.*` settings say commits by `committer`
+/// are signed, or `None` if they are not.
+pub fn signing(repo: &git2::Repository, committer: &git2::Signature) -> Result<Option<Signing>> {
+    if !config::commit_gpg_sign(repo) {
+        return Ok(None);
+    }
+    let format = match config::gpg_format(repo).as_deref() {
+        None | Some("openpgp") => Format::OpenPgp,
+        Some("x509") => Format::X509,
+        Some("ssh") => Format::Ssh,
+        Some(format) => {
+            return Err(AbsorbError::SigningFailed(format!(
+                "cannot sign commits with {} {}",
+                config::GPG_FORMAT_CONFIG_NAME,
+                format
+            ))
+            .into())
+        }
+    };
+    let key = match config::signing_key(repo) {
+        Some(key) => key,
+        None if format == Format::Ssh => {
+            return Err(AbsorbError::SigningFailed(format!(
+                "{} is needed to sign commits with ssh",
+                config::SIGNING_KEY_CONFIG_NAME
+            ))
+            .into())
+        }
+        None => format!(
+            "{} <{}>",
+            String::from_utf8_lossy(committer.name_bytes()),
+            String::from_utf8_lossy(committer.email_bytes())
+        ),
+    };
+    if format == Format::Ssh && key.starts_with("key::") {
+        return Err(AbsorbError::SigningFailed(format!(
+            "{} has to be the path to a key file to sign commits with ssh",
+            config::SIGNING_KEY_CONFIG_NAME
+        ))
+        .into());
+    }
+    let (name, default) = match format {
+        Format::OpenPgp => ("openpgp", "gpg"),
+        Format::X509 => ("x509", "gpgsm"),
+        Format::Ssh => ("ssh", "ssh-keygen"),
+    };
+    let program = config::gpg_program(repo, name).unwrap_or_else(|| default.to_owned());
+    Ok(Some(Signing {
+        format,
+        key,
+        program,
+    }))
+}
+
+/// Makes a commit like [`git2::Repository::commit`] does, signed with
+/// `signing` if there is one.
+#[allow(clippy::too_many_arguments)]
+pub fn commit(
+    repo: &git2::Repository,
+    signing: Option<&Signing>,
+    update_ref: Option<&str>,
+    author: &git2::Signature,
+    committer: &git2::Signature,
+    message: &str,
+    tree: &git2::Tree,
+    parents: &[&git2::Commit],
+) -> Result<git2::Oid> {
+    let signing = match signing {
+        Some(signing) => signing,
+        None => return Ok(repo.commit(update_ref, author, committer, message, tree, parents)?),
+    };
+    let buffer = repo.commit_create_buffer(author, committer, message, tree, parents)?;
+    // the buffer is made from utf8 strings
+    let buffer = String::from_utf8_lossy(&buffer);
+    let signature = sign(signing, &buffer)?;
+    let id = repo.commit_signed(&buffer, &signature, None)?;
+
+    if let Some(update_ref) = update_ref {
+        let summary = message.lines().next().unwrap_or_default();
+        let reflog = format!("commit: {}", summary);
+        let reference = repo.find_reference(update_ref)?;
+        match reference.symbolic_target() {
+            Some(target) => {
+                repo.reference(target, id, true, &reflog)?;
+            }
+            None => {
+                repo.reference(update_ref, id, true, &reflog)?;
+            }
+        }
+    }
+    Ok(id)
+}
+
+/// Runs the program that signs `buffer`, and returns the signature.
+fn sign(signing: &Signing, buffer: &str) -> Result<String> {
+    let mut command = Command::new(&signing.program);
+    match signing.format {
+        Format::OpenPgp | Format::X509 => command.args(["--status-fd=2", "-bsau", &signing.key]),
+        Format::Ssh => command.args(["-Y", "sign", "-n", "git", "-f", &signing.key]),
+    };
+    let mut child = command
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()
+        .map_err(|e| {
+            AbsorbError::SigningFailed(format!("could not run {}: {}", signing.program, e))
+        })?;
+    // the program may fail before reading all of it, which its status says
+    let _ = child
+        .stdin
+        .take()
+        .expect("stdin is piped")
+        .write_all(buffer.as_bytes());
+    let output = child.wait_with_output()?;
+    let signature = String::from_utf8_lossy(&output.stdout).into_owned();
+    if !output.status.success() || signature.is_empty() {
+        return Err(AbsorbError::SigningFailed(format!(
+            "{} failed to sign the commit: {}",
+            signing.program,
+            String::from_utf8_lossy(&output.stderr).trim()
+        ))
+        .into());
+    }
+    Ok(signature)
+}
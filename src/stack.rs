@@ -3,6 +3,7 @@ use anyhow::{anyhow, Result};
 use std::collections::HashMap;
 
 use crate::config;
+use crate::AbsorbError;
 
 #[derive(Debug, PartialEq)]
 pub enum StackEndReason {
@@ -16,9 +17,10 @@ pub enum StackEndReason {
 
 pub fn working_stack<'repo>(
     repo: &'repo git2::Repository,
-    user_provided_base: Option<&str>,
+    user_provided_bases: &[String],
     force_author: bool,
     force_detach: bool,
+    cross_branches: bool,
     logger: &slog::Logger,
 ) -> Result<(Vec<git2::Commit<'repo>>, StackEndReason)> {
     let head = repo.head()?;
@@ -26,9 +28,7 @@ pub fn working_stack<'repo>(
 
     if !head.is_branch() {
         if !force_detach {
-            return Err(anyhow!(
-                "HEAD is not a branch, use --force-detach to override"
-            ));
+            return Err(AbsorbError::DetachedHead.into());
         } else {
             warn!(
                 logger,
@@ -37,24 +37,38 @@ pub fn working_stack<'repo>(
         }
     }
 
+    // the boundary is found by hiding every other branch in this one walk,
+    // which libgit2 speeds up with the commit-graph file whenever git has
+    // written one, without having to be asked
     let mut revwalk = repo.revwalk()?;
     revwalk.set_sorting(git2::Sort::TOPOLOGICAL)?;
     revwalk.push_head()?;
     revwalk.simplify_first_parent()?;
     debug!(logger, "head pushed"; "head" => head.name());
 
-    let base_commit = match user_provided_base {
-        // https://github.com/rust-lang/rfcs/issues/1815
-        // user_provided_base isn't guaranteed to be a commit hash, so peel until a
-        // commit is found.
-        Some(commitish) => Some(repo.revparse_single(commitish)?.peel_to_commit()?),
-        None => None,
-    };
-
-    if let Some(base_commit) = &base_commit {
-        revwalk.hide(base_commit.id())?;
-        debug!(logger, "commit hidden"; "commit" => base_commit.id().to_string());
-    } else {
+    // https://github.com/rust-lang/rfcs/issues/1815
+    // the bases aren't guaranteed to be commit hashes, so peel until a
+    // commit is found.
+    let base_commits = user_provided_bases
+        .iter()
+        .map(|commitish| Ok(repo.revparse_single(commitish)?.peel_to_commit()?.id()))
+        .collect::<Result<Vec<_>>>()?;
+
+    if let [base_commit] = base_commits[..] {
+        revwalk.hide(base_commit)?;
+        debug!(logger, "commit hidden"; "commit" => base_commit.to_string());
+    } else if !base_commits.is_empty() {
+        // the stack is the union of the ranges each base leaves, so only
+        // the commits that every base reaches are left out, which are
+        // those their common ancestors reach, found once for all of them
+        debug!(logger, "bases found";
+               "bases" => base_commits.iter().map(git2::Oid::to_string).collect::<Vec<_>>().join(" "),
+        );
+        for ancestor in common_ancestors(repo, &base_commits)? {
+            revwalk.hide(ancestor)?;
+            debug!(logger, "common ancestor hidden"; "commit" => ancestor.to_string());
+        }
+    } else if !cross_branches {
         for branch in repo.branches(Some(git2::BranchType::Local))? {
             let (branch, _) = branch?;
             let branch = branch.get().name();
@@ -73,22 +87,34 @@ pub fn working_stack<'repo>(
 
     let mut ret = Vec::new();
     let mut stack_end_reason: Option<StackEndReason> = None;
-    let sig = repo.signature();
+    // both sides go through the mailmap, so that commits made under an
+    // older name or email still count as the user's own
+    let mailmap = repo.mailmap().ok();
+    let sig = repo.signature().map(|sig| match &mailmap {
+        Some(mailmap) => mailmap.resolve_signature(&sig).unwrap_or(sig),
+        None => sig,
+    });
     for rev in revwalk {
         let commit = repo.find_commit(rev?)?;
+        // only the first parents were walked on the way here, and merges
+        // of any number of parents end the stack, so no other parent line
+        // ever gets into it
         if commit.parent_count() > 1 {
             debug!(logger, "Stack ends at merge commit"; "commit" => commit.id().to_string());
+            if cross_branches {
+                warn_about_crossed_branches(repo, &head, &ret, logger)?;
+            }
             return Ok((ret, StackEndReason::ReachedMergeCommit));
         }
 
-        if !force_author && is_by_another_author(&sig, &commit) {
+        if !force_author && is_by_another_author(&sig, mailmap.as_ref(), &commit) {
             debug!(logger, "Stopping before commit by another author.";
                   "commit" => commit.id().to_string());
             stack_end_reason = Some(StackEndReason::ReachedAnotherAuthor);
             break;
         }
 
-        if ret.len() == config::max_stack(repo) && user_provided_base.is_none() {
+        if ret.len() == config::max_stack(repo) && user_provided_bases.is_empty() {
             debug!(logger, "Stopping at stack limit.";
                   "limit" => ret.len());
             stack_end_reason = Some(StackEndReason::ReachedLimit);
@@ -99,6 +125,10 @@ pub fn working_stack<'repo>(
         ret.push(commit);
     }
 
+    if cross_branches {
+        warn_about_crossed_branches(repo, &head, &ret, logger)?;
+    }
+
     match stack_end_reason {
         Some(end_reason) => Ok((ret, end_reason)),
         None => {
@@ -122,11 +152,11 @@ pub fn working_stack<'repo>(
                 return Ok((ret, StackEndReason::ReachedMergeCommit));
             }
 
-            if !force_author && is_by_another_author(&sig, &hidden_commit) {
+            if !force_author && is_by_another_author(&sig, mailmap.as_ref(), &hidden_commit) {
                 return Ok((ret, StackEndReason::ReachedAnotherAuthor));
             }
 
-            if user_provided_base.is_some() {
+            if !user_provided_bases.is_empty() {
                 Ok((ret, StackEndReason::CommitsHiddenByBase))
             } else {
                 Ok((ret, StackEndReason::CommitsHiddenByBranches))
@@ -135,6 +165,264 @@ pub fn working_stack<'repo>(
     }
 }
 
+/// The best common ancestors of all of `bases`, whose ancestors are exactly
+/// the commits that each of `bases` reaches.
+fn common_ancestors(repo: &git2::Repository, bases: &[git2::Oid]) -> Result<Vec<git2::Oid>> {
+    let mut common = bases[..1].to_vec();
+    for base in &bases[1..] {
+        let mut next = vec![];
+        for one in &common {
+            match repo.merge_bases(*one, *base) {
+                Ok(found) => next.extend(found.iter().copied()),
+                // unrelated histories have nothing in common
+                Err(e) if e.code() == git2::ErrorCode::NotFound => {}
+                Err(e) => return Err(e.into()),
+            }
+        }
+        next.sort();
+        next.dedup();
+        common = next;
+    }
+    Ok(common)
+}
+
+/// Warns about the local branches, other than the one at HEAD, that point
+/// into `stack`, since rebasing the stack would leave them behind.
+fn warn_about_crossed_branches(
+    repo: &git2::Repository,
+    head: &git2::Reference,
+    stack: &[git2::Commit],
+    logger: &slog::Logger,
+) -> Result<()> {
+    let mut crossed = vec![];
+    for branch in repo.branches(Some(git2::BranchType::Local))? {
+        let (branch, _) = branch?;
+        let branch = branch.get();
+        if branch.name() == head.name() {
+            continue;
+        }
+        if let (Some(name), Some(target)) = (branch.shorthand(), branch.target()) {
+            if stack.iter().any(|commit| commit.id() == target) {
+                crossed.push(name.to_owned());
+            }
+        }
+    }
+    if !crossed.is_empty() {
+        warn!(
+            logger,
+            "Stack crosses other branches, which a rebase will leave behind unless given --update-refs";
+            "branches" => crossed.join(", "),
+        );
+    }
+    Ok(())
+}
+
+/// Resolves the bases of the stack, as given with --base or implied by
+/// absorb.baseFromPush, to something that can be passed to [`working_stack`].
+pub fn resolve_base(
+    repo: &git2::Repository,
+    user_provided_bases: &[&str],
+    logger: &slog::Logger,
+) -> Result<Vec<String>> {
+    if user_provided_bases.is_empty() {
+        if !config::base_from_push(repo) {
+            return Ok(vec![]);
+        }
+        return Ok(push_merge_base(repo, logger)?
+            .map(|base| base.to_string())
+            .into_iter()
+            .collect());
+    }
+    user_provided_bases
+        .iter()
+        .map(|base| match *base {
+            // libgit2 does not understand @{push}, so resolve it ourselves
+            "@{push}" | "HEAD@{push}" => match push_merge_base(repo, logger)? {
+                Some(base) => Ok(base.to_string()),
+                None => Err(anyhow!("HEAD has no push branch")),
+            },
+            base => Ok(base.to_owned()),
+        })
+        .collect()
+}
+
+/// Splits `range`, given as `BASE..TIP`, into the base to pass to
+/// [`working_stack`] and the newest commit that may be absorbed into,
+/// which must be HEAD or one of its ancestors.
+pub fn resolve_range(repo: &git2::Repository, range: &str) -> Result<(String, git2::Oid)> {
+    let (base, tip) = match range.split_once("..") {
+        Some((base, tip)) if !base.is_empty() && !tip.starts_with('.') => (base, tip),
+        _ => return Err(anyhow!("range {} is not of the form BASE..TIP", range)),
+    };
+    let head = repo.head()?.peel_to_commit()?.id();
+    let tip = match tip {
+        "" => head,
+        tip => repo.revparse_single(tip)?.peel_to_commit()?.id(),
+    };
+    if tip != head && !repo.graph_descendant_of(head, tip)? {
+        return Err(anyhow!(
+            "the tip of range {} is not HEAD or an ancestor of it",
+            range
+        ));
+    }
+    Ok((base.to_owned(), tip))
+}
+
+/// The merge-base of HEAD and the branch `git push` would update, if any.
+fn push_merge_base(repo: &git2::Repository, logger: &slog::Logger) -> Result<Option<git2::Oid>> {
+    let push_ref = match push_branch(repo)? {
+        Some(push_ref) => push_ref,
+        None => {
+            debug!(logger, "no push branch configured");
+            return Ok(None);
+        }
+    };
+    let push_commit = match repo.refname_to_id(&push_ref) {
+        Ok(push_commit) => push_commit,
+        Err(e) if e.code() == git2::ErrorCode::NotFound => {
+            debug!(logger, "push branch not fetched"; "ref" => &push_ref);
+            return Ok(None);
+        }
+        Err(e) => return Err(e.into()),
+    };
+    let base = repo.merge_base(repo.head()?.peel_to_commit()?.id(), push_commit)?;
+    debug!(logger, "push branch found";
+           "ref" => &push_ref,
+           "merge-base" => base.to_string(),
+    );
+    Ok(Some(base))
+}
+
+/// The remote-tracking ref for the branch that `git push` would update,
+/// following the same configuration git uses to resolve `@{push}`.
+fn push_branch(repo: &git2::Repository) -> Result<Option<String>> {
+    let head = repo.head()?;
+    let branch = match head.shorthand() {
+        Some(branch) if head.is_branch() => branch,
+        _ => return Ok(None),
+    };
+    let config = repo.config()?.snapshot()?;
+    let get = |name: &str| config.get_string(name).ok();
+
+    let remote = match get(&format!("branch.{}.pushRemote", branch))
+        .or_else(|| get("remote.pushDefault"))
+        .or_else(|| get(&format!("branch.{}.remote", branch)))
+    {
+        Some(remote) => remote,
+        None => return Ok(None),
+    };
+    let remote_branch = match get("push.default").as_deref() {
+        Some("nothing") => return Ok(None),
+        Some("upstream" | "tracking") => match get(&format!("branch.{}.merge", branch))
+            .as_deref()
+            .and_then(|merge| merge.strip_prefix("refs/heads/"))
+        {
+            Some(remote_branch) => remote_branch.to_owned(),
+            None => return Ok(None),
+        },
+        // simple, current and matching all push to a branch of the same name
+        _ => branch.to_owned(),
+    };
+    Ok(Some(format!("refs/remotes/{}/{}", remote, remote_branch)))
+}
+
+/// The subject that autosquash looks for a commit by, given the subject
+/// of its fixup: without any "fixup! ", "squash! " or "amend! " prefixes.
+pub fn autosquash_subject(summary: &str) -> &str {
+    let mut subject = summary;
+    while let Some(rest) = ["fixup! ", "squash! ", "amend! "]
+        .iter()
+        .find_map(|prefix| subject.strip_prefix(prefix))
+    {
+        subject = rest;
+    }
+    subject
+}
+
+/// How many of `commits` autosquash would find by each subject.
+pub fn autosquash_subject_counts<'a, 'repo: 'a>(
+    commits: impl IntoIterator<Item = &'a git2::Commit<'repo>>,
+) -> HashMap<String, u64> {
+    let mut ret = HashMap::new();
+    for commit in commits {
+        let subject = autosquash_subject(commit.summary().unwrap_or(""));
+        *ret.entry(subject.to_owned()).or_insert(0) += 1;
+    }
+    ret
+}
+
+/// The todo list `git rebase --interactive --autosquash` would start with
+/// for `commits`, oldest first, each given by its id, if it has one yet,
+/// and its summary. Every `fixup!`, `squash!` or `amend!` commit moves to
+/// right after the commit it names, by subject or by id, with the action
+/// its prefix asks for. Returns each action with the index of its commit.
+pub fn autosquash_todo(commits: &[(Option<git2::Oid>, &str)]) -> Vec<(&'static str, usize)> {
+    let targets = autosquash_targets(commits);
+    // those that move after each commit
+    let mut followers: Vec<Vec<(&'static str, usize)>> = vec![vec![]; commits.len()];
+    for (idx, target) in targets.iter().enumerate() {
+        if let Some(target) = *target {
+            followers[target].push((autosquash_action(commits[idx].1).unwrap(), idx));
+        }
+    }
+
+    let mut todo = vec![];
+    for (idx, followers) in followers.iter().enumerate() {
+        if targets[idx].is_none() {
+            todo.push(("pick", idx));
+            todo.extend(followers);
+        }
+    }
+    todo
+}
+
+/// The rebase action that the prefix of `summary` asks for, if it has one.
+fn autosquash_action(summary: &str) -> Option<&'static str> {
+    if summary.starts_with("fixup! ") {
+        Some("fixup")
+    } else if summary.starts_with("squash! ") {
+        Some("squash")
+    } else if summary.starts_with("amend! ") {
+        Some("fixup -C")
+    } else {
+        None
+    }
+}
+
+/// For each of `commits`, given as for [`autosquash_todo`], the index of
+/// the commit that autosquash moves it after and squashes it into, if any.
+/// A fixup of a fixup goes with the commit they both fix up.
+pub fn autosquash_targets(commits: &[(Option<git2::Oid>, &str)]) -> Vec<Option<usize>> {
+    let mut targets: Vec<Option<usize>> = vec![None; commits.len()];
+    for (idx, &(_, summary)) in commits.iter().enumerate() {
+        if autosquash_action(summary).is_none() {
+            continue;
+        }
+        let name = autosquash_subject(summary);
+        let earlier = &commits[..idx];
+        let target = earlier
+            .iter()
+            .position(|&(_, subject)| subject == name)
+            .or_else(|| {
+                earlier.iter().position(|&(id, _)| {
+                    name.len() >= 4 && id.is_some_and(|id| id.to_string().starts_with(name))
+                })
+            })
+            .or_else(|| {
+                earlier
+                    .iter()
+                    .position(|&(_, subject)| subject.starts_with(name))
+            });
+        if let Some(mut target) = target {
+            while let Some(earlier_target) = targets[target] {
+                target = earlier_target;
+            }
+            targets[idx] = Some(target);
+        }
+    }
+    targets
+}
+
 pub fn summary_counts<'repo, 'a, I>(commits: I) -> HashMap<String, u64>
 where
     I: IntoIterator<Item = &'a git2::Commit<'repo>>,
@@ -157,11 +445,17 @@ where
 
 fn is_by_another_author(
     sig: &Result<git2::Signature, git2::Error>,
+    mailmap: Option<&git2::Mailmap>,
     hidden_commit: &git2::Commit,
 ) -> bool {
     if let Ok(ref sig) = sig {
-        hidden_commit.author().name_bytes() != sig.name_bytes()
-            || hidden_commit.author().email_bytes() != sig.email_bytes()
+        let author = match mailmap {
+            Some(mailmap) => hidden_commit
+                .author_with_mailmap(mailmap)
+                .unwrap_or_else(|_| hidden_commit.author()),
+            None => hidden_commit.author(),
+        };
+        author.name_bytes() != sig.name_bytes() || author.email_bytes() != sig.email_bytes()
     } else {
         false
     }
@@ -197,17 +491,78 @@ mod tests {
         }
     }
 
+    #[test]
+    fn test_autosquash_subject() {
+        assert_eq!(autosquash_subject("fix tests"), "fix tests");
+        assert_eq!(autosquash_subject("fixup! fix tests"), "fix tests");
+        assert_eq!(autosquash_subject("squash! amend! fix tests"), "fix tests");
+        assert_eq!(autosquash_subject("fixup!fix tests"), "fixup!fix tests");
+    }
+
+    #[test]
+    fn test_autosquash_todo() {
+        let first = git2::Oid::from_str("1234567890abcdef1234567890abcdef12345678").unwrap();
+        let commits = [
+            (Some(first), "Add lines"),
+            (None, "Change lines"),
+            (None, "fixup! Change lines"),
+            (None, "squash! 12345678"),
+            (None, "fixup! fixup! Change lines"),
+            (None, "amend! Change"),
+            (None, "fixup! Nothing like it"),
+        ];
+        assert_eq!(
+            autosquash_todo(&commits),
+            vec![
+                ("pick", 0),
+                ("squash", 3),
+                ("pick", 1),
+                ("fixup", 2),
+                ("fixup", 4),
+                ("fixup -C", 5),
+                ("pick", 6),
+            ]
+        );
+    }
+
     #[test]
     fn test_stack_hides_other_branches() {
         let (_dir, repo) = init_repo();
         let commits = repo_utils::empty_commit_chain(&repo, "HEAD", &[], 2);
         repo.branch("hide", &commits[0], false).unwrap();
 
-        let (stack, reason) = working_stack(&repo, None, false, false, &empty_slog()).unwrap();
+        let (stack, reason) =
+            working_stack(&repo, &[], false, false, false, &empty_slog()).unwrap();
         assert_stack_matches_chain(1, &stack, &commits);
         assert_eq!(reason, StackEndReason::CommitsHiddenByBranches);
     }
 
+    #[test]
+    fn test_stack_crosses_other_branches() {
+        let (_dir, repo) = init_repo();
+        let commits = repo_utils::empty_commit_chain(&repo, "HEAD", &[], 3);
+        repo.branch("bookmark", &commits[1], false).unwrap();
+
+        let (stack, reason) = working_stack(&repo, &[], false, false, true, &empty_slog()).unwrap();
+        assert_stack_matches_chain(3, &stack, &commits);
+        assert_eq!(reason, StackEndReason::ReachedRoot);
+    }
+
+    #[test]
+    fn test_stack_crossing_branches_keeps_other_boundaries() {
+        let (_dir, repo) = init_repo();
+        let commits = repo_utils::empty_commit_chain(&repo, "HEAD", &[], 3);
+        repo.branch("bookmark", &commits[2], false).unwrap();
+        repo.config()
+            .unwrap()
+            .set_i64(config::MAX_STACK_CONFIG_NAME, 2)
+            .unwrap();
+
+        let (stack, reason) = working_stack(&repo, &[], false, false, true, &empty_slog()).unwrap();
+        assert_stack_matches_chain(2, &stack, &commits);
+        assert_eq!(reason, StackEndReason::ReachedLimit);
+    }
+
     #[test]
     fn test_stack_uses_custom_base() {
         let (_dir, repo) = init_repo();
@@ -216,7 +571,8 @@ mod tests {
 
         let (stack, reason) = working_stack(
             &repo,
-            Some(&commits[0].id().to_string()),
+            &[commits[0].id().to_string()],
+            false,
             false,
             false,
             &empty_slog(),
@@ -226,6 +582,139 @@ mod tests {
         assert_eq!(reason, StackEndReason::CommitsHiddenByBase);
     }
 
+    #[test]
+    fn test_stack_unions_several_bases() {
+        let (_dir, repo) = init_repo();
+        let commits = repo_utils::empty_commit_chain(&repo, "HEAD", &[], 5);
+        let side = repo_utils::empty_commit_chain(&repo, "refs/heads/side", &[&commits[0]], 1);
+
+        // each base alone leaves out more than both together
+        let bases = [commits[2].id().to_string(), side[0].id().to_string()];
+        let (stack, reason) =
+            working_stack(&repo, &bases, false, false, false, &empty_slog()).unwrap();
+        let stack: Vec<_> = stack.iter().map(git2::Commit::id).collect();
+        assert_eq!(
+            stack,
+            vec![
+                commits[4].id(),
+                commits[3].id(),
+                commits[2].id(),
+                commits[1].id()
+            ]
+        );
+        assert_eq!(reason, StackEndReason::CommitsHiddenByBase);
+    }
+
+    #[test]
+    fn test_range_is_split_into_base_and_tip() {
+        let (_dir, repo) = init_repo();
+        let commits = repo_utils::empty_commit_chain(&repo, "HEAD", &[], 3);
+        let side = repo_utils::empty_commit_chain(&repo, "refs/heads/side", &[&commits[0]], 1);
+
+        let (base, tip) = resolve_range(&repo, "HEAD~2..HEAD~1").unwrap();
+        assert_eq!(base, "HEAD~2");
+        assert_eq!(tip, commits[1].id());
+        let (_, tip) = resolve_range(&repo, "HEAD~2..").unwrap();
+        assert_eq!(tip, commits[2].id());
+
+        assert!(resolve_range(&repo, "HEAD~2...HEAD").is_err());
+        assert!(resolve_range(&repo, "..HEAD").is_err());
+        assert!(resolve_range(&repo, "HEAD~2").is_err());
+        assert!(resolve_range(&repo, &format!("HEAD~2..{}", side[0].id())).is_err());
+    }
+
+    /// Sets up a triangular workflow: the branch tracks `origin`, but is
+    /// pushed to `fork`, which has diverged after the second commit.
+    fn init_triangular_repo() -> (tempfile::TempDir, git2::Repository, Vec<git2::Oid>) {
+        let (dir, repo) = init_repo();
+        let commits: Vec<_> = repo_utils::empty_commit_chain(&repo, "HEAD", &[], 4)
+            .iter()
+            .map(git2::Commit::id)
+            .collect();
+        let branch = repo.head().unwrap().shorthand().unwrap().to_owned();
+
+        repo.reference(
+            &format!("refs/remotes/origin/{}", branch),
+            commits[0],
+            false,
+            "",
+        )
+        .unwrap();
+        {
+            let fork_parent = repo.find_commit(commits[1]).unwrap();
+            repo_utils::empty_commit(
+                &repo,
+                &format!("refs/remotes/fork/{}", branch),
+                "pushed from elsewhere",
+                &[&fork_parent],
+            );
+        }
+
+        let mut config = repo.config().unwrap();
+        config
+            .set_str(&format!("branch.{}.remote", branch), "origin")
+            .unwrap();
+        config
+            .set_str(
+                &format!("branch.{}.merge", branch),
+                &format!("refs/heads/{}", branch),
+            )
+            .unwrap();
+        config.set_str("remote.pushDefault", "fork").unwrap();
+
+        (dir, repo, commits)
+    }
+
+    #[test]
+    fn test_base_from_push_flag() {
+        let (_dir, repo, commits) = init_triangular_repo();
+
+        let base = resolve_base(&repo, &["@{push}"], &empty_slog()).unwrap();
+        assert_eq!(base, vec![commits[1].to_string()]);
+
+        let (stack, reason) =
+            working_stack(&repo, &base, false, false, false, &empty_slog()).unwrap();
+        let stack: Vec<_> = stack.iter().map(git2::Commit::id).collect();
+        assert_eq!(stack, vec![commits[3], commits[2]]);
+        assert_eq!(reason, StackEndReason::CommitsHiddenByBase);
+    }
+
+    #[test]
+    fn test_base_from_push_config() {
+        let (_dir, repo, commits) = init_triangular_repo();
+
+        assert_eq!(
+            resolve_base(&repo, &[], &empty_slog()).unwrap(),
+            Vec::<String>::new()
+        );
+
+        repo_utils::set_config_flag(&repo, config::BASE_FROM_PUSH_CONFIG_NAME);
+        let base = resolve_base(&repo, &[], &empty_slog()).unwrap();
+        assert_eq!(base, vec![commits[1].to_string()]);
+
+        // an explicit base still wins
+        let base = resolve_base(&repo, &["HEAD~1"], &empty_slog()).unwrap();
+        assert_eq!(base, vec!["HEAD~1"]);
+    }
+
+    #[test]
+    fn test_base_from_push_without_push_branch() {
+        let (_dir, repo) = init_repo();
+        repo_utils::empty_commit_chain(&repo, "HEAD", &[], 2);
+        repo_utils::set_config_flag(&repo, config::BASE_FROM_PUSH_CONFIG_NAME);
+
+        assert_eq!(
+            resolve_base(&repo, &[], &empty_slog()).unwrap(),
+            Vec::<String>::new()
+        );
+        assert_eq!(
+            resolve_base(&repo, &["@{push}"], &empty_slog())
+                .unwrap_err()
+                .to_string(),
+            "HEAD has no push branch"
+        );
+    }
+
     #[test]
     fn test_stack_stops_at_configured_limit() {
         let (_dir, repo) = init_repo();
@@ -238,7 +727,8 @@ mod tests {
             )
             .unwrap();
 
-        let (stack, reason) = working_stack(&repo, None, false, false, &empty_slog()).unwrap();
+        let (stack, reason) =
+            working_stack(&repo, &[], false, false, false, &empty_slog()).unwrap();
         assert_stack_matches_chain(config::MAX_STACK + 1, &stack, &commits);
         assert_eq!(reason, StackEndReason::ReachedLimit);
     }
@@ -254,19 +744,115 @@ mod tests {
         let new_commits =
             repo_utils::empty_commit_chain(&repo, "HEAD", &[old_commits.last().unwrap()], 2);
 
-        let (stack, reason) = working_stack(&repo, None, false, false, &empty_slog()).unwrap();
+        let (stack, reason) =
+            working_stack(&repo, &[], false, false, false, &empty_slog()).unwrap();
         assert_stack_matches_chain(2, &stack, &new_commits);
         assert_eq!(reason, StackEndReason::ReachedAnotherAuthor);
     }
 
+    #[test]
+    fn test_stack_follows_mailmap() {
+        let (dir, repo) = init_repo();
+        repo.config()
+            .unwrap()
+            .set_str("user.email", "old@example.com")
+            .unwrap();
+        let old_commits = repo_utils::empty_commit_chain(&repo, "HEAD", &[], 3);
+        repo.config()
+            .unwrap()
+            .set_str("user.email", "nobody@example.com")
+            .unwrap();
+        let new_commits =
+            repo_utils::empty_commit_chain(&repo, "HEAD", &[old_commits.last().unwrap()], 2);
+        std::fs::write(
+            dir.path().join(".mailmap"),
+            "nobody <nobody@example.com> <old@example.com>\n",
+        )
+        .unwrap();
+
+        let (stack, reason) =
+            working_stack(&repo, &[], false, false, false, &empty_slog()).unwrap();
+        let chain: Vec<_> = old_commits.into_iter().chain(new_commits).collect();
+        assert_stack_matches_chain(5, &stack, &chain);
+        assert_eq!(reason, StackEndReason::ReachedRoot);
+    }
+
     #[test]
     fn test_stack_stops_at_merges() {
         let (_dir, repo) = init_repo();
         let merge = repo_utils::merge_commit(&repo, &[]);
         let commits = repo_utils::empty_commit_chain(&repo, "HEAD", &[&merge], 2);
 
-        let (stack, reason) = working_stack(&repo, None, false, false, &empty_slog()).unwrap();
+        let (stack, reason) =
+            working_stack(&repo, &[], false, false, false, &empty_slog()).unwrap();
+        assert_stack_matches_chain(2, &stack, &commits);
+        assert_eq!(reason, StackEndReason::ReachedMergeCommit);
+    }
+
+    #[test]
+    fn test_stack_stops_at_octopus_merges() {
+        let (_dir, repo) = init_repo();
+        let merge = repo_utils::octopus_merge_commit(&repo, &[], 3);
+        let commits = repo_utils::empty_commit_chain(&repo, "HEAD", &[&merge], 2);
+
+        for cross_branches in [false, true] {
+            let (stack, reason) =
+                working_stack(&repo, &[], false, false, cross_branches, &empty_slog()).unwrap();
+            assert_stack_matches_chain(2, &stack, &commits);
+            assert_eq!(reason, StackEndReason::ReachedMergeCommit);
+        }
+    }
+
+    #[test]
+    fn test_stack_is_empty_at_octopus_merge() {
+        let (_dir, repo) = init_repo();
+        repo_utils::octopus_merge_commit(&repo, &[], 3);
+
+        let (stack, reason) =
+            working_stack(&repo, &[], false, false, false, &empty_slog()).unwrap();
+        assert!(stack.is_empty());
+        assert_eq!(reason, StackEndReason::ReachedMergeCommit);
+    }
+
+    #[test]
+    fn test_stack_stops_at_octopus_merge_given_as_base() {
+        let (_dir, repo) = init_repo();
+        let merge = repo_utils::octopus_merge_commit(&repo, &[], 3);
+        let commits = repo_utils::empty_commit_chain(&repo, "HEAD", &[&merge], 2);
+        let base = [merge.id().to_string()];
+
+        let (stack, reason) =
+            working_stack(&repo, &base, false, false, false, &empty_slog()).unwrap();
         assert_stack_matches_chain(2, &stack, &commits);
         assert_eq!(reason, StackEndReason::ReachedMergeCommit);
     }
+
+    #[test]
+    fn test_stack_is_the_same_with_commit_graph() {
+        let (dir, repo) = init_repo();
+        let commits = repo_utils::empty_commit_chain(&repo, "HEAD", &[], 5);
+        repo.branch("hide", &commits[1], false).unwrap();
+        let stack_of = |repo: &git2::Repository| {
+            let (stack, reason) =
+                working_stack(repo, &[], false, false, false, &empty_slog()).unwrap();
+            (
+                stack.iter().map(git2::Commit::id).collect::<Vec<_>>(),
+                reason,
+            )
+        };
+        let without = stack_of(&repo);
+
+        let status = std::process::Command::new("git")
+            .args(["commit-graph", "write", "--reachable"])
+            .current_dir(dir.path())
+            .status()
+            .unwrap();
+        assert!(status.success());
+        assert!(repo.path().join("objects/info/commit-graph").is_file());
+
+        // the commit-graph is read when the repository is opened
+        let repo = git2::Repository::open(dir.path()).unwrap();
+        assert_eq!(stack_of(&repo), without);
+        assert_eq!(without.0.len(), 3);
+    }
 }
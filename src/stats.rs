@@ -0,0 +1,173 @@
+use anyhow::Result;
+
+use std::io::Write;
+use std::path::Path;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+/// What happened during one run, for appending to a stats file.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct Stats {
+    /// Staged hunks and whole file changes that were considered.
+    pub hunks: usize,
+    /// Those of them that went into a fixup commit.
+    pub absorbed: usize,
+    /// Fixup commits that were created, or would have been.
+    pub fixups: usize,
+    /// Commits in the working stack.
+    pub stack: usize,
+    /// With --timings, how long each phase of the run took.
+    pub timings: Option<Timings>,
+}
+
+/// The phases of a run, in the order they run in.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Phase {
+    Options,
+    Stack,
+    Diffs,
+    Matching,
+    Fixups,
+    Rebase,
+}
+
+const PHASES: [Phase; 6] = [
+    Phase::Options,
+    Phase::Stack,
+    Phase::Diffs,
+    Phase::Matching,
+    Phase::Fixups,
+    Phase::Rebase,
+];
+
+impl Phase {
+    fn name(self) -> &'static str {
+        match self {
+            Phase::Options => "options",
+            Phase::Stack => "stack",
+            Phase::Diffs => "diffs",
+            Phase::Matching => "matching",
+            Phase::Fixups => "fixups",
+            Phase::Rebase => "rebase",
+        }
+    }
+
+    /// What the phase went through, if it counts anything.
+    fn count(self, stats: &Stats) -> Option<usize> {
+        match self {
+            Phase::Stack | Phase::Diffs => Some(stats.stack),
+            Phase::Matching => Some(stats.hunks),
+            Phase::Fixups => Some(stats.fixups),
+            Phase::Options | Phase::Rebase => None,
+        }
+    }
+}
+
+/// The wall-clock time spent in each [`Phase`].
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct Timings {
+    spent: [Duration; PHASES.len()],
+    /// The phase being timed, and since when.
+    current: Option<(Phase, Instant)>,
+}
+
+impl Timings {
+    /// Ends the phase being timed, if any.
+    pub fn finish(&mut self) {
+        if let Some((phase, start)) = self.current.take() {
+            self.spent[phase as usize] += start.elapsed();
+        }
+    }
+
+    fn total(&self) -> Duration {
+        self.spent.iter().sum()
+    }
+
+    /// A table of the time spent in each phase, and what it counted.
+    pub fn table(&self, stats: &Stats) -> String {
+        let mut table = format!("{:<10}{:>12}{:>8}\n", "phase", "time", "count");
+        let rows = PHASES
+            .iter()
+            .map(|&phase| (phase.name(), self.spent[phase as usize], phase.count(stats)))
+            .chain(std::iter::once(("total", self.total(), None)));
+        for (name, spent, count) in rows {
+            let count = count.map_or(String::new(), |count| count.to_string());
+            table.push_str(&format!(
+                "{:<10}{:>10.1}ms{:>8}\n",
+                name,
+                spent.as_secs_f64() * 1000.0,
+                count
+            ));
+        }
+        table
+    }
+
+    /// The timings as a JSON object, in milliseconds.
+    fn json(&self, stats: &Stats) -> String {
+        let phases: Vec<_> = PHASES
+            .iter()
+            .map(|&phase| {
+                let count = phase
+                    .count(stats)
+                    .map_or(String::new(), |count| format!(",\"count\":{}", count));
+                format!(
+                    "\"{}\":{{\"ms\":{}{}}}",
+                    phase.name(),
+                    self.spent[phase as usize].as_millis(),
+                    count
+                )
+            })
+            .collect();
+        format!(
+            "{{{},\"total_ms\":{}}}",
+            phases.join(","),
+            self.total().as_millis()
+        )
+    }
+}
+
+impl Stats {
+    /// Starts timing `phase`, ending the phase before it. Without --timings
+    /// it does nothing, not even read the clock.
+    pub fn enter(&mut self, phase: Phase) {
+        if let Some(timings) = &mut self.timings {
+            timings.finish();
+            timings.current = Some((phase, Instant::now()));
+        }
+    }
+
+    pub fn skipped(&self) -> usize {
+        self.hunks - self.absorbed
+    }
+
+    /// Formats the stats as one line of JSON.
+    pub fn line(&self, elapsed: Duration) -> String {
+        let time = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map_or(0, |since| since.as_secs());
+        let timings = self.timings.map_or(String::new(), |timings| {
+            format!(",\"timings\":{}", timings.json(self))
+        });
+        format!(
+            "{{\"time\":{},\"hunks\":{},\"absorbed\":{},\"skipped\":{},\"fixups\":{},\"stack\":{},\"elapsed_ms\":{}{}}}\n",
+            time,
+            self.hunks,
+            self.absorbed,
+            self.skipped(),
+            self.fixups,
+            self.stack,
+            elapsed.as_millis(),
+            timings,
+        )
+    }
+
+    /// Appends the stats line to the file at `path`, creating it if needed.
+    pub fn append_to(&self, path: &Path, elapsed: Duration) -> Result<()> {
+        let mut file = std::fs::OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(path)?;
+        // a single write, so lines from concurrent runs don't interleave
+        file.write_all(self.line(elapsed).as_bytes())?;
+        Ok(())
+    }
+}
@@ -23,12 +23,12 @@ impl CapturingLogger {
 
     /// Get log messages that would usually be visible when git-absorb is run.
     ///
-    /// Used to filter out debug logs which are too detailed for most tests.
+    /// Used to filter out debug and trace logs which are too detailed for most tests.
     pub fn visible_logs(&mut self) -> Vec<Value> {
         // let logs = slog_test::read_json_values(&mut self.buffer);
         let logs = self.logs();
         logs.iter()
-            .filter(|log| log["level"].as_str().unwrap().ne("DEBG"))
+            .filter(|log| !matches!(log["level"].as_str().unwrap(), "DEBG" | "TRCE"))
             .map(|log| log.clone())
             .collect()
     }
@@ -1,4 +1,3 @@
-#[cfg(test)]
 use git2::Tree;
 use std::path::{Path, PathBuf};
 pub struct Context {
@@ -44,6 +43,48 @@ lines
     (Context { repo, dir }, path)
 }
 
+/// Prepare a repository with a stack of `commits` commits on top of the
+/// initial one, each rewriting its own line of a single file, and stage
+/// `hunks` separate hunks in that file. The first `commits` hunks each sit
+/// next to lines rewritten by the stack; the rest commute with all of it.
+pub fn prepare_synthetic_stack(commits: usize, hunks: usize) -> (Context, PathBuf) {
+    let dir = tempfile::tempdir().unwrap();
+    let repo = git2::Repository::init_opts(
+        dir.path(),
+        git2::RepositoryInitOptions::new().initial_head("master"),
+    )
+    .unwrap();
+    become_author(&repo, "nobody", "nobody@example.com");
+
+    // even lines belong to the commits and odd lines to the hunks, so that
+    // every hunk is separated from its neighbours by an unchanged line
+    let path = PathBuf::from("test-file.txt");
+    let mut lines: Vec<_> = (0..2 * commits.max(hunks) + 1)
+        .map(|idx| format!("line {idx}\n"))
+        .collect();
+    std::fs::write(dir.path().join(&path), lines.concat()).unwrap();
+    {
+        let tree = add(&repo, &path);
+        commit(&repo, "HEAD", "Initial commit.", &tree, &[]);
+    }
+
+    for idx in 0..commits {
+        lines[2 * idx] = format!("commit {idx}\n");
+        std::fs::write(dir.path().join(&path), lines.concat()).unwrap();
+        let tree = add(&repo, &path);
+        let head = repo.head().unwrap().peel_to_commit().unwrap();
+        commit(&repo, "HEAD", &format!("Commit {idx}"), &tree, &[&head]);
+    }
+
+    for idx in 0..hunks {
+        lines[2 * idx + 1] = format!("hunk {idx}\n");
+    }
+    std::fs::write(dir.path().join(&path), lines.concat()).unwrap();
+    add(&repo, &path);
+
+    (Context { repo, dir }, path)
+}
+
 /// Stage the changes made to `path`.
 pub fn add<'r>(repo: &'r git2::Repository, path: &Path) -> git2::Tree<'r> {
     let mut index = repo.index().unwrap();
@@ -121,6 +162,26 @@ pub fn merge_commit<'repo>(
     )
 }
 
+/// Make an octopus merge of `count` parents, each branching from the
+/// supplied grandparents. All but the first parent are on topic branches.
+pub fn octopus_merge_commit<'repo>(
+    repo: &'repo git2::Repository,
+    grandparents: &[&git2::Commit],
+    count: usize,
+) -> git2::Commit<'repo> {
+    let parents: Vec<_> = (0..count)
+        .map(|idx| {
+            let update_ref = match idx {
+                0 => "HEAD".to_owned(),
+                _ => format!("refs/heads/topic{idx}"),
+            };
+            empty_commit(repo, &update_ref, &format!("parent {idx}"), grandparents)
+        })
+        .collect();
+    let parents: Vec<_> = parents.iter().collect();
+    empty_commit(repo, "HEAD", "octopus merge", &parents)
+}
+
 /// Add a chain of empty commits to the repository.
 /// The first commit will have the given parents, and each subsequent commit will have the previous
 /// commit as its parent.
@@ -0,0 +1,115 @@
+//! What a completed run needs to be undone: the branch tip and the index
+//! from before it, and what it left them as, kept in a commit that
+//! [`UNDO_REF`] points at so that none of it is garbage collected.
+
+use anyhow::Result;
+
+use crate::AbsorbError;
+
+/// The commit with what the last run that moved HEAD changed, whose
+/// parents are HEAD from before and after it, and whose tree has the index
+/// from before and after it as the `before` and `after` directories.
+pub const UNDO_REF: &str = "refs/absorb/undo";
+
+/// HEAD and the index, as a run found or left them.
+pub struct State {
+    pub head: git2::Oid,
+    pub index: git2::Oid,
+}
+
+impl State {
+    pub fn current(repo: &git2::Repository) -> Result<State> {
+        Ok(State {
+            head: repo.head()?.peel_to_commit()?.id(),
+            index: repo.index()?.write_tree()?,
+        })
+    }
+}
+
+/// Records how to go back from what a run left, if it moved HEAD, to
+/// `before`. `fixups` are the fixup commits it made, each with its target.
+pub fn record(
+    repo: &git2::Repository,
+    before: &State,
+    fixups: &[(git2::Oid, git2::Oid)],
+) -> Result<()> {
+    let after = State::current(repo)?;
+    if after.head == before.head {
+        return Ok(());
+    }
+    let mut tree = repo.treebuilder(None)?;
+    tree.insert("before", before.index, git2::FileMode::Tree.into())?;
+    tree.insert("after", after.index, git2::FileMode::Tree.into())?;
+    let tree = repo.find_tree(tree.write()?)?;
+
+    let mut message = "absorb: undo information\n".to_owned();
+    if !fixups.is_empty() {
+        message += "\nfixups:\n";
+        for (fixup, target) in fixups {
+            message += &format!("{} {}\n", fixup, target);
+        }
+    }
+    let signature = crate::default_signature(repo)?;
+    let parents = [
+        &repo.find_commit(before.head)?,
+        &repo.find_commit(after.head)?,
+    ];
+    let undo = repo.commit(None, &signature, &signature, &message, &tree, &parents)?;
+    repo.reference(UNDO_REF, undo, true, "absorb: record undo")?;
+    Ok(())
+}
+
+/// Moves the branch back to where it was before the last run that moved
+/// it, and stages again what was staged then, as long as neither changed
+/// since. Returns the branch tip it moved away from.
+pub fn undo(repo: &git2::Repository) -> Result<git2::Oid> {
+    let undo = match repo.find_reference(UNDO_REF) {
+        Ok(reference) => reference.peel_to_commit()?,
+        Err(e) if e.code() == git2::ErrorCode::NotFound => {
+            return Err(AbsorbError::CannotUndo("there is no absorb to undo").into())
+        }
+        Err(e) => return Err(e.into()),
+    };
+    let tree = undo.tree()?;
+    let index_tree =
+        |name: &str| -> Result<git2::Oid> { Ok(tree.get_path(std::path::Path::new(name))?.id()) };
+    let before = State {
+        head: undo.parent_id(0)?,
+        index: index_tree("before")?,
+    };
+    let after = State {
+        head: undo.parent_id(1)?,
+        index: index_tree("after")?,
+    };
+
+    let current = State::current(repo)?;
+    if current.head != after.head {
+        return Err(AbsorbError::CannotUndo("HEAD moved since the last absorb").into());
+    }
+    if current.index != after.index {
+        return Err(AbsorbError::CannotUndo("the index changed since the last absorb").into());
+    }
+
+    // only the entries that the run changed go back, so that every other
+    // one keeps its flags and stat data
+    let before_index = repo.find_tree(before.index)?;
+    let changed = repo.diff_tree_to_tree(
+        Some(&repo.find_tree(after.index)?),
+        Some(&before_index),
+        None,
+    )?;
+    let mut paths = vec![];
+    for delta in changed.deltas() {
+        for file in [delta.old_file(), delta.new_file()] {
+            if let Some(path) = file.path() {
+                paths.push(path);
+            }
+        }
+    }
+    paths.sort();
+    paths.dedup();
+    crate::stage_tree_entries(repo, &before_index, &paths)?;
+    repo.head()?.set_target(before.head, "absorb: undo")?;
+    repo.find_reference(UNDO_REF)?.delete()?;
+    Ok(after.head)
+}
@@ -0,0 +1,68 @@
+use std::path::Path;
+use std::process::Command;
+
+#[test]
+fn git_dir_and_git_work_tree() {
+    // lay the repository out like `git init --separate-git-dir`, but without
+    // a .git file or core.worktree, so that it can only be found through
+    // the environment
+    let dir = tempfile::tempdir().unwrap();
+    let git_dir = dir.path().join("metadata.git");
+    let work_tree = dir.path().join("checkout");
+    let elsewhere = dir.path().join("elsewhere");
+    std::fs::create_dir(&work_tree).unwrap();
+    std::fs::create_dir(&elsewhere).unwrap();
+    let repo = git2::Repository::init_opts(
+        &git_dir,
+        git2::RepositoryInitOptions::new()
+            .initial_head("master")
+            .workdir_path(&work_tree)
+            .no_dotgit_dir(true),
+    )
+    .unwrap();
+    let mut config = repo.config().unwrap();
+    config.remove("core.worktree").unwrap();
+    config.set_str("user.name", "nobody").unwrap();
+    config.set_str("user.email", "nobody@example.com").unwrap();
+
+    let path = Path::new("test-file.txt");
+    std::fs::write(work_tree.join(path), "line\n").unwrap();
+    let mut index = repo.index().unwrap();
+    index.add_path(path).unwrap();
+    index.write().unwrap();
+    let tree = repo.find_tree(index.write_tree().unwrap()).unwrap();
+    let signature = repo.signature().unwrap();
+    repo.commit(
+        Some("HEAD"),
+        &signature,
+        &signature,
+        "Initial commit.",
+        &tree,
+        &[],
+    )
+    .unwrap();
+    std::fs::write(work_tree.join(path), "line\nnew_line\n").unwrap();
+    index.add_path(path).unwrap();
+    index.write().unwrap();
+
+    // run from a directory that is not in the repository at all
+    let output = Command::new(env!("CARGO_BIN_EXE_git-absorb"))
+        .current_dir(&elsewhere)
+        .env("GIT_DIR", &git_dir)
+        .env("GIT_WORK_TREE", &work_tree)
+        .output()
+        .unwrap();
+    assert!(
+        output.status.success(),
+        "{}",
+        String::from_utf8_lossy(&output.stderr)
+    );
+
+    let head = repo.head().unwrap().peel_to_commit().unwrap();
+    assert_eq!(head.message(), Some("fixup! Initial commit.\n"));
+    assert_eq!(head.parent(0).unwrap().message(), Some("Initial commit."));
+    let staged = repo
+        .diff_tree_to_index(Some(&head.tree().unwrap()), None, None)
+        .unwrap();
+    assert_eq!(staged.deltas().len(), 0);
+}
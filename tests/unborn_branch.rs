@@ -0,0 +1,21 @@
+use std::path::Path;
+use std::process::Command;
+
+#[test]
+fn nothing_to_absorb_into_exits_successfully() {
+    let dir = tempfile::tempdir().unwrap();
+    let repo = git2::Repository::init(dir.path()).unwrap();
+    let path = Path::new("test-file.txt");
+    std::fs::write(dir.path().join(path), "line\n").unwrap();
+    let mut index = repo.index().unwrap();
+    index.add_path(path).unwrap();
+    index.write().unwrap();
+
+    let output = Command::new(env!("CARGO_BIN_EXE_git-absorb"))
+        .current_dir(dir.path())
+        .output()
+        .unwrap();
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    assert!(output.status.success(), "{}", stderr);
+    assert!(stderr.contains("No commits to absorb into."), "{}", stderr);
+}